@@ -1,12 +1,20 @@
-mod bus;
+mod bootrom;
+pub(crate) mod bus;
 mod clint;
 pub mod cpu;
 mod csr;
-mod decode;
+pub mod debugger;
+pub mod decode;
 mod exception;
-mod instruction;
+pub mod instruction;
 mod interrupt;
+pub mod isa;
 mod mmu;
 mod plic;
+pub mod progen;
+mod proxy;
 mod reg;
+mod semihosting;
+mod syscon;
+pub mod timing;
 mod uart;
@@ -0,0 +1,22 @@
+//! The built-in first stage, which follows the usual boot convention of entering the next stage
+//! with the hart ID in a0 and the device tree address in a1.
+
+/// Code of the first stage, which loads its parameters from right after itself.
+const HANDOFF: [u32; 6] = [
+    0x00000297, // auipc t0, 0
+    0xf1402573, // csrr a0, mhartid
+    0x0182b583, // ld a1, 24(t0)
+    0x0202b283, // ld t0, 32(t0)
+    0x00028067, // jr t0
+    0x00000000, // padding to align the parameters
+];
+
+/// Build a boot ROM image that jumps to `entry` with `dtb` in a1.
+pub(crate) fn handoff(entry: u64, dtb: u64) -> Vec<u8> {
+    HANDOFF
+        .iter()
+        .flat_map(|inst| inst.to_le_bytes())
+        .chain(dtb.to_le_bytes())
+        .chain(entry.to_le_bytes())
+        .collect()
+}
@@ -1,18 +1,33 @@
+use std::io::{self, Write};
+
 use crate::{bus::Bus, mem::Memory};
 
-use super::{clint, exception::Exception, plic, uart};
+use super::{clint, exception::Exception, plic, syscon, uart};
 
 pub struct RiscvBus {
     mem: Memory,
-    plic: plic::Plic,
-    clint: clint::Clint,
+    pub(crate) plic: plic::Plic,
+    pub(crate) clint: clint::Clint,
     pub(crate) uart: uart::Uart,
+    pub(crate) syscon: syscon::Syscon,
+    /// Read-only memory at `BOOTROM_BASE`, as large as the image it was given.
+    rom: Option<Memory>,
+    /// Log every access to a device region at `debug!` level.
+    pub(crate) mmio_trace: bool,
 }
 
-const DRAM_BASE: u64 = 0x8000_0000;
-const DRAM_SIZE: u64 = 1024 * 1024 * 128;
+pub(crate) const DRAM_BASE: u64 = 0x8000_0000;
+pub(crate) const DRAM_SIZE: u64 = 1024 * 1024 * 128;
 const DRAM_END: u64 = DRAM_SIZE + DRAM_BASE - 1;
 
+pub(crate) const BOOTROM_BASE: u64 = 0x1000;
+pub(crate) const BOOTROM_SIZE: u64 = 0xf000;
+const BOOTROM_END: u64 = BOOTROM_BASE + BOOTROM_SIZE - 1;
+
+pub(crate) const SYSCON_BASE: u64 = 0x10_0000;
+pub(crate) const SYSCON_SIZE: u64 = 0x1000;
+pub(crate) const SYSCON_END: u64 = SYSCON_BASE + SYSCON_SIZE - 1;
+
 pub(crate) const PLIC_BASE: u64 = 0xc00_0000;
 pub(crate) const PLIC_SIZE: u64 = 0x4000000;
 pub(crate) const PLIC_END: u64 = PLIC_BASE + PLIC_SIZE - 1;
@@ -29,16 +44,52 @@ impl RiscvBus {
     pub fn new() -> Self {
         Self {
             mem: Memory::new(crate::mem::Endianness::Little),
-            plic: plic::Plic::new(),
+            plic: plic::Plic::new(plic::DEFAULT_SOURCE_COUNT),
             clint: clint::Clint::new(),
             uart: uart::Uart::new(),
+            syscon: syscon::Syscon::new(),
+            rom: None,
+            mmio_trace: false,
         }
     }
 
+    /// Whether instructions may be fetched from `addr`. Only memory is executable: fetching from a
+    /// device would read its registers, with whatever side effects that has.
+    pub fn is_executable(&self, addr: u64) -> bool {
+        matches!(addr, DRAM_BASE..=DRAM_END | BOOTROM_BASE..=BOOTROM_END)
+    }
+
     pub fn init(&mut self) {
         self.mem.init(DRAM_SIZE);
     }
 
+    /// Fill DRAM with `pattern`, so reads of memory the guest never wrote stand out.
+    pub fn poison_dram(&mut self, pattern: u8) {
+        self.mem.fill(pattern);
+    }
+
+    /// Map `image` as read-only memory at `BOOTROM_BASE`, replacing any previous one.
+    pub fn set_rom(&mut self, image: &[u8]) -> Result<(), Exception> {
+        if image.len() as u64 > BOOTROM_SIZE {
+            return Err(Exception::StoreAMOAccessFault(BOOTROM_END + 1));
+        }
+        let mut rom = Memory::new(crate::mem::Endianness::Little);
+        rom.init(image.len() as u64);
+        rom.load_data(image, 0)
+            .map_err(|_| Exception::StoreAMOAccessFault(BOOTROM_END + 1))?;
+        self.rom = Some(rom);
+        Ok(())
+    }
+
+    /// Read from the ROM, which only covers the length of its image.
+    fn load_rom(&self, addr: u64, size: u64) -> Result<u64, Exception> {
+        let offset = addr - BOOTROM_BASE;
+        match &self.rom {
+            Some(rom) if offset + size <= rom.data.len() as u64 => Ok(rom.load(offset, size)),
+            _ => Err(Exception::LoadAccessFault(addr)),
+        }
+    }
+
     pub fn load_byte(&self, addr: u64) -> Result<u8, Exception> {
         match addr {
             DRAM_BASE..=DRAM_END => Ok(self.mem.read_u8(addr - DRAM_BASE)),
@@ -46,13 +97,28 @@ impl RiscvBus {
         }
     }
 
+    /// Copy `data` to DRAM at `addr`. Nothing is written if any of it would fall outside of DRAM.
     pub fn load_data(&mut self, addr: u64, data: &[u8]) -> Result<(), Exception> {
         match addr {
-            DRAM_BASE..=DRAM_END => Ok(self.mem.load_data(data, addr - DRAM_BASE)),
-            _ => Err(Exception::LoadAccessFault(addr)),
+            DRAM_BASE..=DRAM_END => self
+                .mem
+                .load_data(data, addr - DRAM_BASE)
+                .map_err(|end| Exception::StoreAMOAccessFault(DRAM_BASE + end)),
+            _ => Err(Exception::StoreAMOAccessFault(addr)),
         }
     }
 
+    /// Write the DRAM contents in `[addr, addr + len)` to `out`.
+    pub fn dump_dram(&self, out: &mut impl Write, addr: u64, len: u64) -> io::Result<()> {
+        if !(DRAM_BASE..=DRAM_END).contains(&addr) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("address {:#x} is not in DRAM", addr),
+            ));
+        }
+        self.mem.dump(out, addr - DRAM_BASE, len)
+    }
+
     pub fn load_half(&self, addr: u64) -> Result<u16, Exception> {
         match addr {
             DRAM_BASE..=DRAM_END => Ok(self.mem.read_u16(addr - DRAM_BASE)),
@@ -76,28 +142,40 @@ impl RiscvBus {
 
     pub fn store_byte(&mut self, addr: u64, data: u8) -> Result<(), Exception> {
         match addr {
-            DRAM_BASE..=DRAM_END => Ok(self.mem.write_u8(addr - DRAM_BASE, data)),
+            DRAM_BASE..=DRAM_END => {
+                self.mem.write_u8(addr - DRAM_BASE, data);
+                Ok(())
+            }
             _ => Err(Exception::StoreAMOAccessFault(addr)),
         }
     }
 
     pub fn store_half(&mut self, addr: u64, data: [u8; 2]) -> Result<(), Exception> {
         match addr {
-            DRAM_BASE..=DRAM_END => Ok(self.mem.write_u16(addr - DRAM_BASE, data)),
+            DRAM_BASE..=DRAM_END => {
+                self.mem.write_u16(addr - DRAM_BASE, data);
+                Ok(())
+            }
             _ => Err(Exception::StoreAMOAccessFault(addr)),
         }
     }
 
     pub fn store_word(&mut self, addr: u64, data: [u8; 4]) -> Result<(), Exception> {
         match addr {
-            DRAM_BASE..=DRAM_END => Ok(self.mem.write_u32(addr - DRAM_BASE, data)),
+            DRAM_BASE..=DRAM_END => {
+                self.mem.write_u32(addr - DRAM_BASE, data);
+                Ok(())
+            }
             _ => Err(Exception::StoreAMOAccessFault(addr)),
         }
     }
 
     pub fn store_double(&mut self, addr: u64, data: [u8; 8]) -> Result<(), Exception> {
         match addr {
-            DRAM_BASE..=DRAM_END => Ok(self.mem.write_u64(addr - DRAM_BASE, data)),
+            DRAM_BASE..=DRAM_END => {
+                self.mem.write_u64(addr - DRAM_BASE, data);
+                Ok(())
+            }
             _ => Err(Exception::StoreAMOAccessFault(addr)),
         }
     }
@@ -106,23 +184,111 @@ impl RiscvBus {
 impl Bus for RiscvBus {
     type Exception = Exception;
 
-    fn load(&mut self, addr: u64, size: u64) -> Result<u64, Self::Exception> {
-        match addr {
-            DRAM_BASE..=DRAM_END => Ok(self.mem.load(addr - DRAM_BASE, size)),
+    fn load(&self, addr: u64, size: u64) -> Result<u64, Self::Exception> {
+        if let DRAM_BASE..=DRAM_END = addr {
+            return Ok(self.mem.load(addr - DRAM_BASE, size));
+        }
+        if let BOOTROM_BASE..=BOOTROM_END = addr {
+            return self.load_rom(addr, size);
+        }
+        let value = match addr {
             PLIC_BASE..=PLIC_END => self.plic.load(addr - PLIC_BASE, size),
             CLINT_BASE..=CLINT_END => self.clint.load(addr - CLINT_BASE, size),
             UART_BASE..=UART_END => self.uart.load(addr - UART_BASE, size),
+            SYSCON_BASE..=SYSCON_END => self.syscon.load(addr - SYSCON_BASE, size),
             _ => Err(Exception::LoadAccessFault(addr)),
+        }?;
+        if self.mmio_trace {
+            log::debug!("mmio load  {:#x} size {} -> {:#x}", addr, size, value);
         }
+        Ok(value)
     }
 
     fn store(&mut self, addr: u64, size: u64, data: u64) -> Result<(), Self::Exception> {
+        if let DRAM_BASE..=DRAM_END = addr {
+            self.mem.store(addr - DRAM_BASE, size, data);
+            return Ok(());
+        }
+        if let BOOTROM_BASE..=BOOTROM_END = addr {
+            return Err(Exception::StoreAMOAccessFault(addr));
+        }
         match addr {
-            DRAM_BASE..=DRAM_END => Ok(self.mem.store(addr - DRAM_BASE, size, data)),
             PLIC_BASE..=PLIC_END => self.plic.store(addr - PLIC_BASE, size, data),
             CLINT_BASE..=CLINT_END => self.clint.store(addr - CLINT_BASE, size, data),
             UART_BASE..=UART_END => self.uart.store(addr - UART_BASE, size, data),
+            SYSCON_BASE..=SYSCON_END => self.syscon.store(addr - SYSCON_BASE, size, data),
             _ => Err(Exception::StoreAMOAccessFault(addr)),
+        }?;
+        if self.mmio_trace {
+            log::debug!("mmio store {:#x} size {} <- {:#x}", addr, size, data);
         }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Mutex;
+
+    use log::{Level, Log, Metadata, Record};
+
+    use super::{RiscvBus, CLINT_BASE, DRAM_BASE, DRAM_END, PLIC_BASE};
+    use crate::arch::riscv::{clint::CLINT_MTIMECMP, exception::Exception};
+    use crate::bus::Bus;
+
+    static LINES: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+    struct Capture;
+
+    impl Log for Capture {
+        fn enabled(&self, metadata: &Metadata) -> bool {
+            metadata.level() <= Level::Debug
+        }
+
+        fn log(&self, record: &Record) {
+            LINES.lock().unwrap().push(record.args().to_string());
+        }
+
+        fn flush(&self) {}
+    }
+
+    #[test]
+    fn test_mmio_trace() {
+        static LOGGER: Capture = Capture;
+        log::set_logger(&LOGGER).unwrap();
+        log::set_max_level(log::LevelFilter::Debug);
+
+        let mut bus = RiscvBus::new();
+        bus.mmio_trace = true;
+        bus.store(CLINT_BASE + CLINT_MTIMECMP, 8, 0x1234).unwrap();
+        bus.load(PLIC_BASE, 4).unwrap();
+
+        let lines = LINES.lock().unwrap();
+        let trace: Vec<_> = lines.iter().filter(|l| l.starts_with("mmio")).collect();
+        assert_eq!(
+            trace,
+            [
+                "mmio store 0x2004000 size 8 <- 0x1234",
+                "mmio load  0xc000000 size 4 -> 0x0",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_load_data() {
+        let mut bus = RiscvBus::new();
+        bus.init();
+        assert_eq!(bus.load_data(DRAM_END - 1, &[1, 2]), Ok(()));
+        assert_eq!(bus.load(DRAM_END - 1, 2), Ok(0x0201));
+        // a blob running off the end of DRAM is rejected as a whole
+        assert_eq!(
+            bus.load_data(DRAM_END - 1, &[3; 4]),
+            Err(Exception::StoreAMOAccessFault(DRAM_END + 1))
+        );
+        assert_eq!(bus.load(DRAM_END - 1, 2), Ok(0x0201));
+        assert_eq!(
+            bus.load_data(DRAM_BASE - 4, &[3; 8]),
+            Err(Exception::StoreAMOAccessFault(DRAM_BASE - 4))
+        );
     }
 }
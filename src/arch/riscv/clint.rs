@@ -1,23 +1,61 @@
-use super::{bus::*, exception::Exception};
+use super::exception::Exception;
 
+/// The core-local interruptor. `mtime` is a virtual clock counting retired instructions rather than
+/// host time, so runs are reproducible.
 pub struct Clint {
     mtime: u64,
     mtimecmp: u64,
+    /// How many ticks make `mtime` advance by one.
+    ticks_per_increment: u64,
+    /// Ticks since `mtime` last advanced.
+    ticks: u64,
 }
 
-pub(crate) const CLINT_MTIMECMP: u64 = CLINT_BASE + 0x4000;
-pub(crate) const CLINT_MTIME: u64 = CLINT_BASE + 0xbff8;
+// Register offsets relative to `CLINT_BASE`.
+pub(crate) const CLINT_MTIMECMP: u64 = 0x4000;
+pub(crate) const CLINT_MTIME: u64 = 0xbff8;
 
 impl Clint {
     pub fn new() -> Self {
         Self {
             mtime: 0,
-            mtimecmp: 0,
+            // no timer interrupt until software programs a deadline
+            mtimecmp: u64::MAX,
+            ticks_per_increment: 1,
+            ticks: 0,
         }
     }
 
+    /// Make `mtime` advance once every `ticks` ticks.
+    pub fn set_ticks_per_increment(&mut self, ticks: u64) {
+        self.ticks_per_increment = ticks.max(1);
+        self.ticks = 0;
+    }
+
+    /// Count one tick, advancing `mtime` when enough have passed.
+    pub fn tick(&mut self) {
+        self.ticks += 1;
+        if self.ticks >= self.ticks_per_increment {
+            self.ticks = 0;
+            self.mtime = self.mtime.wrapping_add(1);
+        }
+    }
+
+    pub fn mtime(&self) -> u64 {
+        self.mtime
+    }
+
+    pub fn set_mtimecmp(&mut self, value: u64) {
+        self.mtimecmp = value;
+    }
+
+    /// Return true while `mtime` has reached `mtimecmp`, the level of the machine timer interrupt.
+    pub fn is_interrupting(&self) -> bool {
+        self.mtime >= self.mtimecmp
+    }
+
     pub fn load(&self, addr: u64, size: u64) -> Result<u64, Exception> {
-        if size != 64 {
+        if size != 8 {
             return Err(Exception::LoadAccessFault(addr));
         }
         match addr {
@@ -28,12 +66,18 @@ impl Clint {
     }
 
     pub fn store(&mut self, addr: u64, size: u64, value: u64) -> Result<(), Exception> {
-        if size != 64 {
-            return Err(Exception::LoadAccessFault(addr));
+        if size != 8 {
+            return Err(Exception::StoreAMOAccessFault(addr));
         }
         match addr {
-            CLINT_MTIMECMP => Ok(self.mtimecmp = value),
-            CLINT_MTIME => Ok(self.mtime = value),
+            CLINT_MTIMECMP => {
+                self.mtimecmp = value;
+                Ok(())
+            }
+            CLINT_MTIME => {
+                self.mtime = value;
+                Ok(())
+            }
             _ => Err(Exception::StoreAMOAccessFault(addr)),
         }
     }
@@ -1,29 +1,72 @@
+use std::{
+    collections::VecDeque,
+    fmt,
+    io::{self, Write},
+    ops::Range,
+    time::{Duration, Instant},
+};
+
 use crate::{
-    arch::riscv::{csr::*, plic::get_plic_claim_or_complete, uart::UART_IRQ},
+    arch::riscv::{csr::*, uart::UART_IRQ},
     bus::Bus,
     cpu::Cpu,
-    util::{addr_add, double_classify, float_classify},
+    util::{
+        addr_add, double_classify, float_classify, round_by_mode, signaling_nan,
+        signaling_nan_double,
+    },
 };
 
 use super::{
-    bus::RiscvBus,
+    bootrom,
+    bus::{RiscvBus, BOOTROM_BASE, DRAM_BASE, DRAM_SIZE},
     csr::Csrs,
-    decode::{decode, decode_compressed},
+    decode::{decode_compressed_for, decode_for, Block, DecodeCache},
     exception::Exception,
     instruction::{RiscvInst, RiscvInstWrapper},
     interrupt::Interrupt,
+    isa::Isa,
     mmu::{
-        AccessType::{Load, Store},
-        MMU,
+        AccessType::{self, Instruction, Load, Store},
+        Mmu, PAGE_SIZE,
+    },
+    proxy::{
+        self, ProxyKernel, EBADF, EFAULT, ENOSYS, INITIAL_STACK, STACK_SIZE, STAT_BLKSIZE,
+        STAT_MODE, STAT_SIZE, STDERR, STDIN, STDOUT, SYS_BRK, SYS_EXIT_GROUP, SYS_FSTAT, SYS_WRITE,
+        S_IFCHR_RW,
     },
+    reg::{A0, A1, A2, A7, SP},
+    semihosting::{
+        ADP_STOPPED_APPLICATION_EXIT, ENTRY_MARKER, EXIT_MARKER, SYS_EXIT, SYS_WRITE0, SYS_WRITEC,
+    },
+    timing::{InstLatency, MemLatency, MemTiming},
 };
 
 pub(crate) const HART_COUNT: usize = 8;
 pub(crate) const MAX_HART_COUNT: u64 = 0x3E00;
 
-const MACHINE_MODE: u8 = 0;
-const SUPERVISOR_MODE: u8 = 1;
-const USER_MODE: u8 = 2;
+// Privilege levels, encoded as in the `mstatus.MPP` field.
+pub(crate) const USER_MODE: u8 = 0;
+pub(crate) const SUPERVISOR_MODE: u8 = 1;
+pub(crate) const MACHINE_MODE: u8 = 3;
+
+// Default values of the machine ID CSRs. There is no JEDEC vendor ID, and the architecture ID
+// spells "remu".
+pub const DEFAULT_MVENDORID: u64 = 0;
+pub const DEFAULT_MARCHID: u64 = 0x7265_6d75;
+pub const DEFAULT_MIMPID: u64 = 1;
+
+/// Size of the aligned block an LR reserves. Like on real hardware, a store to any byte of the
+/// block makes a later SC fail.
+pub(crate) const RESERVATION_GRANULE: u64 = 64;
+
+/// Size of the block `cbo.zero` clears.
+const CACHE_BLOCK_SIZE: u64 = 64;
+
+// Single-precision values live in the low half of the 64-bit float registers with the upper half all
+// ones; anything else reads back as the canonical NaN.
+const NAN_BOX: u64 = 0xffff_ffff_0000_0000;
+const CANONICAL_NAN_F32: u32 = 0x7fc0_0000;
+const CANONICAL_NAN_F64: u64 = 0x7ff8_0000_0000_0000;
 
 pub struct RV64Cpu {
     pub(crate) clock: u64,
@@ -31,893 +74,2181 @@ pub struct RV64Cpu {
     pub(crate) x: [u64; 32],
     pub(crate) f: [f64; 32],
     pub(crate) bus: RiscvBus,
-    pub(crate) mmu: MMU,
+    pub(crate) mmu: Mmu,
     pub(crate) csr: Csrs,
     pub(crate) mode: u8,
     pub(crate) hart_id: u64,
+    /// Physical ranges that fault on any load or store, used to catch stray guest accesses.
+    guard_regions: Vec<Range<u64>>,
+    breakpoints: Vec<u64>,
+    latency: InstLatency,
+    /// Decides what loads and stores cost on top of `latency`.
+    mem_timing: MemTiming,
+    /// Cycles the loads and stores of the current instruction spent in memory.
+    mem_cycles: u64,
+    /// Instructions retired since `minstret` was last brought up to date.
+    unflushed_instret: u64,
+    /// Instructions retired in total, whatever the guest does to `minstret`.
+    retired: u64,
+    /// Traps taken in total, both exceptions and interrupts.
+    traps: u64,
+    /// Raw encoding of the last fetched instruction.
+    inst_bits: u32,
+    /// Trap to the handler on an illegal instruction instead of halting.
+    trap_illegal: bool,
+    /// Stop `run` at an `ebreak` instead of trapping.
+    halt_on_ebreak: bool,
+    /// Which exceptions halt the emulator.
+    fatal_policy: FatalPolicy,
+    /// Whether the last step took a trap and no instruction has retired since.
+    in_trap_entry: bool,
+    /// Traps taken since an instruction last retired.
+    consecutive_traps: u64,
+    /// How many traps in a row halt with `HaltReason::TrapStorm`, if any.
+    trap_storm_limit: Option<u64>,
+    /// Physical address of the reservation granule held by the last LR.
+    reservation: Option<u64>,
+    /// Where semihosting calls write to, if they are enabled.
+    semihosting: Option<Box<dyn Write>>,
+    /// Emulates the system calls of a user-mode program, if enabled.
+    proxy: Option<ProxyKernel>,
+    /// The extensions instructions are decoded for.
+    isa: Isa,
+    /// Basic blocks already decoded, flushed by `fence.i`.
+    decode_cache: DecodeCache,
+    /// The block the instructions fetched next are expected to come from.
+    block_cursor: Option<BlockCursor>,
+    /// How many times `fetch` translated the pc and looked up a block.
+    block_lookups: u64,
+    /// The last instructions fetched, oldest first.
+    trace: VecDeque<TraceEntry>,
+    /// How many instructions `trace` keeps, zero when tracing is off.
+    trace_len: usize,
+}
+
+/// An instruction fetched by `step`, kept for post-mortem debugging.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TraceEntry {
+    pub pc: u64,
+    pub bits: u32,
+    pub inst: RiscvInst,
+}
+
+/// A value `read_array` can read from guest memory, where it is stored little-endian.
+pub trait GuestValue: Sized {
+    const SIZE: usize;
+
+    /// Assemble the value from its `SIZE` bytes.
+    fn from_le_bytes(bytes: &[u8]) -> Self;
+}
+
+macro_rules! impl_guest_value {
+    ($($ty:ty),*) => {
+        $(
+            impl GuestValue for $ty {
+                const SIZE: usize = std::mem::size_of::<$ty>();
+
+                fn from_le_bytes(bytes: &[u8]) -> Self {
+                    <$ty>::from_le_bytes(bytes.try_into().expect("a value is SIZE bytes"))
+                }
+            }
+        )*
+    };
+}
+
+impl_guest_value!(u8, u16, u32, u64, i8, i16, i32, i64, f32, f64);
+
+/// Why `run` stopped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HaltReason {
+    /// An exception the emulator cannot recover from.
+    Fatal(Exception),
+    /// The pc reached a breakpoint, before executing the instruction there.
+    Breakpoint(u64),
+    /// The guest powered off the machine with an exit code.
+    Exit(u32),
+    /// The hart kept taking traps without retiring an instruction, as when a trap handler
+    /// faults straight away.
+    TrapStorm,
+}
+
+/// Where `fetch` is in the block it last looked up. The instructions after the first come
+/// straight from the block, as long as the pc, the privilege mode and `satp` are as expected.
+struct BlockCursor {
+    block: Block,
+    index: usize,
+    pc: u64,
+    mode: u8,
+    satp: u64,
+}
+
+/// The most instructions a decoded block holds.
+const MAX_BLOCK_LEN: usize = 64;
+
+/// A summary of a run, as returned by `run_with_stats`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RunStats {
+    pub instructions: u64,
+    pub cycles: u64,
+    pub elapsed: Duration,
+    pub traps: u64,
+    pub halt: HaltReason,
+}
+
+impl RunStats {
+    /// Millions of instructions executed per second of wall-clock time.
+    pub fn mips(&self) -> f64 {
+        self.instructions as f64 / self.elapsed.as_secs_f64().max(f64::MIN_POSITIVE) / 1e6
+    }
+}
+
+impl fmt::Display for RunStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "halted:       {:?}", self.halt)?;
+        writeln!(f, "instructions: {}", self.instructions)?;
+        writeln!(f, "cycles:       {}", self.cycles)?;
+        writeln!(f, "time:         {:.3?}", self.elapsed)?;
+        writeln!(f, "MIPS:         {:.2}", self.mips())?;
+        write!(f, "traps:        {}", self.traps)
+    }
+}
+
+/// `ebreak`, which stops `run` with `HaltReason::Breakpoint` once `set_halt_on_ebreak` is on.
+/// Snippets end with it.
+pub const HALT: u32 = 0x0010_0073;
+
+/// Run `instructions` from the start of DRAM until they reach the `HALT` appended to them, or
+/// otherwise halt, for architecture tests. `setup` sees the CPU after loading, with the pc at the
+/// first instruction, and the final CPU is returned for inspection.
+pub fn run_snippet(instructions: &[u32], setup: impl FnOnce(&mut RV64Cpu)) -> RV64Cpu {
+    let mut cpu = load_snippet(instructions);
+    setup(&mut cpu);
+    cpu.run();
+    cpu
+}
+
+/// Load `instructions` followed by a `HALT` at the start of DRAM, ready for `run_snippet` to run.
+pub fn load_snippet(instructions: &[u32]) -> RV64Cpu {
+    let data: Vec<u8> = instructions
+        .iter()
+        .chain([&HALT])
+        .flat_map(|inst| inst.to_le_bytes())
+        .collect();
+    let mut cpu = RV64Cpu::new();
+    cpu.init();
+    Cpu::load(&mut cpu, data);
+    cpu.pc = DRAM_BASE;
+    cpu.set_halt_on_ebreak(true);
+    cpu
+}
+
+/// Which exceptions stop `run` instead of being taken by the guest's handler.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FatalPolicy {
+    /// Exception codes that halt, as a bit mask indexed by `mcause`.
+    pub causes: u64,
+    /// Halt on an exception raised before the handler of the previous one retires anything, which
+    /// would otherwise trap over and over.
+    pub double_fault: bool,
+}
+
+impl FatalPolicy {
+    /// Let the guest handle every exception, only halting when its handler faults straight away.
+    pub fn log_and_continue() -> Self {
+        Self {
+            causes: 0,
+            double_fault: true,
+        }
+    }
+
+    fn is_fatal(&self, e: Exception) -> bool {
+        self.causes & (1 << e.code()) != 0
+    }
+}
+
+impl Default for FatalPolicy {
+    /// Halt on the exceptions `Exception::is_fatal` picks, which the emulator assumes a guest can't
+    /// recover from.
+    fn default() -> Self {
+        Self {
+            causes: Exception::ALL
+                .iter()
+                .filter(|e| e.is_fatal())
+                .fold(0, |causes, e| causes | (1 << e.code())),
+            double_fault: true,
+        }
+    }
+}
+
+impl Default for RV64Cpu {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl RV64Cpu {
-    fn new() -> Self {
+    pub fn new() -> Self {
+        let isa = Isa::default();
+        let mut csr = Csrs::new();
+        csr.store(MISA, isa.misa());
+        csr.store(MVENDORID, DEFAULT_MVENDORID);
+        csr.store(MARCHID, DEFAULT_MARCHID);
+        csr.store(MIMPID, DEFAULT_MIMPID);
         Self {
             clock: 0,
             pc: 0,
             x: [0; 32],
             f: [0.0; 32],
             bus: RiscvBus::new(),
-            mmu: MMU::new(),
-            csr: Csrs::new(),
+            mmu: Mmu::new(),
+            csr,
             mode: MACHINE_MODE,
             hart_id: 0,
+            guard_regions: Vec::new(),
+            breakpoints: Vec::new(),
+            unflushed_instret: 0,
+            retired: 0,
+            traps: 0,
+            latency: InstLatency::default(),
+            mem_timing: MemTiming::default(),
+            mem_cycles: 0,
+            inst_bits: 0,
+            trap_illegal: false,
+            halt_on_ebreak: false,
+            fatal_policy: FatalPolicy::default(),
+            in_trap_entry: false,
+            consecutive_traps: 0,
+            trap_storm_limit: None,
+            reservation: None,
+            semihosting: None,
+            proxy: None,
+            isa,
+            decode_cache: DecodeCache::default(),
+            block_cursor: None,
+            block_lookups: 0,
+            trace: VecDeque::new(),
+            trace_len: 0,
         }
     }
 
-    fn load(&mut self, addr: u64, size: u64) -> Result<u64, Exception> {
-        let addr = self.mmu.translate(Load, &mut self.bus, addr)?;
-        self.bus.load(addr, size)
-    }
+    /// Execute a single instruction, taking any trap or pending interrupt it leads to.
+    /// Returns the reason to halt if the instruction raised a fatal exception.
+    pub fn step(&mut self) -> Option<HaltReason> {
+        // A trap resumes execution at the trap vector set up by `handle_exception`.
+        let mut cycles = 1;
+        self.mem_cycles = 0;
+        let result = self.fetch().and_then(|inst| {
+            cycles = self.latency.cycles(&inst.get_inst());
+            self.record_trace(inst.get_inst());
+            // `minstret` is only brought up to date at the end of a basic block, or before an
+            // instruction that may observe or modify the counters.
+            if ends_batch(&inst.get_inst()) {
+                self.flush_instret();
+            }
+            self.execute(inst)
+        });
+        cycles += self.mem_cycles;
+        let double_fault = self.in_trap_entry && self.fatal_policy.double_fault;
+        self.in_trap_entry = result.is_err();
+        match result {
+            Err(Exception::Breakpoint(pc)) if self.halt_on_ebreak => {
+                self.update_counters(cycles, false);
+                self.flush_instret();
+                self.in_trap_entry = false;
+                return Some(HaltReason::Breakpoint(pc));
+            }
+            Ok(new_pc) => {
+                self.pc = new_pc;
+                self.update_counters(cycles, true);
+                if let Some(code) = self.bus.syscon.exit_code() {
+                    return Some(HaltReason::Exit(code));
+                }
+            }
+            Err(e) if double_fault => {
+                self.update_counters(cycles, false);
+                self.flush_instret();
+                log::error!("{:?} at {:#x} while entering the previous trap", e, self.pc);
+                self.in_trap_entry = false;
+                return Some(HaltReason::Fatal(e));
+            }
+            // A real core reports the offending encoding in mtval and lets the handler emulate it.
+            Err(Exception::IllegalInstruction(_)) if self.trap_illegal => {
+                self.update_counters(cycles, false);
+                self.flush_instret();
+                self.handle_exception(Exception::IllegalInstruction(self.inst_bits.into()));
+            }
+            Err(e) => {
+                self.update_counters(cycles, false);
+                self.flush_instret();
+                self.handle_exception(e);
+                if self.fatal_policy.is_fatal(e) {
+                    self.in_trap_entry = false;
+                    return Some(HaltReason::Fatal(e));
+                }
+                if e.is_fatal() {
+                    log::warn!("continuing past {:?}", e);
+                }
+            }
+        }
 
-    fn store(&mut self, addr: u64, size: u64, data: u64) -> Result<(), Exception> {
-        let addr = self.mmu.translate(Store, &mut self.bus, addr)?;
-        self.bus.store(addr, size, data)
-    }
+        self.bus.clint.tick();
+        self.update_timer_interrupt();
 
-    pub fn update_paging(&mut self, csr_addr: usize) {
-        if csr_addr != SATP {
-            return;
+        if let Some(interrupt) = self.check_pending_interrupt() {
+            self.handle_interrupt(interrupt);
         }
-
-        let satp = self.csr.load(SATP);
-        self.mmu.set_ppn(satp.into());
+        if self
+            .trap_storm_limit
+            .is_some_and(|limit| self.consecutive_traps >= limit)
+        {
+            log::error!(
+                "{} traps in a row at {:#x}",
+                self.consecutive_traps,
+                self.pc
+            );
+            self.consecutive_traps = 0;
+            return Some(HaltReason::TrapStorm);
+        }
+        None
     }
 
-    pub fn check_pending_interrupt(&mut self) -> Option<Interrupt> {
-        use Interrupt::*;
-        if (self.mode == MACHINE_MODE) && (self.csr.load(MSTATUS) & MASK_MIE) == 0 {
-            return None;
+    fn record_trace(&mut self, inst: RiscvInst) {
+        if self.trace_len == 0 {
+            return;
         }
-        if (self.mode == SUPERVISOR_MODE) && (self.csr.load(SSTATUS) & MASK_SIE) == 0 {
-            return None;
+        if self.trace.len() == self.trace_len {
+            self.trace.pop_front();
         }
+        self.trace.push_back(TraceEntry {
+            pc: self.pc,
+            bits: self.inst_bits,
+            inst,
+        });
+    }
 
-        // In fact, we should using priority to decide which interrupt should be handled first.
-        if self.bus.uart.is_interrupting() {
-            self.bus
-                .store(
-                    get_plic_claim_or_complete(self.hart_id, SUPERVISOR_MODE),
-                    4,
-                    UART_IRQ,
-                )
-                .unwrap();
-            self.csr.set(MIP, MASK_SSIP);
+    /// The last instructions `step` fetched, oldest first, including one that faulted.
+    pub fn trace(&self) -> impl Iterator<Item = &TraceEntry> {
+        self.trace.iter()
+    }
+
+    /// Keep the last `len` fetched instructions, logged when `run` halts on a fatal exception.
+    /// Zero turns tracing off.
+    pub fn set_trace_len(&mut self, len: usize) {
+        self.trace_len = len;
+        while self.trace.len() > len {
+            self.trace.pop_front();
         }
-        let pending = self.csr.load(MIE) & self.csr.load(MIP);
+    }
 
-        if (pending & MASK_MEIP) != 0 {
-            self.csr.clear(MIP, MASK_MEIP);
-            return Some(MachineExternalInterrupt);
+    /// Copy `data` to the physical address `addr`. Data outside of DRAM is written to the devices
+    /// there a byte at a time. Nothing is written if the data would run off the end of DRAM.
+    pub fn load_at(&mut self, addr: u64, data: &[u8]) -> Result<(), Exception> {
+        self.flush_decode_cache();
+        if (DRAM_BASE..DRAM_BASE + DRAM_SIZE).contains(&addr) {
+            return self.bus.load_data(addr, data);
         }
-        if (pending & MASK_MSIP) != 0 {
-            self.csr.clear(MIP, MASK_MSIP);
-            return Some(MachineSoftwareInterrupt);
+        for (offset, &byte) in data.iter().enumerate() {
+            self.bus.store(addr + offset as u64, 1, byte.into())?;
         }
-        if (pending & MASK_MTIP) != 0 {
-            self.csr.clear(MIP, MASK_MTIP);
-            return Some(MachineTimerInterrupt);
+        Ok(())
+    }
+
+    /// Run until the machine halts, like `run`, and summarize what happened along the way.
+    pub fn run_with_stats(&mut self) -> RunStats {
+        let (retired, clock, traps) = (self.retired, self.clock, self.traps);
+        let start = Instant::now();
+        let halt = self.run();
+        RunStats {
+            instructions: self.retired - retired,
+            cycles: self.clock.wrapping_sub(clock),
+            elapsed: start.elapsed(),
+            traps: self.traps - traps,
+            halt,
         }
-        if (pending & MASK_SEIP) != 0 {
-            self.csr.clear(MIP, MASK_SEIP);
-            return Some(SupervisorExternalInterrupt);
+    }
+
+    /// Execute `count` instructions, ignoring breakpoints. Returns early if the machine halts.
+    pub fn run_for(&mut self, count: u64) -> Option<HaltReason> {
+        let reason = (0..count).find_map(|_| self.step());
+        self.flush_instret();
+        reason
+    }
+
+    /// Stop `run` once the pc reaches `addr`.
+    pub fn add_breakpoint(&mut self, addr: u64) {
+        if !self.breakpoints.contains(&addr) {
+            self.breakpoints.push(addr);
         }
-        if (pending & MASK_SSIP) != 0 {
-            self.csr.clear(MIP, MASK_SSIP);
-            return Some(SupervisorSoftwareInterrupt);
+    }
+
+    pub fn remove_breakpoint(&mut self, addr: u64) {
+        self.breakpoints.retain(|&bp| bp != addr);
+    }
+
+    /// The addresses `run` stops at, in the order they were added.
+    pub fn breakpoints(&self) -> &[u64] {
+        &self.breakpoints
+    }
+
+    /// Remove every breakpoint, letting `run` go on until the guest halts.
+    pub fn clear_breakpoints(&mut self) {
+        self.breakpoints.clear();
+    }
+
+    /// Check that a branch or jump target is on an instruction boundary. With the C extension
+    /// instructions only need to be 2-byte aligned.
+    fn jump_target(&self, target: u64) -> Result<u64, Exception> {
+        if !target.is_multiple_of(self.inst_align()) {
+            return Err(Exception::InstructionAddrMisaligned(target));
         }
-        if (pending & MASK_STIP) != 0 {
-            self.csr.clear(MIP, MASK_STIP);
-            return Some(SupervisorTimerInterrupt);
+        Ok(target)
+    }
+
+    /// The alignment of instructions, which is relaxed to 2 bytes by the C extension.
+    fn inst_align(&self) -> u64 {
+        if self.isa.has('c') {
+            2
+        } else {
+            4
         }
-        None
     }
 
-    pub fn fetch(&mut self) -> Result<RiscvInstWrapper, Exception> {
-        let addr = self
-            .mmu
-            .translate(super::mmu::AccessType::Instruction, &mut self.bus, self.pc)
-            .expect("Translation failed");
-        match self.bus.load(addr, 1) {
-            Ok(val) => match val & 0x3 {
-                0x3 => {
-                    let inst = u32::from_le(self.bus.load(addr, 4).unwrap() as u32);
-                    Ok(RiscvInstWrapper::Full(decode(inst)))
-                }
-                _ => {
-                    let inst = u16::from_le(self.bus.load(addr, 2).unwrap() as u16);
-                    Ok(RiscvInstWrapper::Compact(decode_compressed(inst)))
-                }
-            },
-            Err(e) => Err(e),
+    /// Report an instruction the emulator does not support yet as an illegal instruction
+    /// rather than aborting.
+    fn unimplemented_instruction(&self, inst: RiscvInst) -> Exception {
+        log::warn!("unimplemented instruction `{}` at {:#x}", inst, self.pc);
+        Exception::IllegalInstruction(self.pc)
+    }
+
+    /// Write back an integer register, discarding writes to x0.
+    pub fn set_x(&mut self, rd: u8, value: u64) {
+        if rd != 0 {
+            self.x[rd as usize] = value;
         }
     }
 
-    pub fn execute(&mut self, inst: RiscvInstWrapper) -> Result<u64, Exception> {
-        let raw_inst = match inst {
-            RiscvInstWrapper::Full(inst) | RiscvInstWrapper::Compact(inst) => inst,
+    /// Whether the `ebreak` at the pc sits between the semihosting marker instructions.
+    fn is_semihosting_call(&mut self) -> bool {
+        let pc = self.pc;
+        let mut read = |addr: u64| {
+            let addr = self.translate(Instruction, addr)?;
+            self.bus.load(addr, 4)
         };
+        read(pc.wrapping_sub(4)) == Ok(ENTRY_MARKER.into())
+            && read(pc.wrapping_add(4)) == Ok(EXIT_MARKER.into())
+    }
 
-        match raw_inst {
-            RiscvInst::Illegal => return Err(Exception::IllegalInstruction(self.pc)),
-            RiscvInst::Lb { rd, rs1, imm } => {
-                self.x[rd as usize] =
-                    self.load(addr_add(self.x[rs1 as usize], imm), 1)? as u8 as i8 as u64;
-            }
-            RiscvInst::Lh { rd, rs1, imm } => {
-                self.x[rd as usize] =
-                    self.load(addr_add(self.x[rs1 as usize], imm), 2)? as u16 as i16 as u64;
-            }
-            RiscvInst::Lw { rd, rs1, imm } => {
-                self.x[rd as usize] =
-                    self.load(addr_add(self.x[rs1 as usize], imm), 4)? as u32 as i32 as u64;
-            }
-            RiscvInst::Ld { rd, rs1, imm } => {
-                self.x[rd as usize] =
-                    self.load(addr_add(self.x[rs1 as usize], imm), 8)? as i64 as u64;
-            }
-            RiscvInst::Lbu { rd, rs1, imm } => {
-                self.x[rd as usize] =
-                    self.load(addr_add(self.x[rs1 as usize], imm), 1)? as u8 as u64;
-            }
-            RiscvInst::Lhu { rd, rs1, imm } => {
-                self.x[rd as usize] =
-                    self.load(addr_add(self.x[rs1 as usize], imm), 2)? as u16 as u64;
-            }
-            RiscvInst::Lwu { rd, rs1, imm } => {
-                self.x[rd as usize] =
-                    self.load(addr_add(self.x[rs1 as usize], imm), 4)? as u32 as u64;
-            }
-            RiscvInst::Fence => {}
-            RiscvInst::FenceI => {}
-            RiscvInst::Addi { rd, rs1, imm } => {
-                self.x[rd as usize] = self.x[rs1 as usize].wrapping_add(imm as u64);
-            }
-            RiscvInst::Slli { rd, rs1, imm } => {
-                self.x[rd as usize] = self.x[rs1 as usize].wrapping_shl(imm as u32);
+    /// Carry out the semihosting operation in a0 with the parameter in a1.
+    fn semihosting_call(&mut self) -> Result<(), Exception> {
+        let arg = self.x[A1];
+        let mut output = Vec::new();
+        let result = match self.x[A0] {
+            SYS_WRITEC => {
+                output.push(self.load(arg, 1)? as u8);
+                0
+            }
+            SYS_WRITE0 => {
+                let mut addr = arg;
+                loop {
+                    match self.load(addr, 1)? as u8 {
+                        0 => break,
+                        c => output.push(c),
+                    }
+                    addr = addr.wrapping_add(1);
+                }
+                0
             }
-            RiscvInst::Slti { rd, rs1, imm } => {
-                self.x[rd as usize] = if (self.x[rs1 as usize] as i64) < (imm as i64) {
-                    1
+            SYS_EXIT => {
+                let reason = self.load(arg, 8)?;
+                let code = if reason == ADP_STOPPED_APPLICATION_EXIT {
+                    self.load(arg.wrapping_add(8), 8)? as u32
                 } else {
-                    0
-                };
-            }
-            RiscvInst::Sltiu { rd, rs1, imm } => {
-                self.x[rd as usize] = if self.x[rs1 as usize] < (imm as u64) {
                     1
-                } else {
-                    0
                 };
+                self.bus.syscon.power_off(code);
+                0
             }
-            RiscvInst::Xori { rd, rs1, imm } => {
-                self.x[rd as usize] = self.x[rs1 as usize] ^ (imm as u64);
-            }
-            RiscvInst::Srli { rd, rs1, imm } => {
-                self.x[rd as usize] = self.x[rs1 as usize].wrapping_shr(imm as u32);
-            }
-            RiscvInst::Srai { rd, rs1, imm } => {
-                self.x[rd as usize] = (self.x[rs1 as usize] as i64).wrapping_shr(imm as u32) as u64;
-            }
-            RiscvInst::Ori { rd, rs1, imm } => {
-                self.x[rd as usize] = self.x[rs1 as usize] | (imm as u64);
-            }
-            RiscvInst::Andi { rd, rs1, imm } => {
-                self.x[rd as usize] = self.x[rs1 as usize] & (imm as u64);
-            }
-            RiscvInst::Auipc { rd, imm } => {
-                self.x[rd as usize] = self.pc.wrapping_add((imm as u64) << 12);
-            }
-            RiscvInst::Lui { rd, imm } => {
-                self.x[rd as usize] = (imm as u64) << 12;
-            }
-            RiscvInst::Addiw { rd, rs1, imm } => {
-                self.x[rd as usize] = (self.x[rs1 as usize].wrapping_add(imm as u64)) as u32 as u64;
-            }
-            RiscvInst::Slliw { rd, rs1, imm } => {
-                let shamt = (imm & 0x1f) as u32;
-                self.x[rd as usize] = (self.x[rs1 as usize] as u32).wrapping_shl(shamt) as u64;
-            }
-            RiscvInst::Srliw { rd, rs1, imm } => {
-                let shamt = (imm & 0x1f) as u32;
-                self.x[rd as usize] = (self.x[rs1 as usize] as u32).wrapping_shr(shamt) as u64;
-            }
-            RiscvInst::Sraiw { rd, rs1, imm } => {
-                let shamt = (imm & 0x1f) as u32;
-                self.x[rd as usize] = (self.x[rs1 as usize] as i32).wrapping_shr(shamt) as u64;
-            }
-            RiscvInst::Addw { rd, rs1, rs2 } => {
-                self.x[rd as usize] =
-                    (self.x[rs1 as usize].wrapping_add(self.x[rs2 as usize])) as u32 as u64;
-            }
-            RiscvInst::Subw { rd, rs1, rs2 } => {
-                self.x[rd as usize] =
-                    (self.x[rs1 as usize].wrapping_sub(self.x[rs2 as usize])) as u32 as u64;
-            }
-            RiscvInst::Sllw { rd, rs1, rs2 } => {
-                let shamt = (self.x[rs2 as usize] & 0x1f) as u32;
-                self.x[rd as usize] = (self.x[rs1 as usize] as u32).wrapping_shl(shamt) as u64;
-            }
-            RiscvInst::Srlw { rd, rs1, rs2 } => {
-                let shamt = (self.x[rs2 as usize] & 0x1f) as u32;
-                self.x[rd as usize] = (self.x[rs1 as usize] as u32).wrapping_shr(shamt) as u64;
-            }
-            RiscvInst::Sraw { rd, rs1, rs2 } => {
-                let shamt = (self.x[rs2 as usize] & 0x1f) as u32;
-                self.x[rd as usize] = (self.x[rs1 as usize] as i32).wrapping_shr(shamt) as u64;
-            }
-            RiscvInst::Sb { rs1, rs2, imm } => {
-                self.store(
-                    self.x[rs1 as usize].wrapping_add(imm as u64),
-                    1,
-                    self.x[rs2 as usize],
-                )?;
-            }
-            RiscvInst::Sh { rs1, rs2, imm } => {
-                self.store(
-                    self.x[rs1 as usize].wrapping_add(imm as u64),
-                    2,
-                    self.x[rs2 as usize],
-                )?;
-            }
-            RiscvInst::Sw { rs1, rs2, imm } => {
-                self.store(
-                    self.x[rs1 as usize].wrapping_add(imm as u64),
-                    4,
-                    self.x[rs2 as usize],
-                )?;
-            }
-            RiscvInst::Sd { rs1, rs2, imm } => {
-                self.store(
-                    self.x[rs1 as usize].wrapping_add(imm as u64),
-                    8,
-                    self.x[rs2 as usize],
-                )?;
-            }
-            RiscvInst::Add { rd, rs1, rs2 } => {
-                self.x[rd as usize] = self.x[rs1 as usize].wrapping_add(self.x[rs2 as usize]);
-            }
-            RiscvInst::Sub { rd, rs1, rs2 } => {
-                self.x[rd as usize] = self.x[rs1 as usize].wrapping_sub(self.x[rs2 as usize]);
-            }
-            RiscvInst::Sll { rd, rs1, rs2 } => {
-                let shamt = (self.x[rs2 as usize] & 0x3f) as u32;
-                self.x[rd as usize] = self.x[rs1 as usize].wrapping_shl(shamt);
+            op => {
+                log::warn!("unsupported semihosting call {:#x}", op);
+                u64::MAX
             }
-            RiscvInst::Slt { rd, rs1, rs2 } => {
-                self.x[rd as usize] =
-                    if (self.x[rs1 as usize] as i64) < (self.x[rs2 as usize] as i64) {
-                        1
-                    } else {
-                        0
-                    };
+        };
+        if let Some(out) = self.semihosting.as_mut() {
+            if let Err(e) = out.write_all(&output).and_then(|_| out.flush()) {
+                log::warn!("semihosting output failed: {}", e);
             }
-            RiscvInst::Sltu { rd, rs1, rs2 } => {
-                self.x[rd as usize] = if self.x[rs1 as usize] < self.x[rs2 as usize] {
-                    1
-                } else {
-                    0
+        }
+        self.x[A0] = result;
+        Ok(())
+    }
+
+    /// Move the program break to `addr` and return the new break. Like Linux, a break that would
+    /// fall below the start of the heap or run into the stack leaves it where it is and returns
+    /// the old one, so `brk(0)` queries it.
+    fn proxy_brk(&mut self, addr: u64) -> Result<u64, Exception> {
+        let proxy = self.proxy.as_mut().unwrap();
+        let old = proxy.brk;
+        if !(proxy.brk_start..=DRAM_BASE + DRAM_SIZE - STACK_SIZE).contains(&addr) {
+            return Ok(old);
+        }
+        proxy.brk = addr;
+        // Memory handed out again after a shrink must read as zero, as fresh pages would.
+        if addr > old {
+            self.bus.load_data(old, &vec![0; (addr - old) as usize])?;
+        }
+        Ok(addr)
+    }
+
+    /// Carry out the system call in a7 for the proxy kernel.
+    fn proxy_call(&mut self) -> Result<(), Exception> {
+        let (a0, a1, a2) = (self.x[A0], self.x[A1], self.x[A2]);
+        let result = match self.x[A7] {
+            SYS_WRITE => {
+                let data: Result<Vec<u8>, _> = (a1..a1.wrapping_add(a2))
+                    .map(|addr| self.load(addr, 1).map(|byte| byte as u8))
+                    .collect();
+                let proxy = self.proxy.as_mut().unwrap();
+                let written = match (a0, data) {
+                    (STDOUT | STDERR, Err(_)) => Err(EFAULT),
+                    (STDOUT, Ok(data)) => proxy
+                        .stdout
+                        .write_all(&data)
+                        .and_then(|_| proxy.stdout.flush())
+                        .map_err(|e| {
+                            log::warn!("proxy kernel output failed: {}", e);
+                            EFAULT
+                        }),
+                    (STDERR, Ok(data)) => {
+                        io::stderr().write_all(&data).ok();
+                        Ok(())
+                    }
+                    _ => Err(EBADF),
                 };
+                written.map_or_else(|errno| errno.wrapping_neg(), |()| a2)
             }
-            RiscvInst::Xor { rd, rs1, rs2 } => {
-                self.x[rd as usize] = self.x[rs1 as usize] ^ self.x[rs2 as usize];
-            }
-            RiscvInst::Srl { rd, rs1, rs2 } => {
-                let shamt = (self.x[rs2 as usize] & 0x3f) as u32;
-                self.x[rd as usize] = self.x[rs1 as usize].wrapping_shr(shamt);
-            }
-            RiscvInst::Sra { rd, rs1, rs2 } => {
-                let shamt = (self.x[rs2 as usize] & 0x3f) as u32;
-                self.x[rd as usize] = (self.x[rs1 as usize] as i64).wrapping_shr(shamt) as u64;
-            }
-            RiscvInst::Or { rd, rs1, rs2 } => {
-                self.x[rd as usize] = self.x[rs1 as usize] | self.x[rs2 as usize];
-            }
-            RiscvInst::And { rd, rs1, rs2 } => {
-                self.x[rd as usize] = self.x[rs1 as usize] & self.x[rs2 as usize];
+            proxy::SYS_EXIT | SYS_EXIT_GROUP => {
+                self.bus.syscon.power_off(a0 as u32 & 0xff);
+                0
             }
-            RiscvInst::Beq { rs1, rs2, imm } => {
-                if self.x[rs1 as usize] == self.x[rs2 as usize] {
-                    return Ok(self.pc.wrapping_add(imm as u64));
+            SYS_BRK => self.proxy_brk(a0)?,
+            SYS_FSTAT if matches!(a0, STDIN | STDOUT | STDERR) => {
+                for offset in (0..STAT_SIZE).step_by(8) {
+                    self.store(a1.wrapping_add(offset), 8, 0)?;
                 }
+                self.store(a1.wrapping_add(STAT_MODE), 4, S_IFCHR_RW)?;
+                self.store(a1.wrapping_add(STAT_BLKSIZE), 4, 1024)?;
+                0
             }
-            RiscvInst::Bne { rs1, rs2, imm } => {
-                if self.x[rs1 as usize] != self.x[rs2 as usize] {
-                    return Ok(self.pc.wrapping_add(imm as u64));
-                }
-            }
-            RiscvInst::Blt { rs1, rs2, imm } => {
-                if (self.x[rs1 as usize] as i64) < (self.x[rs2 as usize] as i64) {
-                    return Ok(self.pc.wrapping_add(imm as u64));
-                }
-            }
-            RiscvInst::Bge { rs1, rs2, imm } => {
-                if (self.x[rs1 as usize] as i64) >= (self.x[rs2 as usize] as i64) {
-                    return Ok(self.pc.wrapping_add(imm as u64));
-                }
-            }
-            RiscvInst::Bltu { rs1, rs2, imm } => {
-                if self.x[rs1 as usize] < self.x[rs2 as usize] {
-                    return Ok(self.pc.wrapping_add(imm as u64));
-                }
-            }
-            RiscvInst::Bgeu { rs1, rs2, imm } => {
-                if self.x[rs1 as usize] >= self.x[rs2 as usize] {
-                    return Ok(self.pc.wrapping_add(imm as u64));
-                }
-            }
-            RiscvInst::Jalr { rd, rs1, imm } => {
-                let addr = self.x[rs1 as usize].wrapping_add(imm as u64);
-                self.x[rd as usize] = self.pc + 4;
-                return Ok(addr & !1);
-            }
-            RiscvInst::Jal { rd, imm } => {
-                self.x[rd as usize] = self.pc + 4;
-                return Ok(self.pc.wrapping_add(imm as u64));
-            }
-            RiscvInst::Ecall => match self.mode {
-                USER_MODE => {
-                    return Err(Exception::EnvironmentCallFromUMode(self.pc));
-                }
-                SUPERVISOR_MODE => {
-                    return Err(Exception::EnvironmentCallFromSMode(self.pc));
-                }
-                MACHINE_MODE => {
-                    return Err(Exception::EnvironmentCallFromMMode(self.pc));
-                }
-                _ => unreachable!(),
-            },
-            RiscvInst::Ebreak => {
-                return Err(Exception::Breakpoint(self.pc));
+            SYS_FSTAT => EBADF.wrapping_neg(),
+            n => {
+                log::warn!("unsupported system call {}", n);
+                ENOSYS.wrapping_neg()
             }
+        };
+        self.x[A0] = result;
+        Ok(())
+    }
 
-            RiscvInst::Csrrw { rd, rs1, csr } => {
-                let t = self.csr.load(csr.into());
-                self.csr.store(csr.into(), self.x[rs1 as usize]);
-                self.x[rd as usize] = t.into();
-                self.update_paging(csr.into());
-            }
-            RiscvInst::Csrrs { rd, rs1, csr } => {
-                let t = self.csr.load(csr.into());
-                self.csr
-                    .store(csr.into(), (t | self.x[rs1 as usize]).into());
-                self.x[rd as usize] = t.into();
-                self.update_paging(csr.into());
-            }
-            RiscvInst::Csrrc { rd, rs1, csr } => {
-                let t = self.csr.load(csr.into());
-                self.csr
-                    .store(csr.into(), (t & !self.x[rs1 as usize]).into());
-                self.x[rd as usize] = t.into();
-                self.update_paging(csr.into());
-            }
-            RiscvInst::Csrrwi { rd, imm, csr } => {
-                let t = self.csr.load(csr.into());
-                self.csr.store(csr.into(), imm as u64);
-                self.x[rd as usize] = t.into();
-                self.update_paging(csr.into());
-            }
-            RiscvInst::Csrrsi { rd, imm, csr } => {
-                let t = self.csr.load(csr.into());
-                self.csr.store(csr.into(), (t | (imm as u64)).into());
-                self.x[rd as usize] = t.into();
-                self.update_paging(csr.into());
-            }
-            RiscvInst::Csrrci { rd, imm, csr } => {
-                let t = self.csr.load(csr.into());
-                self.csr.store(csr.into(), (t & !(imm as u64)).into());
-                self.x[rd as usize] = t.into();
-                self.update_paging(csr.into());
-            }
+    /// Log every load and store that reaches a device rather than DRAM.
+    pub fn set_mmio_trace(&mut self, enabled: bool) {
+        self.bus.mmio_trace = enabled;
+    }
 
-            RiscvInst::Mul { rd, rs1, rs2 } => {
-                self.x[rd as usize] = self.x[rs1 as usize].wrapping_mul(self.x[rs2 as usize]);
-            }
-            RiscvInst::Mulh { rd, rs1, rs2 } => {
-                let a = self.x[rs1 as usize] as i64;
-                let b = self.x[rs2 as usize] as i64;
-                self.x[rd as usize] = (a.wrapping_mul(b) >> 32) as u64;
-            }
-            RiscvInst::Mulhsu { rd, rs1, rs2 } => {
-                let a = self.x[rs1 as usize] as i64;
-                let b = self.x[rs2 as usize];
-                self.x[rd as usize] = (a.wrapping_mul(b as i64) >> 32) as u64;
-            }
-            RiscvInst::Mulhu { rd, rs1, rs2 } => {
-                let a = self.x[rs1 as usize];
-                let b = self.x[rs2 as usize];
-                self.x[rd as usize] = (a.wrapping_mul(b) >> 32) as u64;
-            }
-            RiscvInst::Div { rd, rs1, rs2 } => {
-                let a = self.x[rs1 as usize] as i64;
-                let b = self.x[rs2 as usize] as i64;
-                self.x[rd as usize] = if b == 0 {
-                    u64::MAX
-                } else {
-                    a.wrapping_div(b) as u64
-                };
-            }
-            RiscvInst::Divu { rd, rs1, rs2 } => {
-                let a = self.x[rs1 as usize];
-                let b = self.x[rs2 as usize];
-                self.x[rd as usize] = if b == 0 { u64::MAX } else { a.wrapping_div(b) };
-            }
-            RiscvInst::Rem { rd, rs1, rs2 } => {
-                let a = self.x[rs1 as usize] as i64;
-                let b = self.x[rs2 as usize] as i64;
-                self.x[rd as usize] = if b == 0 {
-                    a as u64
-                } else {
-                    a.wrapping_rem(b) as u64
-                };
-            }
-            RiscvInst::Remu { rd, rs1, rs2 } => {
-                let a = self.x[rs1 as usize];
-                let b = self.x[rs2 as usize];
-                self.x[rd as usize] = if b == 0 { a } else { a.wrapping_rem(b) };
-            }
-            RiscvInst::Mulw { rd, rs1, rs2 } => {
-                let a = self.x[rs1 as usize] as i32;
-                let b = self.x[rs2 as usize] as i32;
-                self.x[rd as usize] = (a.wrapping_mul(b)) as u64;
-            }
-            RiscvInst::Divw { rd, rs1, rs2 } => {
-                let a = self.x[rs1 as usize] as i32;
-                let b = self.x[rs2 as usize] as i32;
-                self.x[rd as usize] = if b == 0 {
-                    u64::MAX
+    /// Read a single-precision register, yielding the canonical NaN when the value isn't NaN-boxed.
+    fn read_f32(&self, frs: u8) -> f32 {
+        let bits = self.f[frs as usize].to_bits();
+        if bits >> 32 == 0xffff_ffff {
+            f32::from_bits(bits as u32)
+        } else {
+            f32::from_bits(CANONICAL_NAN_F32)
+        }
+    }
+
+    /// Write a single-precision register, NaN-boxing the value into the upper 32 bits.
+    fn write_f32(&mut self, frd: u8, value: f32) {
+        self.f[frd as usize] = f64::from_bits(NAN_BOX | value.to_bits() as u64);
+    }
+
+    /// Write the DRAM contents in `[start, start + len)` to `out`, or the whole DRAM without a range.
+    pub fn dump_dram(&self, out: &mut impl Write, range: Option<(u64, u64)>) -> io::Result<()> {
+        let (start, len) = range.unwrap_or((DRAM_BASE, DRAM_SIZE));
+        self.bus.dump_dram(out, start, len)
+    }
+
+    pub fn pc(&self) -> u64 {
+        self.pc
+    }
+
+    pub fn set_pc(&mut self, pc: u64) {
+        self.pc = pc;
+    }
+
+    pub fn x(&self, reg: usize) -> u64 {
+        self.x[reg]
+    }
+
+    /// The CSR at `addr`, read without the side effects of a CSR instruction.
+    pub fn csr(&self, addr: usize) -> u64 {
+        self.csr.load(addr).into()
+    }
+
+    /// Take illegal instructions as ordinary traps rather than halting on them.
+    pub fn set_trap_illegal(&mut self, enabled: bool) {
+        self.trap_illegal = enabled;
+    }
+
+    /// Halt with `HaltReason::TrapStorm` once `limit` traps are taken in a row without an
+    /// instruction retiring in between, or never with `None`.
+    pub fn set_trap_storm_limit(&mut self, limit: Option<u64>) {
+        self.trap_storm_limit = limit;
+    }
+
+    /// Halt with `HaltReason::Breakpoint` at an `ebreak`, before it traps, rather than letting the
+    /// guest handle it. Semihosting calls are still served.
+    pub fn set_halt_on_ebreak(&mut self, enabled: bool) {
+        self.halt_on_ebreak = enabled;
+    }
+
+    /// Choose which exceptions halt the emulator instead of trapping to the guest.
+    pub fn set_fatal_policy(&mut self, policy: FatalPolicy) {
+        self.fatal_policy = policy;
+    }
+
+    /// Handle semihosting calls, writing their output to `out`. Without it a semihosting call is
+    /// an ordinary breakpoint.
+    pub fn set_semihosting(&mut self, out: Option<Box<dyn Write>>) {
+        self.semihosting = out;
+    }
+
+    /// Run the program as a Linux user process: start it in U-mode with the FPU on and an empty
+    /// argument list on a stack at the top of DRAM, and emulate its system calls, writing its stdout
+    /// to `stdout`. The program break starts at the first page boundary from `brk`, the end of the
+    /// loaded image.
+    pub fn set_proxy(&mut self, stdout: Box<dyn Write>, brk: u64) {
+        self.mode = USER_MODE;
+        let status = self.csr.load(MSTATUS).data;
+        self.csr.store(MSTATUS, (status & !MASK_FS) | FS_INITIAL);
+        let sp = DRAM_BASE + DRAM_SIZE - INITIAL_STACK;
+        // argc, the argv and envp terminators and AT_NULL are all zero, and DRAM may be poisoned
+        for addr in (sp..sp + INITIAL_STACK).step_by(8) {
+            self.bus
+                .store(addr, 8, 0)
+                .expect("the initial stack is in DRAM");
+        }
+        self.x[SP] = sp;
+        let brk = (brk + PAGE_SIZE - 1) & !(PAGE_SIZE - 1);
+        self.proxy = Some(ProxyKernel {
+            stdout,
+            brk_start: brk,
+            brk,
+        });
+    }
+
+    /// Map `image` as a read-only boot ROM and point the pc at its start, the reset vector.
+    /// Fails if the image is larger than the ROM region.
+    pub fn set_bootrom(&mut self, image: &[u8]) -> Result<(), Exception> {
+        self.bus.set_rom(image)?;
+        self.flush_decode_cache();
+        self.pc = BOOTROM_BASE;
+        Ok(())
+    }
+
+    /// Install the built-in boot ROM, which enters `entry` with the hart ID in a0 and the device
+    /// tree address `dtb` in a1, and point the pc at it.
+    pub fn set_boot_handoff(&mut self, entry: u64, dtb: u64) {
+        self.set_bootrom(&bootrom::handoff(entry, dtb))
+            .expect("the built-in boot ROM fits");
+    }
+
+    /// Advance `mtime` once every `count` instructions instead of on every one.
+    pub fn set_insts_per_tick(&mut self, count: u64) {
+        self.bus.clint.set_ticks_per_increment(count);
+    }
+
+    /// Make this the hart with ID `hart_id`, as reported by `mhartid`.
+    pub fn set_hart_id(&mut self, hart_id: u64) {
+        self.hart_id = hart_id;
+        self.csr.store(MHARTID, hart_id);
+    }
+
+    /// Set the values of the read-only `mvendorid`, `marchid` and `mimpid`.
+    pub fn set_machine_ids(&mut self, vendor: u64, arch: u64, imp: u64) {
+        self.csr.store(MVENDORID, vendor);
+        self.csr.store(MARCHID, arch);
+        self.csr.store(MIMPID, imp);
+    }
+
+    /// Only decode instructions from the extensions in `isa`, and report them in `misa`.
+    pub fn set_isa(&mut self, isa: Isa) {
+        self.isa = isa;
+        self.flush_decode_cache();
+        self.csr.store(MISA, isa.misa());
+    }
+
+    /// Fill DRAM with `pattern` instead of zeros, so reads of uninitialized memory are easy to
+    /// spot. Call it before loading the program, as it overwrites all of DRAM.
+    pub fn set_poison(&mut self, pattern: u8) {
+        self.bus.poison_dram(pattern);
+        self.flush_decode_cache();
+    }
+
+    /// Send the bytes the guest transmits over the UART to `out` instead of stdout.
+    pub fn set_uart_output(&mut self, out: Box<dyn Write>) {
+        self.bus.uart.set_output(out);
+    }
+
+    /// Ignore writes delegating causes that can only be taken in M-mode, rather than only warning.
+    pub fn set_strict_delegation(&mut self, enabled: bool) {
+        self.csr.strict_delegation = enabled;
+    }
+
+    /// Use `latency` to model the cycles taken by each instruction.
+    pub fn set_latency(&mut self, latency: InstLatency) {
+        self.latency = latency;
+    }
+
+    /// Use `latency` to model the cycles loads and stores spend in memory, starting from an empty
+    /// cache. Panics if the line size is zero.
+    pub fn set_memory_latency(&mut self, latency: MemLatency) {
+        self.mem_timing = MemTiming::new(latency);
+    }
+
+    /// Make loads and stores touching the physical range `[start, start + len)` raise an access fault.
+    pub fn add_guard_region(&mut self, start: u64, len: u64) {
+        self.guard_regions.push(start..start.saturating_add(len));
+    }
+
+    fn is_guarded(&self, addr: u64, size: u64) -> bool {
+        let end = addr.saturating_add(size);
+        self.guard_regions
+            .iter()
+            .any(|region| addr < region.end && region.start < end)
+    }
+
+    /// Translate a virtual address, bypassing the MMU when the effective privilege is M-mode.
+    fn translate(&mut self, access: AccessType, addr: u64) -> Result<u64, Exception> {
+        let paddr = self.translate_untimed(access, addr);
+        self.mem_cycles += self.mmu.take_walk_loads() * self.mem_timing.walk_latency();
+        paddr
+    }
+
+    /// Translate `addr` like `translate`, leaving the loads of the page table walk for the caller
+    /// to charge or drop.
+    fn translate_untimed(&mut self, access: AccessType, addr: u64) -> Result<u64, Exception> {
+        let mode = match access {
+            Load | Store if self.csr.load(MSTATUS) & MASK_MPRV != 0 => {
+                self.csr.load(MSTATUS).mpp() as u8
+            }
+            _ => self.mode,
+        };
+        if mode == MACHINE_MODE {
+            return Ok(addr);
+        }
+        let status = self.csr.load(MSTATUS).into();
+        self.mmu.translate(access, &self.bus, addr, mode, status)
+    }
+
+    /// Read `len` bytes of guest memory at the virtual address `vaddr`, translated like loads in
+    /// the current mode. With `bypass_permissions`, as a debugger wants, any valid mapping can be
+    /// read whatever its permissions, guard regions are ignored and the TLB is left untouched.
+    /// Either way the read takes no guest time and leaves the cache model alone.
+    pub fn read_mem(
+        &mut self,
+        vaddr: u64,
+        len: u64,
+        bypass_permissions: bool,
+    ) -> Result<Vec<u8>, Exception> {
+        (0..len)
+            .map(|offset| {
+                let addr = vaddr.wrapping_add(offset);
+                let byte = if bypass_permissions {
+                    let paddr = self.translate_debug(addr)?;
+                    self.bus.load(paddr, 1)?
                 } else {
-                    (a.wrapping_div(b)) as u64
+                    let paddr = self.translate_untimed(Load, addr);
+                    self.mmu.take_walk_loads();
+                    let paddr = paddr?;
+                    if self.is_guarded(paddr, 1) {
+                        return Err(Exception::LoadAccessFault(addr));
+                    }
+                    self.bus.load(paddr, 1)?
                 };
+                Ok(byte as u8)
+            })
+            .collect()
+    }
+
+    /// Read the NUL-terminated string at the virtual address `vaddr`, like `read_mem`, replacing
+    /// invalid UTF-8. At most `max_len` bytes are read, the NUL included. Fails if any byte up to
+    /// the NUL can't be read, or with a load access fault past `max_len` bytes if none was a NUL.
+    pub fn read_cstr(
+        &mut self,
+        vaddr: u64,
+        max_len: usize,
+        bypass_permissions: bool,
+    ) -> Result<String, Exception> {
+        let mut bytes = Vec::new();
+        for offset in 0..max_len as u64 {
+            let addr = vaddr.wrapping_add(offset);
+            match self.read_mem(addr, 1, bypass_permissions)?[0] {
+                0 => return Ok(String::from_utf8_lossy(&bytes).into_owned()),
+                byte => bytes.push(byte),
             }
-            RiscvInst::Divuw { rd, rs1, rs2 } => {
-                let a = self.x[rs1 as usize] as u32;
-                let b = self.x[rs2 as usize] as u32;
-                self.x[rd as usize] = if b == 0 {
-                    u64::MAX
-                } else {
-                    (a.wrapping_div(b)) as u64
-                };
+        }
+        Err(Exception::LoadAccessFault(
+            vaddr.wrapping_add(max_len as u64),
+        ))
+    }
+
+    /// Read `len` consecutive values of type `T` at the virtual address `vaddr`, like `read_mem`.
+    pub fn read_array<T: GuestValue>(
+        &mut self,
+        vaddr: u64,
+        len: usize,
+        bypass_permissions: bool,
+    ) -> Result<Vec<T>, Exception> {
+        let bytes = self.read_mem(vaddr, (len * T::SIZE) as u64, bypass_permissions)?;
+        Ok(bytes.chunks_exact(T::SIZE).map(T::from_le_bytes).collect())
+    }
+
+    /// Write `data` to guest memory at the virtual address `vaddr`, translated like stores in the
+    /// current mode, or like `read_mem` with `bypass_permissions`. Bytes before a fault are written.
+    pub fn write_mem(
+        &mut self,
+        vaddr: u64,
+        data: &[u8],
+        bypass_permissions: bool,
+    ) -> Result<(), Exception> {
+        self.flush_decode_cache();
+        for (offset, &byte) in data.iter().enumerate() {
+            let addr = vaddr.wrapping_add(offset as u64);
+            if bypass_permissions {
+                let paddr = self.translate_debug(addr)?;
+                self.bus.store(paddr, 1, byte.into())?;
+            } else {
+                self.store(addr, 1, byte.into())?;
             }
-            RiscvInst::Remw { rd, rs1, rs2 } => {
-                let a = self.x[rs1 as usize] as i32;
-                let b = self.x[rs2 as usize] as i32;
-                self.x[rd as usize] = if b == 0 {
-                    a as u64
-                } else {
-                    (a.wrapping_rem(b)) as u64
-                };
+        }
+        Ok(())
+    }
+
+    fn translate_debug(&mut self, addr: u64) -> Result<u64, Exception> {
+        if self.mode == MACHINE_MODE {
+            return Ok(addr);
+        }
+        self.mmu.translate_debug(&self.bus, addr)
+    }
+
+    fn load(&mut self, addr: u64, size: u64) -> Result<u64, Exception> {
+        let paddr = self.translate(Load, addr)?;
+        if self.is_guarded(paddr, size) {
+            return Err(Exception::LoadAccessFault(addr));
+        }
+        self.mem_cycles += self.mem_timing.access(paddr);
+        self.bus.load(paddr, size)
+    }
+
+    fn store(&mut self, addr: u64, size: u64, data: u64) -> Result<(), Exception> {
+        let paddr = self.translate(Store, addr)?;
+        if self.is_guarded(paddr, size) {
+            return Err(Exception::StoreAMOAccessFault(addr));
+        }
+        if let Some(granule) = self.reservation {
+            if paddr < granule + RESERVATION_GRANULE && granule < paddr + size {
+                self.reservation = None;
             }
-            RiscvInst::Remuw { rd, rs1, rs2 } => {
-                let a = self.x[rs1 as usize] as u32;
-                let b = self.x[rs2 as usize] as u32;
-                self.x[rd as usize] = if b == 0 {
-                    a as u64
+        }
+        self.mem_cycles += self.mem_timing.access(paddr);
+        self.bus.store(paddr, size, data)
+    }
+
+    /// Load `size` bytes for an LR and reserve the granule holding them.
+    fn load_reserved(&mut self, addr: u64, size: u64) -> Result<u64, Exception> {
+        if !addr.is_multiple_of(size) {
+            return Err(Exception::LoadAccessMisaligned(addr));
+        }
+        let paddr = self.translate(Load, addr)?;
+        let val = self.load(addr, size)?;
+        self.reservation = Some(paddr & !(RESERVATION_GRANULE - 1));
+        Ok(val)
+    }
+
+    /// Store `size` bytes for an SC if the granule is still reserved, returning whether it did.
+    /// The reservation is gone afterwards either way.
+    fn store_conditional(&mut self, addr: u64, size: u64, data: u64) -> Result<bool, Exception> {
+        if !addr.is_multiple_of(size) {
+            return Err(Exception::StoreAMOAddrMisaligned(addr));
+        }
+        let paddr = self.translate(Store, addr)?;
+        let reserved = self.reservation.take() == Some(paddr & !(RESERVATION_GRANULE - 1));
+        if reserved {
+            self.store(addr, size, data)?;
+        }
+        Ok(reserved)
+    }
+
+    /// Check that the CSR can be accessed from the current privilege level, and written if `write`.
+    fn check_csr_access(&self, csr: Csr, write: bool) -> Result<(), Exception> {
+        if self.mode < csr.min_prv_level() || (write && csr.readonly()) {
+            return Err(Exception::IllegalInstruction(self.pc));
+        }
+        let addr = csr.addr();
+        if (CYCLE..=HPMCOUNTER31).contains(&addr) && !self.csr.counter_enabled(addr, self.mode) {
+            return Err(Exception::IllegalInstruction(self.pc));
+        }
+        Ok(())
+    }
+
+    /// Resolve the rounding mode of an instruction, reading `frm` for the dynamic mode.
+    fn rounding_mode(&self, rm: u8) -> Result<u8, Exception> {
+        let rm = match rm {
+            0b111 => (self.csr.load(FRM).data & 0b111) as u8,
+            _ => rm,
+        };
+        if rm > 0b100 {
+            return Err(Exception::IllegalInstruction(self.pc));
+        }
+        Ok(rm)
+    }
+
+    /// Accrue floating-point exception flags.
+    fn raise_fflags(&mut self, flags: u64) {
+        let fflags = self.csr.load(FFLAGS).data | flags;
+        self.csr.store(FFLAGS, fflags);
+    }
+
+    /// Convert `x` to an integer in `[min, max]`. NaN and out-of-range values saturate and raise NV,
+    /// while in-range values that are not exact raise NX.
+    fn fcvt_int(&mut self, x: f64, rm: u8, min: i128, max: i128) -> Result<i128, Exception> {
+        let rm = self.rounding_mode(rm)?;
+        if x.is_nan() {
+            self.raise_fflags(MASK_NV);
+            return Ok(max);
+        }
+        let rounded = round_by_mode(x, rm);
+        // the cast saturates, so infinities land outside any of the target ranges
+        let value = rounded as i128;
+        if value < min || value > max {
+            self.raise_fflags(MASK_NV);
+            return Ok(value.clamp(min, max));
+        }
+        if rounded != x {
+            self.raise_fflags(MASK_NX);
+        }
+        Ok(value)
+    }
+
+    /// Pick the smaller of `a` and `b`, or the larger one with `max`, as `fmin`/`fmax` do: a NaN
+    /// operand is ignored, -0.0 orders below +0.0, and two NaNs give `None` for the canonical NaN.
+    /// `signaling` says whether either operand was a signaling NaN, which raises NV.
+    fn fmin_max(&mut self, a: f64, b: f64, signaling: bool, max: bool) -> Option<f64> {
+        if signaling {
+            self.raise_fflags(MASK_NV);
+        }
+        match (a.is_nan(), b.is_nan()) {
+            (true, true) => None,
+            (true, false) => Some(b),
+            (false, true) => Some(a),
+            // the total order only differs from `<` in placing -0.0 below +0.0
+            _ => Some(if (a.total_cmp(&b).is_lt()) != max {
+                a
+            } else {
+                b
+            }),
+        }
+    }
+
+    /// Narrow `x` to single precision, rounding by `rm` and raising NX, OF and UF as needed.
+    fn fcvt_s_d(&mut self, x: f64, rm: u8) -> Result<f32, Exception> {
+        let rm = self.rounding_mode(rm)?;
+        if x.is_nan() {
+            if signaling_nan_double(x) {
+                self.raise_fflags(MASK_NV);
+            }
+            return Ok(f32::from_bits(CANONICAL_NAN_F32));
+        }
+        // the cast rounds to nearest even, the other modes pick one of the two neighbours
+        let nearest = x as f32;
+        if f64::from(nearest) == x {
+            return Ok(nearest);
+        }
+        let (below, above) = if f64::from(nearest) < x {
+            (nearest, nearest.next_up())
+        } else {
+            (nearest.next_down(), nearest)
+        };
+        let rounded = match rm {
+            0b001 if x > 0.0 => below,
+            0b001 => above,
+            0b010 => below,
+            0b011 => above,
+            0b100 if x == (f64::from(below) + f64::from(above)) / 2.0 => {
+                if x > 0.0 {
+                    above
                 } else {
-                    (a.wrapping_rem(b)) as u64
-                };
+                    below
+                }
             }
-            RiscvInst::LrW { rd, rs1, aqrl } | RiscvInst::LrD { rd, rs1, aqrl } => {
-                todo!("atomic")
-            }
-            RiscvInst::ScW { rd, rs1, rs2, aqrl } | RiscvInst::ScD { rd, rs1, rs2, aqrl } => {
-                todo!("atomic")
-            }
-            RiscvInst::AmoswapW { rd, rs1, rs2, aqrl }
-            | RiscvInst::AmoswapD { rd, rs1, rs2, aqrl }
-            | RiscvInst::AmoaddW { rd, rs1, rs2, aqrl }
-            | RiscvInst::AmoaddD { rd, rs1, rs2, aqrl }
-            | RiscvInst::AmoxorW { rd, rs1, rs2, aqrl }
-            | RiscvInst::AmoxorD { rd, rs1, rs2, aqrl }
-            | RiscvInst::AmoandW { rd, rs1, rs2, aqrl }
-            | RiscvInst::AmoandD { rd, rs1, rs2, aqrl }
-            | RiscvInst::AmoorW { rd, rs1, rs2, aqrl }
-            | RiscvInst::AmoorD { rd, rs1, rs2, aqrl }
-            | RiscvInst::AmominW { rd, rs1, rs2, aqrl }
-            | RiscvInst::AmominD { rd, rs1, rs2, aqrl }
-            | RiscvInst::AmomaxW { rd, rs1, rs2, aqrl }
-            | RiscvInst::AmomaxD { rd, rs1, rs2, aqrl }
-            | RiscvInst::AmominuW { rd, rs1, rs2, aqrl }
-            | RiscvInst::AmominuD { rd, rs1, rs2, aqrl }
-            | RiscvInst::AmomaxuW { rd, rs1, rs2, aqrl }
-            | RiscvInst::AmomaxuD { rd, rs1, rs2, aqrl } => todo!("atomic"),
+            _ => nearest,
+        };
+        let mut flags = MASK_NX;
+        // Overflow is decided after rounding with an unbounded exponent, where the neighbour past
+        // f32::MAX is 2^128 and infinity stands in for it.
+        if rounded.is_infinite() || x.abs() >= 2f64.powi(128) {
+            flags |= MASK_OF;
+        }
+        if rounded.abs() < f32::MIN_POSITIVE {
+            flags |= MASK_UF;
+        }
+        self.raise_fflags(flags);
+        Ok(rounded)
+    }
 
-            RiscvInst::Flw { frd, rs1, imm } => {
-                let val = self.load(self.x[rs1 as usize].wrapping_add(imm as u64), 4)? as u32;
-                self.f[frd as usize] = f32::from_bits(val) as f64;
-            }
-            RiscvInst::Fsw { rs1, frs2, imm } => {
-                let val = f32::to_bits(self.f[frs2 as usize] as f32);
-                self.store(self.x[rs1 as usize].wrapping_add(imm as u64), 4, val.into())?;
-            }
-            RiscvInst::FaddS {
-                frd,
-                frs1,
-                frs2,
-                rm,
-            } => {
-                let a = self.f[frs1 as usize] as f32;
-                let b = self.f[frs2 as usize] as f32;
-                self.f[frd as usize] = (a + b) as f64;
-            }
-            RiscvInst::FsubS {
-                frd,
-                frs1,
-                frs2,
-                rm,
-            } => {
-                let a = self.f[frs1 as usize] as f32;
-                let b = self.f[frs2 as usize] as f32;
-                self.f[frd as usize] = (a - b) as f64;
-            }
-            RiscvInst::FmulS {
-                frd,
-                frs1,
-                frs2,
-                rm,
-            } => {
-                let a = self.f[frs1 as usize] as f32;
-                let b = self.f[frs2 as usize] as f32;
-                self.f[frd as usize] = (a * b) as f64;
+    /// Read a CSR, resolving the ones backed by devices rather than the CSR file.
+    fn read_csr(&self, csr: Csr) -> CsrValue {
+        match csr {
+            Csr::TIME => self.bus.clint.mtime().into(),
+            _ => self.csr.load(csr.addr()),
+        }
+    }
+
+    /// Advance the clock and `mcycle` by `cycles`, and `minstret` if an instruction retired,
+    /// unless inhibited by `mcountinhibit`.
+    fn update_counters(&mut self, cycles: u64, retired: bool) {
+        self.clock = self.clock.wrapping_add(cycles);
+        let inhibit = self.csr.load(MCOUNTINHIBIT);
+        if inhibit & MASK_CY == 0 {
+            let cycle = self.csr.load(MCYCLE).data.wrapping_add(cycles);
+            self.csr.store(MCYCLE, cycle);
+        }
+        if retired {
+            self.retired += 1;
+            self.consecutive_traps = 0;
+        }
+        if retired && inhibit & MASK_IR == 0 {
+            self.unflushed_instret += 1;
+        }
+    }
+
+    /// Add the batched retired instructions to `minstret`.
+    pub fn flush_instret(&mut self) {
+        if self.unflushed_instret != 0 {
+            let instret = self
+                .csr
+                .load(MINSTRET)
+                .data
+                .wrapping_add(self.unflushed_instret);
+            self.csr.store(MINSTRET, instret);
+            self.unflushed_instret = 0;
+        }
+    }
+
+    pub fn update_paging(&mut self, csr_addr: usize) {
+        if csr_addr != SATP {
+            return;
+        }
+
+        let satp = self.csr.load(SATP);
+        self.mmu.set_satp(satp.into());
+    }
+
+    /// Raise the PLIC interrupt `source` to the contexts of this hart that enable it at its
+    /// priority. The next `step` takes it if the hart has the interrupt enabled.
+    pub fn raise_external_interrupt(&mut self, source: u32) {
+        self.bus.plic.trigger(self.hart_id, source);
+    }
+
+    /// Raise the machine timer interrupt by moving `mtimecmp` back to `mtime`, so it stays pending
+    /// until software writes a later deadline. The next `step` takes it if the hart has the
+    /// interrupt enabled.
+    pub fn raise_timer_interrupt(&mut self) {
+        let mtime = self.bus.clint.mtime();
+        self.bus.clint.set_mtimecmp(mtime);
+    }
+
+    /// Hold `MTIP` up for as long as the CLINT's `mtime` has reached `mtimecmp`.
+    fn update_timer_interrupt(&mut self) {
+        if self.bus.clint.is_interrupting() {
+            self.csr.set(MIP, MASK_MTIP);
+        } else {
+            self.csr.clear(MIP, MASK_MTIP);
+        }
+    }
+
+    /// Raise `MEIP` and `SEIP` for the interrupts the PLIC holds for this hart.
+    fn update_external_interrupts(&mut self) {
+        if self.bus.uart.is_interrupting() {
+            self.bus.plic.trigger(self.hart_id, UART_IRQ as u32);
+        }
+        if self.bus.plic.external_pending(self.hart_id, MACHINE_MODE) {
+            self.csr.set(MIP, MASK_MEIP);
+        }
+        if self
+            .bus
+            .plic
+            .external_pending(self.hart_id, SUPERVISOR_MODE)
+        {
+            self.csr.set(MIP, MASK_SEIP);
+        }
+    }
+
+    pub fn check_pending_interrupt(&mut self) -> Option<Interrupt> {
+        use Interrupt::*;
+        self.update_external_interrupts();
+        if (self.mode == MACHINE_MODE) && (self.csr.load(MSTATUS) & MASK_MIE) == 0 {
+            return None;
+        }
+        if (self.mode == SUPERVISOR_MODE) && (self.csr.load(SSTATUS) & MASK_SIE) == 0 {
+            return None;
+        }
+
+        // In fact, we should using priority to decide which interrupt should be handled first.
+        let pending = self.csr.load(MIE) & self.csr.load(MIP);
+
+        if (pending & MASK_MEIP) != 0 {
+            self.csr.clear(MIP, MASK_MEIP);
+            return Some(MachineExternalInterrupt);
+        }
+        if (pending & MASK_MSIP) != 0 {
+            self.csr.clear(MIP, MASK_MSIP);
+            return Some(MachineSoftwareInterrupt);
+        }
+        // MTIP follows the CLINT and stays up until software moves mtimecmp past mtime.
+        if (pending & MASK_MTIP) != 0 {
+            return Some(MachineTimerInterrupt);
+        }
+        if (pending & MASK_SEIP) != 0 {
+            self.csr.clear(MIP, MASK_SEIP);
+            return Some(SupervisorExternalInterrupt);
+        }
+        if (pending & MASK_SSIP) != 0 {
+            self.csr.clear(MIP, MASK_SSIP);
+            return Some(SupervisorSoftwareInterrupt);
+        }
+        if (pending & MASK_STIP) != 0 {
+            self.csr.clear(MIP, MASK_STIP);
+            return Some(SupervisorTimerInterrupt);
+        }
+        None
+    }
+
+    pub fn fetch(&mut self) -> Result<RiscvInstWrapper, Exception> {
+        if let Some(inst) = self.next_in_block() {
+            return Ok(inst);
+        }
+        // Jumps check their targets, but the pc can also come from xepc or the loader.
+        if !self.pc.is_multiple_of(self.inst_align()) {
+            return Err(Exception::InstructionAddrMisaligned(self.pc));
+        }
+        let addr = self.translate(Instruction, self.pc)?;
+        self.block_lookups += 1;
+        let block = match self.decode_cache.get(addr) {
+            Some(block) => block,
+            None => self.decode_block(addr)?,
+        };
+        self.block_cursor = Some(BlockCursor {
+            block,
+            index: 0,
+            pc: self.pc,
+            mode: self.mode,
+            satp: self.csr.load(SATP).data,
+        });
+        Ok(self.next_in_block().expect("blocks are never empty"))
+    }
+
+    /// Take the next instruction of the block the last fetch looked up, if the pc has moved on to
+    /// it without anything that could change its translation happening in between.
+    fn next_in_block(&mut self) -> Option<RiscvInstWrapper> {
+        let mut cursor = self.block_cursor.take()?;
+        if cursor.pc != self.pc
+            || cursor.mode != self.mode
+            || cursor.satp != self.csr.load(SATP).data
+        {
+            return None;
+        }
+        let (bits, inst) = cursor.block[cursor.index];
+        cursor.index += 1;
+        cursor.pc += inst.size();
+        if cursor.index < cursor.block.len() {
+            self.block_cursor = Some(cursor);
+        }
+        self.inst_bits = bits;
+        Some(inst)
+    }
+
+    /// Decode the basic block starting at the pc, which lives at the physical address `addr`, and
+    /// cache it if it is in DRAM. It ends at an instruction that ends a batch, at the end of the
+    /// page, or after `MAX_BLOCK_LEN` instructions, and only a fault on its first instruction is
+    /// raised.
+    fn decode_block(&mut self, addr: u64) -> Result<Block, Exception> {
+        let in_dram = (DRAM_BASE..DRAM_BASE + DRAM_SIZE).contains(&addr);
+        let bits = self.fetch_bits(self.pc, addr)?;
+        let first = self.decode_bits(bits);
+        let mut insts = vec![first];
+        // An instruction spilling onto the next page would go stale when only that page is
+        // remapped, so such blocks are not cached.
+        let last = self.pc.wrapping_add(first.1.size() - 1);
+        if self.pc / PAGE_SIZE != last / PAGE_SIZE {
+            return Ok(insts.into());
+        }
+        let (mut pc, mut paddr) = (self.pc, addr);
+        while in_dram && insts.len() < MAX_BLOCK_LEN {
+            let (_, inst) = insts[insts.len() - 1];
+            if ends_batch(&inst.get_inst()) {
+                break;
+            }
+            pc = pc.wrapping_add(inst.size());
+            paddr += inst.size();
+            // Leave any instruction that might cross onto the next page to a block of its own.
+            if pc % PAGE_SIZE > PAGE_SIZE - 4 {
+                break;
+            }
+            let Ok(bits) = self.fetch_bits(pc, paddr) else {
+                break;
+            };
+            insts.push(self.decode_bits(bits));
+        }
+        let block: Block = insts.into();
+        if in_dram {
+            self.decode_cache.insert(addr, block.clone());
+        }
+        Ok(block)
+    }
+
+    /// Decode the instruction fetched as `bits`, also returning its raw bits.
+    fn decode_bits(&self, bits: u32) -> (u32, RiscvInstWrapper) {
+        if bits & 0b11 == 0b11 {
+            (bits, RiscvInstWrapper::Full(decode_for(bits, &self.isa)))
+        } else {
+            let bits = bits as u16;
+            let inst = RiscvInstWrapper::Compact(decode_compressed_for(bits, &self.isa));
+            (bits.into(), inst)
+        }
+    }
+
+    /// Drop every decoded instruction, as `fence.i` does.
+    pub fn flush_decode_cache(&mut self) {
+        self.decode_cache.flush();
+        self.block_cursor = None;
+    }
+
+    /// Read the encoding of the instruction at `pc`, which lives at the physical address `addr`.
+    /// Only the low 16 bits matter when it turns out to be compressed.
+    fn fetch_bits(&mut self, pc: u64, addr: u64) -> Result<u32, Exception> {
+        if !self.bus.is_executable(addr) {
+            return Err(Exception::InstructionAccessFault(pc));
+        }
+        // An aligned word never crosses a page, so a single load covers either size. Near the
+        // end of memory only the half word may exist, which the fallback below handles.
+        if pc.is_multiple_of(4) {
+            if let Ok(word) = self.bus.load(addr, 4) {
+                return Ok(word as u32);
             }
-            RiscvInst::FdivS {
-                frd,
-                frs1,
-                frs2,
-                rm,
-            } => {
-                let a = self.f[frs1 as usize] as f32;
-                let b = self.f[frs2 as usize] as f32;
-                self.f[frd as usize] = (a / b) as f64;
+        }
+        // The bus already assembles the value according to the memory endianness. It reports
+        // faults as loads, which a fetch turns into instruction access faults.
+        let low = self
+            .bus
+            .load(addr, 2)
+            .map_err(|_| Exception::InstructionAccessFault(pc))? as u32;
+        if low & 0b11 != 0b11 {
+            return Ok(low);
+        }
+        // The upper half of a full instruction at the end of a page lives on the next one.
+        let upper = pc.wrapping_add(2);
+        let upper_addr = if upper.is_multiple_of(PAGE_SIZE) {
+            self.translate(Instruction, upper)?
+        } else {
+            addr + 2
+        };
+        if !self.bus.is_executable(upper_addr) {
+            return Err(Exception::InstructionAccessFault(upper));
+        }
+        let high = self
+            .bus
+            .load(upper_addr, 2)
+            .map_err(|_| Exception::InstructionAccessFault(upper))? as u32;
+        Ok(high << 16 | low)
+    }
+
+    pub fn execute(&mut self, inst: RiscvInstWrapper) -> Result<u64, Exception> {
+        let raw_inst = match inst {
+            RiscvInstWrapper::Full(inst) | RiscvInstWrapper::Compact(inst) => inst,
+        };
+        // The fall-through pc, which is also the return address of jumps.
+        let next_pc = if inst.is_compact() {
+            self.pc + 2
+        } else {
+            self.pc + 4
+        };
+
+        // With the FPU off every floating-point instruction is illegal, which lets the guest
+        // enable it lazily.
+        let uses_fpu = raw_inst.uses_fpu();
+        if uses_fpu && self.csr.load(MSTATUS) & MASK_FS == 0 {
+            return Err(Exception::IllegalInstruction(self.pc));
+        }
+
+        match raw_inst {
+            RiscvInst::Illegal => return Err(Exception::IllegalInstruction(self.pc)),
+            RiscvInst::Lb { rd, rs1, imm } => {
+                let val = self.load(addr_add(self.x[rs1 as usize], imm), 1)?;
+                self.set_x(rd, val as u8 as i8 as u64);
             }
-            RiscvInst::FsqrtS { frd, frs1, rm } => {
-                let a = self.f[frs1 as usize] as f32;
-                self.f[frd as usize] = a.sqrt() as f64;
+            RiscvInst::Lh { rd, rs1, imm } => {
+                let val = self.load(addr_add(self.x[rs1 as usize], imm), 2)?;
+                self.set_x(rd, val as u16 as i16 as u64);
             }
-            RiscvInst::FsgnjS { frd, frs1, frs2 } => {
-                let a = self.f[frs1 as usize] as f32;
-                let b = self.f[frs2 as usize] as f32;
-                let sign = b.to_bits() & 0x8000_0000;
-                self.f[frd as usize] = f32::from_bits(a.to_bits() & !0x8000_0000 | sign) as f64;
+            RiscvInst::Lw { rd, rs1, imm } => {
+                let val = self.load(addr_add(self.x[rs1 as usize], imm), 4)?;
+                self.set_x(rd, val as u32 as i32 as u64);
             }
-            RiscvInst::FsgnjnS { frd, frs1, frs2 } => {
-                let a = self.f[frs1 as usize] as f32;
-                let b = self.f[frs2 as usize] as f32;
-                let sign = b.to_bits() & 0x8000_0000;
-                self.f[frd as usize] = f32::from_bits(a.to_bits() | sign) as f64;
+            RiscvInst::Ld { rd, rs1, imm } => {
+                let val = self.load(addr_add(self.x[rs1 as usize], imm), 8)?;
+                self.set_x(rd, val as i64 as u64);
             }
-            RiscvInst::FsgnjxS { frd, frs1, frs2 } => {
-                let a = self.f[frs1 as usize] as f32;
-                let b = self.f[frs2 as usize] as f32;
-                let sign = b.to_bits() & 0x8000_0000;
-                self.f[frd as usize] = f32::from_bits(a.to_bits() ^ sign) as f64;
+            RiscvInst::Lbu { rd, rs1, imm } => {
+                let val = self.load(addr_add(self.x[rs1 as usize], imm), 1)?;
+                self.set_x(rd, val as u8 as u64);
             }
-            RiscvInst::FminS { frd, frs1, frs2 } => {
-                let a = self.f[frs1 as usize] as f32;
-                let b = self.f[frs2 as usize] as f32;
-                self.f[frd as usize] = a.min(b) as f64;
+            RiscvInst::Lhu { rd, rs1, imm } => {
+                let val = self.load(addr_add(self.x[rs1 as usize], imm), 2)?;
+                self.set_x(rd, val as u16 as u64);
             }
-            RiscvInst::FmaxS { frd, frs1, frs2 } => {
-                let a = self.f[frs1 as usize] as f32;
-                let b = self.f[frs2 as usize] as f32;
-                self.f[frd as usize] = a.max(b) as f64;
+            RiscvInst::Lwu { rd, rs1, imm } => {
+                let val = self.load(addr_add(self.x[rs1 as usize], imm), 4)?;
+                self.set_x(rd, val as u32 as u64);
             }
-            RiscvInst::FcvtWS { rd, frs1, rm } => {
-                let a = self.f[frs1 as usize] as f32;
-                self.x[rd as usize] = a as i32 as u64;
+            RiscvInst::Fence => {}
+            RiscvInst::FenceI => self.flush_decode_cache(),
+            RiscvInst::Pause => {}
+            // There are no caches to manage, memory is always coherent.
+            RiscvInst::CboClean { .. }
+            | RiscvInst::CboFlush { .. }
+            | RiscvInst::CboInval { .. } => {}
+            RiscvInst::CboZero { rs1 } => {
+                let block = self.x[rs1 as usize] & !(CACHE_BLOCK_SIZE - 1);
+                for offset in (0..CACHE_BLOCK_SIZE).step_by(8) {
+                    self.store(block + offset, 8, 0)?;
+                }
             }
-            RiscvInst::FcvtWuS { rd, frs1, rm } => {
-                let a = self.f[frs1 as usize] as f32;
-                self.x[rd as usize] = a as u64;
+            RiscvInst::Addi { rd, rs1, imm } => {
+                self.set_x(rd, self.x[rs1 as usize].wrapping_add(imm as u64));
             }
-            RiscvInst::FcvtLS { rd, frs1, rm } => {
-                let a = self.f[frs1 as usize] as f32;
-                self.x[rd as usize] = a as i64 as u64;
+            RiscvInst::Slli { rd, rs1, imm } => {
+                self.set_x(rd, self.x[rs1 as usize].wrapping_shl(imm as u32));
             }
-            RiscvInst::FcvtLuS { rd, frs1, rm } => {
-                let a = self.f[frs1 as usize] as f32;
-                self.x[rd as usize] = a as u64;
+            RiscvInst::Slti { rd, rs1, imm } => {
+                self.set_x(
+                    rd,
+                    if (self.x[rs1 as usize] as i64) < (imm as i64) {
+                        1
+                    } else {
+                        0
+                    },
+                );
             }
-            RiscvInst::FmvXW { rd, frs1 } => {
-                let a = self.f[frs1 as usize] as f32;
-                self.x[rd as usize] = a as u64;
+            RiscvInst::Sltiu { rd, rs1, imm } => {
+                self.set_x(
+                    rd,
+                    if self.x[rs1 as usize] < (imm as u64) {
+                        1
+                    } else {
+                        0
+                    },
+                );
             }
-            RiscvInst::FclassS { rd, frs1 } => {
-                let a = self.f[frs1 as usize] as f32;
-                self.x[rd as usize] = float_classify(a) as u64;
+            RiscvInst::Xori { rd, rs1, imm } => {
+                self.set_x(rd, self.x[rs1 as usize] ^ (imm as u64));
             }
-            RiscvInst::FeqS { rd, frs1, frs2 } => {
-                let a = self.f[frs1 as usize] as f32;
-                let b = self.f[frs2 as usize] as f32;
-                self.x[rd as usize] = (a == b) as u64;
+            RiscvInst::Srli { rd, rs1, imm } => {
+                self.set_x(rd, self.x[rs1 as usize].wrapping_shr(imm as u32));
             }
-            RiscvInst::FltS { rd, frs1, frs2 } => {
-                let a = self.f[frs1 as usize] as f32;
-                let b = self.f[frs2 as usize] as f32;
-                self.x[rd as usize] = (a < b) as u64;
+            RiscvInst::Srai { rd, rs1, imm } => {
+                self.set_x(
+                    rd,
+                    (self.x[rs1 as usize] as i64).wrapping_shr(imm as u32) as u64,
+                );
             }
-            RiscvInst::FleS { rd, frs1, frs2 } => {
-                let a = self.f[frs1 as usize] as f32;
-                let b = self.f[frs2 as usize] as f32;
-                self.x[rd as usize] = (a <= b) as u64;
+            RiscvInst::Ori { rd, rs1, imm } => {
+                self.set_x(rd, self.x[rs1 as usize] | (imm as u64));
             }
-            RiscvInst::FcvtSW { frd, rs1, rm } => {
-                let a = self.x[rs1 as usize] as i32 as u32;
-                self.f[frd as usize] = a as f32 as f64;
+            RiscvInst::Andi { rd, rs1, imm } => {
+                self.set_x(rd, self.x[rs1 as usize] & (imm as u64));
             }
-            RiscvInst::FcvtSWu { frd, rs1, rm } => {
-                let a = self.x[rs1 as usize] as u32;
-                self.f[frd as usize] = a as f32 as f64;
+            // The immediate is already shifted into place, so only sign-extension is needed.
+            RiscvInst::Auipc { rd, imm } => {
+                self.set_x(rd, addr_add(self.pc, imm));
             }
-            RiscvInst::FcvtSL { frd, rs1, rm } => {
-                let a = self.x[rs1 as usize] as i64 as u64;
-                self.f[frd as usize] = a as f32 as f64;
+            RiscvInst::Lui { rd, imm } => {
+                self.set_x(rd, imm as u64);
             }
-            RiscvInst::FcvtSLu { frd, rs1, rm } => {
-                let a = self.x[rs1 as usize] as u64;
-                self.f[frd as usize] = a as f32 as f64;
+            RiscvInst::Addiw { rd, rs1, imm } => {
+                self.set_x(
+                    rd,
+                    (self.x[rs1 as usize].wrapping_add(imm as u64)) as u32 as u64,
+                );
             }
-            RiscvInst::FmvWX { frd, rs1 } => {
-                let a = self.x[rs1 as usize] as u32;
-                self.f[frd as usize] = a as f32 as f64;
+            RiscvInst::Slliw { rd, rs1, imm } => {
+                let shamt = (imm & 0x1f) as u32;
+                self.set_x(rd, (self.x[rs1 as usize] as u32).wrapping_shl(shamt) as u64);
             }
-            RiscvInst::FmaddS {
-                frd,
-                frs1,
-                frs2,
-                frs3,
-                rm,
-            } => {
-                let a = self.f[frs1 as usize] as f32;
-                let b = self.f[frs2 as usize] as f32;
-                let c = self.f[frs3 as usize] as f32;
-                self.f[frd as usize] = (a * b + c) as f64;
+            RiscvInst::Srliw { rd, rs1, imm } => {
+                let shamt = (imm & 0x1f) as u32;
+                self.set_x(rd, (self.x[rs1 as usize] as u32).wrapping_shr(shamt) as u64);
             }
-            RiscvInst::FmsubS {
-                frd,
-                frs1,
-                frs2,
-                frs3,
-                rm,
-            } => {
-                let a = self.f[frs1 as usize] as f32;
-                let b = self.f[frs2 as usize] as f32;
-                let c = self.f[frs3 as usize] as f32;
-                self.f[frd as usize] = (a * b - c) as f64;
+            RiscvInst::Sraiw { rd, rs1, imm } => {
+                let shamt = (imm & 0x1f) as u32;
+                self.set_x(rd, (self.x[rs1 as usize] as i32).wrapping_shr(shamt) as u64);
             }
-            RiscvInst::FnmsubS {
-                frd,
-                frs1,
-                frs2,
-                frs3,
-                rm,
-            } => {
-                let a = self.f[frs1 as usize] as f32;
-                let b = self.f[frs2 as usize] as f32;
-                let c = self.f[frs3 as usize] as f32;
-                self.f[frd as usize] = (-a * b - c) as f64;
+            RiscvInst::Addw { rd, rs1, rs2 } => {
+                self.set_x(
+                    rd,
+                    (self.x[rs1 as usize].wrapping_add(self.x[rs2 as usize])) as u32 as u64,
+                );
             }
-            RiscvInst::FnmaddS {
-                frd,
-                frs1,
-                frs2,
-                frs3,
-                rm,
-            } => {
-                let a = self.f[frs1 as usize] as f32;
-                let b = self.f[frs2 as usize] as f32;
-                let c = self.f[frs3 as usize] as f32;
-                self.f[frd as usize] = (-a * b + c) as f64;
+            RiscvInst::Subw { rd, rs1, rs2 } => {
+                self.set_x(
+                    rd,
+                    (self.x[rs1 as usize].wrapping_sub(self.x[rs2 as usize])) as u32 as u64,
+                );
             }
-            RiscvInst::Fld { frd, rs1, imm } => {
-                let val = self.load(addr_add(self.x[rs1 as usize], imm), 8)?;
-                self.f[frd as usize] = f64::from_bits(val);
+            RiscvInst::Sllw { rd, rs1, rs2 } => {
+                let shamt = (self.x[rs2 as usize] & 0x1f) as u32;
+                self.set_x(rd, (self.x[rs1 as usize] as u32).wrapping_shl(shamt) as u64);
             }
-            RiscvInst::Fsd { rs1, frs2, imm } => {
-                self.store(
-                    addr_add(self.x[rs1 as usize], imm),
-                    8,
-                    self.f[frs2 as usize].to_bits(),
-                );
+            RiscvInst::Srlw { rd, rs1, rs2 } => {
+                let shamt = (self.x[rs2 as usize] & 0x1f) as u32;
+                self.set_x(rd, (self.x[rs1 as usize] as u32).wrapping_shr(shamt) as u64);
             }
-            RiscvInst::FaddD {
-                frd,
-                frs1,
-                frs2,
-                rm,
-            } => {
-                let a = self.f[frs1 as usize];
-                let b = self.f[frs2 as usize];
-                self.f[frd as usize] = a + b;
+            RiscvInst::Sraw { rd, rs1, rs2 } => {
+                let shamt = (self.x[rs2 as usize] & 0x1f) as u32;
+                self.set_x(rd, (self.x[rs1 as usize] as i32).wrapping_shr(shamt) as u64);
             }
-            RiscvInst::FsubD {
-                frd,
-                frs1,
-                frs2,
-                rm,
-            } => {
-                let a = self.f[frs1 as usize];
-                let b = self.f[frs2 as usize];
-                self.f[frd as usize] = a - b;
+            RiscvInst::Sb { rs1, rs2, imm } => {
+                self.store(addr_add(self.x[rs1 as usize], imm), 1, self.x[rs2 as usize])?;
             }
-            RiscvInst::FmulD {
-                frd,
-                frs1,
-                frs2,
-                rm,
-            } => {
-                let a = self.f[frs1 as usize];
-                let b = self.f[frs2 as usize];
-                self.f[frd as usize] = a * b;
+            RiscvInst::Sh { rs1, rs2, imm } => {
+                self.store(addr_add(self.x[rs1 as usize], imm), 2, self.x[rs2 as usize])?;
             }
-            RiscvInst::FdivD {
-                frd,
-                frs1,
-                frs2,
-                rm,
-            } => {
-                let a = self.f[frs1 as usize];
-                let b = self.f[frs2 as usize];
-                self.f[frd as usize] = a / b;
+            RiscvInst::Sw { rs1, rs2, imm } => {
+                self.store(addr_add(self.x[rs1 as usize], imm), 4, self.x[rs2 as usize])?;
             }
-            RiscvInst::FsqrtD { frd, frs1, rm } => {
-                let a = self.f[frs1 as usize];
-                self.f[frd as usize] = a.sqrt();
+            RiscvInst::Sd { rs1, rs2, imm } => {
+                self.store(addr_add(self.x[rs1 as usize], imm), 8, self.x[rs2 as usize])?;
             }
-            RiscvInst::FsgnjD { frd, frs1, frs2 } => {
-                let a = self.f[frs1 as usize];
-                let b = self.f[frs2 as usize];
-                let sign = f64::to_bits(b) & (1 << 63);
-                self.f[frd as usize] = f64::from_bits(f64::to_bits(a) & !(1 << 63) | sign);
+            RiscvInst::Add { rd, rs1, rs2 } => {
+                self.set_x(rd, self.x[rs1 as usize].wrapping_add(self.x[rs2 as usize]));
             }
-            RiscvInst::FsgnjnD { frd, frs1, frs2 } => {
-                let a = self.f[frs1 as usize];
-                let b = self.f[frs2 as usize];
-                let sign = f64::to_bits(b) & (1 << 63);
-                self.f[frd as usize] = f64::from_bits(f64::to_bits(a) & !(1 << 63) | !sign);
+            RiscvInst::Sub { rd, rs1, rs2 } => {
+                self.set_x(rd, self.x[rs1 as usize].wrapping_sub(self.x[rs2 as usize]));
             }
-            RiscvInst::FsgnjxD { frd, frs1, frs2 } => {
-                let a = self.f[frs1 as usize];
-                let b = self.f[frs2 as usize];
-                let sign = f64::to_bits(b) & (1 << 63);
-                self.f[frd as usize] = f64::from_bits(f64::to_bits(a) ^ sign);
+            RiscvInst::Sll { rd, rs1, rs2 } => {
+                let shamt = (self.x[rs2 as usize] & 0x3f) as u32;
+                self.set_x(rd, self.x[rs1 as usize].wrapping_shl(shamt));
             }
-            RiscvInst::FminD { frd, frs1, frs2 } => {
-                let a = self.f[frs1 as usize];
-                let b = self.f[frs2 as usize];
-                self.f[frd as usize] = a.min(b);
+            RiscvInst::Slt { rd, rs1, rs2 } => {
+                self.set_x(
+                    rd,
+                    if (self.x[rs1 as usize] as i64) < (self.x[rs2 as usize] as i64) {
+                        1
+                    } else {
+                        0
+                    },
+                );
             }
-            RiscvInst::FmaxD { frd, frs1, frs2 } => {
-                let a = self.f[frs1 as usize];
-                let b = self.f[frs2 as usize];
-                self.f[frd as usize] = a.max(b);
+            RiscvInst::Sltu { rd, rs1, rs2 } => {
+                self.set_x(
+                    rd,
+                    if self.x[rs1 as usize] < self.x[rs2 as usize] {
+                        1
+                    } else {
+                        0
+                    },
+                );
             }
-            RiscvInst::FcvtSD { frd, frs1, rm } => {
-                let a = self.f[frs1 as usize] as f32;
-                self.f[frd as usize] = a as f64;
+            RiscvInst::Xor { rd, rs1, rs2 } => {
+                self.set_x(rd, self.x[rs1 as usize] ^ self.x[rs2 as usize]);
             }
-            RiscvInst::FcvtDS { frd, frs1, rm } => {
-                let a = self.f[frs1 as usize] as f64;
-                self.f[frd as usize] = a as f32 as f64;
+            RiscvInst::Srl { rd, rs1, rs2 } => {
+                let shamt = (self.x[rs2 as usize] & 0x3f) as u32;
+                self.set_x(rd, self.x[rs1 as usize].wrapping_shr(shamt));
             }
-            RiscvInst::FcvtWD { rd, frs1, rm } => {
-                let a = self.f[frs1 as usize] as f32;
-                self.x[rd as usize] = a as i32 as u64;
+            RiscvInst::Sra { rd, rs1, rs2 } => {
+                let shamt = (self.x[rs2 as usize] & 0x3f) as u32;
+                self.set_x(rd, (self.x[rs1 as usize] as i64).wrapping_shr(shamt) as u64);
             }
-            RiscvInst::FcvtWuD { rd, frs1, rm } => {
-                let a = self.f[frs1 as usize] as f32;
-                self.x[rd as usize] = a as u32 as u64;
+            RiscvInst::Or { rd, rs1, rs2 } => {
+                self.set_x(rd, self.x[rs1 as usize] | self.x[rs2 as usize]);
             }
-            RiscvInst::FcvtLD { rd, frs1, rm } => {
-                let a = self.f[frs1 as usize] as f32;
-                self.x[rd as usize] = a as i64 as u64;
+            RiscvInst::And { rd, rs1, rs2 } => {
+                self.set_x(rd, self.x[rs1 as usize] & self.x[rs2 as usize]);
             }
-            RiscvInst::FcvtLuD { rd, frs1, rm } => {
-                let a = self.f[frs1 as usize] as f32;
-                self.x[rd as usize] = a as u64;
+            RiscvInst::Beq { rs1, rs2, imm } => {
+                if self.x[rs1 as usize] == self.x[rs2 as usize] {
+                    return self.jump_target(addr_add(self.pc, imm));
+                }
             }
-            RiscvInst::FmvXD { rd, frs1 } => {
-                let a = self.f[frs1 as usize] as f32;
-                self.x[rd as usize] = a as u64;
+            RiscvInst::Bne { rs1, rs2, imm } => {
+                if self.x[rs1 as usize] != self.x[rs2 as usize] {
+                    return self.jump_target(addr_add(self.pc, imm));
+                }
             }
-            RiscvInst::FclassD { rd, frs1 } => {
-                let a = self.f[frs1 as usize];
-                self.x[rd as usize] = double_classify(a) as u64;
+            RiscvInst::Blt { rs1, rs2, imm } => {
+                if (self.x[rs1 as usize] as i64) < (self.x[rs2 as usize] as i64) {
+                    return self.jump_target(addr_add(self.pc, imm));
+                }
             }
-            RiscvInst::FeqD { rd, frs1, frs2 } => {
-                let a = self.f[frs1 as usize];
-                let b = self.f[frs2 as usize];
-                self.x[rd as usize] = if a == b { 1 } else { 0 };
+            RiscvInst::Bge { rs1, rs2, imm } => {
+                if (self.x[rs1 as usize] as i64) >= (self.x[rs2 as usize] as i64) {
+                    return self.jump_target(addr_add(self.pc, imm));
+                }
             }
-            RiscvInst::FltD { rd, frs1, frs2 } => {
-                let a = self.f[frs1 as usize];
-                let b = self.f[frs2 as usize];
-                self.x[rd as usize] = if a < b { 1 } else { 0 };
+            RiscvInst::Bltu { rs1, rs2, imm } => {
+                if self.x[rs1 as usize] < self.x[rs2 as usize] {
+                    return self.jump_target(addr_add(self.pc, imm));
+                }
             }
-            RiscvInst::FleD { rd, frs1, frs2 } => {
-                let a = self.f[frs1 as usize];
-                let b = self.f[frs2 as usize];
-                self.x[rd as usize] = if a <= b { 1 } else { 0 };
+            RiscvInst::Bgeu { rs1, rs2, imm } => {
+                if self.x[rs1 as usize] >= self.x[rs2 as usize] {
+                    return self.jump_target(addr_add(self.pc, imm));
+                }
             }
-            RiscvInst::FcvtDW { frd, rs1, rm } => {
-                let a = self.x[rs1 as usize] as i32 as i64;
-                self.f[frd as usize] = a as f64;
+            RiscvInst::Jalr { rd, rs1, imm } => {
+                let addr = addr_add(self.x[rs1 as usize], imm);
+                let target = self.jump_target(addr & !1)?;
+                self.set_x(rd, next_pc);
+                return Ok(target);
             }
-            RiscvInst::FcvtDWu { frd, rs1, rm } => {
-                let a = self.x[rs1 as usize] as u32 as i64;
-                self.f[frd as usize] = a as f64;
+            RiscvInst::Jal { rd, imm } => {
+                let target = self.jump_target(addr_add(self.pc, imm))?;
+                self.set_x(rd, next_pc);
+                return Ok(target);
+            }
+            RiscvInst::Ecall => match self.mode {
+                USER_MODE if self.proxy.is_some() => self.proxy_call()?,
+                USER_MODE => {
+                    return Err(Exception::EnvironmentCallFromUMode(self.pc));
+                }
+                SUPERVISOR_MODE => {
+                    return Err(Exception::EnvironmentCallFromSMode(self.pc));
+                }
+                MACHINE_MODE => {
+                    return Err(Exception::EnvironmentCallFromMMode(self.pc));
+                }
+                _ => unreachable!(),
+            },
+            RiscvInst::Ebreak => {
+                if self.semihosting.is_none() || inst.is_compact() || !self.is_semihosting_call() {
+                    return Err(Exception::Breakpoint(self.pc));
+                }
+                self.semihosting_call()?;
+            }
+
+            RiscvInst::Csrrw { rd, rs1, csr } => {
+                self.check_csr_access(csr, true)?;
+                // with x0 as the destination this is only a write, without any side effects of a
+                // read
+                let t = if rd != 0 {
+                    self.read_csr(csr)
+                } else {
+                    0.into()
+                };
+                self.csr.store(csr.into(), self.x[rs1 as usize]);
+                self.set_x(rd, t.into());
+                self.update_paging(csr.into());
+            }
+            RiscvInst::Csrrs { rd, rs1, csr } => {
+                self.check_csr_access(csr, rs1 != 0)?;
+                let t = self.read_csr(csr);
+                // with x0 as the mask this is only a read, without any side effects of a write
+                if rs1 != 0 {
+                    self.csr
+                        .store(csr.into(), (t | self.x[rs1 as usize]).into());
+                    self.update_paging(csr.into());
+                }
+                self.set_x(rd, t.into());
+            }
+            RiscvInst::Csrrc { rd, rs1, csr } => {
+                self.check_csr_access(csr, rs1 != 0)?;
+                let t = self.read_csr(csr);
+                if rs1 != 0 {
+                    self.csr
+                        .store(csr.into(), (t & !self.x[rs1 as usize]).into());
+                    self.update_paging(csr.into());
+                }
+                self.set_x(rd, t.into());
             }
-            RiscvInst::FcvtDL { frd, rs1, rm } => {
+            RiscvInst::Csrrwi { rd, imm, csr } => {
+                self.check_csr_access(csr, true)?;
+                let t = if rd != 0 {
+                    self.read_csr(csr)
+                } else {
+                    0.into()
+                };
+                self.csr.store(csr.into(), imm as u64);
+                self.set_x(rd, t.into());
+                self.update_paging(csr.into());
+            }
+            RiscvInst::Csrrsi { rd, imm, csr } => {
+                self.check_csr_access(csr, imm != 0)?;
+                let t = self.read_csr(csr);
+                if imm != 0 {
+                    self.csr.store(csr.into(), (t | (imm as u64)).into());
+                    self.update_paging(csr.into());
+                }
+                self.set_x(rd, t.into());
+            }
+            RiscvInst::Csrrci { rd, imm, csr } => {
+                self.check_csr_access(csr, imm != 0)?;
+                let t = self.read_csr(csr);
+                if imm != 0 {
+                    self.csr.store(csr.into(), (t & !(imm as u64)).into());
+                    self.update_paging(csr.into());
+                }
+                self.set_x(rd, t.into());
+            }
+
+            RiscvInst::Mul { rd, rs1, rs2 } => {
+                self.set_x(rd, self.x[rs1 as usize].wrapping_mul(self.x[rs2 as usize]));
+            }
+            RiscvInst::Mulh { rd, rs1, rs2 } => {
                 let a = self.x[rs1 as usize] as i64;
-                self.f[frd as usize] = a as f64;
+                let b = self.x[rs2 as usize] as i64;
+                self.set_x(rd, (a.wrapping_mul(b) >> 32) as u64);
             }
-            RiscvInst::FcvtDLu { frd, rs1, rm } => {
-                let a = self.x[rs1 as usize] as u64;
-                self.f[frd as usize] = a as f64;
+            RiscvInst::Mulhsu { rd, rs1, rs2 } => {
+                let a = self.x[rs1 as usize] as i64;
+                let b = self.x[rs2 as usize];
+                self.set_x(rd, (a.wrapping_mul(b as i64) >> 32) as u64);
             }
-            RiscvInst::FmvDX { frd, rs1 } => {
-                let a = self.x[rs1 as usize] as u64;
-                self.f[frd as usize] = a as f64;
+            RiscvInst::Mulhu { rd, rs1, rs2 } => {
+                let a = self.x[rs1 as usize];
+                let b = self.x[rs2 as usize];
+                self.set_x(rd, a.wrapping_mul(b) >> 32);
             }
-            RiscvInst::FmaddD {
-                frd,
-                frs1,
-                frs2,
-                frs3,
-                rm,
+            RiscvInst::Div { rd, rs1, rs2 } => {
+                let a = self.x[rs1 as usize] as i64;
+                let b = self.x[rs2 as usize] as i64;
+                self.set_x(
+                    rd,
+                    if b == 0 {
+                        u64::MAX
+                    } else {
+                        a.wrapping_div(b) as u64
+                    },
+                );
+            }
+            RiscvInst::Divu { rd, rs1, rs2 } => {
+                let a = self.x[rs1 as usize];
+                let b = self.x[rs2 as usize];
+                self.set_x(rd, if b == 0 { u64::MAX } else { a.wrapping_div(b) });
+            }
+            RiscvInst::Rem { rd, rs1, rs2 } => {
+                let a = self.x[rs1 as usize] as i64;
+                let b = self.x[rs2 as usize] as i64;
+                self.set_x(
+                    rd,
+                    if b == 0 {
+                        a as u64
+                    } else {
+                        a.wrapping_rem(b) as u64
+                    },
+                );
+            }
+            RiscvInst::Remu { rd, rs1, rs2 } => {
+                let a = self.x[rs1 as usize];
+                let b = self.x[rs2 as usize];
+                self.set_x(rd, if b == 0 { a } else { a.wrapping_rem(b) });
+            }
+            RiscvInst::Mulw { rd, rs1, rs2 } => {
+                let a = self.x[rs1 as usize] as i32;
+                let b = self.x[rs2 as usize] as i32;
+                self.set_x(rd, (a.wrapping_mul(b)) as u64);
+            }
+            RiscvInst::Divw { rd, rs1, rs2 } => {
+                let a = self.x[rs1 as usize] as i32;
+                let b = self.x[rs2 as usize] as i32;
+                self.set_x(
+                    rd,
+                    if b == 0 {
+                        u64::MAX
+                    } else {
+                        (a.wrapping_div(b)) as u64
+                    },
+                );
+            }
+            RiscvInst::Divuw { rd, rs1, rs2 } => {
+                let a = self.x[rs1 as usize] as u32;
+                let b = self.x[rs2 as usize] as u32;
+                // the 32-bit result is sign-extended even for unsigned division
+                self.set_x(
+                    rd,
+                    if b == 0 {
+                        u64::MAX
+                    } else {
+                        (a.wrapping_div(b)) as i32 as u64
+                    },
+                );
+            }
+            RiscvInst::Remw { rd, rs1, rs2 } => {
+                let a = self.x[rs1 as usize] as i32;
+                let b = self.x[rs2 as usize] as i32;
+                self.set_x(
+                    rd,
+                    if b == 0 {
+                        a as u64
+                    } else {
+                        (a.wrapping_rem(b)) as u64
+                    },
+                );
+            }
+            RiscvInst::Remuw { rd, rs1, rs2 } => {
+                let a = self.x[rs1 as usize] as u32;
+                let b = self.x[rs2 as usize] as u32;
+                self.set_x(
+                    rd,
+                    if b == 0 {
+                        a as i32 as u64
+                    } else {
+                        (a.wrapping_rem(b)) as i32 as u64
+                    },
+                );
+            }
+            RiscvInst::LrW { rd, rs1, .. } => {
+                let val = self.load_reserved(self.x[rs1 as usize], 4)?;
+                self.set_x(rd, val as i32 as u64);
+            }
+            RiscvInst::LrD { rd, rs1, .. } => {
+                let val = self.load_reserved(self.x[rs1 as usize], 8)?;
+                self.set_x(rd, val);
+            }
+            RiscvInst::ScW { rd, rs1, rs2, .. } => {
+                let stored =
+                    self.store_conditional(self.x[rs1 as usize], 4, self.x[rs2 as usize])?;
+                self.set_x(rd, (!stored).into());
+            }
+            RiscvInst::ScD { rd, rs1, rs2, .. } => {
+                let stored =
+                    self.store_conditional(self.x[rs1 as usize], 8, self.x[rs2 as usize])?;
+                self.set_x(rd, (!stored).into());
+            }
+            RiscvInst::AmoswapW { .. }
+            | RiscvInst::AmoswapD { .. }
+            | RiscvInst::AmoaddW { .. }
+            | RiscvInst::AmoaddD { .. }
+            | RiscvInst::AmoxorW { .. }
+            | RiscvInst::AmoxorD { .. }
+            | RiscvInst::AmoandW { .. }
+            | RiscvInst::AmoandD { .. }
+            | RiscvInst::AmoorW { .. }
+            | RiscvInst::AmoorD { .. }
+            | RiscvInst::AmominW { .. }
+            | RiscvInst::AmominD { .. }
+            | RiscvInst::AmomaxW { .. }
+            | RiscvInst::AmomaxD { .. }
+            | RiscvInst::AmominuW { .. }
+            | RiscvInst::AmominuD { .. }
+            | RiscvInst::AmomaxuW { .. }
+            | RiscvInst::AmomaxuD { .. } => return Err(self.unimplemented_instruction(raw_inst)),
+
+            RiscvInst::Flw { frd, rs1, imm } => {
+                // loads and stores move raw bits, so signaling NaNs keep their payload
+                let val = self.load(addr_add(self.x[rs1 as usize], imm), 4)?;
+                self.f[frd as usize] = f64::from_bits(NAN_BOX | val);
+            }
+            RiscvInst::Fsw { rs1, frs2, imm } => {
+                let val = self.f[frs2 as usize].to_bits() as u32;
+                self.store(addr_add(self.x[rs1 as usize], imm), 4, val.into())?;
+            }
+            RiscvInst::FaddS {
+                frd, frs1, frs2, ..
             } => {
-                let a = self.f[frs1 as usize];
-                let b = self.f[frs2 as usize];
-                let c = self.f[frs3 as usize];
-                self.f[frd as usize] = a * b + c;
+                let a = self.read_f32(frs1);
+                let b = self.read_f32(frs2);
+                self.write_f32(frd, a + b);
             }
-            RiscvInst::FmsubD {
-                frd,
-                frs1,
-                frs2,
-                frs3,
-                rm,
+            RiscvInst::FsubS {
+                frd, frs1, frs2, ..
             } => {
-                let a = self.f[frs1 as usize];
-                let b = self.f[frs2 as usize];
-                let c = self.f[frs3 as usize];
-                self.f[frd as usize] = a * b - c;
+                let a = self.read_f32(frs1);
+                let b = self.read_f32(frs2);
+                self.write_f32(frd, a - b);
             }
-            RiscvInst::FnmsubD {
-                frd,
-                frs1,
-                frs2,
-                frs3,
-                rm,
+            RiscvInst::FmulS {
+                frd, frs1, frs2, ..
             } => {
-                let a = self.f[frs1 as usize];
-                let b = self.f[frs2 as usize];
-                let c = self.f[frs3 as usize];
-                self.f[frd as usize] = -(a * b - c);
+                let a = self.read_f32(frs1);
+                let b = self.read_f32(frs2);
+                self.write_f32(frd, a * b);
             }
-            RiscvInst::FnmaddD {
-                frd,
-                frs1,
-                frs2,
-                frs3,
-                rm,
+            RiscvInst::FdivS {
+                frd, frs1, frs2, ..
             } => {
-                let a = self.f[frs1 as usize];
-                let b = self.f[frs2 as usize];
-                let c = self.f[frs3 as usize];
-                self.f[frd as usize] = -(a * b + c);
+                let a = self.read_f32(frs1);
+                let b = self.read_f32(frs2);
+                self.write_f32(frd, a / b);
             }
-            RiscvInst::Mret => {
-                let mut mstatus = self.csr.load(MSTATUS);
-                // MPP is two bits wide at MSTATUS[12:11]
-                self.mode = mstatus.mpp() as u8;
+            RiscvInst::FsqrtS { frd, frs1, .. } => {
+                let a = self.read_f32(frs1);
+                self.write_f32(frd, a.sqrt());
+            }
+            RiscvInst::FsgnjS { frd, frs1, frs2 } => {
+                let a = self.read_f32(frs1);
+                let b = self.read_f32(frs2);
+                let sign = b.to_bits() & 0x8000_0000;
+                self.write_f32(frd, f32::from_bits(a.to_bits() & !0x8000_0000 | sign));
+            }
+            RiscvInst::FsgnjnS { frd, frs1, frs2 } => {
+                let a = self.read_f32(frs1);
+                let b = self.read_f32(frs2);
+                let sign = !b.to_bits() & 0x8000_0000;
+                self.write_f32(frd, f32::from_bits(a.to_bits() & !0x8000_0000 | sign));
+            }
+            RiscvInst::FsgnjxS { frd, frs1, frs2 } => {
+                let a = self.read_f32(frs1);
+                let b = self.read_f32(frs2);
+                let sign = b.to_bits() & 0x8000_0000;
+                self.write_f32(frd, f32::from_bits(a.to_bits() ^ sign));
+            }
+            RiscvInst::FminS { frd, frs1, frs2 } => {
+                let a = self.read_f32(frs1);
+                let b = self.read_f32(frs2);
+                let signaling = signaling_nan(a) || signaling_nan(b);
+                let v = self.fmin_max(a.into(), b.into(), signaling, false);
+                self.write_f32(
+                    frd,
+                    v.map_or(f32::from_bits(CANONICAL_NAN_F32), |v| v as f32),
+                );
+            }
+            RiscvInst::FmaxS { frd, frs1, frs2 } => {
+                let a = self.read_f32(frs1);
+                let b = self.read_f32(frs2);
+                let signaling = signaling_nan(a) || signaling_nan(b);
+                let v = self.fmin_max(a.into(), b.into(), signaling, true);
+                self.write_f32(
+                    frd,
+                    v.map_or(f32::from_bits(CANONICAL_NAN_F32), |v| v as f32),
+                );
+            }
+            RiscvInst::FcvtWS { rd, frs1, rm } => {
+                let a = self.read_f32(frs1) as f64;
+                let v = self.fcvt_int(a, rm, i32::MIN.into(), i32::MAX.into())?;
+                self.set_x(rd, v as i32 as u64);
+            }
+            RiscvInst::FcvtWuS { rd, frs1, rm } => {
+                let a = self.read_f32(frs1) as f64;
+                let v = self.fcvt_int(a, rm, u32::MIN.into(), u32::MAX.into())?;
+                self.set_x(rd, v as u32 as i32 as u64);
+            }
+            RiscvInst::FcvtLS { rd, frs1, rm } => {
+                let a = self.read_f32(frs1) as f64;
+                let v = self.fcvt_int(a, rm, i64::MIN.into(), i64::MAX.into())?;
+                self.set_x(rd, v as i64 as u64);
+            }
+            RiscvInst::FcvtLuS { rd, frs1, rm } => {
+                let a = self.read_f32(frs1) as f64;
+                let v = self.fcvt_int(a, rm, u64::MIN.into(), u64::MAX.into())?;
+                self.set_x(rd, v as u64);
+            }
+            RiscvInst::FmvXW { rd, frs1 } => {
+                let a = self.f[frs1 as usize].to_bits();
+                self.set_x(rd, a as u32 as i32 as u64);
+            }
+            RiscvInst::FclassS { rd, frs1 } => {
+                let a = self.read_f32(frs1);
+                self.set_x(rd, float_classify(a));
+            }
+            RiscvInst::FeqS { rd, frs1, frs2 } => {
+                let a = self.read_f32(frs1);
+                let b = self.read_f32(frs2);
+                self.set_x(rd, (a == b) as u64);
+            }
+            RiscvInst::FltS { rd, frs1, frs2 } => {
+                let a = self.read_f32(frs1);
+                let b = self.read_f32(frs2);
+                self.set_x(rd, (a < b) as u64);
+            }
+            RiscvInst::FleS { rd, frs1, frs2 } => {
+                let a = self.read_f32(frs1);
+                let b = self.read_f32(frs2);
+                self.set_x(rd, (a <= b) as u64);
+            }
+            RiscvInst::FcvtSW { frd, rs1, .. } => {
+                let a = self.x[rs1 as usize] as i32;
+                self.write_f32(frd, a as f32);
+            }
+            RiscvInst::FcvtSWu { frd, rs1, .. } => {
+                let a = self.x[rs1 as usize] as u32;
+                self.write_f32(frd, a as f32);
+            }
+            RiscvInst::FcvtSL { frd, rs1, .. } => {
+                let a = self.x[rs1 as usize] as i64;
+                self.write_f32(frd, a as f32);
+            }
+            RiscvInst::FcvtSLu { frd, rs1, .. } => {
+                let a = self.x[rs1 as usize];
+                self.write_f32(frd, a as f32);
+            }
+            RiscvInst::FmvWX { frd, rs1 } => {
+                let a = self.x[rs1 as usize] as u32;
+                self.write_f32(frd, f32::from_bits(a));
+            }
+            RiscvInst::FmaddS {
+                frd,
+                frs1,
+                frs2,
+                frs3,
+                ..
+            } => {
+                let a = self.read_f32(frs1);
+                let b = self.read_f32(frs2);
+                let c = self.read_f32(frs3);
+                self.write_f32(frd, a * b + c);
+            }
+            RiscvInst::FmsubS {
+                frd,
+                frs1,
+                frs2,
+                frs3,
+                ..
+            } => {
+                let a = self.read_f32(frs1);
+                let b = self.read_f32(frs2);
+                let c = self.read_f32(frs3);
+                self.write_f32(frd, a * b - c);
+            }
+            RiscvInst::FnmsubS {
+                frd,
+                frs1,
+                frs2,
+                frs3,
+                ..
+            } => {
+                let a = self.read_f32(frs1);
+                let b = self.read_f32(frs2);
+                let c = self.read_f32(frs3);
+                self.write_f32(frd, -a * b - c);
+            }
+            RiscvInst::FnmaddS {
+                frd,
+                frs1,
+                frs2,
+                frs3,
+                ..
+            } => {
+                let a = self.read_f32(frs1);
+                let b = self.read_f32(frs2);
+                let c = self.read_f32(frs3);
+                self.write_f32(frd, -a * b + c);
+            }
+            RiscvInst::Fld { frd, rs1, imm } => {
+                let val = self.load(addr_add(self.x[rs1 as usize], imm), 8)?;
+                self.f[frd as usize] = f64::from_bits(val);
+            }
+            RiscvInst::Fsd { rs1, frs2, imm } => {
+                self.store(
+                    addr_add(self.x[rs1 as usize], imm),
+                    8,
+                    self.f[frs2 as usize].to_bits(),
+                )?;
+            }
+            RiscvInst::FaddD {
+                frd, frs1, frs2, ..
+            } => {
+                let a = self.f[frs1 as usize];
+                let b = self.f[frs2 as usize];
+                self.f[frd as usize] = a + b;
+            }
+            RiscvInst::FsubD {
+                frd, frs1, frs2, ..
+            } => {
+                let a = self.f[frs1 as usize];
+                let b = self.f[frs2 as usize];
+                self.f[frd as usize] = a - b;
+            }
+            RiscvInst::FmulD {
+                frd, frs1, frs2, ..
+            } => {
+                let a = self.f[frs1 as usize];
+                let b = self.f[frs2 as usize];
+                self.f[frd as usize] = a * b;
+            }
+            RiscvInst::FdivD {
+                frd, frs1, frs2, ..
+            } => {
+                let a = self.f[frs1 as usize];
+                let b = self.f[frs2 as usize];
+                self.f[frd as usize] = a / b;
+            }
+            RiscvInst::FsqrtD { frd, frs1, .. } => {
+                let a = self.f[frs1 as usize];
+                self.f[frd as usize] = a.sqrt();
+            }
+            RiscvInst::FsgnjD { frd, frs1, frs2 } => {
+                let a = self.f[frs1 as usize];
+                let b = self.f[frs2 as usize];
+                let sign = f64::to_bits(b) & (1 << 63);
+                self.f[frd as usize] = f64::from_bits(f64::to_bits(a) & !(1 << 63) | sign);
+            }
+            RiscvInst::FsgnjnD { frd, frs1, frs2 } => {
+                let a = self.f[frs1 as usize];
+                let b = self.f[frs2 as usize];
+                let sign = !f64::to_bits(b) & (1 << 63);
+                self.f[frd as usize] = f64::from_bits(f64::to_bits(a) & !(1 << 63) | sign);
+            }
+            RiscvInst::FsgnjxD { frd, frs1, frs2 } => {
+                let a = self.f[frs1 as usize];
+                let b = self.f[frs2 as usize];
+                let sign = f64::to_bits(b) & (1 << 63);
+                self.f[frd as usize] = f64::from_bits(f64::to_bits(a) ^ sign);
+            }
+            RiscvInst::FminD { frd, frs1, frs2 } => {
+                let a = self.f[frs1 as usize];
+                let b = self.f[frs2 as usize];
+                let signaling = signaling_nan_double(a) || signaling_nan_double(b);
+                let v = self.fmin_max(a, b, signaling, false);
+                self.f[frd as usize] = v.unwrap_or(f64::from_bits(CANONICAL_NAN_F64));
+            }
+            RiscvInst::FmaxD { frd, frs1, frs2 } => {
+                let a = self.f[frs1 as usize];
+                let b = self.f[frs2 as usize];
+                let signaling = signaling_nan_double(a) || signaling_nan_double(b);
+                let v = self.fmin_max(a, b, signaling, true);
+                self.f[frd as usize] = v.unwrap_or(f64::from_bits(CANONICAL_NAN_F64));
+            }
+            RiscvInst::FcvtSD { frd, frs1, rm } => {
+                let a = self.f[frs1 as usize];
+                let v = self.fcvt_s_d(a, rm)?;
+                self.write_f32(frd, v);
+            }
+            // Widening is always exact, only NaNs need care.
+            RiscvInst::FcvtDS { frd, frs1, .. } => {
+                let a = self.read_f32(frs1);
+                self.f[frd as usize] = if a.is_nan() {
+                    if signaling_nan(a) {
+                        self.raise_fflags(MASK_NV);
+                    }
+                    f64::from_bits(CANONICAL_NAN_F64)
+                } else {
+                    f64::from(a)
+                };
+            }
+            RiscvInst::FcvtWD { rd, frs1, rm } => {
+                let a = self.f[frs1 as usize];
+                let v = self.fcvt_int(a, rm, i32::MIN.into(), i32::MAX.into())?;
+                self.set_x(rd, v as i32 as u64);
+            }
+            RiscvInst::FcvtWuD { rd, frs1, rm } => {
+                let a = self.f[frs1 as usize];
+                let v = self.fcvt_int(a, rm, u32::MIN.into(), u32::MAX.into())?;
+                self.set_x(rd, v as u32 as i32 as u64);
+            }
+            RiscvInst::FcvtLD { rd, frs1, rm } => {
+                let a = self.f[frs1 as usize];
+                let v = self.fcvt_int(a, rm, i64::MIN.into(), i64::MAX.into())?;
+                self.set_x(rd, v as i64 as u64);
+            }
+            RiscvInst::FcvtLuD { rd, frs1, rm } => {
+                let a = self.f[frs1 as usize];
+                let v = self.fcvt_int(a, rm, u64::MIN.into(), u64::MAX.into())?;
+                self.set_x(rd, v as u64);
+            }
+            RiscvInst::FmvXD { rd, frs1 } => {
+                let a = self.f[frs1 as usize];
+                self.set_x(rd, a.to_bits());
+            }
+            RiscvInst::FclassD { rd, frs1 } => {
+                let a = self.f[frs1 as usize];
+                self.set_x(rd, double_classify(a));
+            }
+            RiscvInst::FeqD { rd, frs1, frs2 } => {
+                let a = self.f[frs1 as usize];
+                let b = self.f[frs2 as usize];
+                self.set_x(rd, if a == b { 1 } else { 0 });
+            }
+            RiscvInst::FltD { rd, frs1, frs2 } => {
+                let a = self.f[frs1 as usize];
+                let b = self.f[frs2 as usize];
+                self.set_x(rd, if a < b { 1 } else { 0 });
+            }
+            RiscvInst::FleD { rd, frs1, frs2 } => {
+                let a = self.f[frs1 as usize];
+                let b = self.f[frs2 as usize];
+                self.set_x(rd, if a <= b { 1 } else { 0 });
+            }
+            RiscvInst::FcvtDW { frd, rs1, .. } => {
+                let a = self.x[rs1 as usize] as i32 as i64;
+                self.f[frd as usize] = a as f64;
+            }
+            RiscvInst::FcvtDWu { frd, rs1, .. } => {
+                let a = self.x[rs1 as usize] as u32 as i64;
+                self.f[frd as usize] = a as f64;
+            }
+            RiscvInst::FcvtDL { frd, rs1, .. } => {
+                let a = self.x[rs1 as usize] as i64;
+                self.f[frd as usize] = a as f64;
+            }
+            RiscvInst::FcvtDLu { frd, rs1, .. } => {
+                let a = self.x[rs1 as usize];
+                self.f[frd as usize] = a as f64;
+            }
+            RiscvInst::FmvDX { frd, rs1 } => {
+                let a = self.x[rs1 as usize];
+                self.f[frd as usize] = f64::from_bits(a);
+            }
+            RiscvInst::FmaddD {
+                frd,
+                frs1,
+                frs2,
+                frs3,
+                ..
+            } => {
+                let a = self.f[frs1 as usize];
+                let b = self.f[frs2 as usize];
+                let c = self.f[frs3 as usize];
+                self.f[frd as usize] = a * b + c;
+            }
+            RiscvInst::FmsubD {
+                frd,
+                frs1,
+                frs2,
+                frs3,
+                ..
+            } => {
+                let a = self.f[frs1 as usize];
+                let b = self.f[frs2 as usize];
+                let c = self.f[frs3 as usize];
+                self.f[frd as usize] = a * b - c;
+            }
+            RiscvInst::FnmsubD {
+                frd,
+                frs1,
+                frs2,
+                frs3,
+                ..
+            } => {
+                let a = self.f[frs1 as usize];
+                let b = self.f[frs2 as usize];
+                let c = self.f[frs3 as usize];
+                self.f[frd as usize] = -(a * b - c);
+            }
+            RiscvInst::FnmaddD {
+                frd,
+                frs1,
+                frs2,
+                frs3,
+                ..
+            } => {
+                let a = self.f[frs1 as usize];
+                let b = self.f[frs2 as usize];
+                let c = self.f[frs3 as usize];
+                self.f[frd as usize] = -(a * b + c);
+            }
+            RiscvInst::Mret => {
+                let mut mstatus = self.csr.load(MSTATUS);
+                // MPP is two bits wide at MSTATUS[12:11]
+                self.mode = mstatus.mpp() as u8;
                 // The MPIE bit is MSTATUS[7] and the MIE bit is the MSTATUS[3].
                 let mpie = mstatus.mpie();
                 // set MIE = MPIE
@@ -953,195 +2284,2189 @@ impl RV64Cpu {
                 // masking occurs also for the implicit read by the SRET instruction.
                 return Ok((self.csr.load(SEPC) & !0b11).into());
             }
-            RiscvInst::Wfi => todo!(),
-            RiscvInst::SfenceVma { rs1, rs2 } => todo!(),
+            // WFI is allowed to be a no-op, the pending interrupt is taken after this step anyway.
+            RiscvInst::Wfi => {}
+            // Drop the cached translations of the page in rs1 and the address space in rs2, or of
+            // every page or address space when the register is x0.
+            RiscvInst::SfenceVma { rs1, rs2 } => {
+                let addr = (rs1 != 0).then(|| self.x[rs1 as usize]);
+                let asid = (rs2 != 0).then(|| self.x[rs2 as usize] as u16);
+                self.mmu.flush(addr, asid);
+            }
         };
 
-        Ok(if inst.is_compact() {
-            self.pc + 2
-        } else {
-            self.pc + 4
-        })
+        // Conservatively consider the floating-point state modified by any instruction using it.
+        if uses_fpu {
+            let status = self.csr.load(MSTATUS).data;
+            self.csr.store(MSTATUS, status | MASK_FS | MASK_SD);
+        }
+
+        Ok(next_pc)
+    }
+}
+
+/// Whether the batched instruction count has to be flushed before executing `inst`.
+fn ends_batch(inst: &RiscvInst) -> bool {
+    inst.changes_control_flow()
+        || matches!(
+            inst,
+            RiscvInst::Csrrw { .. }
+                | RiscvInst::Csrrs { .. }
+                | RiscvInst::Csrrc { .. }
+                | RiscvInst::Csrrwi { .. }
+                | RiscvInst::Csrrsi { .. }
+                | RiscvInst::Csrrci { .. }
+        )
+}
+
+impl Cpu for RV64Cpu {
+    fn init(&mut self) {
+        self.bus.init();
+    }
+
+    fn load(&mut self, data: Vec<u8>) {
+        self.load_at(DRAM_BASE, &data).expect("Load failed");
+    }
+
+    fn reset(&mut self) {
+        self.pc = 0;
+        self.x = [0; 32];
+    }
+
+    fn handle_interrupt(&mut self, int: Interrupt) {
+        self.block_cursor = None;
+        self.traps += 1;
+        self.consecutive_traps += 1;
+        let pc = self.pc;
+        let mode = self.mode;
+        let cause = int.code();
+        let trap_in_s_mode = mode <= SUPERVISOR_MODE && self.csr.is_midelegated(cause);
+        let (
+            status_csr,
+            tvec_csr,
+            cause_csr,
+            tval_csr,
+            epc_csr,
+            mask_pie,
+            pie_i,
+            mask_ie,
+            ie_i,
+            mask_pp,
+            pp_i,
+        ) = if trap_in_s_mode {
+            self.mode = SUPERVISOR_MODE;
+            (
+                SSTATUS, STVEC, SCAUSE, STVAL, SEPC, MASK_SPIE, 5, MASK_SIE, 1, MASK_SPP, 8,
+            )
+        } else {
+            self.mode = MACHINE_MODE;
+            (
+                MSTATUS, MTVEC, MCAUSE, MTVAL, MEPC, MASK_MPIE, 7, MASK_MIE, 3, MASK_MPP, 11,
+            )
+        };
+
+        let tvec = self.csr.load(tvec_csr);
+        let tvec_mode = (tvec & 0b11).into();
+        let tvec_base = (tvec & !0b11).into();
+        match tvec_mode {
+            // DIrect
+            0 => self.pc = tvec_base,
+            1 => self.pc = tvec_base + (cause << 2),
+            _ => unreachable!(),
+        };
+
+        self.csr.store(epc_csr, pc);
+        self.csr.store(cause_csr, cause);
+        self.csr.store(tval_csr, 0);
+
+        let mut status = self.csr.load(status_csr);
+        let ie = (status & mask_ie) >> ie_i;
+        status = (status & !mask_pie) | (ie << pie_i);
+        status.clear(mask_ie);
+        status = (status & !mask_pp) | ((mode as u64) << pp_i);
+        self.csr.store(status_csr, status.into());
+    }
+
+    fn handle_exception(&mut self, e: Exception) {
+        self.block_cursor = None;
+        self.traps += 1;
+        self.consecutive_traps += 1;
+        let pc = self.pc;
+        let mode = self.mode;
+        let cause = e.code();
+        // if an exception happen in U-mode or S-mode, and the exception is delegated to S-mode.
+        // then this exception should be handled in S-mode.
+        let trap_in_s_mode = mode <= SUPERVISOR_MODE && self.csr.is_medelegated(cause);
+        let (
+            status_csr,
+            tvec_csr,
+            cause_csr,
+            tval_csr,
+            epc_csr,
+            mask_pie,
+            pie_i,
+            mask_ie,
+            ie_i,
+            mask_pp,
+            pp_i,
+        ) = if trap_in_s_mode {
+            self.mode = SUPERVISOR_MODE;
+            (
+                SSTATUS, STVEC, SCAUSE, STVAL, SEPC, MASK_SPIE, 5, MASK_SIE, 1, MASK_SPP, 8,
+            )
+        } else {
+            self.mode = MACHINE_MODE;
+            (
+                MSTATUS, MTVEC, MCAUSE, MTVAL, MEPC, MASK_MPIE, 7, MASK_MIE, 3, MASK_MPP, 11,
+            )
+        };
+        // 3.1.7 & 4.1.2
+        // The BASE field in tvec is a WARL field that can hold any valid virtual or physical address,
+        // subject to the following alignment constraints: the address must be 4-byte aligned
+        self.pc = (self.csr.load(tvec_csr) & !0b11).into();
+        // 3.1.14 & 4.1.7
+        // When a trap is taken into S-mode (or M-mode), sepc (or mepc) is written with the virtual address
+        // of the instruction that was interrupted or that encountered the exception.
+        self.csr.store(epc_csr, pc);
+        // 3.1.15 & 4.1.8
+        // When a trap is taken into S-mode (or M-mode), scause (or mcause) is written with a code indicating
+        // the event that caused the trap.
+        self.csr.store(cause_csr, cause);
+        // 3.1.16 & 4.1.9
+        // If stval is written with a nonzero value when a breakpoint, address-misaligned, access-fault, or
+        // page-fault exception occurs on an instruction fetch, load, or store, then stval will contain the
+        // faulting virtual address.
+        // If stval is written with a nonzero value when a misaligned load or store causes an access-fault or
+        // page-fault exception, then stval will contain the virtual address of the portion of the access that
+        // caused the fault
+        self.csr.store(tval_csr, e.tval());
+        // 3.1.6 covers both sstatus and mstatus.
+        let mut status = self.csr.load(status_csr);
+        // get SIE or MIE
+        let ie = Into::<u64>::into(status & mask_ie) >> ie_i;
+        // set SPIE = SIE / MPIE = MIE
+        status = (status & !mask_pie) | (ie << pie_i);
+        // set SIE = 0 / MIE = 0
+        status.clear(mask_ie);
+        // set SPP / MPP = previous mode
+        status = CsrValue {
+            data: Into::<u64>::into(status & !mask_pp) | ((mode as u64) << pp_i),
+        };
+        self.csr.store(status_csr, status.into());
+    }
+
+    fn run(&mut self) -> HaltReason {
+        let reason = loop {
+            if let Some(reason) = self.step() {
+                break reason;
+            }
+            if self.breakpoints.contains(&self.pc) {
+                break HaltReason::Breakpoint(self.pc);
+            }
+        };
+        self.flush_instret();
+        let fatal = matches!(reason, HaltReason::Fatal(_) | HaltReason::TrapStorm);
+        if fatal && !self.trace.is_empty() {
+            log::error!("last {} instructions:", self.trace.len());
+            for entry in &self.trace {
+                log::error!("{}", entry.inst.pretty_print(entry.pc, entry.bits));
+            }
+            log::error!("mcause: {}", Mcause(self.csr.load(MCAUSE).into()));
+            log::error!("mstatus: {}", Mstatus(self.csr.load(MSTATUS).into()));
+        }
+        reason
+    }
+
+    type Exception = Exception;
+    type Interrupt = Interrupt;
+    type HaltReason = HaltReason;
+}
+
+#[cfg(test)]
+mod test {
+
+    use crate::{
+        arch::riscv::{
+            bus::{CLINT_BASE, DRAM_BASE, DRAM_SIZE, PLIC_BASE, SYSCON_BASE, UART_BASE},
+            clint::CLINT_MTIMECMP,
+            csr::{
+                Csr, FFLAGS, FS_INITIAL, MASK_FS, MASK_MEIP, MASK_MIE, MASK_MSIP, MASK_MTIP,
+                MASK_NV, MASK_NX, MASK_OF, MASK_SD, MASK_SEIP, MASK_SPP, MASK_STIP, MCAUSE,
+                MCOUNTEREN, MCYCLE, MEDELEG, MEPC, MIE, MINSTRET, MIP, MSCRATCH, MSTATUS, MTVAL,
+                MTVEC, MVENDORID, SATP, SCAUSE, SCOUNTEREN, SEPC, SSTATUS, STVAL, STVEC,
+            },
+            decode::{decode, decode_compressed},
+            exception::Exception,
+            instruction::{RiscvInst, RiscvInstWrapper},
+            interrupt::Interrupt,
+            isa::Isa,
+            mmu::{PTE_A, PTE_D, PTE_R, PTE_V, PTE_W, PTE_X},
+            reg::{A0, A1, A2, A7, RA, S0, S1, SP, T0},
+            uart::UART_IRQ,
+        },
+        bus::Bus,
+        cpu::Cpu,
+        util::addr_add,
+    };
+
+    use std::{
+        cell::RefCell,
+        fs::{self, File},
+        io::{self, Write},
+        rc::Rc,
+    };
+
+    use crate::arch::riscv::timing::{InstLatency, MemLatency};
+
+    use super::{
+        load_snippet, run_snippet, FatalPolicy, HaltReason, RV64Cpu, DEFAULT_MARCHID,
+        DEFAULT_MVENDORID, HALT, MACHINE_MODE, NAN_BOX, SUPERVISOR_MODE, USER_MODE,
+    };
+
+    /// A recursive factorial function, compiled at -O0.
+    const FACTORIAL: [u32; 28] = [
+        // -O2
+        // 0x00050793, 0x00100513, 0x00100693, 0x00f55a63, 0x00078713, 0xfff7879b, 0x02a7053b,
+        // 0xfed79ae3,
+        // -O0
+        0xfe010113, 0x00113c23, 0x00813823, 0x02010413, 0x00050793, 0xfef42623, 0xfec42783,
+        0x0007871b, 0x00100793, 0x00e7c663, 0x00100793, 0x0300006f, 0xfec42783, 0xfff7879b,
+        0x0007879b, 0x00078513, 0x00000097, 0xfc0080e7, 0x00050793, 0x00078713, 0xfec42783,
+        0x02e787bb, 0x0007879b, 0x00078513, 0x01813083, 0x01013403, 0x02010113, 0x00008067,
+    ];
+
+    /// Set up a call of `FACTORIAL` with 5 that returns to the halt after it.
+    fn factorial_of_5(cpu: &mut RV64Cpu) {
+        cpu.x[RA] = 0x8000_0000 + 0x70;
+        cpu.x[SP] = 0x8000_0000 + 0x400;
+        cpu.x[A0] = 5;
+    }
+
+    #[test]
+    fn test_exec() {
+        assert_eq!(addr_add(64u64, -32i32), 32);
+        assert_eq!(addr_add(0x8000_0000, i32::MIN), 0);
+        assert_eq!(addr_add(0, i32::MIN), 0xffff_ffff_8000_0000);
+        assert_eq!(addr_add(0x8000_0000, i32::MAX), 0xffff_ffff);
+        assert_eq!(addr_add(u64::MAX, 1), 0);
+        let cpu = run_snippet(&FACTORIAL, factorial_of_5);
+
+        assert_eq!(cpu.x[10], 120u64);
+        assert_eq!(cpu.pc, 0x8000_0070);
+        assert_eq!(cpu.csr.load(MCAUSE).data, 0);
+    }
+
+    #[test]
+    fn test_page_fault_fixup() {
+        let mut data: Vec<u32> = vec![
+            0x400012b7, // lui t0, 0x40001
+            0x0002b503, // ld a0, 0(t0)
+            0x0000001f,
+        ];
+        data.resize(0x40, 0);
+        data.extend([
+            // handler at 0x8000_0100: map the 1 GiB page at 0x4000_0000 and retry
+            0x00002317, // auipc t1, 2
+            0xf0030313, // addi t1, t1, -256
+            0x01033383, // ld t2, 16(t1)
+            0x00733423, // sd t2, 8(t1)
+            0x30200073, // mret
+        ]);
+        let data: Vec<u8> = data.iter().flat_map(|x| x.to_le_bytes()).collect();
+        let mut cpu = RV64Cpu::new();
+        cpu.init();
+        Cpu::load(&mut cpu, data);
+
+        // identity map the gigapage holding DRAM with a root table at 0x8000_2000
+        let pte = ((0x8000_0000u64 >> 12) << 10) | PTE_V | PTE_R | PTE_W | PTE_X | PTE_A | PTE_D;
+        cpu.bus.store(0x8000_2010, 8, pte).unwrap();
+        cpu.bus
+            .store(0x8000_1000, 8, 0x1122_3344_5566_7788)
+            .unwrap();
+        cpu.csr.store(SATP, (8 << 60) | 0x80002);
+        cpu.update_paging(SATP);
+        cpu.csr.store(MTVEC, 0x8000_0100);
+        cpu.mode = SUPERVISOR_MODE;
+        cpu.pc = 0x8000_0000;
+        cpu.run();
+
+        assert_eq!(cpu.x[A0], 0x1122_3344_5566_7788);
+        assert_eq!(cpu.bus.load(0x8000_2008, 8).unwrap(), pte);
+    }
+
+    #[test]
+    fn test_read_write_mem() {
+        let cpu = &mut run_snippet(
+            &[
+                0x400012b7, // lui t0, 0x40001
+                0x756d6337, // lui t1, 0x756d6
+                0x57230313, // addi t1, t1, 0x572
+                0x0062a023, // sw t1, 0(t0)
+            ],
+            |cpu| {
+                // identity map DRAM and alias it at 0x4000_0000, both only for S-mode
+                let pte =
+                    ((0x8000_0000u64 >> 12) << 10) | PTE_V | PTE_R | PTE_W | PTE_X | PTE_A | PTE_D;
+                cpu.bus.store(0x8000_2008, 8, pte).unwrap();
+                cpu.bus.store(0x8000_2010, 8, pte).unwrap();
+                cpu.csr.store(SATP, (8 << 60) | 0x80002);
+                cpu.update_paging(SATP);
+                cpu.mode = SUPERVISOR_MODE;
+            },
+        );
+        assert_eq!(cpu.read_mem(0x4000_1000, 4, false), Ok(b"remu".to_vec()));
+
+        // U-mode cannot touch the supervisor pages, unless a debugger bypasses the permissions
+        cpu.mode = USER_MODE;
+        assert_eq!(
+            cpu.read_mem(0x4000_1000, 4, false),
+            Err(Exception::LoadPageFault(0x4000_1000))
+        );
+        cpu.write_mem(0x4000_1002, b"MU", true).unwrap();
+        assert_eq!(cpu.read_mem(0x4000_1000, 4, true), Ok(b"reMU".to_vec()));
+        assert_eq!(
+            cpu.read_mem(0x1_0000_0000, 1, true),
+            Err(Exception::LoadPageFault(0x1_0000_0000))
+        );
+    }
+
+    /// Map the pages at 0x4000_0000 and 0x4000_1000 to the frames at 0x8000_6000 and 0x8000_8000,
+    /// apart from each other, through tables at 0x8000_2000, 0x8000_3000 and 0x8000_4000, and
+    /// switch to S-mode where the mapping applies.
+    fn map_split_pages(cpu: &mut RV64Cpu, leaf_flags: u64) {
+        let table = |addr: u64| ((addr >> 12) << 10) | PTE_V;
+        let leaf = |addr: u64| table(addr) | leaf_flags;
+        cpu.bus.store(0x8000_2008, 8, table(0x8000_3000)).unwrap();
+        cpu.bus.store(0x8000_3000, 8, table(0x8000_4000)).unwrap();
+        cpu.bus.store(0x8000_4000, 8, leaf(0x8000_6000)).unwrap();
+        cpu.bus.store(0x8000_4008, 8, leaf(0x8000_8000)).unwrap();
+        cpu.csr.store(SATP, (8 << 60) | 0x80002);
+        cpu.update_paging(SATP);
+        cpu.mode = SUPERVISOR_MODE;
+    }
+
+    #[test]
+    fn test_read_cstr_and_array() {
+        let mut cpu = RV64Cpu::new();
+        cpu.init();
+        map_split_pages(&mut cpu, PTE_R | PTE_W | PTE_A | PTE_D);
+
+        // the string straddles the two pages, its tail landing on the second frame
+        cpu.write_mem(0x4000_0ffc, b"hello\0", false).unwrap();
+        assert_eq!(cpu.bus.load(0x8000_8000, 2), Ok(0x006f));
+        assert_eq!(
+            cpu.read_cstr(0x4000_0ffc, 6, false),
+            Ok("hello".to_string())
+        );
+        assert_eq!(cpu.read_cstr(0x4000_1004, 1, false), Ok(String::new()));
+        // no NUL within the limit
+        assert_eq!(
+            cpu.read_cstr(0x4000_0ffc, 5, false),
+            Err(Exception::LoadAccessFault(0x4000_1001))
+        );
+
+        let values = [1.5f64, -0.25, f64::MAX];
+        for (i, value) in values.iter().enumerate() {
+            let addr = 0x4000_0ff8 + 8 * i as u64;
+            cpu.write_mem(addr, &value.to_le_bytes(), false).unwrap();
+        }
+        assert_eq!(
+            cpu.read_array::<f64>(0x4000_0ff8, 3, false),
+            Ok(values.to_vec())
+        );
+        assert_eq!(
+            cpu.read_array::<u32>(0x4000_0ff8, 2, false),
+            Ok(vec![0, 0x3ff8_0000])
+        );
+
+        // a string running into an unmapped page faults
+        cpu.write_mem(0x4000_1ffe, b"no", false).unwrap();
+        assert_eq!(
+            cpu.read_cstr(0x4000_1ffe, 16, false),
+            Err(Exception::LoadPageFault(0x4000_2000))
+        );
+    }
+
+    #[test]
+    fn test_read_mem_untimed() {
+        let mut cpu = RV64Cpu::new();
+        cpu.init();
+        map_split_pages(&mut cpu, PTE_R | PTE_W | PTE_A | PTE_D);
+        cpu.set_memory_latency(MemLatency {
+            hit: 1,
+            miss: 10,
+            line_size: 64,
+            lines: 4,
+        });
+        cpu.bus.store(0x8000_6ff8, 8, 0x1234).unwrap();
+
+        // neither the walk nor the read is charged, and the line stays cold
+        assert_eq!(cpu.read_mem(0x4000_0ff8, 2, false), Ok(vec![0x34, 0x12]));
+        assert_eq!(cpu.mem_cycles, 0);
+        assert_eq!(cpu.load(0x4000_0ff8, 1), Ok(0x34));
+        assert_eq!(cpu.mem_cycles, 10);
+    }
+
+    #[test]
+    fn test_fetch_across_pages() {
+        let mut cpu = RV64Cpu::new();
+        cpu.init();
+        map_split_pages(&mut cpu, PTE_R | PTE_X | PTE_A);
+
+        // addi a0, a0, 1 in the last two bytes of the first page and the first two of the next
+        cpu.bus.store(0x8000_6ffe, 2, 0x0513).unwrap();
+        cpu.bus.store(0x8000_8000, 2, 0x0015).unwrap();
+        cpu.pc = 0x4000_0ffe;
+        let inst = cpu.fetch().unwrap();
+        assert_eq!(cpu.inst_bits, 0x00150513);
+        assert_eq!(
+            inst.get_inst(),
+            RiscvInst::Addi {
+                rd: 10,
+                rs1: 10,
+                imm: 1
+            }
+        );
+
+        // a compressed instruction there does not touch the next page, even when it is unmapped
+        cpu.bus.store(0x8000_4008, 8, 0).unwrap();
+        cpu.mmu.flush(None, None);
+        cpu.bus.store(0x8000_6ffe, 2, 0x0505).unwrap(); // c.addi a0, 1
+        assert!(cpu.fetch().unwrap().is_compact());
+        assert_eq!(cpu.inst_bits, 0x0505);
+        // rewriting the code behind the hart's back needs the decode cache flushed, as fence.i does
+        cpu.bus.store(0x8000_6ffe, 2, 0x0513).unwrap();
+        cpu.flush_decode_cache();
+        assert_eq!(
+            cpu.fetch().unwrap_err(),
+            Exception::InstructionPageFault(0x4000_1000)
+        );
+    }
+
+    #[test]
+    fn test_fetch_end_of_dram() {
+        let mut cpu = RV64Cpu::new();
+        cpu.init();
+        let last = DRAM_BASE + DRAM_SIZE - 2;
+        cpu.pc = last;
+
+        // a compressed instruction fits in the last half word
+        cpu.bus.store(last, 2, 0x0505).unwrap(); // c.addi a0, 1
+        assert!(cpu.fetch().unwrap().is_compact());
+
+        // but the upper half of a full one lies past the end of memory
+        cpu.bus.store(last, 2, 0x0513).unwrap();
+        cpu.flush_decode_cache();
+        assert_eq!(
+            cpu.fetch().unwrap_err(),
+            Exception::InstructionAccessFault(DRAM_BASE + DRAM_SIZE)
+        );
+
+        cpu.pc = DRAM_BASE + DRAM_SIZE;
+        assert_eq!(
+            cpu.fetch().unwrap_err(),
+            Exception::InstructionAccessFault(DRAM_BASE + DRAM_SIZE)
+        );
+    }
+
+    #[test]
+    fn test_fetch_from_device() {
+        for addr in [CLINT_BASE + 0x4000, PLIC_BASE, UART_BASE, SYSCON_BASE] {
+            let mut cpu = RV64Cpu::new();
+            cpu.init();
+            cpu.csr.store(MTVEC, 0x8000_0100);
+            cpu.pc = addr;
+            cpu.step();
+            assert_eq!(cpu.csr.load(MCAUSE).data, 1, "{:#x}", addr);
+            assert_eq!(cpu.csr.load(MTVAL).data, addr);
+            assert_eq!(cpu.pc, 0x8000_0100);
+        }
+    }
+
+    #[test]
+    fn test_run_stats() {
+        let mut cpu = load_snippet(&FACTORIAL);
+        factorial_of_5(&mut cpu);
+        let mut steps = 0;
+        while cpu.step().is_none() {
+            steps += 1;
+        }
+
+        let mut cpu = load_snippet(&FACTORIAL);
+        factorial_of_5(&mut cpu);
+        let stats = cpu.run_with_stats();
+        assert_eq!(cpu.x[A0], 120);
+        assert_eq!(stats.instructions, steps);
+        assert!(stats.cycles >= stats.instructions);
+        assert_eq!(stats.traps, 0);
+        assert_eq!(stats.halt, HaltReason::Breakpoint(0x8000_0070));
+        assert!(stats
+            .to_string()
+            .contains(&format!("instructions: {}\n", steps)));
+    }
+
+    #[test]
+    fn test_block_dispatch() {
+        let mut cpu = load_snippet(&FACTORIAL);
+        factorial_of_5(&mut cpu);
+        let stats = cpu.run_with_stats();
+        assert_eq!(cpu.x[A0], 120);
+        // only the first instruction of each block is translated and looked up
+        assert!(cpu.block_lookups * 4 < stats.instructions);
+    }
+
+    #[test]
+    fn test_trap_storm() {
+        let mut cpu = RV64Cpu::new();
+        cpu.init();
+        // the handler at 0x8000_0100 is all illegal instructions
+        Cpu::load(&mut cpu, vec![0xff; 0x200]);
+        cpu.bus.store(0x8000_0000, 4, 0x00000013).unwrap(); // nop
+        cpu.csr.store(MTVEC, 0x8000_0100);
+        cpu.set_fatal_policy(FatalPolicy {
+            causes: 0,
+            double_fault: false,
+        });
+        cpu.set_trap_storm_limit(Some(5));
+        cpu.pc = 0x8000_0000;
+
+        // the nop retires, then the handler keeps re-entering itself
+        assert_eq!(cpu.step(), None);
+        for _ in 0..4 {
+            assert_eq!(cpu.step(), None);
+        }
+        assert_eq!(cpu.step(), Some(HaltReason::TrapStorm));
+        assert_eq!(cpu.pc, 0x8000_0100);
+        assert_eq!(cpu.run(), HaltReason::TrapStorm);
+    }
+
+    #[test]
+    fn test_fence_i() {
+        // f adds 1 to a0 until it is patched to add 16, which only shows after a fence.i
+        let cpu = run_snippet(
+            &[
+                0x00000297, // auipc t0, 0
+                0x01050337, // lui t1, 0x1050
+                0x5133031b, // addiw t1, t1, 0x513 (addi a0, a0, 16)
+                0x018000ef, // jal ra, f
+                0x0262a223, // sw t1, 36(t0)
+                0x010000ef, // jal ra, f
+                0x0000100f, // fence.i
+                0x008000ef, // jal ra, f
+                0x00c0006f, // j end
+                0x00150513, // f: addi a0, a0, 1
+                0x00008067, // ret
+            ],
+            |_| {},
+        );
+        assert_eq!(cpu.x[A0], 1 + 1 + 16);
+    }
+
+    #[test]
+    fn test_negative_offset() {
+        let cpu = run_snippet(
+            &[
+                0x00001297, // auipc t0, 1
+                0xffe00313, // li t1, -2
+                0xfe62bc23, // sd t1, -8(t0)
+                0x8062a023, // sw t1, -2048(t0)
+                0xff82b503, // ld a0, -8(t0)
+                0x8002e583, // lwu a1, -2048(t0)
+            ],
+            |_| {},
+        );
+        // stores and loads with the same negative offset meet below the base
+        let base = DRAM_BASE + 0x1000;
+        assert_eq!(cpu.bus.load(base - 8, 8).unwrap(), -2i64 as u64);
+        assert_eq!(cpu.bus.load(base - 2048, 4).unwrap(), 0xffff_fffe);
+        assert_eq!(cpu.x[A0], -2i64 as u64);
+        assert_eq!(cpu.x[A1], 0xffff_fffe);
+    }
+
+    #[test]
+    fn test_guard_region() {
+        let mut cpu = RV64Cpu::new();
+        cpu.init();
+        cpu.add_guard_region(0x8000_1000, 0x100);
+
+        assert_eq!(
+            cpu.store(0x8000_1010, 4, 0xdead),
+            Err(Exception::StoreAMOAccessFault(0x8000_1010))
+        );
+        // an access straddling the start of the region also faults
+        assert_eq!(
+            cpu.store(0x8000_0ffc, 8, 0xdead),
+            Err(Exception::StoreAMOAccessFault(0x8000_0ffc))
+        );
+        assert_eq!(
+            cpu.load(0x8000_10fc, 4),
+            Err(Exception::LoadAccessFault(0x8000_10fc))
+        );
+        assert_eq!(cpu.store(0x8000_0ff8, 8, 0xbeef), Ok(()));
+        assert_eq!(cpu.store(0x8000_1100, 8, 0xcafe), Ok(()));
+        assert_eq!(cpu.load(0x8000_0ff8, 8), Ok(0xbeef));
+        assert_eq!(cpu.load(0x8000_1100, 8), Ok(0xcafe));
+    }
+
+    #[test]
+    fn test_fcvt_w_s() {
+        let mut cpu = RV64Cpu::new();
+        cpu.csr.store(MSTATUS, MASK_FS);
+        let fcvt_w_s = RiscvInstWrapper::Full(RiscvInst::FcvtWS {
+            rd: A0 as u8,
+            frs1: 1,
+            rm: 0b001,
+        });
+        let cases = [
+            (f32::NAN, i32::MAX as u64, MASK_NV),
+            (f32::INFINITY, i32::MAX as u64, MASK_NV),
+            (f32::NEG_INFINITY, i32::MIN as u64, MASK_NV),
+            (3e9, i32::MAX as u64, MASK_NV),
+            (-1.5, -1i64 as u64, MASK_NX),
+            (42.0, 42, 0),
+        ];
+        for (input, expected, flags) in cases {
+            cpu.csr.store(FFLAGS, 0);
+            cpu.write_f32(1, input);
+            cpu.execute(fcvt_w_s).unwrap();
+            assert_eq!(cpu.x[A0], expected, "fcvt.w.s {}", input);
+            assert_eq!(cpu.csr.load(FFLAGS).data, flags, "fcvt.w.s {}", input);
+        }
+
+        // fcvt.wu.s sign-extends its saturated 32-bit result
+        cpu.write_f32(1, f32::NAN);
+        cpu.execute(RiscvInstWrapper::Full(RiscvInst::FcvtWuS {
+            rd: A0 as u8,
+            frs1: 1,
+            rm: 0b001,
+        }))
+        .unwrap();
+        assert_eq!(cpu.x[A0], u64::MAX);
+    }
+
+    #[test]
+    fn test_fs_state() {
+        let mut cpu = RV64Cpu::new();
+        cpu.pc = 0x8000_0000;
+        // fadd.d fa0, fa1, fa2
+        let fadd = RiscvInstWrapper::Full(decode(0x02c58553));
+        // csrr a0, fflags
+        let frflags = RiscvInstWrapper::Full(decode(0x00102573));
+
+        assert_eq!(
+            cpu.execute(fadd),
+            Err(Exception::IllegalInstruction(0x8000_0000))
+        );
+        assert_eq!(
+            cpu.execute(frflags),
+            Err(Exception::IllegalInstruction(0x8000_0000))
+        );
+        assert_eq!(cpu.csr.load(MSTATUS).data & MASK_FS, 0);
+
+        // Initial
+        cpu.csr.store(MSTATUS, 1 << 13);
+        cpu.f[11] = 1.5;
+        cpu.f[12] = 2.0;
+        cpu.execute(fadd).unwrap();
+        assert_eq!(cpu.f[10], 3.5);
+        let status = cpu.csr.load(MSTATUS).data;
+        assert_eq!(status & MASK_FS, MASK_FS);
+        assert_eq!(status & MASK_SD, MASK_SD);
+        assert!(cpu.execute(frflags).is_ok());
+    }
+
+    #[test]
+    fn test_fclass_s_boxing() {
+        let mut cpu = RV64Cpu::new();
+        cpu.csr.store(MSTATUS, MASK_FS);
+        let fclass_s = RiscvInstWrapper::Full(RiscvInst::FclassS {
+            rd: A0 as u8,
+            frs1: 1,
+        });
+
+        cpu.write_f32(1, -1.0);
+        cpu.execute(fclass_s).unwrap();
+        assert_eq!(cpu.x[A0], 1 << 1);
+
+        // the low half encodes 1.0, but the upper half isn't all ones
+        cpu.f[1] = f64::from_bits(0x0000_0001_3f80_0000);
+        cpu.execute(fclass_s).unwrap();
+        assert_eq!(cpu.x[A0], 1 << 9);
+
+        // neither is a double left in the register
+        cpu.f[1] = 1.0;
+        cpu.execute(fclass_s).unwrap();
+        assert_eq!(cpu.x[A0], 1 << 9);
+    }
+
+    #[test]
+    fn test_fmin_max() {
+        let mut cpu = RV64Cpu::new();
+        cpu.csr.store(MSTATUS, MASK_FS);
+        let snan_f32 = f32::from_bits(0x7f80_0001);
+        let snan_f64 = f64::from_bits(0x7ff0_0000_0000_0001);
+        let qnan_f64 = f64::from_bits(0x7ff8_0000_0000_0000);
+        let cases = [
+            // (a, b, min, max, fflags)
+            (0.0, -0.0, -0.0, 0.0, 0),
+            (f64::NAN, 1.0, 1.0, 1.0, 0),
+            (snan_f64, 2.0, 2.0, 2.0, MASK_NV),
+            (f64::NAN, f64::NAN, qnan_f64, qnan_f64, 0),
+        ];
+        for (a, b, min, max, fflags) in cases {
+            cpu.f[1] = a;
+            cpu.f[2] = b;
+            cpu.csr.store(FFLAGS, 0);
+            cpu.execute(RiscvInstWrapper::Full(RiscvInst::FminD {
+                frd: 3,
+                frs1: 1,
+                frs2: 2,
+            }))
+            .unwrap();
+            cpu.execute(RiscvInstWrapper::Full(RiscvInst::FmaxD {
+                frd: 4,
+                frs1: 1,
+                frs2: 2,
+            }))
+            .unwrap();
+            assert_eq!(cpu.f[3].to_bits(), min.to_bits(), "fmin.d {} {}", a, b);
+            assert_eq!(cpu.f[4].to_bits(), max.to_bits(), "fmax.d {} {}", a, b);
+            assert_eq!(cpu.csr.load(FFLAGS).data, fflags);
+
+            // the same in single precision
+            let a = if a.to_bits() == snan_f64.to_bits() {
+                snan_f32
+            } else {
+                a as f32
+            };
+            cpu.write_f32(1, a);
+            cpu.write_f32(2, b as f32);
+            cpu.csr.store(FFLAGS, 0);
+            cpu.execute(RiscvInstWrapper::Full(RiscvInst::FminS {
+                frd: 3,
+                frs1: 1,
+                frs2: 2,
+            }))
+            .unwrap();
+            cpu.execute(RiscvInstWrapper::Full(RiscvInst::FmaxS {
+                frd: 4,
+                frs1: 1,
+                frs2: 2,
+            }))
+            .unwrap();
+            let boxed = |x: f64| NAN_BOX | u64::from((x as f32).to_bits());
+            assert_eq!(cpu.f[3].to_bits(), boxed(min), "fmin.s {} {}", a, b);
+            assert_eq!(cpu.f[4].to_bits(), boxed(max), "fmax.s {} {}", a, b);
+            assert_eq!(cpu.csr.load(FFLAGS).data, fflags);
+        }
+    }
+
+    #[test]
+    fn test_fcvt_s_d() {
+        let mut cpu = RV64Cpu::new();
+        cpu.csr.store(MSTATUS, MASK_FS);
+        let x = 1.0 + 2f64.powi(-30);
+        let up = 1.0 + f32::EPSILON;
+        let cases = [
+            // (source, rm, result)
+            (x, 0b000, 1.0),
+            (x, 0b001, 1.0),
+            (x, 0b010, 1.0),
+            (x, 0b011, up),
+            (-x, 0b010, -up),
+            (-x, 0b011, -1.0),
+            (1.0 + f64::from(f32::EPSILON) / 2.0, 0b100, up),
+        ];
+        for (a, rm, expected) in cases {
+            cpu.f[1] = a;
+            cpu.csr.store(FFLAGS, 0);
+            cpu.execute(RiscvInstWrapper::Full(RiscvInst::FcvtSD {
+                frd: 2,
+                frs1: 1,
+                rm,
+            }))
+            .unwrap();
+            assert_eq!(cpu.f[2].to_bits(), NAN_BOX | u64::from(expected.to_bits()));
+            assert_eq!(cpu.csr.load(FFLAGS).data, MASK_NX, "{} rm {}", a, rm);
+        }
+
+        // only values that round past f32::MAX overflow, saturating in the directed modes
+        let max = f64::from(f32::MAX);
+        let ulp = 2f64.powi(104);
+        let cases = [
+            // (source, rm, result, flags)
+            (max + ulp / 4.0, 0b000, f32::MAX, MASK_NX),
+            (max + ulp / 2.0, 0b000, f32::INFINITY, MASK_NX | MASK_OF),
+            (max + ulp / 2.0, 0b001, f32::MAX, MASK_NX),
+            (max + ulp, 0b001, f32::MAX, MASK_NX | MASK_OF),
+            (
+                -(max + ulp / 4.0),
+                0b010,
+                f32::NEG_INFINITY,
+                MASK_NX | MASK_OF,
+            ),
+        ];
+        for (a, rm, expected, flags) in cases {
+            cpu.f[1] = a;
+            cpu.csr.store(FFLAGS, 0);
+            cpu.execute(RiscvInstWrapper::Full(RiscvInst::FcvtSD {
+                frd: 2,
+                frs1: 1,
+                rm,
+            }))
+            .unwrap();
+            assert_eq!(cpu.f[2].to_bits(), NAN_BOX | u64::from(expected.to_bits()));
+            assert_eq!(cpu.csr.load(FFLAGS).data, flags, "{} rm {}", a, rm);
+        }
+
+        // exact conversions raise nothing, and widening gives back the same value
+        cpu.f[1] = 1.5;
+        cpu.csr.store(FFLAGS, 0);
+        cpu.execute(RiscvInstWrapper::Full(RiscvInst::FcvtSD {
+            frd: 2,
+            frs1: 1,
+            rm: 0b000,
+        }))
+        .unwrap();
+        cpu.execute(RiscvInstWrapper::Full(RiscvInst::FcvtDS {
+            frd: 3,
+            frs1: 2,
+            rm: 0b000,
+        }))
+        .unwrap();
+        assert_eq!(cpu.f[3], 1.5);
+        assert_eq!(cpu.csr.load(FFLAGS).data, 0);
+    }
+
+    #[test]
+    fn test_divw() {
+        let mut cpu = RV64Cpu::new();
+        let cases = [
+            // (rs1, rs2, divw, divuw, remw, remuw)
+            (-7i64 as u64, 2, -3i64 as u64, 0x7fff_fffc, -1i64 as u64, 1),
+            (
+                0x8000_0000,
+                1,
+                0xffff_ffff_8000_0000,
+                0xffff_ffff_8000_0000,
+                0,
+                0,
+            ),
+            (
+                0x8000_0000,
+                -1i64 as u64,
+                0xffff_ffff_8000_0000,
+                0,
+                0,
+                0xffff_ffff_8000_0000,
+            ),
+            (
+                0x1_8000_0000,
+                0,
+                u64::MAX,
+                u64::MAX,
+                0xffff_ffff_8000_0000,
+                0xffff_ffff_8000_0000,
+            ),
+        ];
+        for (a, b, div, divu, rem, remu) in cases {
+            cpu.x[A0] = a;
+            cpu.x[A1] = b;
+            for (inst, expected) in [
+                (
+                    RiscvInst::Divw {
+                        rd: 12,
+                        rs1: 10,
+                        rs2: 11,
+                    },
+                    div,
+                ),
+                (
+                    RiscvInst::Divuw {
+                        rd: 12,
+                        rs1: 10,
+                        rs2: 11,
+                    },
+                    divu,
+                ),
+                (
+                    RiscvInst::Remw {
+                        rd: 12,
+                        rs1: 10,
+                        rs2: 11,
+                    },
+                    rem,
+                ),
+                (
+                    RiscvInst::Remuw {
+                        rd: 12,
+                        rs1: 10,
+                        rs2: 11,
+                    },
+                    remu,
+                ),
+            ] {
+                cpu.execute(RiscvInstWrapper::Full(inst)).unwrap();
+                assert_eq!(cpu.x[12], expected, "{} with {:#x}, {:#x}", inst, a, b);
+            }
+        }
+    }
+
+    #[test]
+    fn test_flw_fsw_bits() {
+        let mut cpu = RV64Cpu::new();
+        cpu.init();
+        cpu.csr.store(MSTATUS, MASK_FS);
+        cpu.x[A0] = 0x8000_1000;
+        // flw ft0, 0(a0); fsw ft0, 4(a0)
+        let flw = RiscvInstWrapper::Full(decode(0x00052007));
+        let fsw = RiscvInstWrapper::Full(decode(0x00052227));
+
+        for bits in [0x7f80_0001u32, 0xffc0_1234, 0x3f80_0000, 0x8000_0000] {
+            cpu.bus.store(0x8000_1000, 4, bits.into()).unwrap();
+            cpu.execute(flw).unwrap();
+            assert_eq!(cpu.f[0].to_bits(), 0xffff_ffff_0000_0000 | bits as u64);
+            cpu.execute(fsw).unwrap();
+            assert_eq!(cpu.bus.load(0x8000_1004, 4).unwrap(), bits.into());
+        }
+
+        // fsw stores the low half even when the register isn't NaN-boxed
+        cpu.f[0] = f64::from_bits(0x1234_5678_7f80_0001);
+        cpu.execute(fsw).unwrap();
+        assert_eq!(cpu.bus.load(0x8000_1004, 4).unwrap(), 0x7f80_0001);
+    }
+
+    #[test]
+    fn test_fsgnjn() {
+        let mut cpu = RV64Cpu::new();
+        cpu.csr.store(MSTATUS, MASK_FS);
+        let fsgnjn_s = RiscvInstWrapper::Full(RiscvInst::FsgnjnS {
+            frd: 0,
+            frs1: 1,
+            frs2: 2,
+        });
+        let fsgnjn_d = RiscvInstWrapper::Full(RiscvInst::FsgnjnD {
+            frd: 0,
+            frs1: 1,
+            frs2: 2,
+        });
+
+        // the result takes the magnitude of frs1 and the opposite of the sign of frs2
+        for (a, b, expected) in [
+            (0x3fc0_0000u32, 0x4000_0000u32, 0xbfc0_0000u32),
+            (0x3fc0_0000, 0x8000_0000, 0x3fc0_0000),
+            (0xbfc0_0000, 0xc000_0000, 0x3fc0_0000),
+            (0xffc0_0001, 0x0000_0000, 0xffc0_0001),
+        ] {
+            cpu.write_f32(1, f32::from_bits(a));
+            cpu.write_f32(2, f32::from_bits(b));
+            cpu.execute(fsgnjn_s).unwrap();
+            assert_eq!(cpu.f[0].to_bits(), 0xffff_ffff_0000_0000 | expected as u64);
+        }
+
+        for (a, b, expected) in [
+            (
+                0x3ff8_0000_0000_0000u64,
+                0x4000_0000_0000_0000u64,
+                0xbff8_0000_0000_0000u64,
+            ),
+            (
+                0xbff8_0000_0000_0000,
+                0x8000_0000_0000_0000,
+                0x3ff8_0000_0000_0000,
+            ),
+        ] {
+            cpu.f[1] = f64::from_bits(a);
+            cpu.f[2] = f64::from_bits(b);
+            cpu.execute(fsgnjn_d).unwrap();
+            assert_eq!(cpu.f[0].to_bits(), expected);
+        }
+    }
+
+    #[test]
+    fn test_fmv_d_x() {
+        let mut cpu = RV64Cpu::new();
+        cpu.csr.store(MSTATUS, MASK_FS);
+        let fmv_d_x = RiscvInstWrapper::Full(RiscvInst::FmvDX {
+            frd: 0,
+            rs1: A0 as u8,
+        });
+        let fmv_x_d = RiscvInstWrapper::Full(RiscvInst::FmvXD {
+            rd: A1 as u8,
+            frs1: 0,
+        });
+
+        // the bits move unchanged, NaN payloads included, and round-trip through fmv.x.d
+        for bits in [
+            0x3ff0_0000_0000_0000,
+            0x7ff0_0000_0000_0001,
+            0x8000_0000_0000_0000,
+            1,
+        ] {
+            cpu.x[A0] = bits;
+            cpu.execute(fmv_d_x).unwrap();
+            assert_eq!(cpu.f[0].to_bits(), bits);
+            cpu.execute(fmv_x_d).unwrap();
+            assert_eq!(cpu.x[A1], bits);
+        }
+    }
+
+    #[test]
+    fn test_lr_sc() {
+        let mut cpu = RV64Cpu::new();
+        cpu.init();
+        cpu.x[A0] = 0x8000_1000;
+        cpu.x[A1] = 0x1234;
+        // lr.w a2, (a0); sc.w a3, a1, (a0)
+        let lr = RiscvInstWrapper::Full(decode(0x1005262f));
+        let sc = RiscvInstWrapper::Full(decode(0x18b526af));
+        // sw a1, 60(a0), the last word of the granule
+        let sw_same = RiscvInstWrapper::Full(decode(0x02b52e23));
+        // sw a1, 64(a0), the first word of the next granule
+        let sw_next = RiscvInstWrapper::Full(decode(0x04b52023));
+
+        cpu.bus.store(0x8000_1000, 4, 0xffff_fff0).unwrap();
+        cpu.execute(lr).unwrap();
+        assert_eq!(cpu.x[12], 0xffff_ffff_ffff_fff0);
+        cpu.execute(sw_next).unwrap();
+        cpu.execute(sc).unwrap();
+        assert_eq!(cpu.x[13], 0);
+        assert_eq!(cpu.bus.load(0x8000_1000, 4).unwrap(), 0x1234);
+
+        // the reservation is used up by the first SC
+        cpu.execute(sc).unwrap();
+        assert_eq!(cpu.x[13], 1);
+
+        // a store to a neighboring word in the granule breaks the reservation
+        cpu.execute(lr).unwrap();
+        cpu.execute(sw_same).unwrap();
+        cpu.bus.store(0x8000_1000, 4, 0).unwrap();
+        cpu.execute(sc).unwrap();
+        assert_eq!(cpu.x[13], 1);
+        assert_eq!(cpu.bus.load(0x8000_1000, 4).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_compressed_link() {
+        let mut cpu = RV64Cpu::new();
+        cpu.pc = 0x8000_0000;
+        cpu.x[A0] = 0x8000_0100;
+        // c.jalr a0
+        let next_pc = cpu
+            .execute(RiscvInstWrapper::Compact(decode_compressed(0x9502)))
+            .unwrap();
+        assert_eq!(next_pc, 0x8000_0100);
+        assert_eq!(cpu.x[RA], 0x8000_0002);
+    }
+
+    #[test]
+    fn test_instret_batching() {
+        let data: Vec<u32> = vec![
+            0x00100293, // addi t0, zero, 1
+            0x00100293, // addi t0, zero, 1
+            0x00100293, // addi t0, zero, 1
+            0xc0202573, // rdinstret a0
+            0x00100293, // addi t0, zero, 1
+            0x00100293, // addi t0, zero, 1
+            0xc02025f3, // rdinstret a1
+            0x0000001f,
+        ];
+        let data: Vec<u8> = data.iter().flat_map(|x| x.to_le_bytes()).collect();
+        let mut cpu = RV64Cpu::new();
+        cpu.init();
+        cpu.pc = 0x8000_0000;
+        Cpu::load(&mut cpu, data);
+
+        for _ in 0..3 {
+            cpu.step();
+        }
+        // still batched
+        assert_eq!(cpu.csr.load(MINSTRET).data, 0);
+        cpu.run();
+
+        assert_eq!(cpu.x[A0], 3);
+        assert_eq!(cpu.x[A1], 6);
+        assert_eq!(cpu.csr.load(MINSTRET).data, 7);
+    }
+
+    #[test]
+    fn test_no_compressed() {
+        let rv64i = Isa::parse("rv64i").unwrap();
+        // c.addi a0, 1 is illegal without C
+        let cpu = run_snippet(&[0x0505_0505], |cpu| {
+            cpu.set_isa(rv64i);
+            cpu.set_trap_illegal(true);
+            cpu.csr.store(MTVEC, 0x8000_0004);
+        });
+        assert_eq!(cpu.csr.load(MCAUSE).data, 2);
+        assert_eq!(cpu.csr.load(MTVAL).data, 0x0505);
+        assert_eq!(cpu.x[A0], 0);
+
+        // and so is a pc that is only 2-byte aligned
+        let mut cpu = RV64Cpu::new();
+        cpu.init();
+        cpu.set_isa(rv64i);
+        cpu.pc = 0x8000_0002;
+        assert_eq!(
+            cpu.fetch().unwrap_err(),
+            Exception::InstructionAddrMisaligned(0x8000_0002)
+        );
+        cpu.pc = 0x8000_0000;
+        let jal = RiscvInstWrapper::Full(RiscvInst::Jal { rd: 0, imm: 2 });
+        assert_eq!(
+            cpu.execute(jal),
+            Err(Exception::InstructionAddrMisaligned(0x8000_0002))
+        );
+    }
+
+    #[test]
+    fn test_misaligned_jump() {
+        let mut cpu = RV64Cpu::new();
+        cpu.pc = 0x8000_0000;
+        let jal = RiscvInstWrapper::Full(RiscvInst::Jal {
+            rd: RA as u8,
+            imm: 3,
+        });
+        assert_eq!(
+            cpu.execute(jal),
+            Err(Exception::InstructionAddrMisaligned(0x8000_0003))
+        );
+        // rd is not written when the jump faults
+        assert_eq!(cpu.x[RA], 0);
+
+        let beq = RiscvInstWrapper::Full(RiscvInst::Beq {
+            rs1: 0,
+            rs2: 0,
+            imm: -1,
+        });
+        assert_eq!(
+            cpu.execute(beq),
+            Err(Exception::InstructionAddrMisaligned(0x7fff_ffff))
+        );
+
+        // 2-byte aligned targets are fine with the C extension
+        let jal = RiscvInstWrapper::Full(RiscvInst::Jal {
+            rd: RA as u8,
+            imm: 6,
+        });
+        assert_eq!(cpu.execute(jal), Ok(0x8000_0006));
+    }
+
+    #[test]
+    fn test_unimplemented() {
+        let mut cpu = RV64Cpu::new();
+        cpu.pc = 0x8000_0000;
+        // amoadd.w a0, a1, (a2)
+        let amoadd = RiscvInstWrapper::Full(decode(0x00b6252f));
+        assert!(matches!(amoadd.get_inst(), RiscvInst::AmoaddW { .. }));
+        assert_eq!(
+            cpu.execute(amoadd),
+            Err(Exception::IllegalInstruction(0x8000_0000))
+        );
+    }
+
+    #[test]
+    fn test_memory_latency() {
+        let cycles = |stride: u32| {
+            // eight loads from a0, each `stride` bytes after the previous one
+            let loads: Vec<u32> = (0..8)
+                .map(|i| (i * stride) << 20 | 10 << 15 | 0b011 << 12 | 11 << 7 | 0b0000011)
+                .collect();
+            let cpu = run_snippet(&loads, |cpu| {
+                cpu.set_memory_latency(MemLatency {
+                    hit: 1,
+                    miss: 10,
+                    line_size: 64,
+                    lines: 4,
+                });
+                cpu.x[A0] = 0x8000_1000;
+            });
+            cpu.csr.load(MCYCLE).data
+        };
+        // the same line over and over misses once, while eight lines in four slots always miss
+        let few = cycles(8);
+        let many = cycles(64);
+        assert_eq!(many - few, 7 * (10 - 1));
+    }
+
+    #[test]
+    #[should_panic(expected = "line size")]
+    fn test_memory_latency_zero_line_size() {
+        RV64Cpu::new().set_memory_latency(MemLatency {
+            line_size: 0,
+            lines: 4,
+            ..Default::default()
+        });
+    }
+
+    #[test]
+    fn test_tlb_miss_latency() {
+        let cycles = |stride: u64| {
+            // eight loads from a0, each `stride` bytes after the previous one
+            let loads: Vec<u32> = [0x00053583, 0x00550533] // ld a1, 0(a0); add a0, a0, t0
+                .repeat(8);
+            let cpu = run_snippet(&loads, |cpu| {
+                // identity map the first 2 MiB of DRAM with 4 KiB pages
+                let table = |addr: u64| ((addr >> 12) << 10) | PTE_V;
+                cpu.bus.store(0x8000_2010, 8, table(0x8000_3000)).unwrap();
+                cpu.bus.store(0x8000_3000, 8, table(0x8000_4000)).unwrap();
+                for page in 0..512 {
+                    let addr = 0x8000_0000 + page * 0x1000;
+                    let leaf = table(addr) | PTE_R | PTE_W | PTE_X | PTE_A | PTE_D;
+                    cpu.bus.store(0x8000_4000 + page * 8, 8, leaf).unwrap();
+                }
+                cpu.csr.store(SATP, (8 << 60) | 0x80002);
+                cpu.update_paging(SATP);
+                cpu.mode = SUPERVISOR_MODE;
+                // no cache, so only the page table walks cost anything
+                cpu.set_memory_latency(MemLatency {
+                    miss: 10,
+                    ..Default::default()
+                });
+                cpu.x[A0] = 0x8001_0000;
+                cpu.x[T0] = stride;
+            });
+            cpu.csr.load(MCYCLE).data
+        };
+        // a page per load walks three levels of the table seven more times
+        let good = cycles(8);
+        let poor = cycles(0x1000);
+        assert_eq!(poor - good, 7 * 3 * 10);
+    }
+
+    #[test]
+    fn test_latency() {
+        let cycles = |op: u32| {
+            let data: Vec<u32> = vec![
+                0x00700293, // addi t0, zero, 7
+                0x00200313, // addi t1, zero, 2
+                op, 0x0000001f,
+            ];
+            let data: Vec<u8> = data.iter().flat_map(|x| x.to_le_bytes()).collect();
+            let mut cpu = RV64Cpu::new();
+            cpu.init();
+            cpu.pc = 0x8000_0000;
+            Cpu::load(&mut cpu, data);
+            cpu.run();
+            (cpu.csr.load(MCYCLE).data, cpu.clock)
+        };
+        // add t2, t0, t1
+        let (add, add_clock) = cycles(0x006283b3);
+        // div t2, t0, t1
+        let (div, div_clock) = cycles(0x0262c3b3);
+        let latency = InstLatency::default();
+        assert_eq!(div - add, latency.div - latency.alu);
+        assert_eq!(add, add_clock);
+        assert_eq!(div, div_clock);
+    }
+
+    #[test]
+    fn test_x0_memory_access() {
+        let mut cpu = RV64Cpu::new();
+        cpu.init();
+        cpu.x[10] = 0x8000_1000;
+        cpu.bus.store(0x8000_1000, 8, u64::MAX).unwrap();
+
+        // sw x0, 0(a0); sb x0, 4(a0)
+        cpu.execute(RiscvInstWrapper::Full(decode(0x00052023)))
+            .unwrap();
+        cpu.execute(RiscvInstWrapper::Full(decode(0x00050223)))
+            .unwrap();
+        assert_eq!(cpu.bus.load(0x8000_1000, 8).unwrap(), 0xffff_ff00_0000_0000);
+
+        // lw x0, 4(a0) reads but discards the value
+        cpu.execute(RiscvInstWrapper::Full(decode(0x00452003)))
+            .unwrap();
+        assert_eq!(cpu.x[0], 0);
+
+        // the access still happens, so an unmapped address faults
+        cpu.x[10] = 0x10;
+        assert_eq!(
+            cpu.execute(RiscvInstWrapper::Full(decode(0x00052003))),
+            Err(Exception::LoadAccessFault(0x10))
+        );
+        assert_eq!(cpu.x[0], 0);
+    }
+
+    #[test]
+    fn test_cbo() {
+        let mut cpu = RV64Cpu::new();
+        cpu.init();
+        for addr in (0x8000_1000..0x8000_1080).step_by(8) {
+            cpu.bus.store(addr, 8, u64::MAX).unwrap();
+        }
+        cpu.x[A0] = 0x8000_1010;
+        // cbo.clean (a0); cbo.flush (a0); cbo.inval (a0)
+        for bits in [0x0015200f, 0x0025200f, 0x0005200f] {
+            let inst = decode(bits);
+            assert_ne!(inst, RiscvInst::Illegal);
+            cpu.execute(RiscvInstWrapper::Full(inst)).unwrap();
+        }
+        assert_eq!(cpu.bus.load(0x8000_1000, 8).unwrap(), u64::MAX);
+
+        // cbo.zero (a0) clears the whole aligned block around a0
+        cpu.execute(RiscvInstWrapper::Full(decode(0x0045200f)))
+            .unwrap();
+        for addr in (0x8000_1000..0x8000_1040).step_by(8) {
+            assert_eq!(cpu.bus.load(addr, 8).unwrap(), 0);
+        }
+        assert_eq!(cpu.bus.load(0x8000_1040, 8).unwrap(), u64::MAX);
+    }
+
+    #[test]
+    fn test_unmapped_access() {
+        let mut cpu = RV64Cpu::new();
+        cpu.csr.store(MSTATUS, MASK_FS);
+        cpu.x[A1] = 0x10;
+        let cases = [
+            (0x0005b503, Exception::LoadAccessFault(0x10)), // ld a0, 0(a1)
+            (0x00a5b023, Exception::StoreAMOAccessFault(0x10)), // sd a0, 0(a1)
+            (0x1005b52f, Exception::LoadAccessFault(0x10)), // lr.d a0, (a1)
+            (0x0005b507, Exception::LoadAccessFault(0x10)), // fld fa0, 0(a1)
+            (0x00a5b027, Exception::StoreAMOAccessFault(0x10)), // fsd fa0, 0(a1)
+        ];
+        for (bits, fault) in cases {
+            assert_eq!(
+                cpu.execute(RiscvInstWrapper::Full(decode(bits))),
+                Err(fault),
+                "{:#010x}",
+                bits
+            );
+        }
+    }
+
+    #[test]
+    fn test_write_x0() {
+        let mut cpu = RV64Cpu::new();
+        let addi = RiscvInstWrapper::Full(decode(0x00500013)); // addi x0, x0, 5
+        cpu.execute(addi).unwrap();
+        assert_eq!(cpu.x[0], 0);
+    }
+
+    #[test]
+    fn test_memory_dump() {
+        let data: Vec<u32> = vec![
+            0x00001297, // auipc t0, 1
+            0x12345337, // lui t1, 0x12345
+            0x67830313, // addi t1, t1, 0x678
+            0x0062a423, // sw t1, 8(t0)
+            0x0000001f,
+        ];
+        let data: Vec<u8> = data.iter().flat_map(|x| x.to_le_bytes()).collect();
+        let mut cpu = RV64Cpu::new();
+        cpu.init();
+        cpu.pc = 0x8000_0000;
+        Cpu::load(&mut cpu, data);
+        cpu.run();
+
+        let path = std::env::temp_dir().join(format!("remu-dump-{}.bin", std::process::id()));
+        let mut file = File::create(&path).unwrap();
+        cpu.dump_dram(&mut file, Some((0x8000_1000, 16))).unwrap();
+        drop(file);
+        let dump = fs::read(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(dump.len(), 16);
+        assert_eq!(dump[8..12], 0x12345678u32.to_le_bytes());
+        assert!(dump[..8].iter().chain(&dump[12..]).all(|&b| b == 0));
+
+        assert!(cpu.dump_dram(&mut Vec::new(), Some((0, 16))).is_err());
+    }
+
+    #[test]
+    fn test_poweroff() {
+        let data: Vec<u32> = vec![
+            0x001002b7, // lui t0, 0x100
+            0x002a3337, // lui t1, 0x2a3
+            0x3333031b, // addiw t1, t1, 0x333
+            0x0062a023, // sw t1, 0(t0)
+            0x0000001f,
+        ];
+        let data: Vec<u8> = data.iter().flat_map(|x| x.to_le_bytes()).collect();
+        let mut cpu = RV64Cpu::new();
+        cpu.init();
+        cpu.pc = 0x8000_0000;
+        Cpu::load(&mut cpu, data);
+        assert_eq!(cpu.run(), HaltReason::Exit(42));
+        assert_eq!(cpu.pc, 0x8000_0010);
+    }
+
+    #[test]
+    fn test_trap_and_return() {
+        let data: Vec<u32> = vec![
+            0x00000297, // auipc t0, 0
+            0x01c28293, // addi t0, t0, 28
+            0x30529073, // csrw mtvec, t0
+            0x00100513, // addi a0, zero, 1
+            0x00000073, // ecall
+            0x00a50513, // addi a0, a0, 10
+            0x0000001f, // handler
+            0x34102373, // csrr t1, mepc
+            0x00430313, // addi t1, t1, 4
+            0x34131073, // csrw mepc, t1
+            0x02a00593, // addi a1, zero, 42
+            0x30200073, // mret
+        ];
+        let data: Vec<u8> = data.iter().flat_map(|x| x.to_le_bytes()).collect();
+        let mut cpu = RV64Cpu::new();
+        cpu.init();
+        cpu.pc = 0x8000_0000;
+        Cpu::load(&mut cpu, data);
+        cpu.run();
+
+        assert_eq!(cpu.x[A1], 42);
+        assert_eq!(cpu.x[A0], 11);
+        assert_eq!(cpu.mode, MACHINE_MODE);
     }
-}
 
-impl Cpu for RV64Cpu {
-    fn init(&mut self) {
-        self.bus.init();
+    #[test]
+    fn test_trap_illegal() {
+        let mut cpu = RV64Cpu::new();
+        cpu.init();
+        Cpu::load(&mut cpu, vec![0xff, 0xff, 0xff, 0xff]);
+        cpu.csr.store(MTVEC, 0x8000_0100);
+
+        cpu.pc = 0x8000_0000;
+        assert_eq!(
+            cpu.step(),
+            Some(HaltReason::Fatal(Exception::IllegalInstruction(
+                0x8000_0000
+            )))
+        );
+
+        cpu.set_trap_illegal(true);
+        cpu.pc = 0x8000_0000;
+        cpu.mode = MACHINE_MODE;
+        assert_eq!(cpu.step(), None);
+        assert_eq!(cpu.pc, 0x8000_0100);
+        assert_eq!(cpu.csr.load(MCAUSE).data, 2);
+        assert_eq!(cpu.csr.load(MEPC).data, 0x8000_0000);
+        assert_eq!(cpu.csr.load(MTVAL).data, 0xffff_ffff);
     }
 
-    fn load(&mut self, data: Vec<u8>) {
-        self.bus.load_data(0x8000_0000, &data).expect("Load failed");
+    #[test]
+    fn test_fatal_policy() {
+        let mut data: Vec<u32> = vec![
+            0x0002b503, // ld a0, 0(t0)
+        ];
+        data.resize(0x40, 0);
+        data.push(0x30200073); // handler at 0x8000_0100: mret
+        let data: Vec<u8> = data.iter().flat_map(|x| x.to_le_bytes()).collect();
+        let mut cpu = RV64Cpu::new();
+        cpu.init();
+        Cpu::load(&mut cpu, data);
+        cpu.csr.store(MTVEC, 0x8000_0100);
+
+        // a page fault goes to the handler
+        let pte = ((0x8000_0000u64 >> 12) << 10) | PTE_V | PTE_R | PTE_W | PTE_X | PTE_A | PTE_D;
+        cpu.bus.store(0x8000_2010, 8, pte).unwrap();
+        cpu.csr.store(SATP, (8 << 60) | 0x80002);
+        cpu.update_paging(SATP);
+        cpu.mode = SUPERVISOR_MODE;
+        cpu.x[5] = 0x4000_1000;
+        cpu.pc = 0x8000_0000;
+        assert_eq!(cpu.step(), None);
+        assert_eq!(cpu.pc, 0x8000_0100);
+        assert_eq!(cpu.csr.load(MCAUSE).data, 13);
+
+        // by default an access fault halts, but the policy can let the guest take it
+        cpu.csr.store(SATP, 0);
+        cpu.update_paging(SATP);
+        cpu.x[5] = 0x10;
+        cpu.pc = 0x8000_0000;
+        assert_eq!(
+            cpu.step(),
+            Some(HaltReason::Fatal(Exception::LoadAccessFault(0x10)))
+        );
+        cpu.set_fatal_policy(FatalPolicy::log_and_continue());
+        cpu.pc = 0x8000_0000;
+        assert_eq!(cpu.step(), None);
+        assert_eq!(cpu.pc, 0x8000_0100);
+        assert_eq!(cpu.csr.load(MCAUSE).data, 5);
+
+        // a handler that can't even be fetched halts rather than trapping forever
+        assert_eq!(cpu.step(), None);
+        assert_eq!(cpu.pc, 0x8000_0000);
+        cpu.csr.store(MTVEC, 0x20);
+        assert_eq!(cpu.step(), None);
+        assert_eq!(cpu.pc, 0x20);
+        assert!(matches!(cpu.step(), Some(HaltReason::Fatal(_))));
     }
 
-    fn reset(&mut self) {
-        self.pc = 0;
-        self.x = [0; 32];
+    /// A writer whose output can still be inspected after handing it to the CPU.
+    #[derive(Clone, Default)]
+    struct SharedBuf(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for SharedBuf {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
     }
 
-    fn handle_interrupt(&mut self, int: Interrupt) {
-        let pc = self.pc;
-        let mode = self.mode;
-        let cause = int.code();
-        let trap_in_s_mode = mode <= SUPERVISOR_MODE && self.csr.is_midelegated(cause);
-        let (STATUS, TVEC, CAUSE, TVAL, EPC, MASK_PIE, pie_i, MASK_IE, ie_i, MASK_PP, pp_i) =
-            if trap_in_s_mode {
-                self.mode = SUPERVISOR_MODE;
-                (
-                    SSTATUS, STVEC, SCAUSE, STVAL, SEPC, MASK_SPIE, 5, MASK_SIE, 1, MASK_SPP, 8,
-                )
-            } else {
-                self.mode = MACHINE_MODE;
-                (
-                    MSTATUS, MTVEC, MCAUSE, MTVAL, MEPC, MASK_MPIE, 7, MASK_MIE, 3, MASK_MPP, 11,
-                )
-            };
+    #[test]
+    fn test_uart_output() {
+        let mut cpu = RV64Cpu::new();
+        let out = SharedBuf::default();
+        cpu.set_uart_output(Box::new(out.clone()));
+        for &byte in b"hi" {
+            cpu.bus.store(UART_BASE, 1, byte.into()).unwrap();
+        }
+        // emulator diagnostics never reach the guest's sink
+        log::info!("not guest output");
+        assert_eq!(*out.0.borrow(), b"hi");
+    }
 
-        let tvec = self.csr.load(TVEC);
-        let tvec_mode = (tvec & 0b11).into();
-        let tvec_base = (tvec & !0b11).into();
-        match tvec_mode {
-            // DIrect
-            0 => self.pc = tvec_base,
-            1 => self.pc = tvec_base + (cause << 2),
-            _ => unreachable!(),
-        };
+    #[test]
+    fn test_uart_store() {
+        let out = SharedBuf::default();
+        run_snippet(
+            &[
+                0x100002b7, // lui t0, 0x10000
+                0x06800313, // li t1, 'h'
+                0x0062a023, // sw t1, 0(t0)
+                0x06900313, // li t1, 'i'
+                0x00628023, // sb t1, 0(t0)
+            ],
+            |cpu| cpu.set_uart_output(Box::new(out.clone())),
+        );
+        assert_eq!(*out.0.borrow(), b"hi");
+    }
+
+    #[test]
+    fn test_semihosting() {
+        let data: Vec<u8> = [
+            0x01f01013, // slli x0, x0, 0x1f
+            0x00100073, // ebreak
+            0x40705013, // srai x0, x0, 7
+        ]
+        .iter()
+        .flat_map(|x: &u32| x.to_le_bytes())
+        .collect();
+        let mut cpu = RV64Cpu::new();
+        cpu.init();
+        Cpu::load(&mut cpu, data);
+        cpu.bus.store(0x8000_1000, 1, b'A'.into()).unwrap();
+        cpu.bus.store(0x8000_1010, 8, 0x20026).unwrap();
+        cpu.bus.store(0x8000_1018, 8, 7).unwrap();
+        cpu.csr.store(MTVEC, 0x8000_0100);
+
+        // without semihosting it is an ordinary breakpoint
+        cpu.x[A0] = 0x03;
+        cpu.x[A1] = 0x8000_1000;
+        cpu.pc = 0x8000_0004;
+        assert_eq!(cpu.step(), None);
+        assert_eq!(cpu.pc, 0x8000_0100);
+        assert_eq!(cpu.csr.load(MCAUSE).data, 3);
 
-        self.csr.store(EPC, pc);
-        self.csr.store(CAUSE, cause);
-        self.csr.store(TVAL, 0);
+        // SYS_WRITEC
+        let out = SharedBuf::default();
+        cpu.set_semihosting(Some(Box::new(out.clone())));
+        cpu.pc = 0x8000_0004;
+        assert_eq!(cpu.step(), None);
+        assert_eq!(cpu.pc, 0x8000_0008);
+        assert_eq!(*out.0.borrow(), b"A");
 
-        let mut status = self.csr.load(STATUS);
-        let ie = (status & MASK_IE) >> ie_i;
-        status = (status & !MASK_PIE) | (ie << pie_i);
-        status.clear(MASK_IE);
-        status = (status & !MASK_PP) | (mode << pp_i) as u64;
-        self.csr.store(STATUS, status.into());
+        // SYS_EXIT with ADP_Stopped_ApplicationExit and exit code 7
+        cpu.x[A0] = 0x18;
+        cpu.x[A1] = 0x8000_1010;
+        cpu.pc = 0x8000_0004;
+        assert_eq!(cpu.run(), HaltReason::Exit(7));
     }
 
-    fn handle_exception(&mut self, e: Exception) {
-        let pc = self.pc;
-        let mode = self.mode;
-        let cause = e.code();
-        // if an exception happen in U-mode or S-mode, and the exception is delegated to S-mode.
-        // then this exception should be handled in S-mode.
-        let trap_in_s_mode = mode <= SUPERVISOR_MODE && self.csr.is_medelegated(cause);
-        let (STATUS, TVEC, CAUSE, TVAL, EPC, MASK_PIE, pie_i, MASK_IE, ie_i, MASK_PP, pp_i) =
-            if trap_in_s_mode {
-                self.mode = SUPERVISOR_MODE;
-                (
-                    SSTATUS, STVEC, SCAUSE, STVAL, SEPC, MASK_SPIE, 5, MASK_SIE, 1, MASK_SPP, 8,
-                )
-            } else {
-                self.mode = MACHINE_MODE;
-                (
-                    MSTATUS, MTVEC, MCAUSE, MTVAL, MEPC, MASK_MPIE, 7, MASK_MIE, 3, MASK_MPP, 11,
-                )
-            };
-        // 3.1.7 & 4.1.2
-        // The BASE field in tvec is a WARL field that can hold any valid virtual or physical address,
-        // subject to the following alignment constraints: the address must be 4-byte aligned
-        self.pc = (self.csr.load(TVEC) & !0b11).into();
-        // 3.1.14 & 4.1.7
-        // When a trap is taken into S-mode (or M-mode), sepc (or mepc) is written with the virtual address
-        // of the instruction that was interrupted or that encountered the exception.
-        self.csr.store(EPC, pc);
-        // 3.1.15 & 4.1.8
-        // When a trap is taken into S-mode (or M-mode), scause (or mcause) is written with a code indicating
-        // the event that caused the trap.
-        self.csr.store(CAUSE, cause);
-        // 3.1.16 & 4.1.9
-        // If stval is written with a nonzero value when a breakpoint, address-misaligned, access-fault, or
-        // page-fault exception occurs on an instruction fetch, load, or store, then stval will contain the
-        // faulting virtual address.
-        // If stval is written with a nonzero value when a misaligned load or store causes an access-fault or
-        // page-fault exception, then stval will contain the virtual address of the portion of the access that
-        // caused the fault
-        self.csr.store(TVAL, e.value());
-        // 3.1.6 covers both sstatus and mstatus.
-        let mut status = self.csr.load(STATUS);
-        // get SIE or MIE
-        let ie = Into::<u64>::into(status & MASK_IE) >> ie_i;
-        // set SPIE = SIE / MPIE = MIE
-        status = (status & !MASK_PIE) | (ie << pie_i);
-        // set SIE = 0 / MIE = 0
-        status.clear(MASK_IE);
-        // set SPP / MPP = previous mode
-        status = Csr {
-            data: Into::<u64>::into(status & !MASK_PP) | ((mode as u64) << pp_i) as u64,
+    #[test]
+    fn test_proxy() {
+        let out = SharedBuf::default();
+        let cpu = run_snippet(
+            &[
+                0x00100513, // li a0, 1
+                0x00300613, // li a2, 3
+                0x04000893, // li a7, 64 (write)
+                0x00000073, // ecall
+                0x00050413, // mv s0, a0
+                0x0d600893, // li a7, 214 (brk)
+                0x00000513, // li a0, 0
+                0x00000073, // ecall
+                0x00050493, // mv s1, a0
+                0x05d00893, // li a7, 93 (exit)
+                0x00300513, // li a0, 3
+                0x00000073, // ecall
+            ],
+            |cpu| {
+                cpu.set_proxy(Box::new(out.clone()), 0x8000_2000);
+                cpu.bus
+                    .store(0x8000_1000, 4, u32::from_le_bytes(*b"hi\n\0").into())
+                    .unwrap();
+                cpu.x[A1] = 0x8000_1000;
+            },
+        );
+        assert_eq!(cpu.mode, USER_MODE);
+        assert_eq!(*out.0.borrow(), b"hi\n");
+        assert_eq!(cpu.x[S0], 3);
+        assert_eq!(cpu.x[S1], 0x8000_2000);
+        assert_eq!(cpu.bus.syscon.exit_code(), Some(3));
+        // exit stopped the program before the halt
+        assert_eq!(cpu.pc, DRAM_BASE + 12 * 4);
+    }
+
+    #[test]
+    fn test_proxy_brk() {
+        let mut cpu = RV64Cpu::new();
+        cpu.init();
+        cpu.set_proxy(Box::new(io::sink()), 0x8000_1234);
+        let brk = |cpu: &mut RV64Cpu, addr: u64| {
+            cpu.x[A7] = 214;
+            cpu.x[A0] = addr;
+            cpu.proxy_call().unwrap();
+            cpu.x[A0]
         };
-        self.csr.store(STATUS, status.into());
+
+        // the break starts on the next page and only grows on request
+        let start = brk(&mut cpu, 0);
+        assert_eq!(start, 0x8000_2000);
+        let mut last = start;
+        for size in [0x10, 0x1000, 0x3_0000] {
+            let end = brk(&mut cpu, last + size);
+            assert_eq!(end, last + size);
+            last = end;
+        }
+
+        // the heap is writable, and memory handed out again after a shrink is zeroed
+        cpu.store(start + 8, 8, u64::MAX).unwrap();
+        assert_eq!(cpu.load(start + 8, 8), Ok(u64::MAX));
+        assert_eq!(brk(&mut cpu, start), start);
+        assert_eq!(brk(&mut cpu, start + 0x100), start + 0x100);
+        assert_eq!(cpu.load(start + 8, 8), Ok(0));
+
+        // a break below the heap or into the stack fails and returns the old one
+        assert_eq!(brk(&mut cpu, 0x8000_1000), start + 0x100);
+        let sp = cpu.x[SP];
+        assert_eq!(brk(&mut cpu, sp), start + 0x100);
     }
 
-    fn run(&mut self) {
-        loop {
-            self.x[0] = 0; // x0 is always 0
-            let inst = self.fetch();
-            let inst_with_len = match inst {
-                Ok(inst) => inst,
-                Err(_) => {
-                    break;
-                }
-            };
-            match self.execute(inst_with_len) {
-                Ok(new_pc) => self.pc = new_pc,
-                Err(e) => {
-                    self.handle_exception(e);
-                    if e.is_fatal() {
-                        break;
-                    }
-                }
-            }
+    #[test]
+    fn test_proxy_initial_state() {
+        let data: Vec<u8> = [
+            0x00013503, // ld a0, 0(sp)
+            0x00813283, // ld t0, 8(sp)
+            0x00556533, // or a0, a0, t0
+            0x01013283, // ld t0, 16(sp)
+            0x00556533, // or a0, a0, t0
+            0x01813283, // ld t0, 24(sp)
+            0x00556533, // or a0, a0, t0
+            0xf0050053, // fmv.w.x ft0, a0
+            0xe0000553, // fmv.x.w a0, ft0
+            0x05d00893, // li a7, 93 (exit)
+            0x00000073, // ecall
+        ]
+        .iter()
+        .flat_map(|x: &u32| x.to_le_bytes())
+        .collect();
+        let mut cpu = RV64Cpu::new();
+        cpu.init();
+        cpu.set_poison(0xaa);
+        Cpu::load(&mut cpu, data);
+        cpu.pc = DRAM_BASE;
+        cpu.set_proxy(Box::new(io::sink()), 0x8000_2000);
+        assert_eq!(cpu.csr.load(MSTATUS).data & MASK_FS, FS_INITIAL);
 
-            match self.check_pending_interrupt() {
-                Some(interrupt) => self.handle_interrupt(interrupt),
-                None => (),
-            }
+        // the floating-point instructions run, and the stack holds zeros over the pattern
+        assert_eq!(cpu.run(), HaltReason::Exit(0));
+    }
+
+    #[test]
+    fn test_poison() {
+        let data: Vec<u8> = [0x00053583, HALT] // ld a1, 0(a0)
+            .iter()
+            .flat_map(|x: &u32| x.to_le_bytes())
+            .collect();
+        let mut cpu = RV64Cpu::new();
+        cpu.init();
+        cpu.set_poison(0xaa);
+        Cpu::load(&mut cpu, data);
+        cpu.pc = DRAM_BASE;
+        cpu.x[A0] = 0x8000_1000;
+        cpu.run();
+        // the program itself is loaded over the pattern
+        assert_eq!(cpu.x[A1], 0xaaaa_aaaa_aaaa_aaaa);
+        assert_eq!(cpu.load(0x8000_1010, 1), Ok(0xaa));
+    }
+
+    #[test]
+    fn test_interrupt_saves_mode() {
+        // the previous mode lands in mstatus.MPP, which is wider than the mode is
+        for mode in [USER_MODE, SUPERVISOR_MODE, MACHINE_MODE] {
+            let mut cpu = RV64Cpu::new();
+            cpu.mode = mode;
+            cpu.handle_interrupt(Interrupt::MachineTimerInterrupt);
+            assert_eq!(cpu.mode, MACHINE_MODE);
+            assert_eq!(cpu.csr.load(MSTATUS).mpp() as u8, mode);
         }
     }
 
-    type Exception = Exception;
-    type Interrupt = Interrupt;
-}
+    #[test]
+    fn test_interrupt_saves_mie() {
+        // mstatus.MPIE keeps the interrupt enable the handler was entered with
+        for mie in [0, MASK_MIE] {
+            let mut cpu = RV64Cpu::new();
+            cpu.mode = MACHINE_MODE;
+            cpu.csr.store(MSTATUS, mie);
+            cpu.handle_interrupt(Interrupt::MachineTimerInterrupt);
+            let status = cpu.csr.load(MSTATUS);
+            assert_eq!(status.mpie(), (mie != 0) as u64);
+            assert_eq!(status.mie(), 0);
+        }
+    }
 
-#[cfg(test)]
-mod test {
+    #[test]
+    fn test_ecall_delegation() {
+        let mut cpu = RV64Cpu::new();
+        cpu.init();
+        Cpu::load(&mut cpu, 0x00000073u32.to_le_bytes().to_vec()); // ecall
+        cpu.csr.store(MTVEC, 0x8000_0100);
+        cpu.csr.store(STVEC, 0x8000_0200);
+        cpu.csr.store(MEDELEG, 1 << 8);
 
-    use crate::{
-        arch::riscv::reg::{A0, RA, SP},
-        cpu::Cpu,
-        util::addr_add,
-    };
+        // from U-mode, delegated to the S-mode handler
+        cpu.mode = USER_MODE;
+        cpu.pc = DRAM_BASE;
+        assert_eq!(cpu.step(), None);
+        assert_eq!(cpu.mode, SUPERVISOR_MODE);
+        assert_eq!(cpu.pc, 0x8000_0200);
+        assert_eq!(cpu.csr.load(SCAUSE).data, 8);
+        assert_eq!(cpu.csr.load(SEPC).data, DRAM_BASE);
+        assert_eq!(cpu.csr.load(STVAL).data, 0);
+        assert_eq!(cpu.csr.load(SSTATUS).data & MASK_SPP, 0);
+        assert_eq!(cpu.csr.load(MCAUSE).data, 0);
 
-    use super::RV64Cpu;
+        // from S-mode, which is not delegated, to the M-mode handler, as if the S-mode handler had
+        // run and jumped back
+        cpu.pc = DRAM_BASE;
+        cpu.in_trap_entry = false;
+        assert_eq!(cpu.step(), None);
+        assert_eq!(cpu.mode, MACHINE_MODE);
+        assert_eq!(cpu.pc, 0x8000_0100);
+        assert_eq!(cpu.csr.load(MCAUSE).data, 9);
+        assert_eq!(cpu.csr.load(MTVAL).data, 0);
+        assert_eq!(cpu.csr.load(SCAUSE).data, 8);
+    }
 
     #[test]
-    fn test_exec() {
-        assert_eq!(addr_add(64u64, -32i32), 32);
-        // factorial
-        let data: Vec<u32> = vec![
-            // -O2
-            // 0x00050793, 0x00100513, 0x00100693, 0x00f55a63, 0x00078713, 0xfff7879b, 0x02a7053b,
-            // 0xfed79ae3, 0x0000001f,
-            // -O0
-            0xfe010113, 0x00113c23, 0x00813823, 0x02010413, 0x00050793, 0xfef42623, 0xfec42783,
-            0x0007871b, 0x00100793, 0x00e7c663, 0x00100793, 0x0300006f, 0xfec42783, 0xfff7879b,
-            0x0007879b, 0x00078513, 0x00000097, 0xfc0080e7, 0x00050793, 0x00078713, 0xfec42783,
-            0x02e787bb, 0x0007879b, 0x00078513, 0x01813083, 0x01013403, 0x02010113, 0x00008067,
-            0x0000001f,
+    fn test_trace() {
+        let data = [
+            0x00100513, // li a0, 1
+            0x00200593, // li a1, 2
+            0x00b50633, // add a2, a0, a1
+            0x00000000, // illegal
+            0x00300693, // li a3, 3
+        ];
+        let cpu = run_snippet(&data, |cpu| cpu.set_trace_len(3));
+        let trace: Vec<_> = cpu.trace().map(|entry| (entry.pc, entry.bits)).collect();
+        assert_eq!(
+            trace,
+            [
+                (0x8000_0004, 0x00200593),
+                (0x8000_0008, 0x00b50633),
+                (0x8000_000c, 0x00000000),
+            ]
+        );
+        assert_eq!(cpu.trace().last().unwrap().inst, RiscvInst::Illegal);
+
+        // off by default
+        let cpu = run_snippet(&data, |_| {});
+        assert_eq!(cpu.trace().count(), 0);
+    }
+
+    #[test]
+    fn test_external_interrupt() {
+        let cpu = run_snippet(&[0x00000013], |cpu| {
+            cpu.bus.plic.raise(0, SUPERVISOR_MODE, 10);
+        });
+        let mip = cpu.csr.load(MIP).data;
+        assert_ne!(mip & MASK_SEIP, 0);
+        assert_eq!(mip & MASK_MEIP, 0);
+    }
+
+    #[test]
+    fn test_breakpoints() {
+        let mut cpu = load_snippet(&[0x00000013; 4]);
+        for addr in [0x8000_0008, 0x8000_0004, 0x8000_000c, 0x8000_0004] {
+            cpu.add_breakpoint(addr);
+        }
+        assert_eq!(cpu.breakpoints(), [0x8000_0008, 0x8000_0004, 0x8000_000c]);
+
+        cpu.remove_breakpoint(0x8000_0004);
+        assert_eq!(cpu.breakpoints(), [0x8000_0008, 0x8000_000c]);
+        assert_eq!(cpu.run(), HaltReason::Breakpoint(0x8000_0008));
+
+        cpu.clear_breakpoints();
+        assert!(cpu.breakpoints().is_empty());
+        assert_eq!(cpu.run(), HaltReason::Breakpoint(0x8000_0010));
+    }
+
+    #[test]
+    fn test_inject_interrupts() {
+        let mut cpu = load_snippet(&[0x00000013, 0x00000013]); // nop; nop
+        cpu.csr.store(MTVEC, 0x8000_0100);
+        cpu.csr.store(MSTATUS, MASK_MIE);
+        cpu.csr.store(MIE, MASK_MEIP | MASK_MTIP);
+        // give source 5 priority 1 and enable it in the M-mode context of hart 0
+        cpu.bus.plic.store(0x14, 4, 1).unwrap();
+        cpu.bus.plic.store(0x2000, 4, 1 << 5).unwrap();
+
+        // a source no context enables is dropped
+        cpu.raise_external_interrupt(6);
+        assert_eq!(cpu.step(), None);
+        assert_eq!(cpu.pc, 0x8000_0004);
+
+        cpu.raise_external_interrupt(5);
+        assert_eq!(cpu.step(), None);
+        assert_eq!(cpu.pc, 0x8000_0100);
+        assert_eq!(cpu.csr.load(MCAUSE).data, (1 << 63) | 11);
+        assert_eq!(cpu.csr.load(MEPC).data, 0x8000_0008);
+        assert_eq!(cpu.bus.plic.load(0x20_0004, 4), Ok(5));
+        // the trap from M-mode records it in MPP
+        assert_eq!(cpu.csr.load(MSTATUS).mpp() as u8, MACHINE_MODE);
+
+        let mut cpu = load_snippet(&[0x00000013]);
+        cpu.csr.store(MTVEC, 0x8000_0100);
+        cpu.csr.store(MSTATUS, MASK_MIE);
+        cpu.csr.store(MIE, MASK_MTIP);
+        cpu.raise_timer_interrupt();
+        assert_eq!(
+            cpu.bus.clint.load(CLINT_MTIMECMP, 8),
+            Ok(cpu.bus.clint.mtime())
+        );
+        assert_eq!(cpu.step(), None);
+        assert_eq!(cpu.pc, 0x8000_0100);
+        assert_eq!(cpu.csr.load(MCAUSE).data, (1 << 63) | 7);
+    }
+
+    #[test]
+    fn test_timer_interrupt() {
+        let data = [
+            0x020042b7, // lui t0, 0x2004 (mtimecmp)
+            0x00500313, // addi t1, zero, 5
+            0x0062b023, // sd t1, 0(t0)
+            0x00000397, // auipc t2, 0
+            0x01c38393, // addi t2, t2, 28
+            0x30539073, // csrw mtvec, t2
+            0x08000313, // addi t1, zero, 128
+            0x30431073, // csrw mie, t1
+            0x30046073, // csrsi mstatus, 8
+            0x0000006f, // j 0
+            0x344025f3, // csrr a1, mip
+            0x34202573, // csrr a0, mcause
+            0xfff00313, // addi t1, zero, -1
+            0x0062b023, // sd t1, 0(t0)
+            0x34402673, // csrr a2, mip
+        ];
+        let cpu = run_snippet(&data, |_| {});
+        assert_eq!(cpu.x[A0], (1 << 63) | 7);
+        // the interrupt stays pending in the handler until it moves mtimecmp
+        assert_eq!(cpu.x[A1], MASK_MTIP);
+        assert_eq!(cpu.x[A2], 0);
+    }
+
+    #[test]
+    fn test_uart_interrupt() {
+        let mut cpu = RV64Cpu::new();
+        // give the UART priority 1, but leave it disabled in the S-mode context of hart 0
+        cpu.bus.plic.store(UART_IRQ * 4, 4, 1).unwrap();
+        cpu.bus.uart.set_interrupting();
+        cpu.check_pending_interrupt();
+        assert_eq!(cpu.csr.load(MIP).data & MASK_SEIP, 0);
+
+        cpu.bus.plic.store(0x2080, 4, 1 << UART_IRQ).unwrap();
+        cpu.bus.uart.set_interrupting();
+        cpu.check_pending_interrupt();
+        assert_eq!(cpu.csr.load(MIP).data & MASK_SEIP, MASK_SEIP);
+        assert_eq!(cpu.bus.plic.load(0x20_1004, 4), Ok(UART_IRQ));
+    }
+
+    #[test]
+    fn test_bootrom() {
+        let rom: Vec<u32> = vec![
+            0x00000297, // auipc t0, 0
+            0x00700513, // addi a0, zero, 7
+            0x00a2a023, // sw a0, 0(t0)
         ];
-        let data: Vec<u8> = data.iter().flat_map(|x| x.to_le_bytes().to_vec()).collect();
+        let rom: Vec<u8> = rom.iter().flat_map(|x| x.to_le_bytes()).collect();
+        let mut cpu = RV64Cpu::new();
+        cpu.set_bootrom(&rom).unwrap();
+        assert_eq!(cpu.pc, 0x1000);
+
+        assert_eq!(cpu.step(), None);
+        assert_eq!(cpu.step(), None);
+        assert_eq!(cpu.x[A0], 7);
+        assert_eq!(
+            cpu.step(),
+            Some(HaltReason::Fatal(Exception::StoreAMOAccessFault(0x1000)))
+        );
+        assert_eq!(cpu.bus.load(0x1000, 4).unwrap(), 0x00000297);
+        // past the end of the image
+        assert!(cpu.bus.load(0x100c, 4).is_err());
+
+        assert!(cpu.set_bootrom(&vec![0; 0x10000]).is_err());
+    }
+
+    #[test]
+    fn test_boot_handoff() {
+        let mut cpu = RV64Cpu::new();
+        cpu.set_hart_id(1);
+        cpu.set_boot_handoff(0x8020_0000, 0x8700_0000);
+        assert_eq!(cpu.run_for(5), None);
+        assert_eq!(cpu.x[A0], 1);
+        assert_eq!(cpu.x[A1], 0x8700_0000);
+        assert_eq!(cpu.pc, 0x8020_0000);
+    }
+
+    #[test]
+    fn test_load_at() {
+        let mut cpu = RV64Cpu::new();
+        cpu.init();
+        cpu.load_at(0x8000_0000, &[0x11, 0x22, 0x33, 0x44]).unwrap();
+        cpu.load_at(0x8010_0000, &[0x55, 0x66]).unwrap();
+        assert_eq!(cpu.bus.load(0x8000_0000, 4).unwrap(), 0x4433_2211);
+        assert_eq!(cpu.bus.load(0x8010_0000, 2).unwrap(), 0x6655);
+        assert_eq!(cpu.bus.load(0x8000_0004, 4).unwrap(), 0);
+
+        // the end of DRAM is checked before anything is written
+        let end = 0x8000_0000 + 128 * 1024 * 1024;
+        assert_eq!(
+            cpu.load_at(end - 2, &[1, 2, 3, 4]),
+            Err(Exception::StoreAMOAccessFault(end))
+        );
+        assert_eq!(cpu.bus.load(end - 2, 2).unwrap(), 0);
+        assert_eq!(
+            cpu.load_at(0x4000, &[1]),
+            Err(Exception::StoreAMOAccessFault(0x4000))
+        );
+    }
+
+    #[test]
+    fn test_fetch() {
         let mut cpu = RV64Cpu::new();
         cpu.init();
+        // addi a0, a0, 1; c.addi a0, 1
+        let data = [0x13, 0x05, 0x15, 0x00, 0x05, 0x05];
+        Cpu::load(&mut cpu, data.to_vec());
 
         cpu.pc = 0x8000_0000;
-        cpu.x[RA] = 0x8000_0000 + 0x70;
-        cpu.x[SP] = 0x8000_0000 + 0x400;
-        cpu.x[A0] = 5;
+        let inst = cpu.fetch().unwrap();
+        assert!(!inst.is_compact());
+        assert_eq!(
+            inst.get_inst(),
+            RiscvInst::Addi {
+                rd: 10,
+                rs1: 10,
+                imm: 1
+            }
+        );
 
-        Cpu::load(&mut cpu, data);
-        cpu.run();
+        cpu.pc += 4;
+        let inst = cpu.fetch().unwrap();
+        assert!(inst.is_compact());
+        assert_eq!(
+            inst.get_inst(),
+            RiscvInst::Addi {
+                rd: 10,
+                rs1: 10,
+                imm: 1
+            }
+        );
+    }
 
-        assert_eq!(cpu.x[10], 120u64);
+    #[test]
+    fn test_lui_auipc() {
+        let mut cpu = RV64Cpu::new();
+        cpu.pc = 0x8000_0000;
+
+        // lui a0, 0x12345
+        cpu.execute(RiscvInstWrapper::Full(decode(0x12345537)))
+            .unwrap();
+        assert_eq!(cpu.x[A0], 0x12345000);
+
+        // lui a0, 0x80000
+        cpu.execute(RiscvInstWrapper::Full(decode(0x80000537)))
+            .unwrap();
+        assert_eq!(cpu.x[A0], 0xffff_ffff_8000_0000);
+
+        // auipc a0, 0x1
+        cpu.execute(RiscvInstWrapper::Full(decode(0x00001517)))
+            .unwrap();
+        assert_eq!(cpu.x[A0], 0x8000_1000);
+    }
+
+    #[test]
+    fn test_virtual_time() {
+        let data = [
+            0x0200c2b7, // lui t0, 0x200c
+            0xff828293, // addi t0, t0, -8 (mtime)
+            0x0002b503, // ld a0, 0(t0)
+            0x0002b583, // ld a1, 0(t0)
+            0x00000013, // nop
+            0x00000013, // nop
+            0x0002b603, // ld a2, 0(t0)
+        ];
+        let mtimes = || {
+            let cpu = run_snippet(&data, |cpu| cpu.set_insts_per_tick(2));
+            [cpu.x[A0], cpu.x[A1], cpu.x[A2]]
+        };
+        let first = mtimes();
+        assert_eq!(first, [1, 1, 3]);
+        assert_eq!(mtimes(), first);
+
+        let data = [
+            0x020042b7, // lui t0, 0x2004 (mtimecmp)
+            0x00500313, // addi t1, zero, 5
+            0x0062b023, // sd t1, 0(t0)
+            0x00000397, // auipc t2, 0
+            0x01c38393, // addi t2, t2, 28
+            0x30539073, // csrw mtvec, t2
+            0x08000313, // addi t1, zero, 128
+            0x30431073, // csrw mie, t1
+            0x30046073, // csrsi mstatus, 8
+            0x0000006f, // j 0
+            0x34102573, // csrr a0, mepc
+            0xb02025f3, // csrr a1, minstret
+        ];
+        let interrupted_at = || {
+            let cpu = run_snippet(&data, |cpu| cpu.set_insts_per_tick(3));
+            [cpu.x[A0], cpu.x[A1]]
+        };
+        // the timer goes off in the loop at the same point on every run
+        let first = interrupted_at();
+        assert_eq!(first, [0x8000_0024, 16]);
+        assert_eq!(interrupted_at(), first);
+    }
+
+    #[test]
+    fn test_rdtime() {
+        let mut cpu = RV64Cpu::new();
+        for _ in 0..10 {
+            cpu.bus.clint.tick();
+        }
+        // rdtime a0
+        cpu.execute(RiscvInstWrapper::Full(decode(0xc0102573)))
+            .unwrap();
+        assert_eq!(cpu.x[A0], 10);
+    }
+
+    #[test]
+    fn test_counter_gating() {
+        let mut cpu = RV64Cpu::new();
+        cpu.mode = USER_MODE;
+        // rdcycle a0
+        let rdcycle = RiscvInstWrapper::Full(decode(0xc0002573));
+
+        assert!(matches!(
+            cpu.execute(rdcycle),
+            Err(Exception::IllegalInstruction(_))
+        ));
+
+        cpu.csr.store(MCOUNTEREN, 1);
+        cpu.csr.store(SCOUNTEREN, 1);
+        cpu.csr.store(MCYCLE, 42);
+        assert!(cpu.execute(rdcycle).is_ok());
+        assert_eq!(cpu.x[A0], 42);
+    }
+
+    #[test]
+    fn test_machine_ids() {
+        let csrr_mhartid = RiscvInstWrapper::Full(decode(0xf1402573));
+        for hart_id in [0, 1] {
+            let mut cpu = RV64Cpu::new();
+            cpu.set_hart_id(hart_id);
+            cpu.execute(csrr_mhartid).unwrap();
+            assert_eq!(cpu.x[A0], hart_id);
+        }
+
+        let mut cpu = RV64Cpu::new();
+        // csrr a0, marchid
+        cpu.execute(RiscvInstWrapper::Full(decode(0xf1202573)))
+            .unwrap();
+        assert_eq!(cpu.x[A0], DEFAULT_MARCHID);
+        // csrw mvendorid, a0 doesn't decode, nor execute when built by hand
+        assert_eq!(decode(0xf1151073), RiscvInst::Illegal);
+        let csrw = RiscvInst::Csrrw {
+            rd: 0,
+            rs1: A0 as u8,
+            csr: Csr(MVENDORID as u16),
+        };
+        assert!(matches!(
+            cpu.execute(RiscvInstWrapper::Full(csrw)),
+            Err(Exception::IllegalInstruction(_))
+        ));
+        assert_eq!(cpu.csr.load(MVENDORID).data, DEFAULT_MVENDORID);
+    }
+
+    #[test]
+    fn test_csr_read_only_forms() {
+        let mut cpu = RV64Cpu::new();
+        cpu.csr.store(MCYCLE, 42);
+        // csrrsi a0, cycle, 0 is a plain read of a read-only CSR
+        let csrrsi = decode(0xc0006573);
+        assert!(matches!(csrrsi, RiscvInst::Csrrsi { imm: 0, .. }));
+        cpu.execute(RiscvInstWrapper::Full(csrrsi)).unwrap();
+        assert_eq!(cpu.x[A0], 42);
+
+        // csrr a0, sip must not write the read value back through the sip view of mip
+        cpu.csr.set(MIP, MASK_MTIP);
+        cpu.execute(RiscvInstWrapper::Full(decode(0x14402573)))
+            .unwrap();
+        assert_eq!(cpu.x[A0], 0);
+        assert_eq!(cpu.csr.load(MIP).data, MASK_MTIP);
+    }
+
+    #[test]
+    fn test_mip_software_bits() {
+        // csrs mip, t0
+        let csrs = RiscvInstWrapper::Full(decode(0x3442a073));
+        let mut cpu = RV64Cpu::new();
+        cpu.csr.store(MSTATUS, MASK_MIE);
+        cpu.csr.store(MIE, MASK_MTIP);
+        cpu.x[T0] = MASK_MTIP;
+        cpu.execute(csrs).unwrap();
+        assert_eq!(cpu.csr.load(MIP).data, 0);
+        assert!(cpu.check_pending_interrupt().is_none());
+
+        cpu.x[T0] = MASK_MSIP;
+        cpu.execute(csrs).unwrap();
+        assert_eq!(cpu.csr.load(MIP).data, MASK_MSIP);
+
+        // firmware raises supervisor timer interrupts by setting STIP itself
+        cpu.x[T0] = MASK_STIP;
+        cpu.execute(csrs).unwrap();
+        assert_eq!(cpu.csr.load(MIP).data, MASK_MSIP | MASK_STIP);
+        cpu.csr.store(MIE, MASK_STIP);
+        assert!(matches!(
+            cpu.check_pending_interrupt(),
+            Some(Interrupt::SupervisorTimerInterrupt)
+        ));
+    }
+
+    #[test]
+    fn test_csr_write_only_forms() {
+        // No CSR has read side effects yet, so this checks that skipping the read for rd = x0
+        // leaves the write intact and still reads for any other rd.
+        let mut cpu = RV64Cpu::new();
+        cpu.x[A0] = 0x1234;
+        // csrw mscratch, a0
+        cpu.execute(RiscvInstWrapper::Full(decode(0x34051073)))
+            .unwrap();
+        assert_eq!(cpu.csr.load(MSCRATCH).data, 0x1234);
+        // csrwi mscratch, 5
+        cpu.execute(RiscvInstWrapper::Full(decode(0x3402d073)))
+            .unwrap();
+        assert_eq!(cpu.csr.load(MSCRATCH).data, 5);
+        assert_eq!(cpu.x[0], 0);
+        // csrrw a1, mscratch, a0
+        cpu.execute(RiscvInstWrapper::Full(decode(0x340515f3)))
+            .unwrap();
+        assert_eq!(cpu.x[A1], 5);
+        assert_eq!(cpu.csr.load(MSCRATCH).data, 0x1234);
     }
 }
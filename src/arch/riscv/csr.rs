@@ -1,23 +1,36 @@
-use std::ops::{BitAnd, BitOr, Index, Not, Shl, Shr};
+use std::{
+    fmt,
+    ops::{BitAnd, BitOr, Index, Not, Shl, Shr},
+};
+
+use super::cpu::{MACHINE_MODE, SUPERVISOR_MODE};
 
-#[repr(transparent)]
 #[derive(Debug, Clone, Copy)]
 pub struct Csrs {
-    csrs: [Csr; 4096],
+    csrs: [CsrValue; 4096],
+    /// Clear the bits of `medeleg` and `mideleg` that cannot be delegated instead of only warning.
+    pub(crate) strict_delegation: bool,
 }
 
 impl Csrs {
     pub fn new() -> Csrs {
         Self {
-            csrs: [Csr::from(0); 4096],
+            csrs: [CsrValue::from(0); 4096],
+            strict_delegation: false,
         }
     }
 
-    pub fn load(&self, addr: usize) -> Csr {
+    pub fn load(&self, addr: usize) -> CsrValue {
         match addr {
             SIE => self.csrs[MIE] & self.csrs[MIDELEG],
             SIP => self.csrs[MIP] & self.csrs[MIDELEG],
             SSTATUS => self.csrs[MSTATUS] & MASK_SSTATUS,
+            // fflags and frm are fields of fcsr, which holds the floating-point state.
+            FFLAGS => self.csrs[FCSR] & MASK_FFLAGS,
+            FRM => (self.csrs[FCSR] & MASK_FRM) >> FRM_SHIFT,
+            FCSR => self.csrs[FCSR] & (MASK_FRM | MASK_FFLAGS),
+            // The unprivileged counters are read-only shadows of the machine counters.
+            CYCLE | INSTRET | HPMCOUNTER3..=HPMCOUNTER31 => self.csrs[addr - CYCLE + MCYCLE],
             _ => self.csrs[addr],
         }
     }
@@ -29,16 +42,51 @@ impl Csrs {
                     (self.csrs[MIE] & !self.csrs[MIDELEG]) | (self.csrs[MIDELEG] & value)
             }
             SIP => {
-                self.csrs[MIP] =
-                    (self.csrs[MIE] & !self.csrs[MIDELEG]) | (self.csrs[MIDELEG] & value)
+                let mask = self.csrs[MIDELEG].data & MASK_SSIP;
+                self.csrs[MIP] = ((self.csrs[MIP].data & !mask) | (value & mask)).into()
+            }
+            MIP => {
+                self.csrs[MIP] = ((self.csrs[MIP].data & !MASK_MIP_WRITABLE)
+                    | (value & MASK_MIP_WRITABLE))
+                    .into()
             }
             SSTATUS => {
                 self.csrs[MSTATUS] = (self.csrs[MSTATUS] & !MASK_SSTATUS) | (value & MASK_SSTATUS)
             }
+            FFLAGS => self.csrs[FCSR] = (self.csrs[FCSR] & !MASK_FFLAGS) | (value & MASK_FFLAGS),
+            FRM => {
+                self.csrs[FCSR] = (self.csrs[FCSR] & !MASK_FRM) | ((value << FRM_SHIFT) & MASK_FRM)
+            }
+            FCSR => self.csrs[FCSR] = (value & (MASK_FRM | MASK_FFLAGS)).into(),
+            MEDELEG | MIDELEG => self.csrs[addr] = self.check_delegation(addr, value).into(),
             _ => self.csrs[addr] = value.into(),
         }
     }
 
+    /// Warn about delegation bits for causes that always trap to M-mode, clearing them in strict
+    /// mode as the hardware would.
+    fn check_delegation(&self, addr: usize, value: u64) -> u64 {
+        let mask = if addr == MEDELEG {
+            MASK_MEDELEG
+        } else {
+            MASK_MIDELEG
+        };
+        let invalid = value & !mask;
+        if invalid == 0 {
+            return value;
+        }
+        log::warn!(
+            "{}: causes {:#x} cannot be delegated",
+            Csr(addr as u16),
+            invalid
+        );
+        if self.strict_delegation {
+            value & mask
+        } else {
+            value
+        }
+    }
+
     pub fn is_medelegated(&self, cause: u64) -> bool {
         (self.csrs[MEDELEG].data.wrapping_shr(cause as u32) & 1) == 1
     }
@@ -47,6 +95,19 @@ impl Csrs {
         (self.csrs[MIDELEG].data.wrapping_shr(cause as u32) & 1) == 1
     }
 
+    /// Whether the unprivileged counter at `addr` is readable from privilege level `prv`,
+    /// as controlled by `mcounteren` and `scounteren`.
+    pub fn counter_enabled(&self, addr: usize, prv: u8) -> bool {
+        let bit = 1 << (addr - CYCLE);
+        if prv < MACHINE_MODE && self.csrs[MCOUNTEREN].data & bit == 0 {
+            return false;
+        }
+        if prv < SUPERVISOR_MODE && self.csrs[SCOUNTEREN].data & bit == 0 {
+            return false;
+        }
+        true
+    }
+
     pub fn set(&mut self, addr: usize, bit: u64) {
         self.csrs[addr].set(bit);
     }
@@ -57,7 +118,7 @@ impl Csrs {
 }
 
 impl Index<u16> for Csrs {
-    type Output = Csr;
+    type Output = CsrValue;
 
     fn index(&self, index: u16) -> &Self::Output {
         &self.csrs[index as usize]
@@ -65,7 +126,7 @@ impl Index<u16> for Csrs {
 }
 
 impl Index<usize> for Csrs {
-    type Output = Csr;
+    type Output = CsrValue;
 
     fn index(&self, index: usize) -> &Self::Output {
         &self.csrs[index]
@@ -73,11 +134,11 @@ impl Index<usize> for Csrs {
 }
 
 #[derive(Debug, Clone, Copy, Default)]
-pub struct Csr {
+pub struct CsrValue {
     pub(crate) data: u64,
 }
 
-impl Csr {
+impl CsrValue {
     pub fn mpp(&self) -> u64 {
         (self.data & MASK_MPP) >> 11
     }
@@ -111,19 +172,19 @@ impl Csr {
     }
 }
 
-impl PartialEq for Csr {
+impl PartialEq for CsrValue {
     fn eq(&self, other: &Self) -> bool {
         self.data == other.data
     }
 }
 
-impl PartialEq<u64> for Csr {
+impl PartialEq<u64> for CsrValue {
     fn eq(&self, other: &u64) -> bool {
         self.data == *other
     }
 }
 
-impl BitAnd for Csr {
+impl BitAnd for CsrValue {
     type Output = Self;
 
     fn bitand(self, rhs: Self) -> Self::Output {
@@ -133,7 +194,7 @@ impl BitAnd for Csr {
     }
 }
 
-impl BitAnd<u64> for Csr {
+impl BitAnd<u64> for CsrValue {
     type Output = Self;
 
     fn bitand(self, rhs: u64) -> Self::Output {
@@ -143,7 +204,7 @@ impl BitAnd<u64> for Csr {
     }
 }
 
-impl BitOr for Csr {
+impl BitOr for CsrValue {
     type Output = Self;
 
     fn bitor(self, rhs: Self) -> Self::Output {
@@ -153,7 +214,7 @@ impl BitOr for Csr {
     }
 }
 
-impl BitOr<u64> for Csr {
+impl BitOr<u64> for CsrValue {
     type Output = Self;
 
     fn bitor(self, rhs: u64) -> Self::Output {
@@ -163,7 +224,7 @@ impl BitOr<u64> for Csr {
     }
 }
 
-impl Shl<u64> for Csr {
+impl Shl<u64> for CsrValue {
     type Output = Self;
 
     fn shl(self, rhs: u64) -> Self::Output {
@@ -173,17 +234,17 @@ impl Shl<u64> for Csr {
     }
 }
 
-impl Shr<u64> for Csr {
+impl Shr<u64> for CsrValue {
     type Output = Self;
 
     fn shr(self, rhs: u64) -> Self::Output {
         Self {
-            data: self.data << rhs,
+            data: self.data >> rhs,
         }
     }
 }
 
-impl Not for Csr {
+impl Not for CsrValue {
     type Output = Self;
 
     fn not(self) -> Self::Output {
@@ -191,25 +252,115 @@ impl Not for Csr {
     }
 }
 
-impl From<u64> for Csr {
+impl From<u64> for CsrValue {
     fn from(data: u64) -> Self {
         Self { data }
     }
 }
 
-impl From<Csr> for u64 {
-    fn from(val: Csr) -> Self {
+impl From<CsrValue> for u64 {
+    fn from(val: CsrValue) -> Self {
         val.data
     }
 }
 
-/// Get the minimal privilege level required to access the CSR
-pub fn csr_min_prv_level(addr: u16) -> u8 {
-    ((addr >> 8) & 0b11) as u8
+/// The 12-bit address of a CSR, as encoded in the CSR instructions.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Csr(pub u16);
+
+impl Csr {
+    pub const SATP: Csr = Csr(SATP as u16);
+    pub const CYCLE: Csr = Csr(CYCLE as u16);
+    pub const TIME: Csr = Csr(TIME as u16);
+    pub const INSTRET: Csr = Csr(INSTRET as u16);
+    pub const INSTRETH: Csr = Csr(INSTRETH as u16);
+
+    pub fn addr(self) -> usize {
+        self.0 as usize
+    }
+
+    /// Whether the CSR is part of the floating-point state, and so subject to `mstatus.FS`.
+    pub fn is_float(self) -> bool {
+        (FFLAGS..=FCSR).contains(&self.addr())
+    }
+
+    /// Get the minimal privilege level required to access the CSR
+    pub fn min_prv_level(self) -> u8 {
+        ((self.0 >> 8) & 0b11) as u8
+    }
+
+    /// Whether the CSR is read-only, as encoded in the top two address bits.
+    pub fn readonly(self) -> bool {
+        (self.0 >> 10) & 0b11 == 0b11
+    }
+}
+
+impl From<Csr> for usize {
+    fn from(csr: Csr) -> Self {
+        csr.addr()
+    }
 }
 
-pub fn csr_readonly(addr: u16) -> bool {
-    (addr >> 10) & 0b11 == 0b11
+impl fmt::Display for Csr {
+    /// Show the canonical name of known CSRs, or the address otherwise.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let addr = self.addr();
+        let name = match addr {
+            FFLAGS => "fflags",
+            FRM => "frm",
+            FCSR => "fcsr",
+            CYCLE => "cycle",
+            TIME => "time",
+            INSTRET => "instret",
+            CYCLEH => "cycleh",
+            TIMEH => "timeh",
+            INSTRETH => "instreth",
+            SSTATUS => "sstatus",
+            SIE => "sie",
+            STVEC => "stvec",
+            SCOUNTEREN => "scounteren",
+            SSCRATCH => "sscratch",
+            SEPC => "sepc",
+            SCAUSE => "scause",
+            STVAL => "stval",
+            SIP => "sip",
+            SISELECT => "siselect",
+            SIREG => "sireg",
+            STOPEI => "stopei",
+            STOPI => "stopi",
+            SATP => "satp",
+            MVENDORID => "mvendorid",
+            MARCHID => "marchid",
+            MIMPID => "mimpid",
+            MHARTID => "mhartid",
+            MSTATUS => "mstatus",
+            MISA => "misa",
+            MEDELEG => "medeleg",
+            MIDELEG => "mideleg",
+            MIE => "mie",
+            MTVEC => "mtvec",
+            MCOUNTEREN => "mcounteren",
+            MVIEN => "mvien",
+            MVIP => "mvip",
+            MSCRATCH => "mscratch",
+            MEPC => "mepc",
+            MCAUSE => "mcause",
+            MTVAL => "mtval",
+            MIP => "mip",
+            MISELECT => "miselect",
+            MIREG => "mireg",
+            MTOPEI => "mtopei",
+            MTOPI => "mtopi",
+            MCYCLE => "mcycle",
+            MINSTRET => "minstret",
+            MCOUNTINHIBIT => "mcountinhibit",
+            HPMCOUNTER3..=HPMCOUNTER31 => return write!(f, "hpmcounter{}", addr - CYCLE),
+            MHPMCOUNTER3..=MHPMCOUNTER31 => return write!(f, "mhpmcounter{}", addr - MCYCLE),
+            MHPMEVENT3..=MHPMEVENT31 => return write!(f, "mhpmevent{}", addr - MCOUNTINHIBIT),
+            _ => return write!(f, "{:#x}", addr),
+        };
+        write!(f, "{}", name)
+    }
 }
 
 pub const FFLAGS: usize = 0x001;
@@ -219,6 +370,8 @@ pub const FCSR: usize = 0x003;
 pub const CYCLE: usize = 0xC00;
 pub const TIME: usize = 0xC01;
 pub const INSTRET: usize = 0xC02;
+pub const HPMCOUNTER3: usize = 0xC03;
+pub const HPMCOUNTER31: usize = 0xC1F;
 
 // These CSRs are Rv32I only, and they are considered invalid in RV64I
 pub const CYCLEH: usize = 0xC80;
@@ -266,6 +419,16 @@ pub const MTOPI: usize = 0xFB0;
 pub const MCYCLE: usize = 0xB00;
 pub const MTIME: usize = 0xB01;
 pub const MINSTRET: usize = 0xB02;
+pub const MHPMCOUNTER3: usize = 0xB03;
+pub const MHPMCOUNTER31: usize = 0xB1F;
+pub const MCOUNTINHIBIT: usize = 0x320;
+pub const MHPMEVENT3: usize = 0x323;
+pub const MHPMEVENT31: usize = 0x33F;
+
+// mcounteren, scounteren and mcountinhibit field mask
+pub const MASK_CY: u64 = 1 << 0;
+pub const MASK_TM: u64 = 1 << 1;
+pub const MASK_IR: u64 = 1 << 2;
 
 // mstatus and sstatus field mask
 pub const MASK_SIE: u64 = 1 << 1;
@@ -289,6 +452,8 @@ pub const MASK_SXL: u64 = 0b11 << 34;
 pub const MASK_SBE: u64 = 1 << 36;
 pub const MASK_MBE: u64 = 1 << 37;
 pub const MASK_SD: u64 = 1 << 63;
+// mstatus.FS value of an enabled FPU whose state is still the reset one
+pub const FS_INITIAL: u64 = 1 << 13;
 pub const MASK_SSTATUS: u64 = MASK_SIE
     | MASK_SPIE
     | MASK_UBE
@@ -300,6 +465,11 @@ pub const MASK_SSTATUS: u64 = MASK_SIE
     | MASK_UXL
     | MASK_SD;
 
+// Causes that can be delegated to S-mode. Environment calls from M-mode and machine-level interrupts
+// are always taken in M-mode.
+pub const MASK_MEDELEG: u64 = 0xb3ff;
+pub const MASK_MIDELEG: u64 = (1 << 1) | (1 << 5) | (1 << 9);
+
 // MIP / SIP field mask
 pub const MASK_SSIP: u64 = 1 << 1;
 pub const MASK_MSIP: u64 = 1 << 3;
@@ -307,3 +477,229 @@ pub const MASK_STIP: u64 = 1 << 5;
 pub const MASK_MTIP: u64 = 1 << 7;
 pub const MASK_SEIP: u64 = 1 << 9;
 pub const MASK_MEIP: u64 = 1 << 11;
+/// Bits of mip M-mode software can write. MTIP and MEIP follow the CLINT and PLIC, while STIP and
+/// SEIP stay writable for firmware to pass timer and external interrupts on to S-mode.
+pub const MASK_MIP_WRITABLE: u64 = MASK_SSIP | MASK_MSIP | MASK_STIP | MASK_SEIP;
+
+// fflags
+pub const MASK_NX: u64 = 1 << 0;
+pub const MASK_UF: u64 = 1 << 1;
+pub const MASK_OF: u64 = 1 << 2;
+pub const MASK_DZ: u64 = 1 << 3;
+pub const MASK_NV: u64 = 1 << 4;
+
+// fcsr field mask
+pub const MASK_FFLAGS: u64 = 0x1f;
+pub const MASK_FRM: u64 = 0b111 << FRM_SHIFT;
+pub const FRM_SHIFT: u64 = 5;
+
+/// An `mstatus` value shown as its set flags and its multi-bit fields, e.g. `MIE MPP=S`.
+pub struct Mstatus(pub u64);
+
+impl fmt::Display for Mstatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        const FLAGS: [(u64, &str); 15] = [
+            (MASK_SIE, "SIE"),
+            (MASK_MIE, "MIE"),
+            (MASK_SPIE, "SPIE"),
+            (MASK_UBE, "UBE"),
+            (MASK_MPIE, "MPIE"),
+            (MASK_SPP, "SPP"),
+            (MASK_MPRV, "MPRV"),
+            (MASK_SUM, "SUM"),
+            (MASK_MXR, "MXR"),
+            (MASK_TVM, "TVM"),
+            (MASK_TW, "TW"),
+            (MASK_TSR, "TSR"),
+            (MASK_SBE, "SBE"),
+            (MASK_MBE, "MBE"),
+            (MASK_SD, "SD"),
+        ];
+        const STATES: [&str; 4] = ["off", "initial", "clean", "dirty"];
+        for (mask, name) in FLAGS {
+            if self.0 & mask != 0 {
+                write!(f, "{} ", name)?;
+            }
+        }
+        let mpp = ["U", "S", "?", "M"][((self.0 & MASK_MPP) >> 11) as usize];
+        write!(f, "MPP={}", mpp)?;
+        for (mask, shift, name) in [(MASK_FS, 13, "FS"), (MASK_VS, 9, "VS"), (MASK_XS, 15, "XS")] {
+            if self.0 & mask != 0 {
+                write!(
+                    f,
+                    " {}={}",
+                    name,
+                    STATES[((self.0 & mask) >> shift) as usize]
+                )?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// An `mcause` value shown as the kind of trap, its code and its name, e.g.
+/// `interrupt 7 (machine timer)`.
+pub struct Mcause(pub u64);
+
+impl fmt::Display for Mcause {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let code = self.0 & !(1 << 63);
+        let (kind, name) = if self.0 >> 63 == 1 {
+            let name = match code {
+                1 => "supervisor software",
+                3 => "machine software",
+                5 => "supervisor timer",
+                7 => "machine timer",
+                9 => "supervisor external",
+                11 => "machine external",
+                _ => "",
+            };
+            ("interrupt", name)
+        } else {
+            let name = match code {
+                0 => "instruction address misaligned",
+                1 => "instruction access fault",
+                2 => "illegal instruction",
+                3 => "breakpoint",
+                4 => "load address misaligned",
+                5 => "load access fault",
+                6 => "store/AMO address misaligned",
+                7 => "store/AMO access fault",
+                8 => "environment call from U-mode",
+                9 => "environment call from S-mode",
+                11 => "environment call from M-mode",
+                12 => "instruction page fault",
+                13 => "load page fault",
+                15 => "store/AMO page fault",
+                _ => "",
+            };
+            ("exception", name)
+        };
+        write!(f, "{} {}", kind, code)?;
+        if !name.is_empty() {
+            write!(f, " ({})", name)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        Csr, Csrs, Mcause, Mstatus, FCSR, FFLAGS, FRM, MASK_DZ, MASK_MEIP, MASK_MSIP, MASK_MTIP,
+        MASK_NV, MASK_SEIP, MASK_SSIP, MASK_STIP, MEDELEG, MIDELEG, MIP, MSTATUS, MVENDORID, SATP,
+        SIP, SSTATUS,
+    };
+    use crate::arch::riscv::cpu::{MACHINE_MODE, SUPERVISOR_MODE, USER_MODE};
+
+    #[test]
+    fn test_mip_write_mask() {
+        let mut csrs = Csrs::new();
+        let hardware = MASK_MTIP | MASK_MEIP;
+        let software = MASK_MSIP | MASK_SSIP | MASK_STIP | MASK_SEIP;
+        csrs.store(MIP, hardware | software);
+        assert_eq!(csrs.load(MIP).data, software);
+
+        // the pending bits the CLINT and PLIC drive survive software writes
+        csrs.set(MIP, hardware);
+        csrs.store(MIP, MASK_SEIP);
+        assert_eq!(csrs.load(MIP).data, hardware | MASK_SEIP);
+
+        // through sip, only a delegated SSIP is writable
+        csrs.store(SIP, MASK_SSIP);
+        assert_eq!(csrs.load(MIP).data, hardware | MASK_SEIP);
+        csrs.store(MIDELEG, MASK_SSIP | MASK_STIP | MASK_SEIP);
+        csrs.store(SIP, MASK_SSIP);
+        assert_eq!(csrs.load(MIP).data, hardware | MASK_SEIP | MASK_SSIP);
+        csrs.store(SIP, 0);
+        assert_eq!(csrs.load(MIP).data, hardware | MASK_SEIP);
+        assert_eq!(csrs.load(SIP).data, MASK_SEIP);
+    }
+
+    #[test]
+    fn test_readonly() {
+        assert!(Csr::CYCLE.readonly());
+        assert!(Csr::INSTRETH.readonly());
+        assert!(Csr(MVENDORID as u16).readonly());
+        assert!(!Csr(MSTATUS as u16).readonly());
+        assert!(!Csr(FFLAGS as u16).readonly());
+    }
+
+    #[test]
+    fn test_display() {
+        assert_eq!(Csr(MSTATUS as u16).to_string(), "mstatus");
+        assert_eq!(Csr::INSTRETH.to_string(), "instreth");
+        assert_eq!(Csr(0xC04).to_string(), "hpmcounter4");
+        assert_eq!(Csr(0x7c0).to_string(), "0x7c0");
+    }
+
+    #[test]
+    fn test_min_prv_level() {
+        assert_eq!(Csr(FFLAGS as u16).min_prv_level(), USER_MODE);
+        assert_eq!(Csr::CYCLE.min_prv_level(), USER_MODE);
+        assert_eq!(Csr(SSTATUS as u16).min_prv_level(), SUPERVISOR_MODE);
+        assert_eq!(Csr(SATP as u16).min_prv_level(), SUPERVISOR_MODE);
+        assert_eq!(Csr(MSTATUS as u16).min_prv_level(), MACHINE_MODE);
+    }
+
+    #[test]
+    fn test_fcsr_fields() {
+        let mut csrs = Csrs::new();
+        // frm = RUP, fflags = NV | DZ
+        csrs.store(FCSR, (0b011 << 5) | MASK_NV | MASK_DZ);
+        assert_eq!(csrs.load(FRM).data, 0b011);
+        assert_eq!(csrs.load(FFLAGS).data, MASK_NV | MASK_DZ);
+
+        // each field only changes its part of fcsr
+        csrs.store(FFLAGS, MASK_DZ);
+        assert_eq!(csrs.load(FCSR).data, (0b011 << 5) | MASK_DZ);
+        csrs.store(FRM, 0b001);
+        assert_eq!(csrs.load(FCSR).data, (0b001 << 5) | MASK_DZ);
+
+        // and the reserved bits read as zero
+        csrs.store(FCSR, u64::MAX);
+        assert_eq!(csrs.load(FCSR).data, 0xff);
+        csrs.store(FFLAGS, u64::MAX);
+        csrs.store(FRM, u64::MAX);
+        assert_eq!(csrs.load(FFLAGS).data, 0x1f);
+        assert_eq!(csrs.load(FRM).data, 0b111);
+    }
+
+    #[test]
+    fn test_delegation() {
+        let mut csrs = Csrs::new();
+        // ecall from U, S and M, and the machine timer interrupt alongside the supervisor one
+        csrs.store(MEDELEG, (1 << 8) | (1 << 9) | (1 << 11));
+        csrs.store(MIDELEG, (1 << 5) | (1 << 7));
+        // only warned about
+        assert!(csrs.is_medelegated(11));
+        assert!(csrs.is_midelegated(7));
+
+        csrs.strict_delegation = true;
+        csrs.store(MEDELEG, (1 << 8) | (1 << 9) | (1 << 11));
+        csrs.store(MIDELEG, (1 << 5) | (1 << 7));
+        assert_eq!(csrs.load(MEDELEG).data, (1 << 8) | (1 << 9));
+        assert_eq!(csrs.load(MIDELEG).data, 1 << 5);
+        assert!(!csrs.is_medelegated(11));
+        assert!(!csrs.is_midelegated(7));
+    }
+
+    #[test]
+    fn test_format_fields() {
+        use super::{MASK_FS, MASK_MIE, MASK_MPIE};
+
+        assert_eq!(Mstatus(1 << 11 | MASK_MIE).to_string(), "MIE MPP=S");
+        assert_eq!(Mstatus(0).to_string(), "MPP=U");
+        assert_eq!(
+            Mstatus(3 << 11 | MASK_MPIE | MASK_FS | 1 << 63).to_string(),
+            "MPIE SD MPP=M FS=dirty"
+        );
+
+        assert_eq!(
+            Mcause(7 | 1 << 63).to_string(),
+            "interrupt 7 (machine timer)"
+        );
+        assert_eq!(Mcause(2).to_string(), "exception 2 (illegal instruction)");
+        assert_eq!(Mcause(24).to_string(), "exception 24");
+    }
+}
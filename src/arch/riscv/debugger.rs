@@ -0,0 +1,168 @@
+use std::io::{self, BufRead, Write};
+
+use crate::{cpu::Cpu, util::parse_int};
+
+use super::{
+    cpu::{HaltReason, RV64Cpu},
+    csr::{Mcause, Mstatus, MCAUSE, MSTATUS},
+    reg::x_register_name,
+};
+
+const PROMPT: &str = "(remu) ";
+
+const HELP: &str = "\
+s [n]     step n instructions (default 1)
+c         continue until a breakpoint or halt
+r         dump registers
+x <addr>  examine the double word at a virtual address
+b <addr>  set a breakpoint
+d [addr]  delete a breakpoint, or all of them
+i         list the breakpoints
+q         quit";
+
+/// A minimal line-oriented debugger reading commands from `input` and reporting to `output`.
+/// Return the reason the guest last halted for, if it did.
+pub fn repl(
+    cpu: &mut RV64Cpu,
+    input: impl BufRead,
+    mut output: impl Write,
+) -> io::Result<Option<HaltReason>> {
+    let mut halt = None;
+    write!(output, "{}", PROMPT)?;
+    output.flush()?;
+    for line in input.lines() {
+        let line = line?;
+        let mut args = line.split_whitespace();
+        match (args.next(), args.next()) {
+            (None, _) => {}
+            (Some("s"), count) => {
+                let count = match count.map(parse_int).transpose() {
+                    Ok(count) => count.unwrap_or(1),
+                    Err(e) => {
+                        writeln!(output, "{}", e)?;
+                        0
+                    }
+                };
+                for _ in 0..count {
+                    if let Some(reason) = cpu.step() {
+                        print_halt(&mut output, reason)?;
+                        halt = Some(reason);
+                        break;
+                    }
+                }
+                writeln!(output, "pc = {:#018x}", cpu.pc())?;
+            }
+            (Some("c"), _) => {
+                let reason = cpu.run();
+                print_halt(&mut output, reason)?;
+                halt = Some(reason);
+            }
+            (Some("r"), _) => dump_registers(cpu, &mut output)?,
+            (Some(cmd @ ("x" | "b" | "d")), Some(addr)) => match parse_int(addr) {
+                Ok(addr) => match cmd {
+                    "x" => match cpu.read_mem(addr, 8, true) {
+                        Ok(bytes) => {
+                            let value = u64::from_le_bytes(bytes.try_into().unwrap());
+                            writeln!(output, "{:#018x}: {:#018x}", addr, value)?
+                        }
+                        Err(e) => writeln!(output, "cannot access {:#x}: {:?}", addr, e)?,
+                    },
+                    "b" => {
+                        cpu.add_breakpoint(addr);
+                        writeln!(output, "breakpoint at {:#x}", addr)?;
+                    }
+                    _ => {
+                        cpu.remove_breakpoint(addr);
+                        writeln!(output, "deleted breakpoint at {:#x}", addr)?;
+                    }
+                },
+                Err(e) => writeln!(output, "{}", e)?,
+            },
+            (Some("d"), None) => {
+                cpu.clear_breakpoints();
+                writeln!(output, "deleted all breakpoints")?;
+            }
+            (Some("i"), _) => {
+                for addr in cpu.breakpoints() {
+                    writeln!(output, "breakpoint at {:#x}", addr)?;
+                }
+            }
+            (Some("q"), _) => return Ok(halt),
+            (Some(_), _) => writeln!(output, "{}", HELP)?,
+        }
+        write!(output, "{}", PROMPT)?;
+        output.flush()?;
+    }
+    Ok(halt)
+}
+
+fn print_halt(output: &mut impl Write, reason: HaltReason) -> io::Result<()> {
+    match reason {
+        HaltReason::Fatal(e) => writeln!(output, "halted: {:?}", e),
+        HaltReason::Breakpoint(addr) => writeln!(output, "breakpoint hit at {:#x}", addr),
+        HaltReason::Exit(code) => writeln!(output, "exited with code {}", code),
+        HaltReason::TrapStorm => writeln!(output, "halted: trap storm"),
+    }
+}
+
+/// Write the pc and the integer registers to `output`, one per line.
+pub fn dump_registers(cpu: &RV64Cpu, output: &mut impl Write) -> io::Result<()> {
+    writeln!(output, "pc   {:#018x}", cpu.pc())?;
+    for reg in 0..32 {
+        writeln!(
+            output,
+            "{:<4} {:#018x}",
+            x_register_name(reg as u8),
+            cpu.x(reg)
+        )?;
+    }
+    writeln!(output, "mstatus {}", Mstatus(cpu.csr(MSTATUS)))?;
+    writeln!(output, "mcause  {}", Mcause(cpu.csr(MCAUSE)))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        arch::riscv::cpu::{HaltReason, RV64Cpu},
+        cpu::Cpu,
+    };
+
+    use super::repl;
+
+    #[test]
+    fn test_repl() {
+        let data: Vec<u32> = vec![
+            0x00500513, // addi a0, zero, 5
+            0x00350593, // addi a1, a0, 3
+            0x00b50633, // add a2, a0, a1
+            0x0000001f,
+        ];
+        let data: Vec<u8> = data.iter().flat_map(|x| x.to_le_bytes()).collect();
+        let mut cpu = RV64Cpu::new();
+        cpu.init();
+        cpu.pc = 0x8000_0000;
+        Cpu::load(&mut cpu, data);
+
+        let script =
+            "s\ns\nr\nx 0x80000000\nb 0x80000004\nb 0x8000000c\nd 0x80000004\ni\nc\nd\ni\nq\n";
+        let mut output = Vec::new();
+        let halt = repl(&mut cpu, script.as_bytes(), &mut output).unwrap();
+        assert_eq!(halt, Some(HaltReason::Breakpoint(0x8000_000c)));
+        let output = String::from_utf8(output).unwrap();
+
+        assert!(output.contains("pc   0x0000000080000008\n"));
+        assert!(output.contains("a0   0x0000000000000005\n"));
+        assert!(output.contains("a1   0x0000000000000008\n"));
+        assert!(output.contains("a2   0x0000000000000000\n"));
+        assert!(output.contains("mstatus MPP=U\n"));
+        assert!(output.contains("0x0000000080000000: 0x0035059300500513\n"));
+        assert!(output.contains("breakpoint hit at 0x8000000c\n"));
+        // after being set, only the remaining breakpoint is listed, and nothing once all are deleted
+        assert_eq!(output.matches("breakpoint at 0x8000000c\n").count(), 2);
+        assert_eq!(output.matches("breakpoint at 0x80000004\n").count(), 2);
+        assert!(output.contains("deleted breakpoint at 0x80000004\n"));
+        assert!(output.contains("deleted all breakpoints\n(remu) (remu) "));
+        assert_eq!(cpu.x(12), 13);
+    }
+}
@@ -1,6 +1,11 @@
-use crate::arch::riscv::csr::csr_readonly;
+use std::rc::Rc;
 
-use super::instruction::{Ordering, RiscvInst};
+use crate::arch::riscv::csr::Csr;
+
+use super::{
+    instruction::{Ordering, RiscvInst, RiscvInstWrapper},
+    isa::Isa,
+};
 
 fn rd(bits: u32) -> u8 {
     ((bits >> 7) & 0b11111) as u8
@@ -30,6 +35,11 @@ fn csr(bits: u32) -> u16 {
     (bits >> 20) as u16
 }
 
+/// The shift amount of an immediate shift, in the low `width` bits of the immediate.
+fn shamt(bits: u32, width: u32) -> i32 {
+    ((bits >> 20) & ((1 << width) - 1)) as i32
+}
+
 fn i_imm(bits: u32) -> i32 {
     (bits as i32) >> 20
 }
@@ -52,7 +62,7 @@ fn u_imm(bits: u32) -> i32 {
 
 fn j_imm(instr: u32) -> i32 {
     ((instr & 0b10000000_00000000_00000000_00000000) as i32) >> 11
-        | ((instr & 0b00000000_00001111_11110000_00000000) as i32) >> 0
+        | ((instr & 0b00000000_00001111_11110000_00000000) as i32)
         | ((instr & 0b00000000_00010000_00000000_00000000) as i32) >> 9
         | ((instr & 0b01111111_11100000_00000000_00000000) as i32) >> 20
 }
@@ -518,7 +528,8 @@ pub fn decode_compressed(bits: u16) -> RiscvInst {
                 _ => unreachable!(),
             }
         }
-        _ => unreachable!(),
+        // A full-size instruction, which has no compressed form
+        _ => RiscvInst::Illegal,
     }
 }
 
@@ -533,8 +544,10 @@ pub fn decode(bits: u32) -> RiscvInst {
         }};
     }
 
-    // We shouldn't see compressed ops here
-    assert!(bits & 3 == 3);
+    // Compressed ops aren't valid here
+    if bits & 3 != 3 {
+        return RiscvInst::Illegal;
+    }
 
     // Longer ops, treat them as illegal ops
     if bits & 0x1f == 0x1f {
@@ -577,29 +590,19 @@ pub fn decode(bits: u32) -> RiscvInst {
             let imm = i_imm(bits);
             match function {
                 0b000 => RiscvInst::Addi { rd, rs1, imm },
-                0b001 => {
-                    if imm >= 64 {
-                        RiscvInst::Illegal
-                    } else {
-                        RiscvInst::Slli { rd, rs1, imm }
-                    }
-                }
+                // RV64 shifts take a 6-bit shamt, leaving the upper 6 bits to tell them apart.
+                0b001 => match (bits >> 26, shamt(bits, 6)) {
+                    (0b000000, imm) => RiscvInst::Slli { rd, rs1, imm },
+                    _ => RiscvInst::Illegal,
+                },
                 0b010 => RiscvInst::Slti { rd, rs1, imm },
                 0b011 => RiscvInst::Sltiu { rd, rs1, imm },
                 0b100 => RiscvInst::Xori { rd, rs1, imm },
-                0b101 => {
-                    if imm & !0x400 >= 64 {
-                        RiscvInst::Illegal
-                    } else if (imm & 0x400) != 0 {
-                        RiscvInst::Srai {
-                            rd,
-                            rs1,
-                            imm: imm & !0x400,
-                        }
-                    } else {
-                        RiscvInst::Srli { rd, rs1, imm }
-                    }
-                }
+                0b101 => match (bits >> 26, shamt(bits, 6)) {
+                    (0b000000, imm) => RiscvInst::Srli { rd, rs1, imm },
+                    (0b010000, imm) => RiscvInst::Srai { rd, rs1, imm },
+                    _ => RiscvInst::Illegal,
+                },
                 0b110 => RiscvInst::Ori { rd, rs1, imm },
                 0b111 => RiscvInst::Andi { rd, rs1, imm },
                 // full case
@@ -611,10 +614,21 @@ pub fn decode(bits: u32) -> RiscvInst {
         0b0001111 => {
             match function {
                 0b000 => {
+                    // PAUSE is encoded as a FENCE with pred = W, succ = 0, fm = 0, rs1 = x0 and rd = x0.
+                    if bits == 0x0100000f {
+                        return RiscvInst::Pause;
+                    }
                     // TODO Multiple types of fence
                     RiscvInst::Fence
                 }
                 0b001 => RiscvInst::FenceI,
+                0b010 if rd == 0 => match i_imm(bits) {
+                    0b000 => RiscvInst::CboInval { rs1 },
+                    0b001 => RiscvInst::CboClean { rs1 },
+                    0b010 => RiscvInst::CboFlush { rs1 },
+                    0b100 => RiscvInst::CboZero { rs1 },
+                    _ => RiscvInst::Illegal,
+                },
                 _ => RiscvInst::Illegal,
             }
         }
@@ -624,26 +638,16 @@ pub fn decode(bits: u32) -> RiscvInst {
             let imm = i_imm(bits);
             match function {
                 0b000 => RiscvInst::Addiw { rd, rs1, imm },
-                0b001 => {
-                    if imm >= 32 {
-                        RiscvInst::Illegal
-                    } else {
-                        RiscvInst::Slliw { rd, rs1, imm }
-                    }
-                }
-                0b101 => {
-                    if imm & !0x400 >= 32 {
-                        RiscvInst::Illegal
-                    } else if (imm & 0x400) != 0 {
-                        RiscvInst::Sraiw {
-                            rd,
-                            rs1,
-                            imm: imm & !0x400,
-                        }
-                    } else {
-                        RiscvInst::Srliw { rd, rs1, imm }
-                    }
-                }
+                // Word shifts take a 5-bit shamt, as on RV32, under a 7-bit funct7.
+                0b001 => match (funct7(bits), shamt(bits, 5)) {
+                    (0b0000000, imm) => RiscvInst::Slliw { rd, rs1, imm },
+                    _ => RiscvInst::Illegal,
+                },
+                0b101 => match (funct7(bits), shamt(bits, 5)) {
+                    (0b0000000, imm) => RiscvInst::Srliw { rd, rs1, imm },
+                    (0b0100000, imm) => RiscvInst::Sraiw { rd, rs1, imm },
+                    _ => RiscvInst::Illegal,
+                },
                 _ => RiscvInst::Illegal,
             }
         }
@@ -681,58 +685,38 @@ pub fn decode(bits: u32) -> RiscvInst {
         /* Base Opcode AMO */
         0b0101111 => {
             /* A-Extension */
-            let func = funct7(bits) >> 2;
-            let aqrl = match funct7(bits) & 3 {
-                0 => Ordering::Relaxed,
-                1 => Ordering::Release,
-                2 => Ordering::Acquire,
-                3 => Ordering::SeqCst,
-                _ => unreachable!(),
+            // aq is bit 26 and rl bit 25, just above rs2.
+            let aqrl = match ((bits >> 26) & 1, (bits >> 25) & 1) {
+                (0, 0) => Ordering::Relaxed,
+                (0, _) => Ordering::Release,
+                (_, 0) => Ordering::Acquire,
+                _ => Ordering::SeqCst,
             };
-            if function == 0b010 {
-                match func {
-                    0b00010 => {
-                        if rs2 != 0 {
-                            RiscvInst::Illegal
-                        } else {
-                            RiscvInst::LrW { rd, rs1, aqrl }
-                        }
-                    }
-                    0b00011 => RiscvInst::ScW { rd, rs1, rs2, aqrl },
-                    0b00001 => RiscvInst::AmoswapW { rd, rs1, rs2, aqrl },
-                    0b00000 => RiscvInst::AmoaddW { rd, rs1, rs2, aqrl },
-                    0b00100 => RiscvInst::AmoxorW { rd, rs1, rs2, aqrl },
-                    0b01100 => RiscvInst::AmoandW { rd, rs1, rs2, aqrl },
-                    0b01000 => RiscvInst::AmoorW { rd, rs1, rs2, aqrl },
-                    0b10000 => RiscvInst::AmominW { rd, rs1, rs2, aqrl },
-                    0b10100 => RiscvInst::AmomaxW { rd, rs1, rs2, aqrl },
-                    0b11000 => RiscvInst::AmominuW { rd, rs1, rs2, aqrl },
-                    0b11100 => RiscvInst::AmomaxuW { rd, rs1, rs2, aqrl },
-                    _ => RiscvInst::Illegal,
-                }
-            } else if function == 0b011 {
-                match func {
-                    0b00010 => {
-                        if rs2 != 0 {
-                            RiscvInst::Illegal
-                        } else {
-                            RiscvInst::LrD { rd, rs1, aqrl }
-                        }
-                    }
-                    0b00011 => RiscvInst::ScD { rd, rs1, rs2, aqrl },
-                    0b00001 => RiscvInst::AmoswapD { rd, rs1, rs2, aqrl },
-                    0b00000 => RiscvInst::AmoaddD { rd, rs1, rs2, aqrl },
-                    0b00100 => RiscvInst::AmoxorD { rd, rs1, rs2, aqrl },
-                    0b01100 => RiscvInst::AmoandD { rd, rs1, rs2, aqrl },
-                    0b01000 => RiscvInst::AmoorD { rd, rs1, rs2, aqrl },
-                    0b10000 => RiscvInst::AmominD { rd, rs1, rs2, aqrl },
-                    0b10100 => RiscvInst::AmomaxD { rd, rs1, rs2, aqrl },
-                    0b11000 => RiscvInst::AmominuD { rd, rs1, rs2, aqrl },
-                    0b11100 => RiscvInst::AmomaxuD { rd, rs1, rs2, aqrl },
-                    _ => RiscvInst::Illegal,
-                }
-            } else {
-                RiscvInst::Illegal
+            // Only the word and double word widths exist on RV64.
+            match (function, funct7(bits) >> 2) {
+                (0b010, 0b00010) if rs2 == 0 => RiscvInst::LrW { rd, rs1, aqrl },
+                (0b010, 0b00011) => RiscvInst::ScW { rd, rs1, rs2, aqrl },
+                (0b010, 0b00001) => RiscvInst::AmoswapW { rd, rs1, rs2, aqrl },
+                (0b010, 0b00000) => RiscvInst::AmoaddW { rd, rs1, rs2, aqrl },
+                (0b010, 0b00100) => RiscvInst::AmoxorW { rd, rs1, rs2, aqrl },
+                (0b010, 0b01100) => RiscvInst::AmoandW { rd, rs1, rs2, aqrl },
+                (0b010, 0b01000) => RiscvInst::AmoorW { rd, rs1, rs2, aqrl },
+                (0b010, 0b10000) => RiscvInst::AmominW { rd, rs1, rs2, aqrl },
+                (0b010, 0b10100) => RiscvInst::AmomaxW { rd, rs1, rs2, aqrl },
+                (0b010, 0b11000) => RiscvInst::AmominuW { rd, rs1, rs2, aqrl },
+                (0b010, 0b11100) => RiscvInst::AmomaxuW { rd, rs1, rs2, aqrl },
+                (0b011, 0b00010) if rs2 == 0 => RiscvInst::LrD { rd, rs1, aqrl },
+                (0b011, 0b00011) => RiscvInst::ScD { rd, rs1, rs2, aqrl },
+                (0b011, 0b00001) => RiscvInst::AmoswapD { rd, rs1, rs2, aqrl },
+                (0b011, 0b00000) => RiscvInst::AmoaddD { rd, rs1, rs2, aqrl },
+                (0b011, 0b00100) => RiscvInst::AmoxorD { rd, rs1, rs2, aqrl },
+                (0b011, 0b01100) => RiscvInst::AmoandD { rd, rs1, rs2, aqrl },
+                (0b011, 0b01000) => RiscvInst::AmoorD { rd, rs1, rs2, aqrl },
+                (0b011, 0b10000) => RiscvInst::AmominD { rd, rs1, rs2, aqrl },
+                (0b011, 0b10100) => RiscvInst::AmomaxD { rd, rs1, rs2, aqrl },
+                (0b011, 0b11000) => RiscvInst::AmominuD { rd, rs1, rs2, aqrl },
+                (0b011, 0b11100) => RiscvInst::AmomaxuD { rd, rs1, rs2, aqrl },
+                _ => RiscvInst::Illegal,
             }
         }
 
@@ -1215,25 +1199,26 @@ pub fn decode(bits: u32) -> RiscvInst {
         /* SYSTEM */
         0b1110011 => {
             match function {
-                0b000 => match bits {
-                    0x73 => RiscvInst::Ecall,
-                    0x100073 => RiscvInst::Ebreak,
-                    0x30200073 => RiscvInst::Mret,
-                    0x10200073 => RiscvInst::Sret,
-                    0x10500073 => RiscvInst::Wfi,
-                    bits if rd == 0 && funct7(bits) == 0b0001001 => {
-                        RiscvInst::SfenceVma { rs1, rs2 }
-                    }
+                // The privileged instructions are told apart by funct7 and rs2, and none of them
+                // has a destination.
+                0b000 if rd != 0 => RiscvInst::Illegal,
+                0b000 => match (funct7(bits), rs2, rs1) {
+                    (0b0000000, 0b00000, 0) => RiscvInst::Ecall,
+                    (0b0000000, 0b00001, 0) => RiscvInst::Ebreak,
+                    (0b0011000, 0b00010, 0) => RiscvInst::Mret,
+                    (0b0001000, 0b00010, 0) => RiscvInst::Sret,
+                    (0b0001000, 0b00101, 0) => RiscvInst::Wfi,
+                    (0b0001001, rs2, rs1) => RiscvInst::SfenceVma { rs1, rs2 },
                     _ => RiscvInst::Illegal,
                 },
                 0b100 => RiscvInst::Illegal,
                 _ => {
                     // Otherwise this is CSR instruction
-                    let csr = csr(bits);
+                    let csr = Csr(csr(bits));
                     // For CSRRS, CSRRC, CSRRSI, CSRRCI, rs1 = 0 means readonly.
                     // If the CSR is readonly while we try to write it, it is an exception.
                     let readonly = function & 0b010 != 0 && rs1 == 0;
-                    if csr_readonly(csr) && !readonly {
+                    if csr.readonly() && !readonly {
                         return RiscvInst::Illegal;
                     }
                     match function {
@@ -1251,3 +1236,734 @@ pub fn decode(bits: u32) -> RiscvInst {
         _ => RiscvInst::Illegal,
     }
 }
+
+/// Decode `bits`, treating instructions from extensions that `isa` lacks as illegal.
+pub fn decode_for(bits: u32, isa: &Isa) -> RiscvInst {
+    let inst = decode(bits);
+    if isa.supports(&inst) {
+        inst
+    } else {
+        RiscvInst::Illegal
+    }
+}
+
+/// Decode the compressed `bits`, which are all illegal unless `isa` has C.
+pub fn decode_compressed_for(bits: u16, isa: &Isa) -> RiscvInst {
+    let inst = decode_compressed(bits);
+    if isa.has('c') && isa.supports(&inst) {
+        inst
+    } else {
+        RiscvInst::Illegal
+    }
+}
+
+/// Decode the instructions in `bytes` as if they were placed at `base_pc`, returning the address,
+/// the instruction and its raw bits for each of them. A trailing partial instruction is ignored.
+pub fn disassemble(bytes: &[u8], base_pc: u64) -> Vec<(u64, RiscvInst, u32)> {
+    let mut insts = Vec::new();
+    let mut offset = 0;
+    while let Some(half) = bytes.get(offset..offset + 2) {
+        let pc = base_pc.wrapping_add(offset as u64);
+        let low = u16::from_le_bytes([half[0], half[1]]);
+        if low & 0b11 != 0b11 {
+            insts.push((pc, decode_compressed(low), low as u32));
+            offset += 2;
+            continue;
+        }
+        let Some(word) = bytes.get(offset..offset + 4) else {
+            break;
+        };
+        let bits = u32::from_le_bytes([word[0], word[1], word[2], word[3]]);
+        insts.push((pc, decode(bits), bits));
+        offset += 4;
+    }
+    insts
+}
+
+/// Number of slots in the decode cache.
+const DECODE_CACHE_SLOTS: usize = 1024;
+
+/// The instructions of a basic block, decoded along with their raw bits.
+pub(crate) type Block = Rc<[(u32, RiscvInstWrapper)]>;
+
+/// A direct-mapped cache of decoded basic blocks, indexed by the physical address of their first
+/// instruction. It is not kept coherent with stores, so like an instruction cache it has to be
+/// flushed by `fence.i` before freshly written code runs.
+#[derive(Debug, Clone)]
+pub(crate) struct DecodeCache {
+    slots: Vec<Option<(u64, Block)>>,
+}
+
+impl Default for DecodeCache {
+    fn default() -> Self {
+        Self {
+            slots: vec![None; DECODE_CACHE_SLOTS],
+        }
+    }
+}
+
+impl DecodeCache {
+    fn slot(addr: u64) -> usize {
+        (addr / 2) as usize % DECODE_CACHE_SLOTS
+    }
+
+    /// Get the block starting at the physical address `addr`.
+    pub fn get(&self, addr: u64) -> Option<Block> {
+        match &self.slots[Self::slot(addr)] {
+            Some((tag, block)) if *tag == addr => Some(block.clone()),
+            _ => None,
+        }
+    }
+
+    pub fn insert(&mut self, addr: u64, block: Block) {
+        self.slots[Self::slot(addr)] = Some((addr, block));
+    }
+
+    pub fn flush(&mut self) {
+        self.slots.fill(None);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        collections::{BTreeSet, HashMap},
+        mem::discriminant,
+    };
+
+    use proptest::prelude::*;
+
+    use crate::arch::riscv::{
+        instruction::{colorize, Ordering, RiscvInst, RiscvInstWrapper},
+        isa::Isa,
+    };
+
+    use super::{
+        cb_imm, ci_addi16sp_imm, ci_imm, ci_ldsp_imm, ci_lwsp_imm, ciw_imm, cj_imm, cl_ld_imm,
+        cl_lw_imm, css_sdsp_imm, css_swsp_imm, decode, decode_compressed, decode_compressed_for,
+        decode_for, disassemble,
+    };
+
+    /// Feed every compressed encoding and every combination of the dispatch fields of full-size
+    /// encodings to the decoders, which must never panic.
+    #[test]
+    fn test_decode_any() {
+        for bits in 0..=u16::MAX {
+            let inst = decode_compressed(bits);
+            if bits & 0b11 == 0b11 || bits == 0 {
+                assert_eq!(inst, RiscvInst::Illegal, "{:#06x}", bits);
+            }
+        }
+
+        // every combination of opcode, funct3, funct7 and rs2, which covers the fields the
+        // decoder dispatches on
+        for fields in 0..1u32 << 20 {
+            let opcode = fields & 0x7f;
+            let funct3 = (fields >> 7) & 0b111;
+            let rs2 = (fields >> 10) & 0x1f;
+            let funct7 = fields >> 15;
+            let bits =
+                funct7 << 25 | rs2 << 20 | 0b01011 << 15 | funct3 << 12 | 0b00101 << 7 | opcode;
+            let inst = decode(bits);
+            if opcode & 0b11 != 0b11 {
+                assert_eq!(inst, RiscvInst::Illegal, "{:#010x}", bits);
+            }
+        }
+
+        // reserved encodings: all zeros, all ones and the 48-bit and longer prefixes
+        assert_eq!(decode(0), RiscvInst::Illegal);
+        assert_eq!(decode(u32::MAX), RiscvInst::Illegal);
+        assert_eq!(decode(0x0000_001f), RiscvInst::Illegal);
+        assert_eq!(decode(0x0000_003f), RiscvInst::Illegal);
+    }
+
+    proptest! {
+        /// Any word decodes without panicking, the same way with every extension enabled, and is
+        /// illegal if its low bits mark a compressed instruction.
+        #[test]
+        fn test_decode_arbitrary_word(bits: u32) {
+            let inst = decode(bits);
+            prop_assert_eq!(decode_for(bits, &Isa::default()), inst);
+            if bits & 0b11 != 0b11 {
+                prop_assert_eq!(inst, RiscvInst::Illegal);
+            }
+        }
+
+        /// Any half word decodes without panicking, and is illegal if it is all zeros or its low
+        /// bits mark a full-size instruction.
+        #[test]
+        fn test_decode_arbitrary_half_word(bits: u16) {
+            let inst = decode_compressed(bits);
+            prop_assert_eq!(decode_compressed_for(bits, &Isa::default()), inst);
+            if bits & 0b11 == 0b11 || bits == 0 {
+                prop_assert_eq!(inst, RiscvInst::Illegal);
+            }
+        }
+    }
+
+    #[test]
+    fn test_decode_for() {
+        let rv64i = Isa::parse("rv64i").unwrap();
+        let mul = 0x02b50533; // mul a0, a0, a1
+        assert!(matches!(decode(mul), RiscvInst::Mul { .. }));
+        assert_eq!(decode_for(mul, &rv64i), RiscvInst::Illegal);
+        assert_eq!(decode_for(mul, &Isa::default()), decode(mul));
+        // add a0, a0, a1
+        assert_eq!(decode_for(0x00b50533, &rv64i), decode(0x00b50533));
+
+        // c.addi a0, 1
+        assert_eq!(decode_compressed_for(0x0505, &rv64i), RiscvInst::Illegal);
+        let rv64ic = Isa::parse("rv64ic").unwrap();
+        assert_eq!(
+            decode_compressed_for(0x0505, &rv64ic),
+            decode_compressed(0x0505)
+        );
+        // c.fldsp fa0, 0(sp) needs D as well
+        assert_eq!(decode_compressed_for(0x2502, &rv64ic), RiscvInst::Illegal);
+    }
+
+    #[test]
+    fn test_shift_amount() {
+        let (rd, rs1) = (10, 10);
+        // the full 6-bit shamt of RV64
+        assert_eq!(decode(0x03f51513), RiscvInst::Slli { rd, rs1, imm: 63 });
+        assert_eq!(decode(0x03f55513), RiscvInst::Srli { rd, rs1, imm: 63 });
+        assert_eq!(decode(0x43f55513), RiscvInst::Srai { rd, rs1, imm: 63 });
+        // shamt 64 spills into funct6, as does anything above bit 30
+        assert_eq!(decode(0x04051513), RiscvInst::Illegal);
+        assert_eq!(decode(0x04055513), RiscvInst::Illegal);
+        assert_eq!(decode(0x80051513), RiscvInst::Illegal);
+        assert_eq!(decode(0x80055513), RiscvInst::Illegal);
+        assert_eq!(decode(0xc0055513), RiscvInst::Illegal);
+
+        // the word shifts only take the 5-bit shamt of RV32
+        assert_eq!(decode(0x01f5151b), RiscvInst::Slliw { rd, rs1, imm: 31 });
+        assert_eq!(decode(0x41f5551b), RiscvInst::Sraiw { rd, rs1, imm: 31 });
+        assert_eq!(decode(0x03f5151b), RiscvInst::Illegal);
+        assert_eq!(decode(0x0205551b), RiscvInst::Illegal);
+        assert_eq!(decode(0x4205551b), RiscvInst::Illegal);
+    }
+
+    #[test]
+    fn test_amo() {
+        type Amo = fn(u8, u8, u8, Ordering) -> RiscvInst;
+        #[rustfmt::skip]
+        let amos: [(u32, Amo, Amo); 11] = [
+            (0b00010, |rd, rs1, _, aqrl| RiscvInst::LrW { rd, rs1, aqrl },
+                |rd, rs1, _, aqrl| RiscvInst::LrD { rd, rs1, aqrl }),
+            (0b00011, |rd, rs1, rs2, aqrl| RiscvInst::ScW { rd, rs1, rs2, aqrl },
+                |rd, rs1, rs2, aqrl| RiscvInst::ScD { rd, rs1, rs2, aqrl }),
+            (0b00001, |rd, rs1, rs2, aqrl| RiscvInst::AmoswapW { rd, rs1, rs2, aqrl },
+                |rd, rs1, rs2, aqrl| RiscvInst::AmoswapD { rd, rs1, rs2, aqrl }),
+            (0b00000, |rd, rs1, rs2, aqrl| RiscvInst::AmoaddW { rd, rs1, rs2, aqrl },
+                |rd, rs1, rs2, aqrl| RiscvInst::AmoaddD { rd, rs1, rs2, aqrl }),
+            (0b00100, |rd, rs1, rs2, aqrl| RiscvInst::AmoxorW { rd, rs1, rs2, aqrl },
+                |rd, rs1, rs2, aqrl| RiscvInst::AmoxorD { rd, rs1, rs2, aqrl }),
+            (0b01100, |rd, rs1, rs2, aqrl| RiscvInst::AmoandW { rd, rs1, rs2, aqrl },
+                |rd, rs1, rs2, aqrl| RiscvInst::AmoandD { rd, rs1, rs2, aqrl }),
+            (0b01000, |rd, rs1, rs2, aqrl| RiscvInst::AmoorW { rd, rs1, rs2, aqrl },
+                |rd, rs1, rs2, aqrl| RiscvInst::AmoorD { rd, rs1, rs2, aqrl }),
+            (0b10000, |rd, rs1, rs2, aqrl| RiscvInst::AmominW { rd, rs1, rs2, aqrl },
+                |rd, rs1, rs2, aqrl| RiscvInst::AmominD { rd, rs1, rs2, aqrl }),
+            (0b10100, |rd, rs1, rs2, aqrl| RiscvInst::AmomaxW { rd, rs1, rs2, aqrl },
+                |rd, rs1, rs2, aqrl| RiscvInst::AmomaxD { rd, rs1, rs2, aqrl }),
+            (0b11000, |rd, rs1, rs2, aqrl| RiscvInst::AmominuW { rd, rs1, rs2, aqrl },
+                |rd, rs1, rs2, aqrl| RiscvInst::AmominuD { rd, rs1, rs2, aqrl }),
+            (0b11100, |rd, rs1, rs2, aqrl| RiscvInst::AmomaxuW { rd, rs1, rs2, aqrl },
+                |rd, rs1, rs2, aqrl| RiscvInst::AmomaxuD { rd, rs1, rs2, aqrl }),
+        ];
+        let orderings = [
+            Ordering::Relaxed,
+            Ordering::Release,
+            Ordering::Acquire,
+            Ordering::SeqCst,
+        ];
+        let (rd, rs1) = (10, 11);
+        for (funct5, word, double) in amos {
+            let rs2 = if funct5 == 0b00010 { 0 } else { 12 };
+            for (aqrl, ordering) in orderings.into_iter().enumerate() {
+                let amo = |width: u32| {
+                    decode(
+                        funct5 << 27
+                            | (aqrl as u32) << 25
+                            | (rs2 as u32) << 20
+                            | (rs1 as u32) << 15
+                            | width << 12
+                            | (rd as u32) << 7
+                            | 0b0101111,
+                    )
+                };
+                assert_eq!(amo(0b010), word(rd, rs1, rs2, ordering));
+                assert_eq!(amo(0b011), double(rd, rs1, rs2, ordering));
+                // there are no byte, half word or quad word AMOs
+                for width in [0b000, 0b001, 0b100, 0b101, 0b110, 0b111] {
+                    assert_eq!(amo(width), RiscvInst::Illegal, "{:#07b}", funct5);
+                }
+            }
+        }
+
+        // lr.w a0, (a1) with a nonzero rs2 and an undefined funct5
+        assert_eq!(decode(0x10c5a52f), RiscvInst::Illegal);
+        assert_eq!(decode(0x2805a52f), RiscvInst::Illegal);
+        assert_eq!(
+            decode(0x1005a52f),
+            RiscvInst::LrW {
+                rd,
+                rs1,
+                aqrl: Ordering::Relaxed
+            }
+        );
+    }
+
+    /// Every variant of `RiscvInst` must be reachable from `decode`. The variants are read from
+    /// the source of the enum, so an instruction added there but never decoded fails here.
+    #[test]
+    fn test_variant_coverage() {
+        let variants: BTreeSet<&str> = RiscvInst::VARIANTS.iter().copied().collect();
+
+        // Every major opcode, funct3 and bits 31:20 (funct7 with rs2, or the whole immediate),
+        // with x0 and x1 as rd and rs1 for the encodings that require zero registers.
+        let mut reached = HashMap::new();
+        for opcode in (0..0x80).filter(|opcode| opcode & 0b11 == 0b11) {
+            for funct3 in 0..8 {
+                for upper in 0..0x1000 {
+                    for (rd, rs1) in [(0, 0), (0, 1), (1, 0), (1, 1)] {
+                        let bits = upper << 20 | rs1 << 15 | funct3 << 12 | rd << 7 | opcode;
+                        let inst = decode(bits);
+                        reached.entry(discriminant(&inst)).or_insert(inst);
+                    }
+                }
+            }
+        }
+        let reached: BTreeSet<String> = reached
+            .values()
+            .map(|inst| {
+                let name = format!("{:?}", inst);
+                name.split(|c: char| !c.is_ascii_alphanumeric())
+                    .next()
+                    .unwrap()
+                    .to_owned()
+            })
+            .collect();
+
+        let unreachable: Vec<_> = variants
+            .iter()
+            .filter(|variant| !reached.contains(**variant))
+            .collect();
+        assert!(unreachable.is_empty(), "never decoded: {:?}", unreachable);
+        assert_eq!(reached.len(), variants.len());
+    }
+
+    #[test]
+    fn test_all_zero() {
+        // zeroed memory never decodes to something that runs: the half word is the reserved
+        // c.addi4spn with a zero immediate, and the word isn't even a full encoding
+        let isa = Isa::default();
+        assert_eq!(decode_compressed(0x0000), RiscvInst::Illegal);
+        assert_eq!(decode_compressed_for(0x0000, &isa), RiscvInst::Illegal);
+        assert_eq!(decode(0x0000_0000), RiscvInst::Illegal);
+        assert_eq!(decode_for(0x0000_0000, &isa), RiscvInst::Illegal);
+        assert_eq!(
+            disassemble(&[0; 4], 0x8000_0000),
+            vec![
+                (0x8000_0000, RiscvInst::Illegal, 0),
+                (0x8000_0002, RiscvInst::Illegal, 0)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_disassemble() {
+        let bytes = [
+            0x05, 0x05, // c.addi a0, 1
+            0x13, 0x05, 0x15, 0x00, // addi a0, a0, 1
+            0x8d, 0x45, // c.li a1, 3
+            0x13, 0x05, // first half of addi a0, a0, 1
+        ];
+        let insts = disassemble(&bytes, 0x8000_0000);
+        let addi = RiscvInst::Addi {
+            rd: 10,
+            rs1: 10,
+            imm: 1,
+        };
+        let li = RiscvInst::Addi {
+            rd: 11,
+            rs1: 0,
+            imm: 3,
+        };
+        assert_eq!(
+            insts,
+            vec![
+                (0x8000_0000, addi, 0x0505),
+                (0x8000_0002, addi, 0x00150513),
+                (0x8000_0006, li, 0x458d),
+            ]
+        );
+
+        // an odd trailing byte is ignored as well
+        assert_eq!(disassemble(&bytes[..3], 0).len(), 1);
+        assert!(disassemble(&[], 0).is_empty());
+    }
+
+    #[test]
+    fn test_pause() {
+        assert_eq!(decode(0x0100000f), RiscvInst::Pause);
+        // fence iorw, iorw
+        assert_eq!(decode(0x0ff0000f), RiscvInst::Fence);
+    }
+
+    #[test]
+    fn test_system() {
+        assert_eq!(decode(0x00000073), RiscvInst::Ecall);
+        assert_eq!(decode(0x00100073), RiscvInst::Ebreak);
+        assert_eq!(decode(0x30200073), RiscvInst::Mret);
+        assert_eq!(decode(0x10200073), RiscvInst::Sret);
+        assert_eq!(decode(0x10500073), RiscvInst::Wfi);
+        // sfence.vma a0, a1
+        assert_eq!(
+            decode(0x12b50073),
+            RiscvInst::SfenceVma { rs1: 10, rs2: 11 }
+        );
+        // sfence.vma a0, a1 with rd = a2
+        assert_eq!(decode(0x12b50673), RiscvInst::Illegal);
+        // ecall with rd = a0
+        assert_eq!(decode(0x00000573), RiscvInst::Illegal);
+        // wfi with rs1 = a0
+        assert_eq!(decode(0x10550073), RiscvInst::Illegal);
+    }
+
+    #[test]
+    fn test_hint() {
+        // c.nop
+        assert!(!RiscvInstWrapper::Compact(decode_compressed(0x0001)).is_hint());
+        // c.nop 1
+        assert!(RiscvInstWrapper::Compact(decode_compressed(0x0005)).is_hint());
+        // c.slli x0, 1
+        assert!(RiscvInstWrapper::Compact(decode_compressed(0x0006)).is_hint());
+        // c.srli64 a0
+        assert!(RiscvInstWrapper::Compact(decode_compressed(0x8101)).is_hint());
+        // srli a0, a0, 0
+        assert!(!RiscvInstWrapper::Full(decode(0x00055513)).is_hint());
+        // addi x0, a0, 1
+        assert!(decode(0x00150013).is_hint());
+        assert!(decode(0x00150013).to_string().ends_with("# hint"));
+    }
+
+    #[test]
+    fn test_compressed_branch_target() {
+        // c.beqz a0, 8
+        let inst = decode_compressed(0xc501);
+        assert_eq!(
+            inst.pretty_print(0x8000_0010, 0xc501).to_string(),
+            "80000010:       c501            c.beqz  a0, pc + 8 <80000018>"
+        );
+        // c.j -4
+        let inst = decode_compressed(0xbff5);
+        assert_eq!(
+            inst.pretty_print(0x8000_0010, 0xbff5).to_string(),
+            "80000010:       bff5            c.j     pc - 4 <8000000c>"
+        );
+    }
+
+    #[test]
+    fn test_compressed_imm() {
+        type ImmFn = fn(u16) -> i32;
+        // boundary encodings from the assembler, with the offset each one should decode to
+        let cases: [(ImmFn, u16, i32, &str); 22] = [
+            (ci_imm, 0x1501, -32, "c.addi a0, -32"),
+            (ci_imm, 0x157d, -1, "c.addi a0, -1"),
+            (ci_imm, 0x057d, 31, "c.addi a0, 31"),
+            (ci_imm, 0x7501, -32, "c.lui a0, 0xfffe0"),
+            (ci_imm, 0x657d, 31, "c.lui a0, 0x1f"),
+            (ci_lwsp_imm, 0x557e, 252, "c.lwsp a0, 252(sp)"),
+            (ci_ldsp_imm, 0x757e, 504, "c.ldsp a0, 504(sp)"),
+            (ci_addi16sp_imm, 0x7101, -512, "c.addi16sp sp, -512"),
+            (ci_addi16sp_imm, 0x717d, -16, "c.addi16sp sp, -16"),
+            (ci_addi16sp_imm, 0x6141, 16, "c.addi16sp sp, 16"),
+            (ci_addi16sp_imm, 0x617d, 496, "c.addi16sp sp, 496"),
+            (css_swsp_imm, 0xdfaa, 252, "c.swsp a0, 252(sp)"),
+            (css_sdsp_imm, 0xffaa, 504, "c.sdsp a0, 504(sp)"),
+            (ciw_imm, 0x1fe8, 1020, "c.addi4spn a0, sp, 1020"),
+            (cl_lw_imm, 0x5de8, 124, "c.lw a0, 124(a1)"),
+            (cl_ld_imm, 0x7de8, 248, "c.ld a0, 248(a1)"),
+            (cb_imm, 0xd101, -256, "c.beqz a0, -256"),
+            (cb_imm, 0xdd7d, -2, "c.beqz a0, -2"),
+            (cb_imm, 0xcd7d, 254, "c.beqz a0, 254"),
+            (cj_imm, 0xb001, -2048, "c.j -2048"),
+            (cj_imm, 0xbffd, -2, "c.j -2"),
+            (cj_imm, 0xaffd, 2046, "c.j 2046"),
+        ];
+        for (imm, bits, expected, asm) in cases {
+            assert_eq!(imm(bits), expected, "{} ({:#06x})", asm, bits);
+        }
+    }
+
+    #[test]
+    fn test_stack_relative() {
+        use RiscvInst::*;
+
+        // each offset bit on its own, so a swapped bit in the immediate layout shows up
+        let word_offsets = [4, 8, 16, 32, 64, 128];
+        let double_offsets = [8, 16, 32, 64, 128, 256];
+        // c.swsp s1 and c.lwsp a1
+        let swsp = [0xc226, 0xc426, 0xc826, 0xd026, 0xc0a6, 0xc126];
+        let lwsp = [0x4592, 0x45a2, 0x45c2, 0x5582, 0x4586, 0x458a];
+        // c.sdsp s1 and c.ldsp a1
+        let sdsp = [0xe426, 0xe826, 0xf026, 0xe0a6, 0xe126, 0xe226];
+        let ldsp = [0x65a2, 0x65c2, 0x7582, 0x6586, 0x658a, 0x6592];
+        for (i, imm) in word_offsets.into_iter().enumerate() {
+            assert_eq!(
+                decode_compressed(swsp[i]),
+                Sw {
+                    rs1: 2,
+                    rs2: 9,
+                    imm
+                }
+            );
+            assert_eq!(
+                decode_compressed(lwsp[i]),
+                Lw {
+                    rd: 11,
+                    rs1: 2,
+                    imm
+                }
+            );
+        }
+        for (i, imm) in double_offsets.into_iter().enumerate() {
+            assert_eq!(
+                decode_compressed(sdsp[i]),
+                Sd {
+                    rs1: 2,
+                    rs2: 9,
+                    imm
+                }
+            );
+            assert_eq!(
+                decode_compressed(ldsp[i]),
+                Ld {
+                    rd: 11,
+                    rs1: 2,
+                    imm
+                }
+            );
+        }
+    }
+
+    #[test]
+    fn test_op_fp_decode() {
+        use RiscvInst::*;
+
+        type Build = fn(u8, u8, u8, u8) -> RiscvInst;
+        // (funct7, fixed rs2, fixed funct3, instruction from rd, rs1, rs2 and funct3). Without a
+        // fixed rs2 it is a register, without a fixed funct3 it is the rounding mode.
+        #[rustfmt::skip]
+        let table: [(u32, Option<u8>, Option<u32>, Build); 50] = [
+            (0b0000000, None,    None,        |frd, frs1, frs2, rm| FaddS { frd, frs1, frs2, rm }),
+            (0b0000100, None,    None,        |frd, frs1, frs2, rm| FsubS { frd, frs1, frs2, rm }),
+            (0b0001000, None,    None,        |frd, frs1, frs2, rm| FmulS { frd, frs1, frs2, rm }),
+            (0b0001100, None,    None,        |frd, frs1, frs2, rm| FdivS { frd, frs1, frs2, rm }),
+            (0b0101100, Some(0), None,        |frd, frs1, _, rm| FsqrtS { frd, frs1, rm }),
+            (0b0010000, None,    Some(0b000), |frd, frs1, frs2, _| FsgnjS { frd, frs1, frs2 }),
+            (0b0010000, None,    Some(0b001), |frd, frs1, frs2, _| FsgnjnS { frd, frs1, frs2 }),
+            (0b0010000, None,    Some(0b010), |frd, frs1, frs2, _| FsgnjxS { frd, frs1, frs2 }),
+            (0b0010100, None,    Some(0b000), |frd, frs1, frs2, _| FminS { frd, frs1, frs2 }),
+            (0b0010100, None,    Some(0b001), |frd, frs1, frs2, _| FmaxS { frd, frs1, frs2 }),
+            (0b1100000, Some(0), None,        |rd, frs1, _, rm| FcvtWS { rd, frs1, rm }),
+            (0b1101000, Some(0), None,        |frd, rs1, _, rm| FcvtSW { frd, rs1, rm }),
+            (0b1100000, Some(1), None,        |rd, frs1, _, rm| FcvtWuS { rd, frs1, rm }),
+            (0b1101000, Some(1), None,        |frd, rs1, _, rm| FcvtSWu { frd, rs1, rm }),
+            (0b1100000, Some(2), None,        |rd, frs1, _, rm| FcvtLS { rd, frs1, rm }),
+            (0b1101000, Some(2), None,        |frd, rs1, _, rm| FcvtSL { frd, rs1, rm }),
+            (0b1100000, Some(3), None,        |rd, frs1, _, rm| FcvtLuS { rd, frs1, rm }),
+            (0b1101000, Some(3), None,        |frd, rs1, _, rm| FcvtSLu { frd, rs1, rm }),
+            (0b1010000, None,    Some(0b000), |rd, frs1, frs2, _| FleS { rd, frs1, frs2 }),
+            (0b1010000, None,    Some(0b001), |rd, frs1, frs2, _| FltS { rd, frs1, frs2 }),
+            (0b1010000, None,    Some(0b010), |rd, frs1, frs2, _| FeqS { rd, frs1, frs2 }),
+            (0b1110000, Some(0), Some(0b000), |rd, frs1, _, _| FmvXW { rd, frs1 }),
+            (0b1110000, Some(0), Some(0b001), |rd, frs1, _, _| FclassS { rd, frs1 }),
+            (0b1111000, Some(0), Some(0b000), |frd, rs1, _, _| FmvWX { frd, rs1 }),
+            (0b0000001, None,    None,        |frd, frs1, frs2, rm| FaddD { frd, frs1, frs2, rm }),
+            (0b0000101, None,    None,        |frd, frs1, frs2, rm| FsubD { frd, frs1, frs2, rm }),
+            (0b0001001, None,    None,        |frd, frs1, frs2, rm| FmulD { frd, frs1, frs2, rm }),
+            (0b0001101, None,    None,        |frd, frs1, frs2, rm| FdivD { frd, frs1, frs2, rm }),
+            (0b0101101, Some(0), None,        |frd, frs1, _, rm| FsqrtD { frd, frs1, rm }),
+            (0b0010001, None,    Some(0b000), |frd, frs1, frs2, _| FsgnjD { frd, frs1, frs2 }),
+            (0b0010001, None,    Some(0b001), |frd, frs1, frs2, _| FsgnjnD { frd, frs1, frs2 }),
+            (0b0010001, None,    Some(0b010), |frd, frs1, frs2, _| FsgnjxD { frd, frs1, frs2 }),
+            (0b0010101, None,    Some(0b000), |frd, frs1, frs2, _| FminD { frd, frs1, frs2 }),
+            (0b0010101, None,    Some(0b001), |frd, frs1, frs2, _| FmaxD { frd, frs1, frs2 }),
+            (0b1100001, Some(0), None,        |rd, frs1, _, rm| FcvtWD { rd, frs1, rm }),
+            (0b1101001, Some(0), None,        |frd, rs1, _, rm| FcvtDW { frd, rs1, rm }),
+            (0b1100001, Some(1), None,        |rd, frs1, _, rm| FcvtWuD { rd, frs1, rm }),
+            (0b1101001, Some(1), None,        |frd, rs1, _, rm| FcvtDWu { frd, rs1, rm }),
+            (0b1100001, Some(2), None,        |rd, frs1, _, rm| FcvtLD { rd, frs1, rm }),
+            (0b1101001, Some(2), None,        |frd, rs1, _, rm| FcvtDL { frd, rs1, rm }),
+            (0b1100001, Some(3), None,        |rd, frs1, _, rm| FcvtLuD { rd, frs1, rm }),
+            (0b1101001, Some(3), None,        |frd, rs1, _, rm| FcvtDLu { frd, rs1, rm }),
+            (0b1010001, None,    Some(0b000), |rd, frs1, frs2, _| FleD { rd, frs1, frs2 }),
+            (0b1010001, None,    Some(0b001), |rd, frs1, frs2, _| FltD { rd, frs1, frs2 }),
+            (0b1010001, None,    Some(0b010), |rd, frs1, frs2, _| FeqD { rd, frs1, frs2 }),
+            (0b1110001, Some(0), Some(0b000), |rd, frs1, _, _| FmvXD { rd, frs1 }),
+            (0b1110001, Some(0), Some(0b001), |rd, frs1, _, _| FclassD { rd, frs1 }),
+            (0b1111001, Some(0), Some(0b000), |frd, rs1, _, _| FmvDX { frd, rs1 }),
+            (0b0100000, Some(1), None,        |frd, frs1, _, rm| FcvtSD { frd, frs1, rm }),
+            (0b0100001, Some(0), None,        |frd, frs1, _, rm| FcvtDS { frd, frs1, rm }),
+        ];
+        let encode = |funct7: u32, rs2: u8, funct3: u32, rd: u8, rs1: u8| {
+            funct7 << 25
+                | (rs2 as u32) << 20
+                | (rs1 as u32) << 15
+                | funct3 << 12
+                | (rd as u32) << 7
+                | 0b1010011
+        };
+
+        // every defined encoding decodes to its instruction and operands
+        for &(funct7, fixed_rs2, fixed_funct3, build) in &table {
+            for (rd, rs1, rs2) in [(0, 0, 0), (1, 2, 3), (31, 17, 8), (10, 31, 31)] {
+                for rm in [0b000, 0b001, 0b010, 0b011, 0b100, 0b111] {
+                    let funct3 = fixed_funct3.unwrap_or(rm);
+                    let rs2 = fixed_rs2.unwrap_or(rs2);
+                    let bits = encode(funct7, rs2, funct3, rd, rs1);
+                    let expected = build(rd, rs1, rs2, funct3 as u8);
+                    assert_eq!(decode(bits), expected, "{:#010x}", bits);
+                }
+            }
+        }
+
+        // everything else in the opcode space is illegal
+        for funct7 in 0..0x80 {
+            for rs2 in 0..32 {
+                for funct3 in 0..8 {
+                    let defined = table.iter().any(|&(f7, fixed_rs2, fixed_funct3, _)| {
+                        f7 == funct7
+                            && fixed_rs2.is_none_or(|r| r == rs2)
+                            && match fixed_funct3 {
+                                Some(f3) => f3 == funct3,
+                                None => funct3 <= 0b100 || funct3 == 0b111,
+                            }
+                    });
+                    let bits = encode(funct7, rs2, funct3, 1, 2);
+                    assert_eq!(decode(bits) != Illegal, defined, "{:#010x}", bits);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_csr_disasm() {
+        assert_eq!(decode(0x30059573).to_string(), "csrrw   a0, mstatus, a1");
+        assert_eq!(decode(0x7c01e573).to_string(), "csrrsi  a0, 0x7c0, 3");
+    }
+
+    #[test]
+    fn test_compressed_disasm() {
+        // c.addi a0, 1
+        let inst = decode_compressed(0x0505);
+        assert_eq!(
+            inst,
+            RiscvInst::Addi {
+                rd: 10,
+                rs1: 10,
+                imm: 1
+            }
+        );
+        assert_eq!(
+            inst.pretty_print(0x8000_0000, 0x0505).to_string(),
+            "80000000:       0505            c.addi  a0, 1"
+        );
+        assert_eq!(
+            inst.pretty_print(0x8000_0000, 0x00150513).to_string(),
+            "80000000:       00150513        addi    a0, a0, 1"
+        );
+
+        // each form leaves out the operands it hardwires or repeats, as written in assembly
+        let cases = [
+            (0x0001, "c.nop   "),
+            (0x458d, "c.li    a1, 3"),
+            (0x852e, "c.mv    a0, a1"),
+            (0x952e, "c.add   a0, a1"),
+            (0x8c05, "c.sub   s0, s1"),
+            (0x9c25, "c.addw  s0, s1"),
+            (0x8082, "c.jr    ra"),
+            (0x9502, "c.jalr  a0"),
+            (0x6141, "c.addi16sp      sp, 16"),
+            (0x0808, "c.addi4spn      a0, sp, 16"),
+            (0x6522, "c.ldsp  a0, 8(sp)"),
+            (0xe500, "c.sd    s0, 8(a0)"),
+            (0x050e, "c.slli  a0, 3"),
+            (0x9865, "c.andi  s0, -7"),
+            (0x357d, "c.addiw a0, -1"),
+        ];
+        for (bits, text) in cases {
+            let inst = decode_compressed(bits);
+            let asm = inst.pretty_print(0x8000_0000, bits.into()).to_string();
+            assert!(
+                asm.ends_with(&format!("{:04x}            {}", bits, text)),
+                "{}",
+                asm
+            );
+        }
+    }
+
+    #[test]
+    fn test_upper_immediate() {
+        // the immediate is kept shifted into place, but printed as the 20 bits written in assembly
+        let cases = [
+            (0x12345537, 0x1234_5000, "lui     a0, 0x12345"),
+            (0x00001517, 0x1000, "auipc   a0, 0x1"),
+            (0xfffff537, -0x1000, "lui     a0, 0xfffff"),
+        ];
+        for (bits, imm, text) in cases {
+            let inst = decode(bits);
+            assert!(matches!(
+                inst,
+                RiscvInst::Lui { rd: 10, imm: i } | RiscvInst::Auipc { rd: 10, imm: i } if i == imm
+            ));
+            assert!(inst.pretty_print(0, bits).to_string().ends_with(text));
+        }
+        // c.lui a0, 0xfffe0
+        let inst = decode_compressed(0x7501);
+        assert_eq!(
+            inst,
+            RiscvInst::Lui {
+                rd: 10,
+                imm: -0x20000
+            }
+        );
+        assert!(inst
+            .pretty_print(0, 0x7501)
+            .to_string()
+            .ends_with("c.lui   a0, 0xfffe0"));
+    }
+
+    #[test]
+    fn test_colorize() {
+        let (m, r, i, x) = ("\x1b[33m", "\x1b[36m", "\x1b[35m", "\x1b[0m");
+        assert_eq!(
+            colorize("ld      ra, -8(sp)"),
+            format!("{m}ld{x}      {r}ra{x}, {i}-8{x}({r}sp{x})")
+        );
+        assert_eq!(
+            colorize("fadd.s  fa0, fa1, fa2, rne"),
+            format!("{m}fadd.s{x}  {r}fa0{x}, {r}fa1{x}, {r}fa2{x}, rne")
+        );
+        assert_eq!(
+            colorize("beq     a0, zero, pc + 8 <80000010>"),
+            format!("{m}beq{x}     {r}a0{x}, {r}zero{x}, pc + {i}8{x} {i}<80000010>{x}")
+        );
+        assert_eq!(
+            colorize("addi    zero, a0, 1  # hint"),
+            format!("{m}addi{x}    {r}zero{x}, {r}a0{x}, {i}1{x}  # hint")
+        );
+
+        let inst = decode(0x00150513);
+        let colored = inst
+            .pretty_print_colored(0x8000_0000, 0x00150513)
+            .to_string();
+        assert_eq!(
+            colored,
+            format!("80000000:       00150513        {m}addi{x}    {r}a0{x}, {r}a0{x}, {i}1{x}")
+        );
+    }
+}
@@ -1,4 +1,4 @@
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub enum Exception {
     InstructionAddrMisaligned(u64),
     InstructionAccessFault(u64),
@@ -21,6 +21,24 @@ pub enum Exception {
 }
 
 impl Exception {
+    /// One exception of each kind, all with a zero value.
+    pub const ALL: [Exception; 14] = [
+        Exception::InstructionAddrMisaligned(0),
+        Exception::InstructionAccessFault(0),
+        Exception::IllegalInstruction(0),
+        Exception::Breakpoint(0),
+        Exception::LoadAccessMisaligned(0),
+        Exception::LoadAccessFault(0),
+        Exception::StoreAMOAddrMisaligned(0),
+        Exception::StoreAMOAccessFault(0),
+        Exception::EnvironmentCallFromUMode(0),
+        Exception::EnvironmentCallFromSMode(0),
+        Exception::EnvironmentCallFromMMode(0),
+        Exception::InstructionPageFault(0),
+        Exception::LoadPageFault(0),
+        Exception::StoreAMOPageFault(0),
+    ];
+
     pub fn value(self) -> u64 {
         match self {
             Exception::InstructionAddrMisaligned(addr) => addr,
@@ -40,6 +58,17 @@ impl Exception {
         }
     }
 
+    /// The value written to `mtval` or `stval` when the exception is taken. An environment call
+    /// carries no extra information, so it writes zero.
+    pub fn tval(self) -> u64 {
+        match self {
+            Exception::EnvironmentCallFromUMode(_)
+            | Exception::EnvironmentCallFromSMode(_)
+            | Exception::EnvironmentCallFromMMode(_) => 0,
+            _ => self.value(),
+        }
+    }
+
     pub fn code(self) -> u64 {
         match self {
             Exception::InstructionAddrMisaligned(_) => 0,
@@ -1,5 +1,7 @@
+use crate::util::addr_add;
+
 use super::{
-    csr::{csr_min_prv_level, INSTRET, INSTRETH, SATP},
+    csr::Csr,
     reg::{f_register_name, x_register_name},
 };
 
@@ -39,15 +41,61 @@ impl RiscvInstWrapper {
         }
     }
 
+    /// The size of the instruction in bytes.
+    pub fn size(&self) -> u64 {
+        if self.is_compact() {
+            2
+        } else {
+            4
+        }
+    }
+
     pub fn get_inst(&self) -> RiscvInst {
         match self {
             RiscvInstWrapper::Full(inst) | RiscvInstWrapper::Compact(inst) => *inst,
         }
     }
+
+    /// Whether this instruction is a HINT encoding, including the compressed-only ones.
+    pub fn is_hint(&self) -> bool {
+        match self {
+            RiscvInstWrapper::Full(inst) => inst.is_hint(),
+            // c.slli, c.srli and c.srai with a zero shift amount are hints as well.
+            RiscvInstWrapper::Compact(inst) => {
+                inst.is_hint()
+                    || matches!(
+                        inst,
+                        RiscvInst::Slli { imm: 0, .. }
+                            | RiscvInst::Srli { imm: 0, .. }
+                            | RiscvInst::Srai { imm: 0, .. }
+                    )
+            }
+        }
+    }
+}
+
+/// Declare the instruction enum along with `VARIANTS`, the names of its variants.
+macro_rules! riscv_inst {
+    (
+        $(#[$meta:meta])*
+        pub enum $name:ident {
+            $($variant:ident $({ $($field:ident: $ty:ty),* })?,)*
+        }
+    ) => {
+        $(#[$meta])*
+        pub enum $name {
+            $($variant $({ $($field: $ty),* })?,)*
+        }
+
+        impl $name {
+            /// The name of every variant, in declaration order.
+            pub const VARIANTS: &'static [&'static str] = &[$(stringify!($variant)),*];
+        }
+    };
 }
 
+riscv_inst! {
 /// RISC-V Instructions
-#[rustfmt::skip]
 #[derive(Debug,Clone, Copy, PartialEq)]
 pub enum RiscvInst {
     Illegal,
@@ -65,6 +113,13 @@ pub enum RiscvInst {
     // Fence instructions
     Fence,
     FenceI,
+    Pause,
+
+    // Cache-block operations (Zicbom, Zicboz)
+    CboClean { rs1: u8 },
+    CboFlush { rs1: u8 },
+    CboInval { rs1: u8 },
+    CboZero { rs1: u8 },
 
     // Immediate instructions
     Addi { rd: u8, rs1: u8, imm: i32 },
@@ -78,6 +133,7 @@ pub enum RiscvInst {
     Andi { rd: u8, rs1: u8, imm: i32 },
 
     // PC relative instructions
+    // `imm` holds the upper 20 bits already shifted into place.
     Auipc { rd: u8, imm: i32 },
     Lui { rd: u8, imm: i32 },
 
@@ -91,13 +147,13 @@ pub enum RiscvInst {
     Sllw { rd: u8, rs1: u8, rs2: u8 },
     Srlw { rd: u8, rs1: u8, rs2: u8 },
     Sraw { rd: u8, rs1: u8, rs2: u8 },
-    
+
     // Store instructions
     Sb { rs1: u8, rs2: u8, imm: i32 },
     Sh { rs1: u8, rs2: u8, imm: i32 },
     Sw { rs1: u8, rs2: u8, imm: i32 },
     Sd { rs1: u8, rs2: u8, imm: i32 },
-    
+
     // Register instructions
     Add { rd: u8, rs1: u8, rs2: u8 },
     Sub { rd: u8, rs1: u8, rs2: u8 },
@@ -128,12 +184,12 @@ pub enum RiscvInst {
     Ebreak,
 
     // CSR instructions
-    Csrrw { rd: u8, rs1: u8, csr: u16 },
-    Csrrs { rd: u8, rs1: u8, csr: u16 },
-    Csrrc { rd: u8, rs1: u8, csr: u16 },
-    Csrrwi { rd: u8, imm: u8, csr: u16 },
-    Csrrsi { rd: u8, imm: u8, csr: u16 },
-    Csrrci { rd: u8, imm: u8, csr: u16 },
+    Csrrw { rd: u8, rs1: u8, csr: Csr },
+    Csrrs { rd: u8, rs1: u8, csr: Csr },
+    Csrrc { rd: u8, rs1: u8, csr: Csr },
+    Csrrwi { rd: u8, imm: u8, csr: Csr },
+    Csrrsi { rd: u8, imm: u8, csr: Csr },
+    Csrrci { rd: u8, imm: u8, csr: Csr },
 
     // Multiply Extension
     Mul { rd: u8, rs1: u8, rs2: u8 },
@@ -144,7 +200,7 @@ pub enum RiscvInst {
     Divu { rd: u8, rs1: u8, rs2: u8 },
     Rem { rd: u8, rs1: u8, rs2: u8 },
     Remu { rd: u8, rs1: u8, rs2: u8 },
-    
+
     // Multiply Extension (RV64)
     Mulw { rd: u8, rs1: u8, rs2: u8 },
     Divw { rd: u8, rs1: u8, rs2: u8 },
@@ -248,6 +304,7 @@ pub enum RiscvInst {
     Wfi,
     SfenceVma { rs1: u8, rs2: u8 },
 }
+}
 
 impl RiscvInst {
     /// Whether this instruction changes the control flow.
@@ -281,20 +338,229 @@ impl RiscvInst {
             RiscvInst::Csrrc { csr, .. } |
             RiscvInst::Csrrwi { csr, .. } |
             RiscvInst::Csrrsi { csr, .. } |
-            RiscvInst::Csrrci { csr, .. } => match *csr as usize {
+            RiscvInst::Csrrci { csr, .. } => match *csr {
                 // A common way of using basic blocks is to `batch' instret and pc increment. So if CSR to be accessed is
                 // instret, consider it as special.
-                INSTRET |
-                INSTRETH |
+                Csr::INSTRET |
+                Csr::INSTRETH |
                 // SATP shouldn't belong here, but somehow Linux assumes setting SATP changes
                 // addressing mode immediately...
-                SATP => true,
+                Csr::SATP => true,
                 _ => false,
             }
             _ => false,
         }
     }
 
+    /// Whether this instruction touches the floating-point state, which `mstatus.FS` guards.
+    pub fn uses_fpu(&self) -> bool {
+        match self {
+            RiscvInst::Flw { .. }
+            | RiscvInst::Fsw { .. }
+            | RiscvInst::FaddS { .. }
+            | RiscvInst::FsubS { .. }
+            | RiscvInst::FmulS { .. }
+            | RiscvInst::FdivS { .. }
+            | RiscvInst::FsqrtS { .. }
+            | RiscvInst::FsgnjS { .. }
+            | RiscvInst::FsgnjnS { .. }
+            | RiscvInst::FsgnjxS { .. }
+            | RiscvInst::FminS { .. }
+            | RiscvInst::FmaxS { .. }
+            | RiscvInst::FcvtWS { .. }
+            | RiscvInst::FcvtWuS { .. }
+            | RiscvInst::FcvtLS { .. }
+            | RiscvInst::FcvtLuS { .. }
+            | RiscvInst::FmvXW { .. }
+            | RiscvInst::FclassS { .. }
+            | RiscvInst::FeqS { .. }
+            | RiscvInst::FltS { .. }
+            | RiscvInst::FleS { .. }
+            | RiscvInst::FcvtSW { .. }
+            | RiscvInst::FcvtSWu { .. }
+            | RiscvInst::FcvtSL { .. }
+            | RiscvInst::FcvtSLu { .. }
+            | RiscvInst::FmvWX { .. }
+            | RiscvInst::FmaddS { .. }
+            | RiscvInst::FmsubS { .. }
+            | RiscvInst::FnmsubS { .. }
+            | RiscvInst::FnmaddS { .. }
+            | RiscvInst::Fld { .. }
+            | RiscvInst::Fsd { .. }
+            | RiscvInst::FaddD { .. }
+            | RiscvInst::FsubD { .. }
+            | RiscvInst::FmulD { .. }
+            | RiscvInst::FdivD { .. }
+            | RiscvInst::FsqrtD { .. }
+            | RiscvInst::FsgnjD { .. }
+            | RiscvInst::FsgnjnD { .. }
+            | RiscvInst::FsgnjxD { .. }
+            | RiscvInst::FminD { .. }
+            | RiscvInst::FmaxD { .. }
+            | RiscvInst::FcvtSD { .. }
+            | RiscvInst::FcvtDS { .. }
+            | RiscvInst::FcvtWD { .. }
+            | RiscvInst::FcvtWuD { .. }
+            | RiscvInst::FcvtLD { .. }
+            | RiscvInst::FcvtLuD { .. }
+            | RiscvInst::FmvXD { .. }
+            | RiscvInst::FclassD { .. }
+            | RiscvInst::FeqD { .. }
+            | RiscvInst::FltD { .. }
+            | RiscvInst::FleD { .. }
+            | RiscvInst::FcvtDW { .. }
+            | RiscvInst::FcvtDWu { .. }
+            | RiscvInst::FcvtDL { .. }
+            | RiscvInst::FcvtDLu { .. }
+            | RiscvInst::FmvDX { .. }
+            | RiscvInst::FmaddD { .. }
+            | RiscvInst::FmsubD { .. }
+            | RiscvInst::FnmsubD { .. }
+            | RiscvInst::FnmaddD { .. } => true,
+            RiscvInst::Csrrw { csr, .. }
+            | RiscvInst::Csrrs { csr, .. }
+            | RiscvInst::Csrrc { csr, .. }
+            | RiscvInst::Csrrwi { csr, .. }
+            | RiscvInst::Csrrsi { csr, .. }
+            | RiscvInst::Csrrci { csr, .. } => csr.is_float(),
+            _ => false,
+        }
+    }
+
+    /// The standard extension this instruction belongs to, as its `misa` letter. Zicsr and
+    /// Zifencei count as part of the base.
+    pub fn extension(&self) -> char {
+        match self {
+            RiscvInst::Mul { .. }
+            | RiscvInst::Mulh { .. }
+            | RiscvInst::Mulhsu { .. }
+            | RiscvInst::Mulhu { .. }
+            | RiscvInst::Div { .. }
+            | RiscvInst::Divu { .. }
+            | RiscvInst::Rem { .. }
+            | RiscvInst::Remu { .. }
+            | RiscvInst::Mulw { .. }
+            | RiscvInst::Divw { .. }
+            | RiscvInst::Divuw { .. }
+            | RiscvInst::Remw { .. }
+            | RiscvInst::Remuw { .. } => 'M',
+            RiscvInst::LrW { .. }
+            | RiscvInst::LrD { .. }
+            | RiscvInst::ScW { .. }
+            | RiscvInst::ScD { .. }
+            | RiscvInst::AmoswapW { .. }
+            | RiscvInst::AmoswapD { .. }
+            | RiscvInst::AmoaddW { .. }
+            | RiscvInst::AmoaddD { .. }
+            | RiscvInst::AmoxorW { .. }
+            | RiscvInst::AmoxorD { .. }
+            | RiscvInst::AmoandW { .. }
+            | RiscvInst::AmoandD { .. }
+            | RiscvInst::AmoorW { .. }
+            | RiscvInst::AmoorD { .. }
+            | RiscvInst::AmominW { .. }
+            | RiscvInst::AmominD { .. }
+            | RiscvInst::AmomaxW { .. }
+            | RiscvInst::AmomaxD { .. }
+            | RiscvInst::AmominuW { .. }
+            | RiscvInst::AmominuD { .. }
+            | RiscvInst::AmomaxuW { .. }
+            | RiscvInst::AmomaxuD { .. } => 'A',
+            RiscvInst::Flw { .. }
+            | RiscvInst::Fsw { .. }
+            | RiscvInst::FaddS { .. }
+            | RiscvInst::FsubS { .. }
+            | RiscvInst::FmulS { .. }
+            | RiscvInst::FdivS { .. }
+            | RiscvInst::FsqrtS { .. }
+            | RiscvInst::FsgnjS { .. }
+            | RiscvInst::FsgnjnS { .. }
+            | RiscvInst::FsgnjxS { .. }
+            | RiscvInst::FminS { .. }
+            | RiscvInst::FmaxS { .. }
+            | RiscvInst::FcvtWS { .. }
+            | RiscvInst::FcvtWuS { .. }
+            | RiscvInst::FcvtLS { .. }
+            | RiscvInst::FcvtLuS { .. }
+            | RiscvInst::FmvXW { .. }
+            | RiscvInst::FclassS { .. }
+            | RiscvInst::FeqS { .. }
+            | RiscvInst::FltS { .. }
+            | RiscvInst::FleS { .. }
+            | RiscvInst::FcvtSW { .. }
+            | RiscvInst::FcvtSWu { .. }
+            | RiscvInst::FcvtSL { .. }
+            | RiscvInst::FcvtSLu { .. }
+            | RiscvInst::FmvWX { .. }
+            | RiscvInst::FmaddS { .. }
+            | RiscvInst::FmsubS { .. }
+            | RiscvInst::FnmsubS { .. }
+            | RiscvInst::FnmaddS { .. } => 'F',
+            RiscvInst::Fld { .. }
+            | RiscvInst::Fsd { .. }
+            | RiscvInst::FaddD { .. }
+            | RiscvInst::FsubD { .. }
+            | RiscvInst::FmulD { .. }
+            | RiscvInst::FdivD { .. }
+            | RiscvInst::FsqrtD { .. }
+            | RiscvInst::FsgnjD { .. }
+            | RiscvInst::FsgnjnD { .. }
+            | RiscvInst::FsgnjxD { .. }
+            | RiscvInst::FminD { .. }
+            | RiscvInst::FmaxD { .. }
+            | RiscvInst::FcvtSD { .. }
+            | RiscvInst::FcvtDS { .. }
+            | RiscvInst::FcvtWD { .. }
+            | RiscvInst::FcvtWuD { .. }
+            | RiscvInst::FcvtLD { .. }
+            | RiscvInst::FcvtLuD { .. }
+            | RiscvInst::FmvXD { .. }
+            | RiscvInst::FclassD { .. }
+            | RiscvInst::FeqD { .. }
+            | RiscvInst::FltD { .. }
+            | RiscvInst::FleD { .. }
+            | RiscvInst::FcvtDW { .. }
+            | RiscvInst::FcvtDWu { .. }
+            | RiscvInst::FcvtDL { .. }
+            | RiscvInst::FcvtDLu { .. }
+            | RiscvInst::FmvDX { .. }
+            | RiscvInst::FmaddD { .. }
+            | RiscvInst::FmsubD { .. }
+            | RiscvInst::FnmsubD { .. }
+            | RiscvInst::FnmaddD { .. } => 'D',
+            _ => 'I',
+        }
+    }
+
+    /// Whether this instruction is a HINT encoding, i.e. an integer computation with rd = x0 that has
+    /// no architectural effect. `addi x0, x0, 0` is the canonical NOP and is not considered a hint.
+    pub fn is_hint(&self) -> bool {
+        match *self {
+            RiscvInst::Addi { rd: 0, rs1, imm } => rs1 != 0 || imm != 0,
+            RiscvInst::Lui { rd: 0, .. }
+            | RiscvInst::Auipc { rd: 0, .. }
+            | RiscvInst::Slti { rd: 0, .. }
+            | RiscvInst::Sltiu { rd: 0, .. }
+            | RiscvInst::Xori { rd: 0, .. }
+            | RiscvInst::Ori { rd: 0, .. }
+            | RiscvInst::Andi { rd: 0, .. }
+            | RiscvInst::Slli { rd: 0, .. }
+            | RiscvInst::Srli { rd: 0, .. }
+            | RiscvInst::Srai { rd: 0, .. }
+            | RiscvInst::Add { rd: 0, .. }
+            | RiscvInst::Sub { rd: 0, .. }
+            | RiscvInst::Sll { rd: 0, .. }
+            | RiscvInst::Slt { rd: 0, .. }
+            | RiscvInst::Sltu { rd: 0, .. }
+            | RiscvInst::Xor { rd: 0, .. }
+            | RiscvInst::Srl { rd: 0, .. }
+            | RiscvInst::Sra { rd: 0, .. }
+            | RiscvInst::Or { rd: 0, .. }
+            | RiscvInst::And { rd: 0, .. } => true,
+            _ => false,
+        }
+    }
+
     /// Get the minimal privilege level required to execute the instruction.
     pub fn min_prv_level(self) -> u8 {
         match self {
@@ -303,7 +569,7 @@ impl RiscvInst {
             | RiscvInst::Csrrc { csr, .. }
             | RiscvInst::Csrrwi { csr, .. }
             | RiscvInst::Csrrsi { csr, .. }
-            | RiscvInst::Csrrci { csr, .. } => csr_min_prv_level(csr),
+            | RiscvInst::Csrrci { csr, .. } => csr.min_prv_level(),
             RiscvInst::Mret => 3,
             RiscvInst::Sret | RiscvInst::Wfi | RiscvInst::SfenceVma { .. } => 1,
             _ => 0,
@@ -339,6 +605,11 @@ impl RiscvInst {
 
             RiscvInst::Fence => (0, 0, 0),
             RiscvInst::FenceI => (0, 0, 0),
+            RiscvInst::Pause => (0, 0, 0),
+            RiscvInst::CboClean { rs1 }
+            | RiscvInst::CboFlush { rs1 }
+            | RiscvInst::CboInval { rs1 }
+            | RiscvInst::CboZero { rs1 } => (0, rs1, 0),
 
             RiscvInst::Ecall | RiscvInst::Ebreak => (0, 0, 0),
 
@@ -504,7 +775,7 @@ impl RiscvInst {
     /// Return the mnemonic of this op withouth the suffix.
     pub fn mnemonic(&self) -> &'static str {
         match *self {
-            RiscvInst::Illegal { .. } => "illegal",
+            RiscvInst::Illegal => "illegal",
             RiscvInst::Lb { .. } => "lb",
             RiscvInst::Lh { .. } => "lh",
             RiscvInst::Lw { .. } => "lw",
@@ -512,8 +783,13 @@ impl RiscvInst {
             RiscvInst::Lbu { .. } => "lbu",
             RiscvInst::Lhu { .. } => "lhu",
             RiscvInst::Lwu { .. } => "lwu",
-            RiscvInst::Fence { .. } => "fence",
-            RiscvInst::FenceI { .. } => "fence.i",
+            RiscvInst::Fence => "fence",
+            RiscvInst::FenceI => "fence.i",
+            RiscvInst::Pause => "pause",
+            RiscvInst::CboClean { .. } => "cbo.clean",
+            RiscvInst::CboFlush { .. } => "cbo.flush",
+            RiscvInst::CboInval { .. } => "cbo.inval",
+            RiscvInst::CboZero { .. } => "cbo.zero",
             RiscvInst::Addi { .. } => "addi",
             RiscvInst::Slli { .. } => "slli",
             RiscvInst::Slti { .. } => "slti",
@@ -556,8 +832,8 @@ impl RiscvInst {
             RiscvInst::Bgeu { .. } => "bgeu",
             RiscvInst::Jalr { .. } => "jalr",
             RiscvInst::Jal { .. } => "jal",
-            RiscvInst::Ecall { .. } => "ecall",
-            RiscvInst::Ebreak { .. } => "ebreak",
+            RiscvInst::Ecall => "ecall",
+            RiscvInst::Ebreak => "ebreak",
             RiscvInst::Csrrw { .. } => "csrrw",
             RiscvInst::Csrrs { .. } => "csrrs",
             RiscvInst::Csrrc { .. } => "csrrc",
@@ -661,9 +937,9 @@ impl RiscvInst {
             RiscvInst::FmsubD { .. } => "fmsub.d",
             RiscvInst::FnmsubD { .. } => "fnmsub.d",
             RiscvInst::FnmaddD { .. } => "fnmadd.d",
-            RiscvInst::Mret { .. } => "mret",
-            RiscvInst::Sret { .. } => "sret",
-            RiscvInst::Wfi { .. } => "wfi",
+            RiscvInst::Mret => "mret",
+            RiscvInst::Sret => "sret",
+            RiscvInst::Wfi => "wfi",
             RiscvInst::SfenceVma { .. } => "sfence.vma",
         }
     }
@@ -702,16 +978,77 @@ impl RiscvInst {
         }
     }
 
-    /// Print the instruction with optional pc information.
-    fn print(&self, fmt: &mut fmt::Formatter, pc: Option<u64>) -> fmt::Result {
-        let mnemonic = self.mnemonic();
+    /// Print the instruction under the given mnemonic with optional pc information.
+    fn print(&self, fmt: &mut fmt::Formatter, pc: Option<u64>, mnemonic: &str) -> fmt::Result {
+        self.print_mnemonic(fmt, mnemonic)?;
+        self.print_operands(fmt, pc)?;
+        self.print_hint(fmt)
+    }
+
+    /// Like `print`, for the compressed instruction `mnemonic` that expands to `self`: the
+    /// operands its 16-bit form hardwires or repeats are left out, as in `c.li a1, 3`.
+    fn print_compressed(
+        &self,
+        fmt: &mut fmt::Formatter,
+        pc: Option<u64>,
+        mnemonic: &str,
+    ) -> fmt::Result {
+        self.print_mnemonic(fmt, mnemonic)?;
+        match (mnemonic, *self) {
+            ("c.nop", _) => (),
+            (
+                "c.addi" | "c.addiw" | "c.li" | "c.addi16sp" | "c.slli" | "c.srli" | "c.srai"
+                | "c.andi",
+                RiscvInst::Addi { rd, imm, .. }
+                | RiscvInst::Addiw { rd, imm, .. }
+                | RiscvInst::Slli { rd, imm, .. }
+                | RiscvInst::Srli { rd, imm, .. }
+                | RiscvInst::Srai { rd, imm, .. }
+                | RiscvInst::Andi { rd, imm, .. },
+            ) => write!(fmt, "{}, {}", x_register_name(rd), imm)?,
+            (
+                "c.mv" | "c.add" | "c.sub" | "c.xor" | "c.or" | "c.and" | "c.subw" | "c.addw",
+                RiscvInst::Add { rd, rs2, .. }
+                | RiscvInst::Sub { rd, rs2, .. }
+                | RiscvInst::Xor { rd, rs2, .. }
+                | RiscvInst::Or { rd, rs2, .. }
+                | RiscvInst::And { rd, rs2, .. }
+                | RiscvInst::Subw { rd, rs2, .. }
+                | RiscvInst::Addw { rd, rs2, .. },
+            ) => write!(fmt, "{}, {}", x_register_name(rd), x_register_name(rs2))?,
+            ("c.jr" | "c.jalr", RiscvInst::Jalr { rs1, .. }) => {
+                write!(fmt, "{}", x_register_name(rs1))?
+            }
+            ("c.j", RiscvInst::Jal { imm, .. }) => print_offset(fmt, pc, imm)?,
+            (
+                "c.beqz" | "c.bnez",
+                RiscvInst::Beq { rs1, imm, .. } | RiscvInst::Bne { rs1, imm, .. },
+            ) => {
+                write!(fmt, "{}, ", x_register_name(rs1))?;
+                print_offset(fmt, pc, imm)?
+            }
+            _ => self.print_operands(fmt, pc)?,
+        }
+        self.print_hint(fmt)
+    }
+
+    fn print_mnemonic(&self, fmt: &mut fmt::Formatter, mnemonic: &str) -> fmt::Result {
         let suffix = self.suffix();
         let len = mnemonic.len() + suffix.len();
         write!(fmt, "{}{}", mnemonic, suffix)?;
 
         // Pad to 8-byte align. At least pad 1 space.
-        write!(fmt, "{:1$}", "", 8 - len % 8)?;
+        write!(fmt, "{:1$}", "", 8 - len % 8)
+    }
+
+    fn print_hint(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        if self.is_hint() {
+            write!(fmt, "  # hint")?;
+        }
+        Ok(())
+    }
 
+    fn print_operands(&self, fmt: &mut fmt::Formatter, pc: Option<u64>) -> fmt::Result {
         match *self {
             RiscvInst::Illegal => (),
 
@@ -720,12 +1057,8 @@ impl RiscvInst {
             }
 
             RiscvInst::Jal { rd, imm } => {
-                let (sign, uimm) = if imm < 0 { ('-', -imm) } else { ('+', imm) };
-                write!(fmt, "{}, pc {} {}", x_register_name(rd), sign, uimm)?;
-                if let Some(pc) = pc {
-                    let target_pc = pc.wrapping_add(imm as u64);
-                    write!(fmt, " <{:x}>", target_pc)?;
-                }
+                write!(fmt, "{}, ", x_register_name(rd))?;
+                print_offset(fmt, pc, imm)?
             }
 
             RiscvInst::Beq { rs1, rs2, imm }
@@ -734,19 +1067,8 @@ impl RiscvInst {
             | RiscvInst::Bge { rs1, rs2, imm }
             | RiscvInst::Bltu { rs1, rs2, imm }
             | RiscvInst::Bgeu { rs1, rs2, imm } => {
-                let (sign, uimm) = if imm < 0 { ('-', -imm) } else { ('+', imm) };
-                write!(
-                    fmt,
-                    "{}, {}, pc {} {}",
-                    x_register_name(rs1),
-                    x_register_name(rs2),
-                    sign,
-                    uimm
-                )?;
-                if let Some(pc) = pc {
-                    let target_pc = pc.wrapping_add(imm as u64);
-                    write!(fmt, " <{:x}>", target_pc)?;
-                }
+                write!(fmt, "{}, {}, ", x_register_name(rs1), x_register_name(rs2))?;
+                print_offset(fmt, pc, imm)?
             }
 
             RiscvInst::Lb { rd, rs1, imm }
@@ -766,6 +1088,7 @@ impl RiscvInst {
 
             RiscvInst::Fence
             | RiscvInst::FenceI
+            | RiscvInst::Pause
             | RiscvInst::Ecall
             | RiscvInst::Ebreak
             | RiscvInst::Mret
@@ -774,6 +1097,10 @@ impl RiscvInst {
             RiscvInst::SfenceVma { rs1, rs2 } => {
                 write!(fmt, "{}, {}", x_register_name(rs1), x_register_name(rs2))?
             }
+            RiscvInst::CboClean { rs1 }
+            | RiscvInst::CboFlush { rs1 }
+            | RiscvInst::CboInval { rs1 }
+            | RiscvInst::CboZero { rs1 } => write!(fmt, "({})", x_register_name(rs1))?,
 
             RiscvInst::Sb { rs1, rs2, imm }
             | RiscvInst::Sh { rs1, rs2, imm }
@@ -845,7 +1172,7 @@ impl RiscvInst {
             | RiscvInst::Csrrs { rd, rs1, csr }
             | RiscvInst::Csrrc { rd, rs1, csr } => write!(
                 fmt,
-                "{}, #{}, {}",
+                "{}, {}, {}",
                 x_register_name(rd),
                 csr,
                 x_register_name(rs1)
@@ -854,7 +1181,7 @@ impl RiscvInst {
             RiscvInst::Csrrwi { rd, imm, csr }
             | RiscvInst::Csrrsi { rd, imm, csr }
             | RiscvInst::Csrrci { rd, imm, csr } => {
-                write!(fmt, "{}, #{}, {}", x_register_name(rd), csr, imm)?
+                write!(fmt, "{}, {}, {}", x_register_name(rd), csr, imm)?
             }
 
             RiscvInst::LrW { rd, rs1, .. } | RiscvInst::LrD { rd, rs1, .. } => {
@@ -1057,21 +1384,98 @@ impl RiscvInst {
                 f_register_name(frs3)
             )?,
         }
-
         Ok(())
     }
 
     /// Pretty-print the assembly with program counter and binary instrumentation
     pub fn pretty_print<'a>(&'a self, pc: u64, bits: u32) -> impl fmt::Display + 'a {
-        Disasm { pc, bits, op: self }
+        Disasm {
+            pc,
+            bits,
+            op: self,
+            color: false,
+        }
+    }
+
+    /// Like `pretty_print`, with the mnemonic, registers and immediates colored by ANSI escapes
+    /// for a terminal.
+    pub fn pretty_print_colored<'a>(&'a self, pc: u64, bits: u32) -> impl fmt::Display + 'a {
+        Disasm {
+            pc,
+            bits,
+            op: self,
+            color: true,
+        }
+    }
+}
+
+/// Print the pc-relative offset `imm` of a jump or branch, followed by its target if `pc` is known.
+fn print_offset(fmt: &mut fmt::Formatter, pc: Option<u64>, imm: i32) -> fmt::Result {
+    let (sign, uimm) = if imm < 0 { ('-', -imm) } else { ('+', imm) };
+    write!(fmt, "pc {} {}", sign, uimm)?;
+    if let Some(pc) = pc {
+        write!(fmt, " <{:x}>", addr_add(pc, imm))?;
+    }
+    Ok(())
+}
+
+/// Get the mnemonic of a compressed instruction from its raw bits.
+pub fn compressed_mnemonic(bits: u16) -> &'static str {
+    let rd = (bits >> 7) & 0x1f;
+    let rs2 = (bits >> 2) & 0x1f;
+    let bit12 = (bits >> 12) & 1;
+    match (bits & 0b11, bits >> 13) {
+        (0b00, 0b000) => "c.addi4spn",
+        (0b00, 0b001) => "c.fld",
+        (0b00, 0b010) => "c.lw",
+        (0b00, 0b011) => "c.ld",
+        (0b00, 0b101) => "c.fsd",
+        (0b00, 0b110) => "c.sw",
+        (0b00, 0b111) => "c.sd",
+        (0b01, 0b000) if rd == 0 => "c.nop",
+        (0b01, 0b000) => "c.addi",
+        (0b01, 0b001) => "c.addiw",
+        (0b01, 0b010) => "c.li",
+        (0b01, 0b011) if rd == 2 => "c.addi16sp",
+        (0b01, 0b011) => "c.lui",
+        (0b01, 0b100) => match ((bits >> 10) & 0b11, bit12, (bits >> 5) & 0b11) {
+            (0b00, ..) => "c.srli",
+            (0b01, ..) => "c.srai",
+            (0b10, ..) => "c.andi",
+            (_, 0, 0b00) => "c.sub",
+            (_, 0, 0b01) => "c.xor",
+            (_, 0, 0b10) => "c.or",
+            (_, 0, _) => "c.and",
+            (_, _, 0b00) => "c.subw",
+            (_, _, 0b01) => "c.addw",
+            _ => "c.unknown",
+        },
+        (0b01, 0b101) => "c.j",
+        (0b01, 0b110) => "c.beqz",
+        (0b01, 0b111) => "c.bnez",
+        (0b10, 0b000) => "c.slli",
+        (0b10, 0b001) => "c.fldsp",
+        (0b10, 0b010) => "c.lwsp",
+        (0b10, 0b011) => "c.ldsp",
+        (0b10, 0b100) => match (bit12, rd, rs2) {
+            (0, _, 0) => "c.jr",
+            (0, ..) => "c.mv",
+            (_, 0, 0) => "c.ebreak",
+            (_, _, 0) => "c.jalr",
+            _ => "c.add",
+        },
+        (0b10, 0b101) => "c.fsdsp",
+        (0b10, 0b110) => "c.swsp",
+        (0b10, 0b111) => "c.sdsp",
+        _ => "c.unknown",
     }
 }
 
-/// Be cautious if you want to rely on the printed the information from this trait implementation.
-/// For compressed jump and branches, the immediate will be incorrect. Use `RiscvInst::pretty_print` instead.
+/// This prints the expanded form of compressed instructions and no branch targets.
+/// Use `RiscvInst::pretty_print` to show the compressed mnemonic and the resolved target.
 impl fmt::Display for RiscvInst {
     fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
-        self.print(fmt, None)
+        self.print(fmt, None, self.mnemonic())
     }
 }
 
@@ -1079,6 +1483,77 @@ struct Disasm<'a> {
     pc: u64,
     bits: u32,
     op: &'a RiscvInst,
+    color: bool,
+}
+
+/// The assembly of an instruction alone, as `Disasm` shows it.
+struct Asm<'a> {
+    pc: u64,
+    op: &'a RiscvInst,
+    mnemonic: &'a str,
+    compressed: bool,
+}
+
+impl<'a> fmt::Display for Asm<'a> {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        if self.compressed {
+            self.op.print_compressed(fmt, Some(self.pc), self.mnemonic)
+        } else {
+            self.op.print(fmt, Some(self.pc), self.mnemonic)
+        }
+    }
+}
+
+const MNEMONIC_COLOR: &str = "\x1b[33m";
+const REGISTER_COLOR: &str = "\x1b[36m";
+const IMMEDIATE_COLOR: &str = "\x1b[35m";
+const RESET_COLOR: &str = "\x1b[0m";
+
+/// Color the assembly `text`: its leading mnemonic, the register names and the numbers, including
+/// resolved targets like `<80000010>`. Comments are left alone.
+pub fn colorize(text: &str) -> String {
+    let is_register = |word: &str| {
+        (0..32).any(|reg| x_register_name(reg) == word || f_register_name(reg) == word)
+    };
+    let mut out = String::new();
+    let mut rest = text;
+    let mut first = true;
+    while let Some(c) = rest.chars().next() {
+        let len = match c {
+            '#' => rest.len(),
+            '<' => rest.find('>').map_or(rest.len(), |end| end + 1),
+            '-' if rest[1..].starts_with(|c: char| c.is_ascii_digit()) => {
+                1 + rest[1..]
+                    .find(|c: char| !c.is_ascii_alphanumeric())
+                    .unwrap_or(rest.len() - 1)
+            }
+            _ if c.is_ascii_alphanumeric() || c == '.' || c == '_' => rest
+                .find(|c: char| !(c.is_ascii_alphanumeric() || c == '.' || c == '_'))
+                .unwrap_or(rest.len()),
+            _ => c.len_utf8(),
+        };
+        let (token, tail) = rest.split_at(len);
+        let color = match c {
+            '#' => None,
+            _ if first && c.is_ascii_alphabetic() => Some(MNEMONIC_COLOR),
+            '<' | '-' | '0'..='9' => Some(IMMEDIATE_COLOR),
+            _ if is_register(token) => Some(REGISTER_COLOR),
+            _ => None,
+        };
+        if !c.is_whitespace() {
+            first = false;
+        }
+        match color {
+            Some(color) => {
+                out.push_str(color);
+                out.push_str(token);
+                out.push_str(RESET_COLOR);
+            }
+            None => out.push_str(token),
+        }
+        rest = tail;
+    }
+    out
 }
 
 impl<'a> fmt::Display for Disasm<'a> {
@@ -1096,6 +1571,23 @@ impl<'a> fmt::Display for Disasm<'a> {
         }
 
         write!(fmt, "        ")?;
-        self.op.print(fmt, Some(self.pc))
+        // Compressed instructions execute as their expansion but are shown in their own syntax.
+        let compressed = self.bits & 3 != 3;
+        let mnemonic = if compressed {
+            compressed_mnemonic(self.bits as u16)
+        } else {
+            self.op.mnemonic()
+        };
+        let asm = Asm {
+            pc: self.pc,
+            op: self.op,
+            mnemonic,
+            compressed,
+        };
+        if self.color {
+            write!(fmt, "{}", colorize(&asm.to_string()))
+        } else {
+            write!(fmt, "{}", asm)
+        }
     }
 }
@@ -1,5 +1,6 @@
 pub const MASK_INTERRUPT_BIT: u64 = 1 << 63;
 
+#[allow(clippy::enum_variant_names)]
 #[derive(Debug, Copy, Clone)]
 pub enum Interrupt {
     SupervisorSoftwareInterrupt,
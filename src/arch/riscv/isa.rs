@@ -0,0 +1,105 @@
+use std::{fmt, str::FromStr};
+
+use super::instruction::RiscvInst;
+
+/// The extensions supported by the hart, kept as the letter bits of `misa`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Isa(u64);
+
+/// `misa.MXL` for a 64-bit hart.
+const MXL_64: u64 = 2 << 62;
+
+/// The extensions that can be selected, in canonical order.
+const EXTENSIONS: &str = "imafdc";
+
+fn bit(ext: char) -> u64 {
+    1 << (ext as u8 - b'a')
+}
+
+impl Isa {
+    /// Parse an ISA string like `rv64imafdc`. `g` stands for `imafd`, and the letters can come in
+    /// any order, but the base `i` is required and `d` requires `f`.
+    pub fn parse(s: &str) -> Result<Self, String> {
+        let lower = s.to_ascii_lowercase();
+        let letters = lower
+            .strip_prefix("rv64")
+            .ok_or_else(|| format!("`{}` is not an RV64 ISA string", s))?;
+        let mut bits = 0;
+        for ext in letters.chars() {
+            match ext {
+                'g' => bits |= "imafd".chars().map(bit).fold(0, |a, b| a | b),
+                _ if EXTENSIONS.contains(ext) => bits |= bit(ext),
+                _ => return Err(format!("unsupported extension `{}` in `{}`", ext, s)),
+            }
+        }
+        let isa = Isa(bits);
+        if !isa.has('i') {
+            return Err(format!("`{}` lacks the base integer ISA", s));
+        }
+        if isa.has('d') && !isa.has('f') {
+            return Err(format!("`{}` has D without F", s));
+        }
+        Ok(isa)
+    }
+
+    /// Whether the extension with the letter `ext` is enabled.
+    pub fn has(&self, ext: char) -> bool {
+        self.0 & bit(ext.to_ascii_lowercase()) != 0
+    }
+
+    /// Whether the extension of `inst` is enabled.
+    pub fn supports(&self, inst: &RiscvInst) -> bool {
+        self.has(inst.extension())
+    }
+
+    /// The value `misa` reads as.
+    pub fn misa(&self) -> u64 {
+        MXL_64 | self.0
+    }
+}
+
+impl Default for Isa {
+    fn default() -> Self {
+        Isa::parse("rv64imafdc").unwrap()
+    }
+}
+
+impl FromStr for Isa {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Isa::parse(s)
+    }
+}
+
+impl fmt::Display for Isa {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "rv64")?;
+        for ext in EXTENSIONS.chars().filter(|&ext| self.has(ext)) {
+            write!(f, "{}", ext)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Isa;
+
+    #[test]
+    fn test_parse() {
+        let isa = Isa::parse("rv64gc").unwrap();
+        assert_eq!(isa, Isa::default());
+        assert_eq!(isa.to_string(), "rv64imafdc");
+        assert_eq!(isa.misa(), 0x8000_0000_0000_112d);
+
+        let isa = Isa::parse("RV64IC").unwrap();
+        assert!(isa.has('C') && !isa.has('m'));
+        assert_eq!(isa.to_string(), "rv64ic");
+
+        assert!(Isa::parse("rv32i").is_err());
+        assert!(Isa::parse("rv64mac").is_err());
+        assert!(Isa::parse("rv64id").is_err());
+        assert!(Isa::parse("rv64iv").is_err());
+    }
+}
@@ -1,6 +1,12 @@
+use std::collections::HashMap;
+
 use crate::bus::Bus;
 
-use super::{bus::RiscvBus, exception::Exception};
+use super::{
+    bus::RiscvBus,
+    csr::{MASK_MXR, MASK_SUM},
+    exception::Exception,
+};
 
 pub const PAGE_SIZE: u64 = 4096;
 
@@ -12,6 +18,13 @@ pub const PTE_U: u64 = 0x1 << 4;
 pub const PTE_G: u64 = 0x1 << 5;
 pub const PTE_A: u64 = 0x1 << 6;
 pub const PTE_D: u64 = 0x1 << 7;
+/// Bits 60:54 are reserved for future standard use and must be zero.
+pub const PTE_RESERVED: u64 = 0x7f << 54;
+/// Svpbmt page-based memory type. Memory types only matter for caching, so PMA and NC/IO pages are
+/// translated alike; the remaining encoding is reserved.
+pub const PTE_PBMT: u64 = 0x3 << 61;
+/// Svnapot naturally aligned power-of-two mapping. Svnapot isn't implemented, so it must be zero.
+pub const PTE_N: u64 = 0x1 << 63;
 
 /// Type of access. This excludes STATUS, PRV and other states that may influence permission check.
 #[derive(Debug, PartialEq, Eq, Clone, Copy)]
@@ -34,7 +47,10 @@ impl PageTableEntry64 {
                 return Err(());
             }
         } else {
-            if self.0 & PTE_U != 0 && status & (1 << 18) == 0 {
+            // SUM lets S-mode load and store to user pages, but never execute from them.
+            if self.0 & PTE_U != 0
+                && (status & MASK_SUM == 0 || matches!(access, Accessibility::Execute))
+            {
                 return Err(());
             }
         }
@@ -45,7 +61,7 @@ impl PageTableEntry64 {
 
         match access {
             Accessibility::Read => {
-                if self.0 & PTE_R == 0 && (self.0 & PTE_X == 0 || status & (1 << 19) == 0) {
+                if self.0 & PTE_R == 0 && (self.0 & PTE_X == 0 || status & MASK_MXR == 0) {
                     return Err(());
                 }
             }
@@ -55,6 +71,7 @@ impl PageTableEntry64 {
                 }
             }
             Accessibility::Execute => {
+                // MXR only makes executable pages readable, never readable pages executable.
                 if self.0 & PTE_X == 0 {
                     return Err(());
                 }
@@ -64,6 +81,12 @@ impl PageTableEntry64 {
         Ok(())
     }
 
+    /// Whether the PTE sets bits this MMU has to reject: the reserved bits, the reserved memory type
+    /// or the Svnapot bit.
+    pub fn has_reserved_bits(&self) -> bool {
+        self.0 & (PTE_RESERVED | PTE_N) != 0 || self.0 & PTE_PBMT == PTE_PBMT
+    }
+
     pub fn is_valid(&self) -> bool {
         self.0 & PTE_V != 0
     }
@@ -133,54 +156,173 @@ pub enum AddressingMode {
     Sv57,
 }
 
+impl AddressingMode {
+    /// Whether `addr` is a valid virtual address in this mode: in the paged modes for RV64, the
+    /// bits above the top bit of the virtual address must all equal it.
+    fn is_canonical(&self, addr: u64) -> bool {
+        let va_bits = match self {
+            AddressingMode::Bare | AddressingMode::Sv32 => return true,
+            AddressingMode::Sv39 => 39,
+            AddressingMode::Sv48 => 48,
+            AddressingMode::Sv57 => 57,
+        };
+        let shift = 64 - va_bits;
+        ((addr << shift) as i64 >> shift) as u64 == addr
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
 pub enum AccessType {
     Load,
     Store,
     Instruction,
 }
 
-pub struct MMU {
+/// A cached translation of a 4 KiB virtual page.
+#[derive(Debug, Clone, Copy)]
+struct TlbEntry {
+    /// Physical page number the virtual page maps to.
+    ppn: u64,
+    /// The leaf PTE, whose permissions every access through the entry is checked against.
+    pte: u64,
+}
+
+pub struct Mmu {
     addressing_mode: AddressingMode,
     physical_page_number: u64,
+    /// Address space identifier from satp.
+    asid: u16,
+    /// Translations keyed by ASID and virtual page number. Global mappings, which belong to every
+    /// address space, are kept under `None`.
+    tlb: HashMap<(Option<u16>, u64), TlbEntry>,
+    /// Page table entries loaded by the walks of TLB misses since the last `take_walk_loads`.
+    walk_loads: u64,
 }
 
-impl MMU {
+impl Mmu {
     pub fn new() -> Self {
         Self {
             addressing_mode: AddressingMode::Bare,
             physical_page_number: 0,
+            asid: 0,
+            tlb: HashMap::new(),
+            walk_loads: 0,
         }
     }
 
-    pub fn set_ppn(&mut self, satp: u64) {
+    /// Update the translation mode, address space and root page table from a satp value.
+    /// Like the hardware, this leaves the TLB alone until the next `sfence.vma`.
+    pub fn set_satp(&mut self, satp: u64) {
+        self.addressing_mode = match satp >> 60 {
+            8 => AddressingMode::Sv39,
+            _ => AddressingMode::Bare,
+        };
+        self.asid = ((satp >> 44) & 0xffff) as u16;
         self.physical_page_number = satp & 0xfff_ffff_ffff;
     }
 
+    /// Drop cached translations as `sfence.vma` does: only those of the page holding `addr` if
+    /// given, and only the non-global ones of `asid` if given.
+    pub fn flush(&mut self, addr: Option<u64>, asid: Option<u16>) {
+        let vpn = addr.map(|addr| addr >> 12);
+        self.tlb.retain(|&(entry_asid, entry_vpn), _| {
+            let vpn_matches = vpn.is_none_or(|vpn| vpn == entry_vpn);
+            let asid_matches = asid.is_none_or(|asid| entry_asid == Some(asid));
+            !(vpn_matches && asid_matches)
+        });
+    }
+
+    /// Translate `addr` for an access from privilege level `prv`, with `status` the value of
+    /// `mstatus` for its SUM and MXR bits.
     pub fn translate(
-        &self,
+        &mut self,
         access_type: AccessType,
-        bus: &mut RiscvBus,
+        bus: &RiscvBus,
         addr: u64,
+        prv: u8,
+        status: u64,
     ) -> Result<u64, Exception> {
         match self.addressing_mode {
             AddressingMode::Bare => self.translate_bare(addr),
-            AddressingMode::Sv39 => self.translate_sv39(access_type, bus, addr),
+            AddressingMode::Sv39 => {
+                let vpn = addr >> 12;
+                let cached = self
+                    .tlb
+                    .get(&(Some(self.asid), vpn))
+                    .or_else(|| self.tlb.get(&(None, vpn)))
+                    .copied();
+                let entry = match cached {
+                    Some(entry) => entry,
+                    None => {
+                        // a walk that faults still loaded the entries it got through
+                        let mut loads = 0;
+                        let walk = self.translate_sv39(access_type, bus, addr, &mut loads);
+                        self.walk_loads += loads;
+                        let (paddr, global, pte) = walk?;
+                        let asid = if global { None } else { Some(self.asid) };
+                        let entry = TlbEntry {
+                            ppn: paddr >> 12,
+                            pte,
+                        };
+                        self.tlb.insert((asid, vpn), entry);
+                        entry
+                    }
+                };
+                let access = match access_type {
+                    AccessType::Load => Accessibility::Read,
+                    AccessType::Store => Accessibility::Write,
+                    AccessType::Instruction => Accessibility::Execute,
+                };
+                PageTableEntry64(entry.pte)
+                    .check_permission(access, prv, status)
+                    .map_err(|_| page_fault(access_type, addr))?;
+                Ok((entry.ppn << 12) | (addr & 0xfff))
+            }
             AddressingMode::Sv32 | AddressingMode::Sv48 | AddressingMode::Sv57 => {
                 todo!("translate sv32, sv48, sv57")
             }
         }
     }
 
+    /// Translate `addr` for a debugger, walking the page table without consulting or filling the
+    /// TLB and without checking the permissions of the mapping.
+    pub fn translate_debug(&self, bus: &RiscvBus, addr: u64) -> Result<u64, Exception> {
+        match self.addressing_mode {
+            AddressingMode::Bare => self.translate_bare(addr),
+            AddressingMode::Sv39 => self
+                .translate_sv39(AccessType::Load, bus, addr, &mut 0)
+                .map(|(paddr, _, _)| paddr),
+            // There is no walk for these yet, and a debugger must not take the emulator down, so
+            // they fault like an unmapped address.
+            AddressingMode::Sv32 | AddressingMode::Sv48 | AddressingMode::Sv57 => {
+                Err(Exception::LoadPageFault(addr))
+            }
+        }
+    }
+
+    /// Get the number of page table entries the TLB misses loaded since the last call, which is
+    /// what they cost on top of the access itself.
+    pub fn take_walk_loads(&mut self) -> u64 {
+        std::mem::take(&mut self.walk_loads)
+    }
+
     fn translate_bare(&self, addr: u64) -> Result<u64, Exception> {
         Ok(addr)
     }
 
+    /// Walk the Sv39 page table, returning the physical address, whether the mapping is global and
+    /// the leaf PTE. Every entry loaded is counted in `loads`, whether or not the walk faults.
     fn translate_sv39(
         &self,
         access_type: AccessType,
-        bus: &mut RiscvBus,
+        bus: &RiscvBus,
         addr: u64,
-    ) -> Result<u64, Exception> {
+        loads: &mut u64,
+    ) -> Result<(u64, bool, u64), Exception> {
+        if !AddressingMode::Sv39.is_canonical(addr) {
+            return Err(page_fault(access_type, addr));
+        }
+
         let levels = 3;
 
         let vpn = [
@@ -192,24 +334,33 @@ impl MMU {
         let mut root = self.physical_page_number << 12;
         let mut i = levels - 1;
         let mut pte: PageTableEntry64;
+        // a global PTE makes every mapping below it global as well
+        let mut global = false;
 
-        let err: Result<u64, Exception> = match access_type {
-            AccessType::Instruction => Err(Exception::InstructionPageFault(addr)),
-            AccessType::Load => Err(Exception::LoadPageFault(addr)),
-            AccessType::Store => Err(Exception::StoreAMOPageFault(addr)),
-        };
+        let err = Err(page_fault(access_type, addr));
 
         loop {
             pte = bus.load(root + vpn[i as usize] * 8, 8)?.into();
+            *loads += 1;
 
-            if !pte.is_valid() || (!pte.is_readable() && pte.is_writable()) {
+            if !pte.is_valid()
+                || (!pte.is_readable() && pte.is_writable())
+                || pte.has_reserved_bits()
+            {
                 return err;
             }
 
+            global |= pte.0 & PTE_G != 0;
+
             if pte.is_readable() || pte.is_executable() {
                 break;
             }
 
+            // memory types only apply to leaf PTEs
+            if pte.0 & PTE_PBMT != 0 {
+                return err;
+            }
+
             root = pte.get_ppn(AddressingMode::Sv39) << 12;
 
             i -= 1;
@@ -221,11 +372,288 @@ impl MMU {
         let ppn = pte.get_ppns(AddressingMode::Sv39);
 
         let offset = addr & 0xfff;
-        match i {
-            0 => Ok((pte.get_ppn(AddressingMode::Sv39) << 12) | offset),
-            1 => Ok((ppn[2] << 30) | (ppn[1] << 21) | (vpn[0] << 12) | offset),
-            2 => Ok((ppn[2] << 30) | (vpn[1] << 21) | (vpn[0] << 12) | offset),
-            _ => err,
+        let paddr = match i {
+            0 => (pte.get_ppn(AddressingMode::Sv39) << 12) | offset,
+            1 => (ppn[2] << 30) | (ppn[1] << 21) | (vpn[0] << 12) | offset,
+            2 => (ppn[2] << 30) | (vpn[1] << 21) | (vpn[0] << 12) | offset,
+            _ => return err,
+        };
+        Ok((paddr, global, pte.0))
+    }
+}
+
+/// The page fault an access of `access_type` to `addr` raises.
+fn page_fault(access_type: AccessType, addr: u64) -> Exception {
+    match access_type {
+        AccessType::Instruction => Exception::InstructionPageFault(addr),
+        AccessType::Load => Exception::LoadPageFault(addr),
+        AccessType::Store => Exception::StoreAMOPageFault(addr),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        AccessType, AddressingMode, Mmu, PTE_A, PTE_D, PTE_G, PTE_N, PTE_R, PTE_U, PTE_V, PTE_W,
+        PTE_X,
+    };
+    use crate::{
+        arch::riscv::{
+            bus::RiscvBus,
+            csr::{MASK_MXR, MASK_SUM},
+            exception::Exception,
+        },
+        bus::Bus,
+    };
+
+    #[test]
+    fn test_pte_extension_bits() {
+        let mut bus = RiscvBus::new();
+        bus.init();
+        let mut mmu = Mmu::new();
+        // root table at 0x8000_2000
+        mmu.set_satp((8 << 60) | 0x80002);
+
+        // identity map the gigapage holding DRAM as non-cacheable memory
+        let pte = ((0x8000_0000u64 >> 12) << 10) | PTE_V | PTE_R | PTE_W | PTE_X | PTE_A | PTE_D;
+        bus.store(0x8000_2010, 8, pte | (1 << 61)).unwrap();
+        assert_eq!(
+            mmu.translate(AccessType::Load, &bus, 0x8000_1234, 1, 0),
+            Ok(0x8000_1234)
+        );
+
+        // the reserved memory type faults
+        bus.store(0x8000_2010, 8, pte | (3 << 61)).unwrap();
+        mmu.flush(None, None);
+        assert_eq!(
+            mmu.translate(AccessType::Load, &bus, 0x8000_1234, 1, 0),
+            Err(Exception::LoadPageFault(0x8000_1234))
+        );
+
+        // without Svnapot the N bit faults
+        bus.store(0x8000_2010, 8, pte | PTE_N).unwrap();
+        mmu.flush(None, None);
+        assert_eq!(
+            mmu.translate(AccessType::Store, &bus, 0x8000_1234, 1, 0),
+            Err(Exception::StoreAMOPageFault(0x8000_1234))
+        );
+    }
+
+    #[test]
+    fn test_asid() {
+        let mut bus = RiscvBus::new();
+        bus.init();
+        let mut mmu = Mmu::new();
+        let leaf = PTE_V | PTE_R | PTE_W | PTE_X | PTE_A | PTE_D;
+        let gigapage = |base: u64| ((base >> 12) << 10) | leaf;
+        let satp = |asid: u64, root: u64| (8 << 60) | (asid << 44) | (root >> 12);
+
+        // map 0x4000_0000 to different gigapages in two address spaces
+        bus.store(0x8000_2008, 8, gigapage(0x8000_0000)).unwrap();
+        bus.store(0x8000_3008, 8, gigapage(0xc000_0000)).unwrap();
+        // and 0x0 to the same global gigapage
+        bus.store(0x8000_2000, 8, gigapage(0x4000_0000) | PTE_G)
+            .unwrap();
+        let translate = |mmu: &mut Mmu, bus: &RiscvBus, satp: u64, addr: u64| {
+            mmu.set_satp(satp);
+            mmu.translate(AccessType::Load, bus, addr, 1, 0).unwrap()
+        };
+        assert_eq!(
+            translate(&mut mmu, &bus, satp(1, 0x8000_2000), 0x4000_0123),
+            0x8000_0123
+        );
+        assert_eq!(
+            translate(&mut mmu, &bus, satp(1, 0x8000_2000), 0x123),
+            0x4000_0123
+        );
+        assert_eq!(
+            translate(&mut mmu, &bus, satp(2, 0x8000_3000), 0x4000_0123),
+            0xc000_0123
+        );
+        // the global mapping is visible from the other address space without a walk
+        assert_eq!(
+            translate(&mut mmu, &bus, satp(2, 0x8000_3000), 0x123),
+            0x4000_0123
+        );
+
+        // remap the page in both tables; the TLB still holds the old translations
+        bus.store(0x8000_2008, 8, gigapage(0x1_0000_0000)).unwrap();
+        bus.store(0x8000_3008, 8, gigapage(0x1_4000_0000)).unwrap();
+        assert_eq!(
+            translate(&mut mmu, &bus, satp(2, 0x8000_3000), 0x4000_0123),
+            0xc000_0123
+        );
+
+        // sfence.vma x0, asid only flushes the non-global entries of that address space
+        mmu.flush(None, Some(2));
+        assert_eq!(
+            translate(&mut mmu, &bus, satp(2, 0x8000_3000), 0x4000_0123),
+            0x1_4000_0123
+        );
+        assert_eq!(
+            translate(&mut mmu, &bus, satp(2, 0x8000_3000), 0x123),
+            0x4000_0123
+        );
+        assert_eq!(
+            translate(&mut mmu, &bus, satp(1, 0x8000_2000), 0x4000_0123),
+            0x8000_0123
+        );
+
+        mmu.flush(None, None);
+        assert_eq!(
+            translate(&mut mmu, &bus, satp(1, 0x8000_2000), 0x4000_0123),
+            0x1_0000_0123
+        );
+    }
+
+    #[test]
+    fn test_execute_permission() {
+        let mut bus = RiscvBus::new();
+        bus.init();
+        let mut mmu = Mmu::new();
+        mmu.set_satp((8 << 60) | 0x80002);
+        // a readable and writable, but not executable, gigapage over DRAM
+        let pte = ((0x8000_0000u64 >> 12) << 10) | PTE_V | PTE_R | PTE_W | PTE_A | PTE_D;
+        bus.store(0x8000_2010, 8, pte).unwrap();
+
+        assert_eq!(
+            mmu.translate(AccessType::Instruction, &bus, 0x8000_1000, 1, 0),
+            Err(Exception::InstructionPageFault(0x8000_1000))
+        );
+        assert_eq!(
+            mmu.translate(AccessType::Load, &bus, 0x8000_1000, 1, 0),
+            Ok(0x8000_1000)
+        );
+        // the permissions are checked on TLB hits as well
+        assert_eq!(
+            mmu.translate(AccessType::Instruction, &bus, 0x8000_1000, 1, 0),
+            Err(Exception::InstructionPageFault(0x8000_1000))
+        );
+    }
+
+    #[test]
+    fn test_mxr() {
+        let mut bus = RiscvBus::new();
+        bus.init();
+        let mut mmu = Mmu::new();
+        mmu.set_satp((8 << 60) | 0x80002);
+        let page = |base: u64, flags: u64| ((base >> 12) << 10) | PTE_V | PTE_A | flags;
+        // a read-only gigapage over DRAM and an execute-only one above it
+        bus.store(0x8000_2010, 8, page(0x8000_0000, PTE_R)).unwrap();
+        bus.store(0x8000_2018, 8, page(0x8000_0000, PTE_X)).unwrap();
+
+        for status in [0, MASK_MXR] {
+            assert_eq!(
+                mmu.translate(AccessType::Instruction, &bus, 0x8000_1000, 1, status),
+                Err(Exception::InstructionPageFault(0x8000_1000))
+            );
+            assert_eq!(
+                mmu.translate(AccessType::Instruction, &bus, 0xc000_1000, 1, status),
+                Ok(0x8000_1000)
+            );
+            assert_eq!(
+                mmu.translate(AccessType::Load, &bus, 0x8000_1000, 1, status),
+                Ok(0x8000_1000)
+            );
+        }
+        // only loads from the execute-only page honor MXR
+        assert_eq!(
+            mmu.translate(AccessType::Load, &bus, 0xc000_1000, 1, 0),
+            Err(Exception::LoadPageFault(0xc000_1000))
+        );
+        assert_eq!(
+            mmu.translate(AccessType::Load, &bus, 0xc000_1000, 1, MASK_MXR),
+            Ok(0x8000_1000)
+        );
+    }
+
+    #[test]
+    fn test_sum_never_grants_execute() {
+        let mut bus = RiscvBus::new();
+        bus.init();
+        let mut mmu = Mmu::new();
+        mmu.set_satp((8 << 60) | 0x80002);
+        // a user gigapage over DRAM
+        let pte = ((0x8000_0000u64 >> 12) << 10) | PTE_V | PTE_U | PTE_R | PTE_X | PTE_A;
+        bus.store(0x8000_2010, 8, pte).unwrap();
+
+        assert_eq!(
+            mmu.translate(AccessType::Load, &bus, 0x8000_1000, 1, MASK_SUM),
+            Ok(0x8000_1000)
+        );
+        assert_eq!(
+            mmu.translate(AccessType::Instruction, &bus, 0x8000_1000, 1, MASK_SUM),
+            Err(Exception::InstructionPageFault(0x8000_1000))
+        );
+        // user mode itself still runs it
+        assert_eq!(
+            mmu.translate(AccessType::Instruction, &bus, 0x8000_1000, 0, 0),
+            Ok(0x8000_1000)
+        );
+    }
+
+    #[test]
+    fn test_non_canonical() {
+        let mut bus = RiscvBus::new();
+        bus.init();
+        let mut mmu = Mmu::new();
+        // the root table is outside of memory, so walking it would raise an access fault
+        mmu.set_satp(8 << 60);
+
+        for addr in [0x40_0000_0000, 0xffff_ff7f_ffff_f000, 0x8000_0000_0000_0000] {
+            assert_eq!(
+                mmu.translate(AccessType::Load, &bus, addr, 1, 0),
+                Err(Exception::LoadPageFault(addr))
+            );
+            assert_eq!(
+                mmu.translate(AccessType::Store, &bus, addr, 1, 0),
+                Err(Exception::StoreAMOPageFault(addr))
+            );
+        }
+        // a canonical address does walk the table, starting from root entry 256
+        assert_eq!(
+            mmu.translate(AccessType::Load, &bus, 0xffff_ffc0_0000_0000, 1, 0),
+            Err(Exception::LoadAccessFault(0x800))
+        );
+    }
+
+    #[test]
+    fn test_faulting_walk_loads() {
+        let mut bus = RiscvBus::new();
+        bus.init();
+        let mut mmu = Mmu::new();
+        // root table at 0x8000_2000
+        mmu.set_satp((8 << 60) | 0x80002);
+
+        // an invalid root entry faults after one load
+        assert_eq!(
+            mmu.translate(AccessType::Load, &bus, 0x4000_0000, 1, 0),
+            Err(Exception::LoadPageFault(0x4000_0000))
+        );
+        assert_eq!(mmu.take_walk_loads(), 1);
+
+        // an invalid entry in the next table after two
+        bus.store(0x8000_2008, 8, ((0x8000_3000u64 >> 12) << 10) | PTE_V)
+            .unwrap();
+        assert_eq!(
+            mmu.translate(AccessType::Store, &bus, 0x4000_0000, 1, 0),
+            Err(Exception::StoreAMOPageFault(0x4000_0000))
+        );
+        assert_eq!(mmu.take_walk_loads(), 2);
+    }
+
+    #[test]
+    fn test_translate_debug_unsupported_mode() {
+        let mut bus = RiscvBus::new();
+        bus.init();
+        let mut mmu = Mmu::new();
+        // satp doesn't take Sv48 and Sv57 yet, but a debugger must see a fault there, not a panic
+        for mode in [AddressingMode::Sv48, AddressingMode::Sv57] {
+            mmu.addressing_mode = mode;
+            assert_eq!(
+                mmu.translate_debug(&bus, 0x4000_1000),
+                Err(Exception::LoadPageFault(0x4000_1000))
+            );
         }
     }
 }
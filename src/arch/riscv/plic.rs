@@ -1,39 +1,44 @@
 use super::{
-    bus::*,
-    cpu::{HART_COUNT, MAX_HART_COUNT},
+    cpu::{HART_COUNT, MACHINE_MODE, MAX_HART_COUNT, SUPERVISOR_MODE},
     exception::Exception,
 };
 
-const SOURCE_COUNT: usize = 32;
+/// The number of interrupt sources, including the reserved source 0, of the default platform.
+pub(crate) const DEFAULT_SOURCE_COUNT: usize = 32;
 const MAX_SOURCE_COUNT: u64 = 1024;
 
-#[derive(Debug, Clone, Copy)]
+/// The number of 32-bit words holding one bit for each of `source_count` sources.
+fn bit_words(source_count: usize) -> usize {
+    source_count.div_ceil(32)
+}
+
+#[derive(Debug, Clone)]
 pub struct PlicContext {
     pub priority_threshold: u32,
     pub claim_or_complete: u32,
-    pub enable_bits: [u32; (SOURCE_COUNT - 1) / 32 + 1],
+    pub enable_bits: Vec<u32>,
 }
 
 impl PlicContext {
-    pub fn new() -> Self {
+    pub fn new(source_count: usize) -> Self {
         PlicContext {
             priority_threshold: 0,
             claim_or_complete: 0,
-            enable_bits: [0; (SOURCE_COUNT - 1) / 32 + 1],
+            enable_bits: vec![0; bit_words(source_count)],
         }
     }
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone)]
 pub struct Plic {
     pending: u64,
     senable: u64,
     spriority: u64,
     sclaim: u64,
 
-    source_priority: [u32; (SOURCE_COUNT - 1) / 32 + 1],
-    pending_bits: [u32; (SOURCE_COUNT - 1) / 32 + 1],
-    context: [PlicContext; HART_COUNT],
+    source_priority: Vec<u32>,
+    pending_bits: Vec<u32>,
+    context: Vec<PlicContext>,
 }
 
 const INT_PRIORITY_BASE: u64 = 0x0;
@@ -47,6 +52,8 @@ const INT_PENDING_END: u64 = INT_PENDING_BASE + INT_PENDING_STRIDE * INT_PENDING
 
 const INT_ENABLE_BITS_BASE: u64 = 0x2000;
 const INT_ENABLE_BITS_STRIDE: u64 = 0x80;
+/// Each word of a context's enable bits covers 32 sources.
+const INT_ENABLE_BITS_WORD: u64 = 0x4;
 const INT_ENABLE_BITS_END: u64 = INT_ENABLE_BITS_BASE + INT_ENABLE_BITS_STRIDE * MAX_HART_COUNT - 1;
 
 const INT_CONTEXT_BASE: u64 = 0x200000;
@@ -56,14 +63,16 @@ const INT_CONTEXT_END: u64 = INT_CONTEXT_BASE + INT_CONTEXT_STRIDE * MAX_HART_CO
 #[derive(Debug, PartialEq)]
 enum PlicOp {
     InterruptPriorityOfSource(u32),
+    /// The word of pending bits, 32 sources each.
     InterruptPendingBit(u32),
+    /// The context, and the word of its enable bits, 32 sources each.
     EnableBitsForSourcesAndOnContext(u32, u32),
     PriorityThresholdForContext(u32),
     ClaimOrCompleteForContext(u32),
 }
 
-fn parse_addr(addr: u64) -> Result<PlicOp, ()> {
-    let relative = addr - PLIC_BASE;
+/// Decode an offset into the PLIC region.
+fn parse_addr(relative: u64) -> Result<PlicOp, ()> {
     match relative {
         INT_PRIORITY_BASE..=INT_PRIORITY_END => {
             let source = ((relative - INT_PRIORITY_BASE) / INT_PRIORITY_STRIDE) as u32;
@@ -74,9 +83,10 @@ fn parse_addr(addr: u64) -> Result<PlicOp, ()> {
             Ok(PlicOp::InterruptPendingBit(source))
         }
         INT_ENABLE_BITS_BASE..=INT_ENABLE_BITS_END => {
-            let source = ((relative - INT_ENABLE_BITS_BASE) / INT_ENABLE_BITS_STRIDE) as u32;
-            let context = ((relative - INT_ENABLE_BITS_BASE) % INT_ENABLE_BITS_STRIDE) as u32;
-            Ok(PlicOp::EnableBitsForSourcesAndOnContext(source, context))
+            let context = ((relative - INT_ENABLE_BITS_BASE) / INT_ENABLE_BITS_STRIDE) as u32;
+            let offset = (relative - INT_ENABLE_BITS_BASE) % INT_ENABLE_BITS_STRIDE;
+            let word = (offset / INT_ENABLE_BITS_WORD) as u32;
+            Ok(PlicOp::EnableBitsForSourcesAndOnContext(context, word))
         }
         INT_CONTEXT_BASE..=INT_CONTEXT_END => {
             let context = ((relative - INT_CONTEXT_BASE) / INT_CONTEXT_STRIDE) as u32;
@@ -91,102 +101,284 @@ fn parse_addr(addr: u64) -> Result<PlicOp, ()> {
     }
 }
 
-pub(crate) fn get_plic_claim_or_complete(hart_id: u64, mode: u8) -> u64 {
-    PLIC_BASE + INT_CONTEXT_BASE + INT_CONTEXT_STRIDE * (2 * hart_id + mode as u64) + 4
+/// The context through which the PLIC interrupts `mode` on `hart`. Each hart has two contexts,
+/// the even one for M-mode and the odd one for S-mode.
+pub(crate) fn context_of(hart: u64, mode: u8) -> usize {
+    match mode {
+        MACHINE_MODE => 2 * hart as usize,
+        SUPERVISOR_MODE => 2 * hart as usize + 1,
+        _ => unreachable!("the PLIC has no context for mode {}", mode),
+    }
 }
 
 impl Plic {
-    pub fn new() -> Self {
+    /// A PLIC with `source_count` interrupt sources, counting the reserved source 0. Registers of
+    /// sources past the last one read as zero and ignore writes.
+    pub fn new(source_count: usize) -> Self {
+        assert!(
+            source_count as u64 <= MAX_SOURCE_COUNT,
+            "the PLIC supports at most {} sources",
+            MAX_SOURCE_COUNT
+        );
         Self {
             pending: 0,
             senable: 0,
             spriority: 0,
             sclaim: 0,
 
-            pending_bits: [0; (SOURCE_COUNT - 1) / 32 + 1],
-            source_priority: [0; (SOURCE_COUNT - 1) / 32 + 1],
-            context: [PlicContext::new(); HART_COUNT],
+            pending_bits: vec![0; bit_words(source_count)],
+            source_priority: vec![0; source_count],
+            context: vec![PlicContext::new(source_count); 2 * HART_COUNT],
+        }
+    }
+
+    /// Hand `source` to the handler of `mode` on `hart`, which will find it in its claim
+    /// register until it writes the ID back to complete it.
+    pub fn raise(&mut self, hart: u64, mode: u8, source: u32) {
+        if let Some(context) = self.context.get_mut(context_of(hart, mode)) {
+            context.claim_or_complete = source;
         }
     }
 
+    /// Raise `source` as a device would: mark it pending, and hand it to the handler of each mode
+    /// on `hart` whose context enables it and has a threshold below the priority of the source. A
+    /// priority of 0 never interrupts, and neither does the reserved source 0.
+    pub fn trigger(&mut self, hart: u64, source: u32) {
+        if source == 0 {
+            return;
+        }
+        let priority = self
+            .source_priority
+            .get(source as usize)
+            .copied()
+            .unwrap_or(0);
+        let (word, bit) = (source as usize / 32, 1 << (source % 32));
+        if let Some(bits) = self.pending_bits.get_mut(word) {
+            *bits |= bit;
+        }
+        for mode in [MACHINE_MODE, SUPERVISOR_MODE] {
+            let interrupts = self
+                .context
+                .get(context_of(hart, mode))
+                .is_some_and(|context| {
+                    context
+                        .enable_bits
+                        .get(word)
+                        .is_some_and(|bits| bits & bit != 0)
+                        && priority > context.priority_threshold
+                });
+            if interrupts {
+                self.raise(hart, mode, source);
+            }
+        }
+    }
+
+    /// Whether an interrupt is waiting for the handler of `mode` on `hart`, which the hart sees as
+    /// `MEIP` or `SEIP`.
+    pub fn external_pending(&self, hart: u64, mode: u8) -> bool {
+        self.context
+            .get(context_of(hart, mode))
+            .is_some_and(|context| context.claim_or_complete != 0)
+    }
+
     pub fn load(&self, addr: u64, size: u64) -> Result<u64, Exception> {
         if size != 4 {
             return Err(Exception::LoadAccessFault(addr));
         }
-        match parse_addr(addr) {
+        let value = match parse_addr(addr) {
             Ok(PlicOp::InterruptPriorityOfSource(source)) => {
-                Ok(self.source_priority[source as usize] as u64)
-            }
-            Ok(PlicOp::InterruptPendingBit(source)) => {
-                Ok(self.pending_bits[source as usize] as u64)
-            }
-            Ok(PlicOp::EnableBitsForSourcesAndOnContext(source, context)) => {
-                Ok(self.context[context as usize].enable_bits[source as usize] as u64)
-            }
-            Ok(PlicOp::PriorityThresholdForContext(context)) => {
-                Ok(self.context[context as usize].priority_threshold as u64)
+                self.source_priority.get(source as usize)
             }
-            Ok(PlicOp::ClaimOrCompleteForContext(context)) => {
-                Ok(self.context[context as usize].claim_or_complete as u64)
-            }
-            Err(_) => Ok(0),
-        }
+            Ok(PlicOp::InterruptPendingBit(word)) => self.pending_bits.get(word as usize),
+            Ok(PlicOp::EnableBitsForSourcesAndOnContext(context, word)) => self
+                .context
+                .get(context as usize)
+                .and_then(|c| c.enable_bits.get(word as usize)),
+            Ok(PlicOp::PriorityThresholdForContext(context)) => self
+                .context
+                .get(context as usize)
+                .map(|c| &c.priority_threshold),
+            Ok(PlicOp::ClaimOrCompleteForContext(context)) => self
+                .context
+                .get(context as usize)
+                .map(|c| &c.claim_or_complete),
+            Err(_) => None,
+        };
+        Ok(value.copied().unwrap_or(0).into())
     }
 
     pub fn store(&mut self, addr: u64, size: u64, value: u64) -> Result<(), Exception> {
         if size != 4 {
             return Err(Exception::StoreAMOAccessFault(addr));
         }
-        match parse_addr(addr) {
-            Ok(PlicOp::InterruptPriorityOfSource(source)) => Ok({
-                self.source_priority[source as usize] = value as u32;
-            }),
-            Ok(PlicOp::InterruptPendingBit(source)) => Ok({
-                self.pending_bits[source as usize] = value as u32;
-            }),
-            Ok(PlicOp::EnableBitsForSourcesAndOnContext(source, context)) => Ok({
-                self.context[context as usize].enable_bits[source as usize] = value as u32;
-            }),
-            Ok(PlicOp::PriorityThresholdForContext(context)) => Ok({
-                self.context[context as usize].priority_threshold = value as u32;
-            }),
-            Ok(PlicOp::ClaimOrCompleteForContext(context)) => Ok({
-                self.context[context as usize].claim_or_complete = value as u32;
-            }),
-            Err(_) => Ok(()),
+        let reg = match parse_addr(addr) {
+            Ok(PlicOp::InterruptPriorityOfSource(source)) => {
+                self.source_priority.get_mut(source as usize)
+            }
+            Ok(PlicOp::InterruptPendingBit(word)) => self.pending_bits.get_mut(word as usize),
+            Ok(PlicOp::EnableBitsForSourcesAndOnContext(context, word)) => self
+                .context
+                .get_mut(context as usize)
+                .and_then(|c| c.enable_bits.get_mut(word as usize)),
+            Ok(PlicOp::PriorityThresholdForContext(context)) => self
+                .context
+                .get_mut(context as usize)
+                .map(|c| &mut c.priority_threshold),
+            // Writing the claimed ID back completes the interrupt.
+            // Loads can't change state, so the pending bit drops here rather than at the claim.
+            Ok(PlicOp::ClaimOrCompleteForContext(context)) => {
+                if let Some(c) = self.context.get_mut(context as usize) {
+                    if u64::from(c.claim_or_complete) == value {
+                        c.claim_or_complete = 0;
+                        let source = value as usize;
+                        if let Some(bits) = self.pending_bits.get_mut(source / 32) {
+                            *bits &= !(1 << (source % 32));
+                        }
+                    }
+                }
+                None
+            }
+            Err(_) => None,
+        };
+        if let Some(reg) = reg {
+            *reg = value as u32;
         }
+        Ok(())
     }
 }
 
 #[cfg(test)]
 mod test {
-    use crate::arch::riscv::bus::PLIC_BASE;
-
     #[test]
     fn test_parse_addr() {
         assert_eq!(
-            super::parse_addr(PLIC_BASE + 0x000FFC),
+            super::parse_addr(0x000FFC),
             Ok(super::PlicOp::InterruptPriorityOfSource(0x3FF))
         );
 
         assert_eq!(
-            super::parse_addr(PLIC_BASE + 0x002084),
-            Ok(super::PlicOp::EnableBitsForSourcesAndOnContext(0x1, 0x4))
+            super::parse_addr(0x002084),
+            Ok(super::PlicOp::EnableBitsForSourcesAndOnContext(0x1, 0x1))
         );
 
         assert_eq!(
-            super::parse_addr(PLIC_BASE + 0x201000),
+            super::parse_addr(0x201000),
             Ok(super::PlicOp::PriorityThresholdForContext(0x1))
         );
 
         assert_eq!(
-            super::parse_addr(PLIC_BASE + 0x201004),
+            super::parse_addr(0x201004),
             Ok(super::PlicOp::ClaimOrCompleteForContext(0x1))
         );
 
         assert_eq!(
-            super::parse_addr(PLIC_BASE + 0x3FFF004),
+            super::parse_addr(0x3FFF004),
             Ok(super::PlicOp::ClaimOrCompleteForContext(0x3DFF))
         );
     }
+
+    #[test]
+    fn test_source_count() {
+        use super::{Plic, INT_ENABLE_BITS_BASE, INT_PENDING_BASE, INT_PRIORITY_BASE};
+
+        let mut plic = Plic::new(64);
+        let priority = INT_PRIORITY_BASE + 40 * 4;
+        let pending = INT_PENDING_BASE + 4;
+        let enable = INT_ENABLE_BITS_BASE + 4;
+        plic.store(priority, 4, 7).unwrap();
+        plic.store(pending, 4, 1 << 8).unwrap();
+        plic.store(enable, 4, 1 << 8).unwrap();
+        assert_eq!(plic.load(priority, 4), Ok(7));
+        assert_eq!(plic.load(pending, 4), Ok(1 << 8));
+        assert_eq!(plic.load(enable, 4), Ok(1 << 8));
+        // context 1 has its own enable bits
+        assert_eq!(plic.load(enable + 0x80, 4), Ok(0));
+
+        // sources past the configured ones are hardwired to zero
+        let mut plic = Plic::new(32);
+        plic.store(priority, 4, 7).unwrap();
+        plic.store(enable, 4, 1 << 8).unwrap();
+        assert_eq!(plic.load(priority, 4), Ok(0));
+        assert_eq!(plic.load(enable, 4), Ok(0));
+    }
+
+    #[test]
+    fn test_enable_bits() {
+        use super::{Plic, INT_ENABLE_BITS_BASE};
+
+        // source 33 is bit 1 of the second word, here on context 1
+        let mut plic = Plic::new(64);
+        let enable = INT_ENABLE_BITS_BASE + 0x80 + 4;
+        plic.store(enable, 4, 1 << 1).unwrap();
+        assert_eq!(plic.load(enable, 4), Ok(1 << 1));
+        assert_eq!(plic.load(enable - 4, 4), Ok(0));
+        assert_eq!(plic.load(enable - 0x80, 4), Ok(0));
+    }
+
+    #[test]
+    fn test_trigger_priority() {
+        use super::{Plic, INT_CONTEXT_BASE, INT_ENABLE_BITS_BASE, INT_PRIORITY_BASE};
+        use crate::arch::riscv::cpu::MACHINE_MODE;
+
+        let mut plic = Plic::new(32);
+        let priority = INT_PRIORITY_BASE + 5 * 4;
+        plic.store(INT_ENABLE_BITS_BASE, 4, 1 << 5).unwrap();
+
+        // an enabled source of priority 0 never interrupts
+        plic.trigger(0, 5);
+        assert!(!plic.external_pending(0, MACHINE_MODE));
+
+        // nor does one at or below the threshold of the context
+        plic.store(priority, 4, 2).unwrap();
+        plic.store(INT_CONTEXT_BASE, 4, 2).unwrap();
+        plic.trigger(0, 5);
+        assert!(!plic.external_pending(0, MACHINE_MODE));
+
+        plic.store(priority, 4, 3).unwrap();
+        plic.trigger(0, 5);
+        assert!(plic.external_pending(0, MACHINE_MODE));
+    }
+
+    #[test]
+    fn test_pending_bits() {
+        use super::{Plic, INT_CONTEXT_BASE, INT_ENABLE_BITS_BASE, INT_PENDING_BASE};
+        use crate::arch::riscv::cpu::MACHINE_MODE;
+
+        let mut plic = Plic::new(64);
+        plic.store(33 * 4, 4, 1).unwrap();
+
+        // a source no context enables is still pending
+        plic.trigger(0, 33);
+        assert_eq!(plic.load(INT_PENDING_BASE + 4, 4), Ok(1 << 1));
+        assert!(!plic.external_pending(0, MACHINE_MODE));
+
+        // and completing it clears the bit
+        plic.store(INT_ENABLE_BITS_BASE + 4, 4, 1 << 1).unwrap();
+        plic.trigger(0, 33);
+        assert!(plic.external_pending(0, MACHINE_MODE));
+        plic.store(INT_CONTEXT_BASE + 4, 4, 33).unwrap();
+        assert_eq!(plic.load(INT_PENDING_BASE + 4, 4), Ok(0));
+    }
+
+    #[test]
+    fn test_external_pending() {
+        use super::{context_of, Plic, DEFAULT_SOURCE_COUNT, INT_CONTEXT_BASE};
+        use crate::arch::riscv::cpu::{MACHINE_MODE, SUPERVISOR_MODE};
+
+        assert_eq!(context_of(0, MACHINE_MODE), 0);
+        assert_eq!(context_of(0, SUPERVISOR_MODE), 1);
+        assert_eq!(context_of(3, SUPERVISOR_MODE), 7);
+
+        let mut plic = Plic::new(DEFAULT_SOURCE_COUNT);
+        plic.raise(1, SUPERVISOR_MODE, 10);
+        assert!(plic.external_pending(1, SUPERVISOR_MODE));
+        assert!(!plic.external_pending(1, MACHINE_MODE));
+        assert!(!plic.external_pending(0, SUPERVISOR_MODE));
+
+        // the handler claims the interrupt and completes it
+        let claim = INT_CONTEXT_BASE + 3 * 0x1000 + 4;
+        assert_eq!(plic.load(claim, 4), Ok(10));
+        plic.store(claim, 4, 10).unwrap();
+        assert!(!plic.external_pending(1, SUPERVISOR_MODE));
+    }
 }
@@ -0,0 +1,258 @@
+//! Seeded random programs for differential testing against another RV64IMAFDC model, such as
+//! spike.
+//!
+//! A program starts in M-mode at `DRAM_BASE` with every register zero but `gp`, which points at
+//! `SCRATCH_SIZE` bytes of zeroed memory at `SCRATCH_BASE`, and the FPU on. Its loads, stores and
+//! LR and SC only touch the scratch memory, it never branches or traps, and it ends with an `ebreak`.
+//! `dump` prints the final state in a line-oriented format that is easy to produce from another
+//! model.
+
+use std::fmt::Write;
+
+use crate::{bus::Bus, cpu::Cpu};
+
+use super::{
+    bus::DRAM_BASE,
+    cpu::{load_snippet, HaltReason, RV64Cpu},
+    csr::{FCSR, MASK_FS, MSTATUS},
+    decode::{decode, decode_compressed},
+    instruction::RiscvInst,
+    reg::GP,
+};
+
+/// Where the scratch memory of a program starts.
+pub const SCRATCH_BASE: u64 = DRAM_BASE + 0x10_0000;
+/// The size of the scratch memory, small enough for every offset to fit an immediate.
+pub const SCRATCH_SIZE: u64 = 0x800;
+
+/// Opcodes whose encodings only compute on registers, given a legal encoding.
+const COMPUTE_OPCODES: [u32; 11] = [
+    0b0010011, // OP-IMM
+    0b0011011, // OP-IMM-32
+    0b0110011, // OP
+    0b0111011, // OP-32
+    0b0110111, // LUI
+    0b0010111, // AUIPC
+    0b1010011, // OP-FP
+    0b1000011, // MADD
+    0b1000111, // MSUB
+    0b1001011, // NMSUB
+    0b1001111, // NMADD
+];
+
+/// Loads and stores as (opcode, funct3, size in bytes).
+const MEMORY_OPS: [(u32, u32, u64); 15] = [
+    (0b0000011, 0b000, 1), // lb
+    (0b0000011, 0b001, 2), // lh
+    (0b0000011, 0b010, 4), // lw
+    (0b0000011, 0b011, 8), // ld
+    (0b0000011, 0b100, 1), // lbu
+    (0b0000011, 0b101, 2), // lhu
+    (0b0000011, 0b110, 4), // lwu
+    (0b0000111, 0b010, 4), // flw
+    (0b0000111, 0b011, 8), // fld
+    (0b0100011, 0b000, 1), // sb
+    (0b0100011, 0b001, 2), // sh
+    (0b0100011, 0b010, 4), // sw
+    (0b0100011, 0b011, 8), // sd
+    (0b0100111, 0b010, 4), // fsw
+    (0b0100111, 0b011, 8), // fsd
+];
+
+/// The funct5 of LR and SC. The other AMOs decode but don't execute yet, so they are left out.
+const AMO_FUNCTS: [u32; 2] = [0b00010, 0b00011];
+
+/// A reproducible source of random programs: the same seed always gives the same program.
+pub struct ProgramGenerator {
+    state: u64,
+}
+
+impl ProgramGenerator {
+    pub fn new(seed: u64) -> Self {
+        Self { state: seed }
+    }
+
+    /// Generate `len` words of instructions, without the final `ebreak`. A word is either one
+    /// full-size instruction or two compressed ones.
+    pub fn generate(&mut self, len: usize) -> Vec<u32> {
+        (0..len)
+            .map(|_| match self.below(8) {
+                0 => self.compressed() | self.compressed() << 16,
+                1 | 2 => self.memory(),
+                3 => self.amo(),
+                _ => self.compute(),
+            })
+            .collect()
+    }
+
+    /// splitmix64, which is good enough for picking encodings and needs no dependency.
+    fn next(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    fn below(&mut self, n: u64) -> u64 {
+        self.next() % n
+    }
+
+    /// A destination register, which is never `gp`.
+    fn rd(&mut self) -> u32 {
+        loop {
+            let rd = self.below(32) as u32;
+            if rd != GP as u32 {
+                return rd;
+            }
+        }
+    }
+
+    /// A register or floating-point computation, found by filling the fields of an opcode at
+    /// random until the decoder accepts the encoding.
+    fn compute(&mut self) -> u32 {
+        loop {
+            let opcode = COMPUTE_OPCODES[self.below(COMPUTE_OPCODES.len() as u64) as usize];
+            let bits = (self.next() as u32 & !0xfff) | self.rd() << 7 | opcode;
+            // the reserved rounding modes trap, and no other floating-point funct3 uses them
+            let funct3 = (bits >> 12) & 0b111;
+            if opcode & 0b1000000 != 0 && (funct3 == 0b101 || funct3 == 0b110) {
+                continue;
+            }
+            if decode(bits) != RiscvInst::Illegal {
+                return bits;
+            }
+        }
+    }
+
+    /// An aligned load or store to the scratch memory.
+    fn memory(&mut self) -> u32 {
+        let (opcode, funct3, size) = MEMORY_OPS[self.below(MEMORY_OPS.len() as u64) as usize];
+        let offset = (self.below(SCRATCH_SIZE / size) * size) as u32;
+        let base = (GP as u32) << 15 | funct3 << 12 | opcode;
+        if opcode & 0b0100000 == 0 {
+            offset << 20 | self.rd() << 7 | base
+        } else {
+            let rs2 = self.below(32) as u32;
+            (offset >> 5) << 25 | rs2 << 20 | (offset & 0x1f) << 7 | base
+        }
+    }
+
+    /// An LR or SC on the start of the scratch memory.
+    fn amo(&mut self) -> u32 {
+        let funct5 = AMO_FUNCTS[self.below(AMO_FUNCTS.len() as u64) as usize];
+        let width = 0b010 + self.below(2) as u32;
+        let aqrl = self.below(4) as u32;
+        let rs2 = if funct5 == 0b00010 {
+            0
+        } else {
+            self.below(32) as u32
+        };
+        funct5 << 27
+            | aqrl << 25
+            | rs2 << 20
+            | (GP as u32) << 15
+            | width << 12
+            | self.rd() << 7
+            | 0b0101111
+    }
+
+    /// A compressed integer computation, found by drawing encodings until one decodes to an
+    /// instruction that only computes on registers other than `gp`.
+    fn compressed(&mut self) -> u32 {
+        loop {
+            let bits = (self.next() as u16 & !0b11) | (1 + self.below(2) as u16);
+            let inst = decode_compressed(bits);
+            let computes = matches!(
+                inst,
+                RiscvInst::Addi { .. }
+                    | RiscvInst::Addiw { .. }
+                    | RiscvInst::Slli { .. }
+                    | RiscvInst::Srli { .. }
+                    | RiscvInst::Srai { .. }
+                    | RiscvInst::Andi { .. }
+                    | RiscvInst::Lui { .. }
+                    | RiscvInst::Add { .. }
+                    | RiscvInst::Addw { .. }
+                    | RiscvInst::Sub { .. }
+                    | RiscvInst::Subw { .. }
+                    | RiscvInst::Xor { .. }
+                    | RiscvInst::Or { .. }
+                    | RiscvInst::And { .. }
+            );
+            if computes && inst.regs().0 != GP as u8 {
+                return bits.into();
+            }
+        }
+    }
+}
+
+/// Run `program` from the state described in the module documentation until its final `ebreak`.
+pub fn run(program: &[u32]) -> (RV64Cpu, HaltReason) {
+    let mut cpu = load_snippet(program);
+    cpu.x[GP] = SCRATCH_BASE;
+    cpu.csr.store(MSTATUS, MASK_FS);
+    let halt = cpu.run();
+    (cpu, halt)
+}
+
+/// Print the pc, the integer and floating-point registers, `fcsr` and the scratch memory, one
+/// value per line as `name value` in hex. Floating-point registers are printed as the raw 64 bits
+/// holding them, NaN-boxed for single precision, and memory as `address doubleword` lines.
+pub fn dump(cpu: &RV64Cpu) -> String {
+    let mut out = String::new();
+    writeln!(out, "pc {:#018x}", cpu.pc).unwrap();
+    for (i, x) in cpu.x.iter().enumerate() {
+        writeln!(out, "x{} {:#018x}", i, x).unwrap();
+    }
+    for (i, f) in cpu.f.iter().enumerate() {
+        writeln!(out, "f{} {:#018x}", i, f.to_bits()).unwrap();
+    }
+    writeln!(out, "fcsr {:#x}", cpu.csr.load(FCSR).data).unwrap();
+    for addr in (SCRATCH_BASE..SCRATCH_BASE + SCRATCH_SIZE).step_by(8) {
+        let data = cpu
+            .bus
+            .load(addr, 8)
+            .expect("the scratch memory is in DRAM");
+        writeln!(out, "{:#x} {:#018x}", addr, data).unwrap();
+    }
+    out
+}
+
+#[cfg(test)]
+mod test {
+    use super::{dump, run, ProgramGenerator, SCRATCH_BASE};
+    use crate::arch::riscv::{
+        bus::DRAM_BASE,
+        cpu::HaltReason,
+        decode::{decode, decode_compressed},
+        instruction::RiscvInst,
+    };
+
+    #[test]
+    fn test_same_seed() {
+        let program = ProgramGenerator::new(42).generate(500);
+        assert_eq!(program, ProgramGenerator::new(42).generate(500));
+        assert_ne!(program, ProgramGenerator::new(43).generate(500));
+
+        for word in &program {
+            let legal = if word & 0b11 == 0b11 {
+                decode(*word) != RiscvInst::Illegal
+            } else {
+                decode_compressed(*word as u16) != RiscvInst::Illegal
+                    && decode_compressed((word >> 16) as u16) != RiscvInst::Illegal
+            };
+            assert!(legal, "{:#010x}", word);
+        }
+
+        // the program runs to its ebreak and ends in the same state every time
+        let (cpu, halt) = run(&program);
+        let end = DRAM_BASE + 4 * program.len() as u64;
+        assert_eq!(halt, HaltReason::Breakpoint(end));
+        let state = dump(&cpu);
+        assert_eq!(state, dump(&run(&program).0));
+        assert!(state.starts_with(&format!("pc {:#018x}\nx0 0x0000000000000000\n", end)));
+        assert!(state.contains(&format!("\nx3 {:#018x}\n", SCRATCH_BASE)));
+        assert_eq!(state.lines().count(), 1 + 32 + 32 + 1 + 256);
+    }
+}
@@ -0,0 +1,46 @@
+//! A proxy kernel, which runs user-mode Linux programs without an OS by emulating their system
+//! calls on the host.
+//!
+//! A system call is an `ecall` from U-mode with the call number in `a7` and its arguments in
+//! `a0`-`a5`. The result, or a negated errno, is returned in `a0`.
+
+// System call numbers, from the generic Linux table RISC-V uses.
+pub(crate) const SYS_FSTAT: u64 = 80;
+pub(crate) const SYS_WRITE: u64 = 64;
+pub(crate) const SYS_EXIT: u64 = 93;
+pub(crate) const SYS_EXIT_GROUP: u64 = 94;
+pub(crate) const SYS_BRK: u64 = 214;
+
+pub(crate) const STDIN: u64 = 0;
+pub(crate) const STDOUT: u64 = 1;
+pub(crate) const STDERR: u64 = 2;
+
+pub(crate) const EBADF: u64 = 9;
+pub(crate) const EFAULT: u64 = 14;
+pub(crate) const ENOSYS: u64 = 38;
+
+/// Size of `struct stat` on RV64.
+pub(crate) const STAT_SIZE: u64 = 128;
+/// Offset of `st_mode` in `struct stat`.
+pub(crate) const STAT_MODE: u64 = 16;
+/// Offset of `st_blksize` in `struct stat`.
+pub(crate) const STAT_BLKSIZE: u64 = 56;
+/// `st_mode` of the standard streams, a character device writable by its owner.
+pub(crate) const S_IFCHR_RW: u64 = 0o020620;
+
+/// Space kept free for the stack below the top of DRAM, which the heap may not grow into.
+pub(crate) const STACK_SIZE: u64 = 8 * 1024 * 1024;
+
+/// Bytes of the initial stack holding `argc`, the empty `argv` and `envp` and the auxiliary
+/// vector terminator.
+pub(crate) const INITIAL_STACK: u64 = 64;
+
+/// The emulated process state.
+pub(crate) struct ProxyKernel {
+    /// Where writes to stdout go.
+    pub stdout: Box<dyn std::io::Write>,
+    /// The lowest address the program break may take.
+    pub brk_start: u64,
+    /// The current program break.
+    pub brk: u64,
+}
@@ -0,0 +1,21 @@
+//! RISC-V semihosting, which lets bare-metal programs use the host for basic I/O.
+//!
+//! A semihosting call is an `ebreak` surrounded by two marker instructions. `a0` holds the
+//! operation and `a1` its parameter, usually a pointer to a block of XLEN-sized fields. The result
+//! is returned in `a0`.
+
+/// `slli x0, x0, 0x1f`, right before the `ebreak`.
+pub(crate) const ENTRY_MARKER: u32 = 0x01f0_1013;
+/// `srai x0, x0, 7`, right after the `ebreak`.
+pub(crate) const EXIT_MARKER: u32 = 0x4070_5013;
+
+// Operation numbers, shared with the Arm semihosting specification.
+/// Write the character `a1` points to.
+pub(crate) const SYS_WRITEC: u64 = 0x03;
+/// Write the NUL-terminated string `a1` points to.
+pub(crate) const SYS_WRITE0: u64 = 0x04;
+/// Stop the program, with `a1` pointing to the reason and the exit code.
+pub(crate) const SYS_EXIT: u64 = 0x18;
+
+/// The reason `SYS_EXIT` uses for a normal program exit.
+pub(crate) const ADP_STOPPED_APPLICATION_EXIT: u64 = 0x20026;
@@ -0,0 +1,48 @@
+use super::exception::Exception;
+
+/// A SiFive test finisher compatible device that lets the guest power off the machine.
+pub struct Syscon {
+    exit_code: Option<u32>,
+}
+
+// Register offsets relative to `SYSCON_BASE`.
+pub(crate) const SYSCON_FINISHER: u64 = 0;
+
+// Values written to the finisher register. The upper 16 bits of a failure hold the exit code.
+pub(crate) const FINISHER_FAIL: u64 = 0x3333;
+pub(crate) const FINISHER_PASS: u64 = 0x5555;
+
+impl Syscon {
+    pub fn new() -> Self {
+        Self { exit_code: None }
+    }
+
+    /// The exit code requested by the guest, if it has powered off.
+    pub fn exit_code(&self) -> Option<u32> {
+        self.exit_code
+    }
+
+    /// Power off the machine as if the guest had written to the finisher.
+    pub fn power_off(&mut self, code: u32) {
+        self.exit_code = Some(code);
+    }
+
+    pub fn load(&self, addr: u64, size: u64) -> Result<u64, Exception> {
+        match (addr, size) {
+            (SYSCON_FINISHER, 4) => Ok(0),
+            _ => Err(Exception::LoadAccessFault(addr)),
+        }
+    }
+
+    pub fn store(&mut self, addr: u64, size: u64, value: u64) -> Result<(), Exception> {
+        if addr != SYSCON_FINISHER || size != 4 {
+            return Err(Exception::StoreAMOAccessFault(addr));
+        }
+        match value & 0xffff {
+            FINISHER_PASS => self.exit_code = Some(0),
+            FINISHER_FAIL => self.exit_code = Some((value >> 16) as u32 & 0xffff),
+            _ => {}
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,226 @@
+use super::instruction::RiscvInst;
+
+/// Cycles taken by each class of instruction, used to advance `mcycle` and the core clock.
+/// The defaults describe a simple in-order core; override the fields to model another one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InstLatency {
+    pub alu: u64,
+    pub branch: u64,
+    pub load: u64,
+    pub store: u64,
+    pub atomic: u64,
+    pub mul: u64,
+    pub div: u64,
+    pub fp: u64,
+    pub fp_div: u64,
+    pub fp_sqrt: u64,
+    pub system: u64,
+}
+
+impl Default for InstLatency {
+    fn default() -> Self {
+        Self {
+            alu: 1,
+            branch: 1,
+            load: 2,
+            store: 1,
+            atomic: 4,
+            mul: 3,
+            div: 20,
+            fp: 4,
+            fp_div: 20,
+            fp_sqrt: 25,
+            system: 1,
+        }
+    }
+}
+
+/// Cycles loads and stores spend in the memory hierarchy, on top of their `InstLatency`. A
+/// direct-mapped cache of `lines` lines of `line_size` bytes tells hits from misses. A TLB miss
+/// costs a `miss` for every page table entry its walk loads, whatever the access. The default
+/// adds nothing, and with no lines every access is a hit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemLatency {
+    pub hit: u64,
+    pub miss: u64,
+    pub line_size: u64,
+    pub lines: usize,
+}
+
+impl Default for MemLatency {
+    fn default() -> Self {
+        Self {
+            hit: 0,
+            miss: 0,
+            line_size: 64,
+            lines: 0,
+        }
+    }
+}
+
+/// The state behind `MemLatency`: which line each cache slot holds.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct MemTiming {
+    latency: MemLatency,
+    tags: Vec<Option<u64>>,
+}
+
+impl MemTiming {
+    /// Panics if `latency` has a zero line size, which no address could be split into.
+    pub fn new(latency: MemLatency) -> Self {
+        assert!(
+            latency.line_size != 0,
+            "the cache line size must not be zero"
+        );
+        Self {
+            latency,
+            tags: vec![None; latency.lines],
+        }
+    }
+
+    /// Get the number of cycles each page table load of a TLB miss takes.
+    pub fn walk_latency(&self) -> u64 {
+        self.latency.miss
+    }
+
+    /// Get the number of cycles an access to the physical address `addr` spends in memory, and
+    /// bring its line into the cache.
+    pub fn access(&mut self, addr: u64) -> u64 {
+        if self.tags.is_empty() {
+            return self.latency.hit;
+        }
+        let line = addr / self.latency.line_size;
+        let slot = &mut self.tags[line as usize % self.latency.lines];
+        if *slot == Some(line) {
+            self.latency.hit
+        } else {
+            *slot = Some(line);
+            self.latency.miss
+        }
+    }
+}
+
+impl InstLatency {
+    /// Get the number of cycles `inst` takes to execute.
+    pub fn cycles(&self, inst: &RiscvInst) -> u64 {
+        match inst {
+            RiscvInst::Beq { .. }
+            | RiscvInst::Bne { .. }
+            | RiscvInst::Blt { .. }
+            | RiscvInst::Bge { .. }
+            | RiscvInst::Bltu { .. }
+            | RiscvInst::Bgeu { .. }
+            | RiscvInst::Jal { .. }
+            | RiscvInst::Jalr { .. } => self.branch,
+
+            RiscvInst::Lb { .. }
+            | RiscvInst::Lh { .. }
+            | RiscvInst::Lw { .. }
+            | RiscvInst::Ld { .. }
+            | RiscvInst::Lbu { .. }
+            | RiscvInst::Lhu { .. }
+            | RiscvInst::Lwu { .. }
+            | RiscvInst::Flw { .. }
+            | RiscvInst::Fld { .. } => self.load,
+
+            RiscvInst::Sb { .. }
+            | RiscvInst::Sh { .. }
+            | RiscvInst::Sw { .. }
+            | RiscvInst::Sd { .. }
+            | RiscvInst::Fsw { .. }
+            | RiscvInst::Fsd { .. }
+            | RiscvInst::CboZero { .. } => self.store,
+
+            RiscvInst::LrW { .. }
+            | RiscvInst::LrD { .. }
+            | RiscvInst::ScW { .. }
+            | RiscvInst::ScD { .. }
+            | RiscvInst::AmoswapW { .. }
+            | RiscvInst::AmoswapD { .. }
+            | RiscvInst::AmoaddW { .. }
+            | RiscvInst::AmoaddD { .. }
+            | RiscvInst::AmoxorW { .. }
+            | RiscvInst::AmoxorD { .. }
+            | RiscvInst::AmoandW { .. }
+            | RiscvInst::AmoandD { .. }
+            | RiscvInst::AmoorW { .. }
+            | RiscvInst::AmoorD { .. }
+            | RiscvInst::AmominW { .. }
+            | RiscvInst::AmominD { .. }
+            | RiscvInst::AmomaxW { .. }
+            | RiscvInst::AmomaxD { .. }
+            | RiscvInst::AmominuW { .. }
+            | RiscvInst::AmominuD { .. }
+            | RiscvInst::AmomaxuW { .. }
+            | RiscvInst::AmomaxuD { .. } => self.atomic,
+
+            RiscvInst::Mul { .. }
+            | RiscvInst::Mulh { .. }
+            | RiscvInst::Mulhsu { .. }
+            | RiscvInst::Mulhu { .. }
+            | RiscvInst::Mulw { .. } => self.mul,
+
+            RiscvInst::Div { .. }
+            | RiscvInst::Divu { .. }
+            | RiscvInst::Rem { .. }
+            | RiscvInst::Remu { .. }
+            | RiscvInst::Divw { .. }
+            | RiscvInst::Divuw { .. }
+            | RiscvInst::Remw { .. }
+            | RiscvInst::Remuw { .. } => self.div,
+
+            RiscvInst::FdivS { .. } | RiscvInst::FdivD { .. } => self.fp_div,
+            RiscvInst::FsqrtS { .. } | RiscvInst::FsqrtD { .. } => self.fp_sqrt,
+
+            RiscvInst::FaddS { .. }
+            | RiscvInst::FsubS { .. }
+            | RiscvInst::FmulS { .. }
+            | RiscvInst::FmaddS { .. }
+            | RiscvInst::FmsubS { .. }
+            | RiscvInst::FnmsubS { .. }
+            | RiscvInst::FnmaddS { .. }
+            | RiscvInst::FaddD { .. }
+            | RiscvInst::FsubD { .. }
+            | RiscvInst::FmulD { .. }
+            | RiscvInst::FmaddD { .. }
+            | RiscvInst::FmsubD { .. }
+            | RiscvInst::FnmsubD { .. }
+            | RiscvInst::FnmaddD { .. }
+            | RiscvInst::FcvtWS { .. }
+            | RiscvInst::FcvtWuS { .. }
+            | RiscvInst::FcvtLS { .. }
+            | RiscvInst::FcvtLuS { .. }
+            | RiscvInst::FcvtSW { .. }
+            | RiscvInst::FcvtSWu { .. }
+            | RiscvInst::FcvtSL { .. }
+            | RiscvInst::FcvtSLu { .. }
+            | RiscvInst::FcvtSD { .. }
+            | RiscvInst::FcvtDS { .. }
+            | RiscvInst::FcvtWD { .. }
+            | RiscvInst::FcvtWuD { .. }
+            | RiscvInst::FcvtLD { .. }
+            | RiscvInst::FcvtLuD { .. }
+            | RiscvInst::FcvtDW { .. }
+            | RiscvInst::FcvtDWu { .. }
+            | RiscvInst::FcvtDL { .. }
+            | RiscvInst::FcvtDLu { .. } => self.fp,
+
+            RiscvInst::Ecall
+            | RiscvInst::Ebreak
+            | RiscvInst::Mret
+            | RiscvInst::Sret
+            | RiscvInst::Wfi
+            | RiscvInst::SfenceVma { .. }
+            | RiscvInst::Fence
+            | RiscvInst::FenceI
+            | RiscvInst::Csrrw { .. }
+            | RiscvInst::Csrrs { .. }
+            | RiscvInst::Csrrc { .. }
+            | RiscvInst::Csrrwi { .. }
+            | RiscvInst::Csrrsi { .. }
+            | RiscvInst::Csrrci { .. } => self.system,
+
+            _ => self.alu,
+        }
+    }
+}
@@ -14,6 +14,8 @@ pub(crate) struct Uart {
     uart: Arc<(Mutex<[u8; UART_SIZE as usize]>, Condvar)>,
     /// Bit if an interrupt happens.
     interrupt: Arc<AtomicBool>,
+    /// Where transmitted bytes go, stdout unless redirected.
+    output: Box<dyn Write>,
 }
 
 // uart interrupt request
@@ -49,11 +51,29 @@ impl Uart {
         // receive part
         let read_uart = Arc::clone(&uart);
         let read_interrupt = Arc::clone(&interrupt);
+        thread::spawn(move || Self::receive(io::stdin(), &read_uart, &read_interrupt));
+
+        Self {
+            uart,
+            interrupt,
+            output: Box::new(io::stdout()),
+        }
+    }
+
+    /// Move bytes from `input` into the receive holding register one at a time, until `input`
+    /// reaches EOF.
+    fn receive(
+        mut input: impl Read,
+        uart: &(Mutex<[u8; UART_SIZE as usize]>, Condvar),
+        interrupt: &AtomicBool,
+    ) {
         let mut byte = [0];
-        thread::spawn(move || loop {
-            match io::stdin().read(&mut byte) {
+        loop {
+            match input.read(&mut byte) {
+                // stdin is closed, nothing more will ever be received.
+                Ok(0) => break,
                 Ok(_) => {
-                    let (uart, cvar) = &*read_uart;
+                    let (uart, cvar) = uart;
                     let mut array = uart.lock().unwrap();
                     // if data have been received but not yet be transferred.
                     // this thread wait for it to be transferred.
@@ -62,14 +82,23 @@ impl Uart {
                     }
                     // data have been transferred, so receive next one.
                     array[UART_RHR as usize] = byte[0];
-                    read_interrupt.store(true, Ordering::Release);
+                    interrupt.store(true, Ordering::Release);
                     array[UART_LSR as usize] |= MASK_UART_LSR_RX;
                 }
                 Err(e) => println!("{}", e),
             }
-        });
+        }
+    }
+
+    /// Send transmitted bytes to `output` instead of stdout.
+    pub fn set_output(&mut self, output: Box<dyn Write>) {
+        self.output = output;
+    }
 
-        Self { uart, interrupt }
+    /// Flag an interrupt as if a byte had arrived.
+    #[cfg(test)]
+    pub(crate) fn set_interrupting(&self) {
+        self.interrupt.store(true, Ordering::Release);
     }
 
     /// Return true if an interrupt is pending. Clear the interrupt flag by swapping a value.
@@ -77,8 +106,14 @@ impl Uart {
         self.interrupt.swap(false, Ordering::Acquire)
     }
 
-    pub fn load(&mut self, addr: u64, size: u64) -> Result<u64, Exception> {
-        if size != 1 {
+    /// The registers are a byte wide. Drivers that access them with 16- or 32-bit loads and stores
+    /// get the register at the address, zero-extended, and write its low byte.
+    fn is_valid_access(size: u64) -> bool {
+        matches!(size, 1 | 2 | 4)
+    }
+
+    pub fn load(&self, addr: u64, size: u64) -> Result<u64, Exception> {
+        if !Self::is_valid_access(size) {
             return Err(Exception::LoadAccessFault(addr));
         }
         let (uart, cvar) = &*self.uart;
@@ -96,7 +131,7 @@ impl Uart {
     }
 
     pub fn store(&mut self, addr: u64, size: u64, value: u64) -> Result<(), Exception> {
-        if size != 1 {
+        if !Self::is_valid_access(size) {
             return Err(Exception::StoreAMOAccessFault(addr));
         }
         let (uart, _) = &*self.uart;
@@ -104,8 +139,11 @@ impl Uart {
         let index = addr;
         match index {
             UART_THR => {
-                print!("{}", value as u8 as char);
-                io::stdout().flush().unwrap();
+                // A guest can't do anything about a host write error, so drop the byte.
+                let _ = self
+                    .output
+                    .write_all(&[value as u8])
+                    .and_then(|_| self.output.flush());
             }
             _ => {
                 array[index as usize] = value as u8;
@@ -114,3 +152,20 @@ impl Uart {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_receive_stops_at_eof() {
+        let uart = (Mutex::new([0; UART_SIZE as usize]), Condvar::new());
+        let interrupt = AtomicBool::new(false);
+        // returns instead of spinning once the input is exhausted
+        Uart::receive(&b"a"[..], &uart, &interrupt);
+        assert!(interrupt.load(Ordering::Acquire));
+        let array = uart.0.lock().unwrap();
+        assert_eq!(array[UART_LSR as usize], MASK_UART_LSR_RX);
+        assert_eq!(array[UART_RHR as usize], b'a');
+    }
+}
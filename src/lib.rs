@@ -0,0 +1,9 @@
+#[allow(dead_code)]
+pub mod arch;
+pub mod bus;
+pub mod cpu;
+pub mod loader;
+pub mod mem;
+pub mod util;
+
+pub use arch::riscv::decode::disassemble;
@@ -0,0 +1,156 @@
+use std::{error::Error, fmt};
+
+use goblin::elf::{header::EM_RISCV, program_header::PT_LOAD, Elf};
+
+use crate::arch::riscv::cpu::RV64Cpu;
+
+/// Errors raised while validating or loading an executable.
+#[derive(Debug, PartialEq, Eq)]
+pub enum LoadError {
+    /// The ELF targets another architecture, holding its `e_machine`.
+    UnsupportedMachine(u16),
+    /// The ELF class does not match the XLEN of the core.
+    ClassMismatch { expected: u32, found: u32 },
+    /// A segment extends past the end of the file.
+    TruncatedSegment(u64),
+    /// A segment cannot be placed in memory, holding its physical address.
+    SegmentOutOfRange(u64),
+    /// The symbol table has no symbol with this name.
+    UndefinedSymbol(String),
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LoadError::UnsupportedMachine(machine) => write!(
+                f,
+                "unsupported machine type {} ({}), expected RISC-V",
+                machine,
+                goblin::elf::header::machine_to_str(*machine)
+            ),
+            LoadError::ClassMismatch { expected, found } => write!(
+                f,
+                "ELF class mismatch: the core is RV{} but the file is {}-bit",
+                expected, found
+            ),
+            LoadError::TruncatedSegment(offset) => {
+                write!(f, "segment at file offset {:#x} is truncated", offset)
+            }
+            LoadError::SegmentOutOfRange(addr) => {
+                write!(f, "segment at {:#x} is outside of memory", addr)
+            }
+            LoadError::UndefinedSymbol(name) => write!(f, "undefined symbol `{}`", name),
+        }
+    }
+}
+
+impl Error for LoadError {}
+
+/// Check that the ELF can be executed on a RISC-V core with the given XLEN.
+pub fn check_elf(elf: &Elf, xlen: u32) -> Result<(), LoadError> {
+    if elf.header.e_machine != EM_RISCV {
+        return Err(LoadError::UnsupportedMachine(elf.header.e_machine));
+    }
+    let class = if elf.is_64 { 64 } else { 32 };
+    if class != xlen {
+        return Err(LoadError::ClassMismatch {
+            expected: xlen,
+            found: class,
+        });
+    }
+    Ok(())
+}
+
+/// Copy the loadable segments of the ELF into memory and point the pc at its entry.
+pub fn load_elf(cpu: &mut RV64Cpu, elf: &Elf, buffer: &[u8]) -> Result<(), LoadError> {
+    for ph in elf.program_headers.iter().filter(|ph| ph.p_type == PT_LOAD) {
+        let data = buffer
+            .get(ph.file_range())
+            .ok_or(LoadError::TruncatedSegment(ph.p_offset))?;
+        // The rest of the segment is .bss, which DRAM may not already hold zeros for.
+        let bss = vec![0; ph.p_memsz.saturating_sub(ph.p_filesz) as usize];
+        cpu.load_at(ph.p_paddr, data)
+            .and_then(|_| cpu.load_at(ph.p_paddr + data.len() as u64, &bss))
+            .map_err(|_| LoadError::SegmentOutOfRange(ph.p_paddr))?;
+    }
+    cpu.pc = elf.entry;
+    Ok(())
+}
+
+/// The first address past every loadable segment, where the program break of a user process
+/// starts.
+pub fn image_end(elf: &Elf) -> u64 {
+    elf.program_headers
+        .iter()
+        .filter(|ph| ph.p_type == PT_LOAD)
+        .map(|ph| ph.p_paddr + ph.p_memsz)
+        .max()
+        .unwrap_or(0)
+}
+
+/// Look up the address of the symbol `name` in the ELF symbol table.
+pub fn find_symbol(elf: &Elf, name: &str) -> Result<u64, LoadError> {
+    elf.syms
+        .iter()
+        .find(|sym| sym.st_shndx != 0 && elf.strtab.get_at(sym.st_name) == Some(name))
+        .map(|sym| sym.st_value)
+        .ok_or_else(|| LoadError::UndefinedSymbol(name.to_string()))
+}
+
+#[cfg(test)]
+mod test {
+    use goblin::elf::{
+        header::{EM_RISCV, EM_X86_64},
+        Elf,
+    };
+
+    use super::{check_elf, LoadError};
+
+    /// Build an ELF header without any program or section headers.
+    fn header(is_64: bool, machine: u16) -> Vec<u8> {
+        let mut data = vec![0x7f, b'E', b'L', b'F', if is_64 { 2 } else { 1 }, 1, 1];
+        data.resize(16, 0);
+        data.extend_from_slice(&2u16.to_le_bytes()); // e_type
+        data.extend_from_slice(&machine.to_le_bytes());
+        data.extend_from_slice(&1u32.to_le_bytes()); // e_version
+        if is_64 {
+            data.extend_from_slice(&[0; 24]); // e_entry, e_phoff, e_shoff
+            data.extend_from_slice(&0u32.to_le_bytes()); // e_flags
+            for field in [64u16, 56, 0, 64, 0, 0] {
+                data.extend_from_slice(&field.to_le_bytes());
+            }
+        } else {
+            data.extend_from_slice(&[0; 12]); // e_entry, e_phoff, e_shoff
+            data.extend_from_slice(&0u32.to_le_bytes()); // e_flags
+            for field in [52u16, 32, 0, 40, 0, 0] {
+                data.extend_from_slice(&field.to_le_bytes());
+            }
+        }
+        data
+    }
+
+    #[test]
+    fn test_check_elf() {
+        let data = header(true, EM_RISCV);
+        assert_eq!(check_elf(&Elf::parse(&data).unwrap(), 64), Ok(()));
+
+        let data = header(true, EM_X86_64);
+        let err = check_elf(&Elf::parse(&data).unwrap(), 64).unwrap_err();
+        assert_eq!(err, LoadError::UnsupportedMachine(EM_X86_64));
+        assert!(err.to_string().contains("X86_64"));
+
+        let data = header(false, EM_RISCV);
+        let err = check_elf(&Elf::parse(&data).unwrap(), 64).unwrap_err();
+        assert_eq!(
+            err,
+            LoadError::ClassMismatch {
+                expected: 64,
+                found: 32
+            }
+        );
+        assert_eq!(
+            err.to_string(),
+            "ELF class mismatch: the core is RV64 but the file is 32-bit"
+        );
+    }
+}
@@ -1,14 +1,26 @@
-#[allow(dead_code)]
-pub mod arch;
-pub mod bus;
-pub mod cpu;
-pub mod mem;
-pub mod util;
-
-use clap::{arg, command, Parser};
-use env_logger::Env;
-use goblin::Object;
-use std::{fs, path::Path};
+use clap::Parser;
+use env_logger::{Env, Target};
+use goblin::{
+    elf::{section_header::SHF_EXECINSTR, Elf},
+    Object,
+};
+use remu::{
+    arch::riscv::{
+        cpu::{HaltReason, RV64Cpu},
+        debugger,
+        isa::Isa,
+    },
+    cpu::Cpu,
+    disassemble, loader,
+    util::parse_int,
+};
+use std::{
+    env,
+    fs::{self, File},
+    io::{self, BufWriter, IsTerminal, Write},
+    path::{Path, PathBuf},
+    process,
+};
 
 #[macro_use]
 extern crate log;
@@ -20,13 +32,165 @@ struct Args {
     /// File name to execute
     #[arg()]
     file: String,
+
+    /// Write the DRAM contents to this file when the program halts
+    #[arg(long, value_name = "FILE")]
+    memory_dump: Option<PathBuf>,
+
+    /// Only dump `len` bytes starting at physical address `start`
+    #[arg(long, value_name = "START:LEN", value_parser = parse_range, requires = "memory_dump")]
+    dump_range: Option<(u64, u64)>,
+
+    /// Start an interactive debugger instead of running to completion
+    #[arg(long)]
+    interactive: bool,
+
+    /// Print the disassembly of the executable sections instead of running
+    #[arg(long, conflicts_with = "interactive")]
+    disassemble: bool,
+
+    /// Never color the disassembly, which is otherwise colored on a terminal
+    #[arg(long)]
+    no_color: bool,
+
+    /// Log every load and store that hits a device region
+    #[arg(long)]
+    mmio_trace: bool,
+
+    /// Trap to the guest's handler on illegal instructions instead of halting
+    #[arg(long)]
+    trap_illegal: bool,
+
+    /// Stop at an `ebreak` instead of trapping to the guest's handler
+    #[arg(long)]
+    halt_on_ebreak: bool,
+
+    /// Print a summary of the run to stderr when the program halts
+    #[arg(short, long)]
+    verbose: bool,
+
+    /// Handle semihosting calls, writing their output to stdout
+    #[arg(long)]
+    semihosting: bool,
+
+    /// Run the program as a Linux user process, emulating its system calls
+    #[arg(long, conflicts_with = "bootrom")]
+    proxy: bool,
+
+    /// Only enable these extensions, e.g. `rv64imac`
+    #[arg(long, value_name = "ISA", default_value = "rv64imafdc")]
+    isa: Isa,
+
+    /// Start from a boot ROM at the reset vector 0x1000, which enters the program with the hart ID
+    /// in a0. Without a FILE the built-in one is used, otherwise the raw image in FILE
+    #[arg(long, value_name = "FILE", num_args = 0..=1, require_equals = true)]
+    bootrom: Option<Option<PathBuf>>,
+
+    /// Fill DRAM with BYTE, 0xaa by default, instead of zeros so uninitialized reads stand out
+    #[arg(
+        long,
+        value_name = "BYTE",
+        value_parser = parse_byte,
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "0xaa"
+    )]
+    poison: Option<u8>,
+
+    /// Advance mtime once every N instructions
+    #[arg(long, value_name = "N", value_parser = parse_int, default_value = "1")]
+    insts_per_tick: u64,
+
+    /// Write the guest's UART output to this file instead of stdout
+    #[arg(long, value_name = "FILE")]
+    uart_output: Option<PathBuf>,
+
+    /// Keep the last N instructions and log them when the program halts on a fatal exception
+    #[arg(long, value_name = "N", default_value = "0")]
+    trace_len: usize,
+
+    /// Halt after N traps in a row without an instruction retiring in between
+    #[arg(long, value_name = "N", value_parser = parse_int)]
+    trap_storm_limit: Option<u64>,
+
+    /// Execute N instructions, then dump the registers and exit
+    #[arg(long, value_name = "N", value_parser = parse_int, conflicts_with = "interactive")]
+    count_exit: Option<u64>,
+
+    /// Start at this symbol instead of the ELF entry point
+    #[arg(long, value_name = "SYMBOL")]
+    entry: Option<String>,
+}
+
+fn parse_range(s: &str) -> Result<(u64, u64), String> {
+    let (start, len) = s
+        .split_once(':')
+        .ok_or_else(|| format!("expected START:LEN, found `{}`", s))?;
+    Ok((parse_int(start)?, parse_int(len)?))
+}
+
+fn parse_byte(s: &str) -> Result<u8, String> {
+    let value = parse_int(s)?;
+    u8::try_from(value).map_err(|_| format!("{:#x} does not fit in a byte", value))
+}
+
+/// The exit status of the emulator after the guest halted for `reason`: the low byte of the
+/// guest's own exit code, which is all the OS keeps, or 1 if it crashed.
+fn exit_status(reason: HaltReason) -> i32 {
+    match reason {
+        HaltReason::Exit(0) => 0,
+        // A failure whose low byte is zero must not pass for a success.
+        HaltReason::Exit(code) => match code & 0xff {
+            0 => 1,
+            low => low as i32,
+        },
+        HaltReason::Fatal(_) | HaltReason::TrapStorm => 1,
+        HaltReason::Breakpoint(_) => 0,
+    }
+}
+
+/// Write the disassembly of every executable section of the ELF to `out`, with ANSI colors if
+/// `color` is set.
+fn disassemble_elf(elf: &Elf, buffer: &[u8], out: &mut impl Write, color: bool) -> io::Result<()> {
+    for sh in &elf.section_headers {
+        if sh.sh_flags & u64::from(SHF_EXECINSTR) == 0 {
+            continue;
+        }
+        let Some(bytes) = sh.file_range().and_then(|range| buffer.get(range)) else {
+            continue;
+        };
+        let name = elf.shdr_strtab.get_at(sh.sh_name).unwrap_or("?");
+        writeln!(out, "\nDisassembly of section {}:\n", name)?;
+        for (pc, inst, bits) in disassemble(bytes, sh.sh_addr) {
+            if color {
+                writeln!(out, "{}", inst.pretty_print_colored(pc, bits))?;
+            } else {
+                writeln!(out, "{}", inst.pretty_print(pc, bits))?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Write the DRAM contents in `range`, or the whole DRAM, to `path`.
+fn dump_memory(cpu: &RV64Cpu, path: &Path, range: Option<(u64, u64)>) -> io::Result<()> {
+    let mut out = BufWriter::new(File::create(path)?);
+    cpu.dump_dram(&mut out, range)
 }
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
     let args = Args::parse();
 
-    // Setup logging to output all logs
-    env_logger::Builder::from_env(Env::default().default_filter_or("info")).init();
+    // Setup logging to output all logs, and the MMIO trace when asked for. Logs go to stderr
+    // so they never mix with the guest's UART output.
+    let filter = if args.mmio_trace {
+        "info,remu::arch::riscv::bus=debug"
+    } else {
+        "info"
+    };
+    env_logger::Builder::from_env(Env::default().default_filter_or(filter))
+        .target(Target::Stderr)
+        .init();
 
     // open the file as binary
     let path = Path::new(&args.file);
@@ -34,6 +198,69 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     match Object::parse(&buffer)? {
         Object::Elf(elf) => {
             info!("elf: {:#?}", &elf.header);
+            loader::check_elf(&elf, 64)?;
+            if args.disassemble {
+                let color = !args.no_color
+                    && io::stdout().is_terminal()
+                    && env::var_os("NO_COLOR").is_none();
+                disassemble_elf(&elf, &buffer, &mut io::stdout().lock(), color)?;
+                return Ok(());
+            }
+            let mut cpu = RV64Cpu::new();
+            cpu.init();
+            if let Some(pattern) = args.poison {
+                cpu.set_poison(pattern);
+            }
+            cpu.set_isa(args.isa);
+            cpu.set_insts_per_tick(args.insts_per_tick);
+            cpu.set_mmio_trace(args.mmio_trace);
+            cpu.set_trap_illegal(args.trap_illegal);
+            cpu.set_halt_on_ebreak(args.halt_on_ebreak);
+            cpu.set_trace_len(args.trace_len);
+            cpu.set_trap_storm_limit(args.trap_storm_limit);
+            if let Some(path) = &args.uart_output {
+                cpu.set_uart_output(Box::new(File::create(path)?));
+            }
+            if args.semihosting {
+                cpu.set_semihosting(Some(Box::new(io::stdout())));
+            }
+            loader::load_elf(&mut cpu, &elf, &buffer)?;
+            if let Some(symbol) = &args.entry {
+                cpu.set_pc(loader::find_symbol(&elf, symbol)?);
+            }
+            if args.proxy {
+                cpu.set_proxy(Box::new(io::stdout()), loader::image_end(&elf));
+            }
+            match &args.bootrom {
+                // There is no device tree to hand over yet.
+                Some(None) => cpu.set_boot_handoff(cpu.pc(), 0),
+                Some(Some(path)) => cpu
+                    .set_bootrom(&fs::read(path)?)
+                    .map_err(|_| "the boot ROM is larger than 60 KiB")?,
+                None => {}
+            }
+            let halt = if args.interactive {
+                debugger::repl(&mut cpu, io::stdin().lock(), io::stdout())?
+            } else if let Some(count) = args.count_exit {
+                let halt = cpu.run_for(count);
+                if let Some(reason) = halt {
+                    info!("halted before {} instructions: {:?}", count, reason);
+                }
+                debugger::dump_registers(&cpu, &mut io::stdout())?;
+                halt
+            } else if args.verbose {
+                let stats = cpu.run_with_stats();
+                eprintln!("{}", stats);
+                Some(stats.halt)
+            } else {
+                Some(cpu.run())
+            };
+            if let Some(path) = &args.memory_dump {
+                dump_memory(&cpu, path, args.dump_range)?;
+            }
+            if let Some(status) = halt.map(exit_status).filter(|&status| status != 0) {
+                process::exit(status);
+            }
         }
         _ => {
             error!("Unsupported file format");
@@ -41,3 +268,74 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     }
     Ok(())
 }
+
+#[cfg(test)]
+mod test {
+    use remu::{
+        arch::riscv::cpu::{HaltReason, RV64Cpu},
+        cpu::Cpu,
+    };
+
+    use goblin::elf::Elf;
+
+    use super::{disassemble_elf, exit_status, parse_byte, parse_range};
+
+    #[test]
+    fn test_disassemble_elf() {
+        let buffer = include_bytes!("../tests/programs/sum.elf");
+        let elf = Elf::parse(buffer).unwrap();
+        let mut plain = Vec::new();
+        disassemble_elf(&elf, buffer, &mut plain, false).unwrap();
+        let plain = String::from_utf8(plain).unwrap();
+        assert!(plain.starts_with("\nDisassembly of section .text:\n\n"));
+        // the same lines the trace shows, without any escapes
+        let first = remu::disassemble(&buffer[0x1000..0x1004], 0x8000_0000)[0];
+        assert!(plain.contains(&format!("{}\n", first.1.pretty_print(first.0, first.2))));
+        assert!(!plain.contains('\x1b'));
+
+        let mut colored = Vec::new();
+        disassemble_elf(&elf, buffer, &mut colored, true).unwrap();
+        let colored = String::from_utf8(colored).unwrap();
+        assert!(colored.contains("\x1b["));
+        let mut stripped = String::new();
+        let mut rest = colored.as_str();
+        while let Some(start) = rest.find('\x1b') {
+            stripped.push_str(&rest[..start]);
+            rest = &rest[start + rest[start..].find('m').unwrap() + 1..];
+        }
+        stripped.push_str(rest);
+        assert_eq!(stripped, plain);
+    }
+
+    #[test]
+    fn test_parse_range() {
+        assert_eq!(parse_range("0x80001000:16"), Ok((0x8000_1000, 16)));
+        assert!(parse_range("0x80001000").is_err());
+        assert!(parse_range("foo:16").is_err());
+    }
+
+    #[test]
+    fn test_parse_byte() {
+        assert_eq!(parse_byte("0xaa"), Ok(0xaa));
+        assert!(parse_byte("0x100").is_err());
+    }
+
+    #[test]
+    fn test_exit_status() {
+        assert_eq!(exit_status(HaltReason::Exit(42)), 42);
+        assert_eq!(exit_status(HaltReason::Exit(0)), 0);
+        assert_eq!(exit_status(HaltReason::Exit(256)), 1);
+        assert_eq!(exit_status(HaltReason::Exit(0x1_0000)), 1);
+        assert_eq!(exit_status(HaltReason::Exit(258)), 2);
+        assert_eq!(exit_status(HaltReason::TrapStorm), 1);
+
+        // an illegal instruction is fatal
+        let mut cpu = RV64Cpu::new();
+        cpu.init();
+        cpu.load(vec![0; 4]);
+        cpu.set_pc(0x8000_0000);
+        let reason = cpu.run();
+        assert!(matches!(reason, HaltReason::Fatal(_)));
+        assert_ne!(exit_status(reason), 0);
+    }
+}
@@ -1,4 +1,7 @@
-use std::ops::{Index, IndexMut};
+use std::{
+    io::{self, Write},
+    ops::{Index, IndexMut},
+};
 
 pub struct Memory {
     pub data: Vec<u8>,
@@ -24,13 +27,50 @@ impl Memory {
         }
     }
 
-    pub fn load_data(&mut self, data: &[u8], addr: u64) {
-        for i in 0..data.len() {
-            self.data[addr as usize + i] = data[i];
+    /// Overwrite every byte with `byte`.
+    pub fn fill(&mut self, byte: u8) {
+        self.data.fill(byte);
+    }
+
+    /// Copy `data` to `addr`. Fails with the first address past the memory, without writing
+    /// anything, if the data does not fit.
+    pub fn load_data(&mut self, data: &[u8], addr: u64) -> Result<(), u64> {
+        let end = addr.saturating_add(data.len() as u64);
+        match self.data.get_mut(addr as usize..end as usize) {
+            Some(bytes) => {
+                bytes.copy_from_slice(data);
+                Ok(())
+            }
+            None => Err(self.data.len() as u64),
+        }
+    }
+
+    /// Write `len` bytes starting at `addr` to `out`.
+    pub fn dump(&self, out: &mut impl Write, addr: u64, len: u64) -> io::Result<()> {
+        let range = (addr as usize)..(addr.saturating_add(len) as usize);
+        match self.data.get(range) {
+            Some(bytes) => out.write_all(bytes),
+            None => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("range {:#x}+{:#x} is out of memory", addr, len),
+            )),
         }
     }
 
     pub fn load(&self, addr: u64, size: u64) -> u64 {
+        // Aligned little-endian accesses, by far the most common, read the value in one go.
+        if let Endianness::Little = self.endianness {
+            if matches!(size, 1 | 2 | 4 | 8) && addr.is_multiple_of(size) {
+                let mut bytes = [0; 8];
+                let start = addr as usize;
+                bytes[..size as usize].copy_from_slice(&self.data[start..start + size as usize]);
+                return u64::from_le_bytes(bytes);
+            }
+        }
+        self.load_bytewise(addr, size)
+    }
+
+    fn load_bytewise(&self, addr: u64, size: u64) -> u64 {
         let mut val: u64 = 0;
         match self.endianness {
             Endianness::Little => {
@@ -49,6 +89,18 @@ impl Memory {
     }
 
     pub fn store(&mut self, addr: u64, size: u64, val: u64) {
+        if let Endianness::Little = self.endianness {
+            if matches!(size, 1 | 2 | 4 | 8) && addr.is_multiple_of(size) {
+                let start = addr as usize;
+                self.data[start..start + size as usize]
+                    .copy_from_slice(&val.to_le_bytes()[..size as usize]);
+                return;
+            }
+        }
+        self.store_bytewise(addr, size, val);
+    }
+
+    fn store_bytewise(&mut self, addr: u64, size: u64, val: u64) {
         match self.endianness {
             Endianness::Little => {
                 for i in 0..size {
@@ -115,3 +167,42 @@ impl IndexMut<usize> for Memory {
         &mut self.data[index]
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::{Endianness, Memory};
+
+    #[test]
+    fn test_fast_path() {
+        let mut fast = Memory::new(Endianness::Little);
+        fast.init(64);
+        let mut slow = Memory::new(Endianness::Little);
+        slow.init(64);
+        let value: u64 = 0x0123_4567_89ab_cdef;
+        for size in [1, 2, 4, 8] {
+            for addr in 0..16 {
+                fast.store(addr, size, value.rotate_left(addr as u32 * 8));
+                slow.store_bytewise(addr, size, value.rotate_left(addr as u32 * 8));
+                assert_eq!(fast.data, slow.data, "store size {} at {}", size, addr);
+                assert_eq!(
+                    fast.load(addr, size),
+                    slow.load_bytewise(addr, size),
+                    "load size {} at {}",
+                    size,
+                    addr
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_load_data() {
+        let mut mem = Memory::new(Endianness::Little);
+        mem.init(16);
+        assert_eq!(mem.load_data(&[1, 2], 14), Ok(()));
+        assert_eq!(mem.data[14..], [1, 2]);
+        assert_eq!(mem.load_data(&[3; 4], 14), Err(16));
+        assert_eq!(mem.load_data(&[3], u64::MAX), Err(16));
+        assert_eq!(mem.data[14..], [1, 2]);
+    }
+}
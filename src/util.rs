@@ -33,9 +33,15 @@ pub(crate) fn float_classify(x: f32) -> u64 {
     res
 }
 
+/// Whether `value` is a NaN with the quiet bit, the top bit of the fraction, set.
 pub(crate) fn quiet_nan(value: f32) -> bool {
     let bits = value.to_bits();
-    (bits & 0x7f800000) == 0x7f800000 && (bits & 0x007fffff) != 0
+    (bits & 0x7fc00000) == 0x7fc00000
+}
+
+/// Whether `value` is a NaN without the quiet bit set.
+pub(crate) fn signaling_nan(value: f32) -> bool {
+    value.is_nan() && !quiet_nan(value)
 }
 
 pub(crate) fn double_classify(x: f64) -> u64 {
@@ -75,13 +81,35 @@ pub(crate) fn double_classify(x: f64) -> u64 {
 
 pub(crate) fn quiet_nan_double(value: f64) -> bool {
     let bits = value.to_bits();
-    (bits & 0x7ff0000000000000) == 0x7ff0000000000000 && (bits & 0x000fffffffffffff) != 0
+    (bits & 0x7ff8000000000000) == 0x7ff8000000000000
 }
 
-pub(crate) fn addr_add(addr: u64, offset: i32) -> u64 {
-    if offset.is_negative() {
-        addr - offset.wrapping_abs() as u32 as u64
-    } else {
-        addr + offset as u64
+pub(crate) fn signaling_nan_double(value: f64) -> bool {
+    value.is_nan() && !quiet_nan_double(value)
+}
+
+/// Round `x` to an integral value using a RISC-V rounding mode (RNE, RTZ, RDN, RUP or RMM).
+pub(crate) fn round_by_mode(x: f64, rm: u8) -> f64 {
+    match rm {
+        0 => x.round_ties_even(),
+        1 => x.trunc(),
+        2 => x.floor(),
+        3 => x.ceil(),
+        _ => x.round(),
     }
 }
+
+/// Parse a decimal or `0x`-prefixed hexadecimal integer.
+pub fn parse_int(s: &str) -> Result<u64, String> {
+    let res = match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        Some(hex) => u64::from_str_radix(hex, 16),
+        None => s.parse(),
+    };
+    res.map_err(|e| format!("invalid number `{}`: {}", s, e))
+}
+
+/// Add the sign-extended `offset` to `addr`, wrapping around the address space as address
+/// arithmetic does on the hart.
+pub(crate) fn addr_add(addr: u64, offset: i32) -> u64 {
+    (addr as i64).wrapping_add(offset as i64) as u64
+}
@@ -0,0 +1,87 @@
+use goblin::elf::Elf;
+use remu::{
+    arch::riscv::{
+        cpu::{HaltReason, RV64Cpu},
+        debugger,
+    },
+    cpu::Cpu,
+    loader,
+};
+
+/// Address of `result` in `programs/sum.elf`.
+const RESULT: u64 = 0x8000_00a0;
+/// Return address of the call to `main` in `_start`.
+const MAIN_RETURN: u64 = 0x8000_000c;
+
+#[test]
+fn test_run_elf() {
+    let buffer = include_bytes!("programs/sum.elf");
+    let elf = Elf::parse(buffer).unwrap();
+    loader::check_elf(&elf, 64).unwrap();
+
+    let mut cpu = RV64Cpu::new();
+    cpu.init();
+    loader::load_elf(&mut cpu, &elf, buffer).unwrap();
+    assert_eq!(cpu.pc(), 0x8000_0000);
+
+    assert_eq!(cpu.run(), HaltReason::Exit(0));
+    // a0 holds the return value of main
+    assert_eq!(cpu.x(10), 30);
+    // the stack pointer is restored after the call
+    assert_eq!(cpu.x(2), 0x8001_0000);
+
+    let mut result = Vec::new();
+    cpu.dump_dram(&mut result, Some((RESULT, 8))).unwrap();
+    assert_eq!(result, 30u64.to_le_bytes());
+}
+
+#[test]
+fn test_entry_symbol() {
+    let buffer = include_bytes!("programs/sum.elf");
+    let elf = Elf::parse(buffer).unwrap();
+
+    let mut cpu = RV64Cpu::new();
+    cpu.init();
+    loader::load_elf(&mut cpu, &elf, buffer).unwrap();
+    assert_eq!(
+        loader::find_symbol(&elf, "missing"),
+        Err(loader::LoadError::UndefinedSymbol("missing".to_string()))
+    );
+
+    // call main directly, returning into the tail of _start
+    cpu.set_pc(loader::find_symbol(&elf, "main").unwrap());
+    assert_eq!(cpu.pc(), 0x8000_0050);
+    cpu.set_x(1, MAIN_RETURN);
+    cpu.set_x(2, 0x8002_0000);
+
+    assert_eq!(cpu.run(), HaltReason::Exit(0));
+    assert_eq!(cpu.x(10), 30);
+    assert_eq!(cpu.x(2), 0x8002_0000);
+
+    let mut result = Vec::new();
+    cpu.dump_dram(&mut result, Some((RESULT, 8))).unwrap();
+    assert_eq!(result, 30u64.to_le_bytes());
+}
+
+#[test]
+fn test_run_for() {
+    let buffer = include_bytes!("programs/sum.elf");
+    let elf = Elf::parse(buffer).unwrap();
+
+    let mut cpu = RV64Cpu::new();
+    cpu.init();
+    loader::load_elf(&mut cpu, &elf, buffer).unwrap();
+
+    // auipc sp; auipc ra and jalr of the call land at the start of main
+    assert_eq!(cpu.run_for(3), None);
+    assert_eq!(cpu.pc(), loader::find_symbol(&elf, "main").unwrap());
+
+    let mut output = Vec::new();
+    debugger::dump_registers(&cpu, &mut output).unwrap();
+    let output = String::from_utf8(output).unwrap();
+    assert!(output.starts_with("pc   0x0000000080000050\n"));
+    assert!(output.contains("ra   0x000000008000000c\n"));
+
+    // a halt ends the count early
+    assert_eq!(cpu.run_for(1000), Some(HaltReason::Exit(0)));
+}
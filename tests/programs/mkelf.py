@@ -0,0 +1,85 @@
+#!/usr/bin/env python3
+"""Link a single-section RISC-V object into a minimal static ELF executable.
+
+There is no cross linker in the test environment, so the fixtures are assembled
+with `llvm-mc -mattr=-relax` (which resolves every fixup locally) and the `.text`
+section is placed at DRAM_BASE here:
+
+    llvm-mc -triple=riscv64 -mattr=-relax,-c -filetype=obj sum.S -o sum.o
+    python3 mkelf.py sum.o sum.elf
+"""
+
+import os
+import struct
+import subprocess
+import sys
+import tempfile
+
+BASE = 0x8000_0000
+TEXT_OFFSET = 0x1000
+EM_RISCV = 243
+
+
+def main(obj, out):
+    with tempfile.TemporaryDirectory() as tmp:
+        binary = os.path.join(tmp, "text.bin")
+        subprocess.run(
+            ["llvm-objcopy", "-O", "binary", "--only-section=.text", obj, binary],
+            check=True,
+        )
+        with open(binary, "rb") as f:
+            text = f.read()
+    symbols = []
+    nm = subprocess.run(["llvm-nm", obj], check=True, capture_output=True, text=True)
+    for line in nm.stdout.splitlines():
+        value, kind, name = line.split()
+        if kind.lower() == "t":
+            symbols.append((name, BASE + int(value, 16), kind == "T"))
+
+    # local symbols have to precede the global ones
+    symbols.sort(key=lambda sym: sym[2])
+    first_global = 1 + sum(not is_global for _, _, is_global in symbols)
+
+    strtab = b"\0"
+    symtab = bytes(24)
+    for name, value, is_global in symbols:
+        info = (1 if is_global else 0) << 4
+        symtab += struct.pack("<IBBHQQ", len(strtab), info, 0, 1, value, 0)
+        strtab += name.encode() + b"\0"
+    shstrtab = b"\0.text\0.symtab\0.strtab\0.shstrtab\0"
+
+    symtab_off = TEXT_OFFSET + len(text)
+    strtab_off = symtab_off + len(symtab)
+    shstrtab_off = strtab_off + len(strtab)
+    shoff = (shstrtab_off + len(shstrtab) + 7) & ~7
+
+    ehdr = b"\x7fELF" + bytes([2, 1, 1]) + bytes(9)
+    ehdr += struct.pack(
+        "<HHIQQQIHHHHHH", 2, EM_RISCV, 1, BASE, 64, shoff, 0, 64, 56, 1, 64, 5, 4
+    )
+    phdr = struct.pack(
+        "<IIQQQQQQ", 1, 7, TEXT_OFFSET, BASE, BASE, len(text), len(text), 0x1000
+    )
+    sections = [
+        bytes(64),
+        struct.pack("<IIQQQQIIQQ", 1, 1, 6, BASE, TEXT_OFFSET, len(text), 0, 0, 4, 0),
+        struct.pack(
+            "<IIQQQQIIQQ", 7, 2, 0, 0, symtab_off, len(symtab), 3, first_global, 8, 24
+        ),
+        struct.pack("<IIQQQQIIQQ", 15, 3, 0, 0, strtab_off, len(strtab), 0, 0, 1, 0),
+        struct.pack(
+            "<IIQQQQIIQQ", 23, 3, 0, 0, shstrtab_off, len(shstrtab), 0, 0, 1, 0
+        ),
+    ]
+
+    image = bytearray(ehdr + phdr)
+    image += bytes(TEXT_OFFSET - len(image))
+    image += text + symtab + strtab + shstrtab
+    image += bytes(shoff - len(image))
+    image += b"".join(sections)
+    with open(out, "wb") as f:
+        f.write(image)
+
+
+if __name__ == "__main__":
+    main(sys.argv[1], sys.argv[2])